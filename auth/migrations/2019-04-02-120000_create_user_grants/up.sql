@@ -0,0 +1,10 @@
+-- Scopes users granted to a provider on top of the ones needed to sign in
+CREATE TABLE user_grants (
+    user_id TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    -- Provider name: "google"
+    provider TEXT NOT NULL,
+    -- Space delimited, as reported by the provider
+    scopes TEXT NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (user_id, provider)
+);
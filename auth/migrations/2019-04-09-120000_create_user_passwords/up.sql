@@ -0,0 +1,11 @@
+-- Password credentials, for signing in without a login provider
+CREATE TABLE user_passwords (
+    user_id TEXT PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+    -- Lowercase email address, so a forgotten password can be reset by email
+    username TEXT NOT NULL UNIQUE,
+    -- Argon2id PHC string: "$argon2id$v=19$m=19456,t=2,p=1$…"
+    password_hash TEXT NOT NULL,
+    -- Which PEPPER_<n> was the hash's secret
+    pepper_id SMALLINT NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
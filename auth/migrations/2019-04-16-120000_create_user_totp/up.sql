@@ -0,0 +1,20 @@
+-- Authenticator app secrets for two factor authentication
+CREATE TABLE user_totp (
+    user_id TEXT PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+    -- Encrypted with TOTP_ENCRYPTION_KEY: base64 of nonce, ciphertext and tag
+    secret_ciphertext TEXT NOT NULL,
+    -- Only required at sign in once the user proved their app has it
+    confirmed BOOL NOT NULL DEFAULT false,
+    -- Time step of the last code accepted, so codes can't be replayed
+    last_used_step BIGINT NOT NULL DEFAULT 0,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Single use codes for when the authenticator app is lost
+CREATE TABLE user_recovery_codes (
+    user_id TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    -- SHA-256 hex of the normalized code
+    code_hash TEXT NOT NULL,
+    used_at TIMESTAMPTZ,
+    PRIMARY KEY (user_id, code_hash)
+);
@@ -0,0 +1,16 @@
+-- Passkeys and security keys, for signing in without a password or as a second factor
+CREATE TABLE user_webauthn_credentials (
+    -- base64url, as the browser hands it back
+    credential_id TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    -- COSE_Key from the registration
+    public_key BYTEA NOT NULL,
+    -- Counts up with each signature on authenticators which keep a counter
+    sign_count BIGINT NOT NULL DEFAULT 0,
+    -- Given by the user, to tell their keys apart
+    name TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    last_used_at TIMESTAMPTZ
+);
+
+CREATE INDEX user_webauthn_credentials_user_id ON user_webauthn_credentials (user_id);
@@ -0,0 +1,114 @@
+//! Maintenance commands which are run from the command line instead of served over http
+use actix::prelude::*;
+use actix_redis::RedisActor;
+use futures::{future, Future};
+use std::collections::BTreeMap;
+
+use crate::config::Config;
+use crate::mem::{models, MemExecutor, MemModel};
+use crate::prelude::*;
+
+/// Count the live sessions sealed with each pepper, so a retired pepper is only removed from the
+/// environment once no sessions depend on it anymore.
+pub fn pepper_report(config: Config) {
+    let sys = System::new("pepper-report");
+    let mem = MemExecutor::new(RedisActor::start(config.redis_url.clone()));
+
+    Arbiter::spawn(
+        count_by_pepper(&mem, |session: &models::LoginSession| session.pepper_id)
+            .join3(
+                count_by_pepper(&mem, |session: &models::UserSession| session.pepper_id),
+                count_by_pepper(&mem, |token: &models::RefreshToken| token.pepper_id),
+            )
+            .then(move |res| {
+                match res {
+                    Ok((login_counts, user_counts, refresh_counts)) => {
+                        print_pepper_report(&config, &login_counts, &user_counts, &refresh_counts)
+                    }
+                    Err(err) => eprintln!("Unable to count sessions: {}", err),
+                }
+                System::current().stop();
+                Ok(())
+            }),
+    );
+
+    sys.run();
+}
+
+fn count_by_pepper<T, F>(mem: &MemExecutor, pepper_id: F) -> AppFuture<BTreeMap<u8, usize>>
+where
+    T: serde::de::DeserializeOwned + MemModel + 'static,
+    F: Fn(&T) -> u8 + 'static,
+{
+    let mem = mem.clone();
+    Box::new(
+        mem.keys::<T>()
+            .and_then(move |keys| {
+                future::join_all(
+                    keys.iter()
+                        .map(|key| mem.get_json::<T>(key))
+                        .collect::<Vec<_>>(),
+                )
+            })
+            .map(move |sessions: Vec<Option<T>>| {
+                let mut counts = BTreeMap::new();
+                // sessions which expired after the scan are skipped
+                for session in sessions.iter().filter_map(Option::as_ref) {
+                    *counts.entry(pepper_id(session)).or_insert(0) += 1;
+                }
+                counts
+            }),
+    )
+}
+
+fn print_pepper_report(
+    config: &Config,
+    login_counts: &BTreeMap<u8, usize>,
+    user_counts: &BTreeMap<u8, usize>,
+    refresh_counts: &BTreeMap<u8, usize>,
+) {
+    let active_id = config.peppers.active_id();
+    let mut ids = config.peppers.ids();
+    ids.extend(login_counts.keys());
+    ids.extend(user_counts.keys());
+    ids.extend(refresh_counts.keys());
+    ids.sort();
+    ids.dedup();
+
+    println!(
+        "{:<8}{:<10}{:>16}{:>16}{:>16}",
+        "PEPPER", "STATUS", "LOGIN SESSIONS", "USER SESSIONS", "REFRESH TOKENS"
+    );
+    let mut retired_total = 0;
+    for id in ids {
+        let (login_count, user_count, refresh_count) = (
+            login_counts.get(&id).cloned().unwrap_or(0),
+            user_counts.get(&id).cloned().unwrap_or(0),
+            refresh_counts.get(&id).cloned().unwrap_or(0),
+        );
+        let status = if id == active_id {
+            "active"
+        } else {
+            retired_total += login_count + user_count + refresh_count;
+            if config.peppers.get(id).is_some() {
+                "retired"
+            } else {
+                // sessions with these tokens can no longer authenticate
+                "missing"
+            }
+        };
+        println!(
+            "{:<8}{:<10}{:>16}{:>16}{:>16}",
+            format!("{}", id),
+            status,
+            login_count,
+            user_count,
+            refresh_count
+        );
+    }
+    println!();
+    println!(
+        "{} live session(s) and refresh token(s) still use retired peppers",
+        retired_total
+    );
+}
@@ -0,0 +1,267 @@
+//! Passwordless sign in, where a one time link is sent to the person's email address.
+//!
+//! The link's page asks the person to confirm before the link is used, since some mail
+//! scanners open every link they find. It also only works in the browser which asked for it,
+//! so nobody can sign someone else's browser in to their own account by sending them a link.
+use actix::prelude::*;
+use actix_web::{http::header::CONTENT_TYPE, Form, HttpRequest, HttpResponse, Json, Query};
+use futures::{
+    future::{self, Either},
+    Future,
+};
+use std::sync::Arc;
+
+use super::login_errors::{self, LoginError};
+use super::{AppState, Config};
+use crate::auth;
+use crate::cookies;
+use crate::db::{self, users, DbExecutor};
+use crate::mail::{Email, SendEmail};
+use crate::mem::{models, sessions, MemExecutor};
+use crate::prelude::*;
+
+/// Recorded as [models::IAm::provider] for people who signed in by email
+pub const EMAIL_PROVIDER: &str = "email";
+/// Prefix for the external ids of email logins, see [users::ExtResourceId]
+pub const EMAIL_LOGIN_PREFIX: &str = "email";
+
+#[derive(Deserialize)]
+pub struct EmailStartBody {
+    email: String,
+    redirect_uri: Option<String>,
+    /// Name of the client app, for its entries in the redirect allowlist
+    client: Option<String>,
+}
+
+/// Email a sign in link for this login session
+pub fn start_email_login(
+    (login, req, body): (auth::AuthLogin, HttpRequest<AppState>, Json<EmailStartBody>),
+) -> AppFuture<HttpResponse> {
+    let settings: Arc<Config> = req.state().config.clone();
+    let mem: MemExecutor = req.state().mem.clone();
+    let mailer = req.state().mailer.clone();
+    if !settings.email_login {
+        return Box::new(future::err(Error::BadRequest(String::from(
+            "Signing in by email is not enabled",
+        ))));
+    }
+    let email = match normalize_email(&body.email) {
+        Some(email) => email,
+        None => {
+            return Box::new(future::err(Error::BadRequest(String::from(
+                "email is not a valid email address",
+            ))))
+        }
+    };
+    let redirect_uri_opt = body.redirect_uri.as_ref();
+    if let Some(redirect_uri) = redirect_uri_opt {
+        let client = body.client.as_ref().map(String::as_str);
+        if !settings
+            .login_redirect_allowlist
+            .allows(client, redirect_uri)
+        {
+            return Box::new(future::err(Error::BadRequest(format!(
+                "{} is not an allowed redirect_uri",
+                redirect_uri
+            ))));
+        }
+    }
+    let browser_binding = cookies::browser_binding(&req);
+
+    Box::new(
+        sessions::create_email_login(
+            &mem,
+            &login.access_key,
+            email.clone(),
+            redirect_uri_opt,
+            &browser_binding,
+            &settings.email_login_expiration(),
+        )
+        .and_then({
+            let settings = settings.clone();
+            move |token| {
+                mailer
+                    .send(SendEmail(sign_in_email(&settings, email, &token)))
+                    .flatten()
+            }
+        })
+        .map(move |_| {
+            HttpResponse::Ok()
+                .cookie(cookies::handoff_cookie(
+                    &settings,
+                    &browser_binding,
+                    &settings.email_login_expiration(),
+                ))
+                .json(json!({
+                    "success": "Sign in link sent",
+                }))
+        }),
+    )
+}
+
+#[derive(Deserialize)]
+pub struct EmailVerifyParams {
+    token: String,
+}
+
+/// The page the emailed link opens, which only uses the link once the person confirms
+pub fn email_login_page(
+    (query, req): (Query<EmailVerifyParams>, HttpRequest<AppState>),
+) -> HttpResponse {
+    // only hex tokens are ever sent, and the token is written into the page
+    if query.token.is_empty() || !query.token.chars().all(|c| c.is_ascii_hexdigit()) {
+        return login_errors::redirect_with_error(
+            &req.state().config.default_login_redirect(),
+            LoginError::InvalidRequest,
+        );
+    }
+    HttpResponse::Ok()
+        .header(CONTENT_TYPE, "text/html; charset=utf-8")
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html>
+<head><meta charset="utf-8"><title>Sign in</title></head>
+<body>
+<form method="post" action="verify">
+<input type="hidden" name="token" value="{}">
+<button type="submit">Continue signing in</button>
+</form>
+</body>
+</html>
+"#,
+            query.token
+        ))
+}
+
+/// Use the link, signing in its login session, and send the person back to the app
+pub fn verify_email_login(
+    (form, req): (Form<EmailVerifyParams>, HttpRequest<AppState>),
+) -> AppFuture<HttpResponse> {
+    let settings: Arc<Config> = req.state().config.clone();
+    let mem: MemExecutor = req.state().mem.clone();
+    let db: Addr<DbExecutor> = req.state().db.clone();
+    let login_session_expiration = settings.login_session_expiration();
+    let default_redirect = settings.default_login_redirect();
+    let browser_binding = req
+        .cookie(cookies::HANDOFF_COOKIE)
+        .map(|cookie| cookie.value().to_string());
+
+    Box::new(
+        sessions::take_email_login(&mem, &form.token, browser_binding)
+            .then(|taken| match taken {
+                Ok(Ok(email_login)) => Ok(email_login),
+                Ok(Err(handoff_error)) => Err((LoginError::from(handoff_error), None)),
+                Err(err) => {
+                    warn!("verify_email_login: taking email login failed: {:?}", err);
+                    Err((LoginError::ServerError, None))
+                }
+            })
+            .and_then(move |email_login: models::EmailLogin| {
+                let failure_redirect = email_login.redirect_uri.clone();
+                link_email_login(db, mem, email_login, login_session_expiration).map_err(
+                    move |err| {
+                        warn!("verify_email_login: linking failed: {:?}", err);
+                        (LoginError::ServerError, failure_redirect)
+                    },
+                )
+            })
+            .then(move |result| {
+                Ok::<_, Error>(match result {
+                    Ok(redirect_uri_opt) => HttpResponse::Found()
+                        .header("Location", redirect_uri_opt.unwrap_or(default_redirect))
+                        .finish(),
+                    Err((login_error, failure_redirect)) => login_errors::redirect_with_error(
+                        &failure_redirect.unwrap_or(default_redirect),
+                        login_error,
+                    ),
+                })
+            }),
+    )
+}
+
+/// Sign in the user with this email login, or fill in who is signing up
+fn link_email_login(
+    db: Addr<DbExecutor>,
+    mem: MemExecutor,
+    email_login: models::EmailLogin,
+    login_session_expiration: std::time::Duration,
+) -> AppFuture<Option<String>> {
+    let models::EmailLogin {
+        session_key,
+        email,
+        redirect_uri,
+        ..
+    } = email_login;
+    let login_key = auth::LoginAccessKey(session_key);
+    Box::new(
+        db.send(users::GetLoginForResource(users::ExtResourceId::new(
+            EMAIL_LOGIN_PREFIX,
+            &email,
+        )))
+        .flatten()
+        .and_then(move |user_login_opt: Option<db::models::UserLogin>| {
+            if let Some(user_login) = user_login_opt {
+                Either::A(sessions::link_login_session_to_user_id(
+                    &mem,
+                    &login_key,
+                    user_login.user_id,
+                    &login_session_expiration,
+                ))
+            } else {
+                Either::B(sessions::link_login_session_to_i_am(
+                    &mem,
+                    &login_key,
+                    models::IAm {
+                        provider: EMAIL_PROVIDER.to_string(),
+                        resource_name: email.clone(),
+                        email: Some(email),
+                        given_name: None,
+                        full_name: None,
+                        photo_url: None,
+                        granted_scopes: None,
+                    },
+                    &login_session_expiration,
+                ))
+            }
+        })
+        .map(move |_| redirect_uri),
+    )
+}
+
+fn sign_in_email(settings: &Config, to: String, token: &str) -> Email {
+    let link = format!(
+        "{}/auth/v0/email/verify?token={}",
+        settings.http_public_url.trim_end_matches('/'),
+        token
+    );
+    Email {
+        to,
+        subject: String::from("Your sign in link"),
+        text: format!(
+            "Open this link to sign in:\n\n{}\n\nIt expires in {} minutes and works once. If you did not try to sign in, you can ignore this email.\n",
+            link,
+            settings.email_login_expiration_secs / 60
+        ),
+    }
+}
+
+/// Email addresses are matched case insensitively, so their logins are stored lowercase
+pub(super) fn normalize_email(email: &str) -> Option<String> {
+    let email = email.trim().to_lowercase();
+    let mut parts = email.splitn(2, '@');
+    let valid = match (parts.next(), parts.next()) {
+        (Some(local), Some(domain)) => {
+            !local.is_empty()
+                && domain.contains('.')
+                && !domain.starts_with('.')
+                && !domain.ends_with('.')
+                && !domain.contains('@')
+        }
+        _ => false,
+    };
+    if valid && email.len() <= 254 && !email.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        Some(email)
+    } else {
+        None
+    }
+}
@@ -0,0 +1,86 @@
+use actix_web::{client, error, FutureResponse, HttpMessage};
+use futures::{future, Future};
+
+#[derive(Deserialize, Debug)]
+struct GithubTokenJson {
+    // success
+    pub access_token: Option<String>,
+    // error
+    pub error: Option<String>,
+    pub error_description: Option<String>,
+}
+
+pub fn exchange_code_for_token(
+    token_url: &str,
+    code: &str,
+    code_verifier: &str,
+    redirect_uri: &str,
+    client_id: &str,
+    client_secret: &str,
+) -> FutureResponse<String> {
+    // https://developer.github.com/apps/building-oauth-apps/authorizing-oauth-apps/#2-users-are-redirected-back-to-your-site-by-github
+    let params = [
+        ("code", code),
+        ("code_verifier", code_verifier),
+        ("client_id", client_id),
+        ("client_secret", client_secret),
+        ("redirect_uri", redirect_uri),
+    ];
+
+    Box::new(
+        client::post(token_url)
+            .header("User-Agent", "Actix-web")
+            .header("Accept-Encoding", "identity")
+            // otherwise the response is form encoded
+            .header("Accept", "application/json")
+            .form(&params)
+            .unwrap()
+            .send()
+            .timeout(std::time::Duration::from_secs(10))
+            .map_err(|e| {
+                warn!("Failed to send code params for Token exchange: {:?}", e);
+                error::ErrorFailedDependency("Code exchange send error")
+            })
+            .and_then(|resp: actix_web::client::ClientResponse| {
+                if resp.status().is_success() {
+                    future::Either::A(resp.json::<GithubTokenJson>().map_err(|e| {
+                        warn!("Failed to parse GithubTokenJson {:?}", e);
+                        error::ErrorFailedDependency("Code exchange json parse error")
+                    }))
+                } else {
+                    future::Either::B(future::err(error::ErrorBadRequest(format!(
+                        "Code exchange request error [{}], please try again",
+                        resp.status()
+                    ))))
+                }
+            })
+            .and_then(|token_json: GithubTokenJson| {
+                // GitHub reports errors with a 200 status
+                token_json.access_token.ok_or_else(|| {
+                    error::ErrorInternalServerError(format!(
+                        "Error with received tokens: {}",
+                        token_json
+                            .error_description
+                            .or(token_json.error)
+                            .unwrap_or("Access token missing".to_string())
+                    ))
+                })
+            }),
+    )
+}
+
+pub fn get_login_url(
+    authorize_url: &str,
+    state: &str,
+    code_challenge: &str,
+    redirect_uri: &str,
+    client_id: &str,
+) -> String {
+    // profile, and email addresses even when they are private
+    let scopes = "read:user%20user:email";
+
+    format!(
+        "{}?client_id={}&redirect_uri={}&scope={}&state={}&code_challenge={}&code_challenge_method=S256",
+        authorize_url, client_id, redirect_uri, scopes, state, code_challenge
+    )
+}
@@ -0,0 +1,90 @@
+use actix_web::{client, error, FutureResponse, HttpMessage};
+use futures::{future, Future};
+
+// https://developer.github.com/v3/users/#get-the-authenticated-user
+#[derive(Deserialize, Debug)]
+struct GithubUser {
+    id: u64,              // 583231
+    login: String,        // "octocat"
+    name: Option<String>, // "The Octocat"
+    avatar_url: Option<String>,
+}
+
+// https://developer.github.com/v3/users/emails/#list-email-addresses-for-a-user
+#[derive(Deserialize, Debug)]
+struct GithubEmail {
+    email: String,
+    primary: bool,
+    verified: bool,
+}
+
+#[derive(Debug)]
+pub struct IAm {
+    /// The numeric id, since logins can be renamed
+    pub id: String,
+    pub login: String,
+    pub name: Option<String>,
+    /// Primary email address, only when it has been verified
+    pub email: Option<String>,
+    pub avatar_url: Option<String>,
+}
+
+/// `api_url` is the root of the REST API, https://api.github.com
+pub fn who_am_i(api_url: &str, access_token: &str) -> FutureResponse<IAm> {
+    Box::new(
+        get_json::<GithubUser>(&format!("{}/user", api_url), access_token)
+            .join(get_json::<Vec<GithubEmail>>(
+                &format!("{}/user/emails", api_url),
+                access_token,
+            ))
+            .map(|(user, emails): (GithubUser, Vec<GithubEmail>)| {
+                trace!("Successfully retrieved IAm => {:?}", user);
+
+                IAm {
+                    id: user.id.to_string(),
+                    login: user.login,
+                    name: user.name,
+                    email: emails
+                        .into_iter()
+                        .find(|email| email.primary && email.verified)
+                        .map(|email| email.email),
+                    avatar_url: user.avatar_url,
+                }
+            }),
+    )
+}
+
+fn get_json<T>(url: &str, access_token: &str) -> FutureResponse<T>
+where
+    T: serde::de::DeserializeOwned + 'static,
+{
+    Box::new(
+        client::get(url)
+            // GitHub rejects requests without a User-Agent
+            .header("User-Agent", "Actix-web")
+            .header("Accept-Encoding", "identity")
+            .header("Accept", "application/vnd.github.v3+json")
+            .header("Authorization", format!("token {}", access_token))
+            .finish()
+            .unwrap()
+            .send()
+            .timeout(std::time::Duration::from_secs(10))
+            .map_err(|e| {
+                warn!("Failed to send WhoAmI for GithubUser {:?}", e);
+                error::ErrorInternalServerError("Who am I send error")
+            })
+            .and_then(|resp: actix_web::client::ClientResponse| {
+                if resp.status().is_success() {
+                    future::Either::A(resp.json::<T>().map_err(|e| {
+                        warn!("Failed to parse GitHub response {:?}", e);
+                        error::ErrorInternalServerError("Who am I json parse error")
+                    }))
+                } else {
+                    future::Either::B(future::err(error::ErrorBadRequest(format!(
+                        "Who am I request error [{}], please try again",
+                        resp.status()
+                    ))))
+                }
+            }),
+    )
+}
@@ -0,0 +1,2 @@
+pub mod github_oauth_client;
+pub mod github_user_client;
@@ -0,0 +1,4 @@
+//! Signing in with GitHub accounts
+pub mod clients;
+mod provider;
+pub use provider::{GithubEndpoints, GithubProvider};
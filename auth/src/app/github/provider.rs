@@ -0,0 +1,98 @@
+use futures::{future, Future};
+
+use super::clients::{github_oauth_client, github_user_client};
+use crate::app::providers::{provider_error, IdentityProvider, ProviderTokens};
+use crate::mem::models::IAm;
+use crate::prelude::*;
+
+const GITHUB_PROVIDER: &str = "github";
+
+/// Configurable so logins can be tested against a mock provider
+pub struct GithubEndpoints {
+    pub authorize_url: String,
+    pub token_url: String,
+    pub api_url: String,
+}
+
+pub struct GithubProvider {
+    client_id: String,
+    client_secret: String,
+    endpoints: GithubEndpoints,
+}
+
+impl GithubProvider {
+    pub fn new(client_id: String, client_secret: String, endpoints: GithubEndpoints) -> Self {
+        GithubProvider {
+            client_id,
+            client_secret,
+            endpoints,
+        }
+    }
+}
+
+impl IdentityProvider for GithubProvider {
+    fn name(&self) -> &str {
+        GITHUB_PROVIDER
+    }
+
+    fn login_prefix(&self) -> &str {
+        "gh"
+    }
+
+    fn login_url(
+        &self,
+        state: &str,
+        _nonce: &str,
+        code_challenge: &str,
+        redirect_uri: &str,
+    ) -> AppFuture<String> {
+        Box::new(future::ok(github_oauth_client::get_login_url(
+            &self.endpoints.authorize_url,
+            state,
+            code_challenge,
+            redirect_uri,
+            &self.client_id,
+        )))
+    }
+
+    fn exchange_code(
+        &self,
+        code: &str,
+        code_verifier: &str,
+        redirect_uri: &str,
+    ) -> AppFuture<ProviderTokens> {
+        Box::new(
+            github_oauth_client::exchange_code_for_token(
+                &self.endpoints.token_url,
+                code,
+                code_verifier,
+                redirect_uri,
+                &self.client_id,
+                &self.client_secret,
+            )
+            .map(|access_token| ProviderTokens {
+                access_token,
+                id_token: None,
+                granted_scopes: None,
+            })
+            .map_err(|err| provider_error(GITHUB_PROVIDER, err)),
+        )
+    }
+
+    fn who_am_i(&self, tokens: &ProviderTokens, _nonce: &str) -> AppFuture<IAm> {
+        Box::new(
+            github_user_client::who_am_i(&self.endpoints.api_url, &tokens.access_token)
+                .map(|i_am: github_user_client::IAm| IAm {
+                    email: i_am.email,
+                    // without a name, people are known by their login
+                    full_name: i_am.name.or(Some(i_am.login)),
+                    given_name: None,
+                    photo_url: i_am.avatar_url,
+                    resource_name: i_am.id,
+                    provider: GITHUB_PROVIDER.to_string(),
+                    granted_scopes: None,
+                })
+                .map_err(|err| provider_error(GITHUB_PROVIDER, err)),
+        )
+    }
+}
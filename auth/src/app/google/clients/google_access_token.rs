@@ -4,4 +4,8 @@ use chrono::{DateTime, Utc};
 pub struct GoogleAccessToken {
     pub access_token: String,
     pub expires_at: DateTime<Utc>,
+    /// Issued alongside the access token for the openid scope
+    pub id_token: Option<String>,
+    /// Scopes granted so far, including earlier grants with `include_granted_scopes`
+    pub scope: Option<String>,
 }
@@ -12,6 +12,9 @@ struct GoogleTokenAuthCodeJson {
     pub refresh_token: Option<String>,
     pub expires_in: Option<i64>,
     pub token_type: Option<String>,
+    pub id_token: Option<String>,
+    /// Space delimited, every scope the person has granted us so far
+    pub scope: Option<String>,
     // error
     pub error: Option<String>,
     pub error_description: Option<String>,
@@ -36,17 +39,17 @@ impl ExchangeResult {
 }
 
 pub fn exchange_code_for_token(
+    token_url: &str,
     code: &str,
+    code_verifier: &str,
     redirect_uri: &str,
     client_id: &str,
     client_secret: &str,
 ) -> FutureResponse<ExchangeResult> {
-    // Construct a request against http://localhost:8020/token, the access token endpoint
-    let google_token_endpoint = "https://www.googleapis.com/oauth2/v4/token";
-
     // https://developers.google.com/identity/protocols/OAuth2WebServer#offline
     let params = [
         ("code", code.as_ref()),
+        ("code_verifier", code_verifier),
         ("client_id", client_id),
         ("client_secret", client_secret),
         ("redirect_uri", &redirect_uri),
@@ -57,7 +60,7 @@ pub fn exchange_code_for_token(
     // https://github.com/actix/actix-web/issues/674#issuecomment-466720953
 
     Box::new(
-        client::post(google_token_endpoint)
+        client::post(token_url)
             .header("User-Agent", "Actix-web")
             .header("Accept-Encoding", "identity")
             .form(&params)
@@ -89,6 +92,8 @@ pub fn exchange_code_for_token(
                         let access_token = GoogleAccessToken {
                             access_token: access,
                             expires_at,
+                            id_token: token_map.id_token,
+                            scope: token_map.scope,
                         };
                         Ok(match token_map.refresh_token {
                             Some(refresh) => ExchangeResult::AccessAndRefreshTokens {
@@ -110,21 +115,23 @@ pub fn exchange_code_for_token(
     )
 }
 
+/// Always with `include_granted_scopes`, so tokens carry earlier grants along with the new ones
+/// https://developers.google.com/identity/protocols/OAuth2WebServer#incrementalAuth
 pub fn get_login_url(
+    authorize_url: &str,
     state: &str,
+    nonce: &str,
+    code_challenge: &str,
     redirect_uri: &str,
     client_id: &str,
     domain: Option<&str>,
+    scopes: &[String],
 ) -> String {
-    let oauth_endpoint = "https://accounts.google.com/o/oauth2/v2/auth";
-    // let calendar_scope = "https://www.googleapis.com/auth/calendar";
-    // let emails_readonly_scope = "https://www.googleapis.com/auth/user.emails.read";
-    let profile_scope = "https://www.googleapis.com/auth/userinfo.profile";
-    let scopes = format!("{}", profile_scope);
-    let nonce = crate::utils::secure_rand_hex(8);
+    // the scopes are urls themselves, and separated by encoded spaces
+    let scopes = scopes.join("%20");
 
     format!(
-        "{}?response_type=code&client_id={}&redirect_uri={}&scope={}&state={}&hd={}&nonce={}&prompt=select_account",
-        oauth_endpoint, client_id, redirect_uri, scopes, state, domain.unwrap_or(""), nonce
+        "{}?response_type=code&client_id={}&redirect_uri={}&scope={}&state={}&hd={}&nonce={}&code_challenge={}&code_challenge_method=S256&prompt=select_account&include_granted_scopes=true",
+        authorize_url, client_id, redirect_uri, scopes, state, domain.unwrap_or(""), nonce, code_challenge
     )
 }
@@ -1,5 +1,3 @@
-use super::GoogleAccessToken;
-
 use actix_web::{client, error, FutureResponse, HttpMessage};
 use futures::{future, Future};
 
@@ -72,12 +70,13 @@ pub struct IAm {
     pub photo_url: String,
 }
 
-pub fn who_am_i(access_token: &GoogleAccessToken) -> FutureResponse<IAm> {
+/// `people_url` is the resource of whoever signed in, https://people.googleapis.com/v1/people/me
+pub fn who_am_i(people_url: &str, access_token: &str) -> FutureResponse<IAm> {
     // https://people.googleapis.com/v1/{resourceName=people/*}
     let person_fields = "names,emailAddresses,photos";
     let url = format!(
-        "https://people.googleapis.com/v1/people/me?personFields={}&access_token={}",
-        person_fields, &access_token.access_token
+        "{}?personFields={}&access_token={}",
+        people_url, person_fields, access_token
     );
 
     Box::new(
@@ -4,6 +4,8 @@
 // use futures::future::{self, Either, Future};
 
 pub mod clients;
+mod provider;
+pub use provider::{GoogleAllowlist, GoogleEndpoints, GoogleProvider, GoogleScopes};
 // use clients::{google_oauth_client, google_people_client, GoogleAccessToken};
 /*
 use crate::db::user_tokens;
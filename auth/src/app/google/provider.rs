@@ -0,0 +1,293 @@
+use chrono::Utc;
+use futures::{future, Future};
+
+use super::clients::{google_oauth_client, google_people_client};
+use crate::app::providers::{provider_error, IdentityProvider, ProviderTokens};
+use crate::jwt;
+use crate::mem::models::IAm;
+use crate::prelude::*;
+
+const GOOGLE_PROVIDER: &str = "google";
+
+/// Google's ID tokens may name their issuer with or without the scheme
+const GOOGLE_ISSUERS: &[&str] = &["https://accounts.google.com", "accounts.google.com"];
+
+/// Clock skew allowed when checking the ID token's expiration
+const EXPIRATION_LEEWAY_SECS: i64 = 60;
+
+/// Configurable so logins can be tested against a mock provider
+pub struct GoogleEndpoints {
+    pub authorize_url: String,
+    pub token_url: String,
+    pub people_url: String,
+}
+
+/// Who may sign in, by the hosted domain of their Google Workspace account or by their verified
+/// email. When both are empty, every Google account may sign in.
+pub struct GoogleAllowlist {
+    pub domains: Vec<String>,
+    pub emails: Vec<String>,
+}
+
+impl GoogleAllowlist {
+    fn allows(&self, hosted_domain: Option<&str>, email: Option<&str>) -> bool {
+        if self.domains.is_empty() && self.emails.is_empty() {
+            return true;
+        }
+        let domain_allowed = hosted_domain
+            .map(|hd| {
+                self.domains
+                    .iter()
+                    .any(|domain| domain.eq_ignore_ascii_case(hd))
+            })
+            .unwrap_or(false);
+        let email_allowed = email
+            .map(|email| {
+                self.emails
+                    .iter()
+                    .any(|allowed| allowed.eq_ignore_ascii_case(email))
+            })
+            .unwrap_or(false);
+        domain_allowed || email_allowed
+    }
+}
+
+/// What to ask for at sign in, and what a user may grant later by name, like "calendar"
+pub struct GoogleScopes {
+    /// Must include openid for the ID token, which tells us the account's hosted domain, and email
+    /// for its email claims
+    pub login: Vec<String>,
+    /// Several entries may share a name to grant their scopes together
+    pub optional: Vec<(String, String)>,
+}
+
+impl GoogleScopes {
+    /// The scopes for `names`, if every name is configured
+    fn optional_scopes(&self, names: &[String]) -> Result<Vec<String>> {
+        let mut scopes = Vec::new();
+        for name in names {
+            let before = scopes.len();
+            scopes.extend(
+                self.optional
+                    .iter()
+                    .filter(|(scope_name, _)| scope_name == name)
+                    .map(|(_, scope)| scope.clone()),
+            );
+            if scopes.len() == before {
+                return Err(Error::BadRequest(format!(
+                    "{} is not an optional Google scope",
+                    name
+                )));
+            }
+        }
+        Ok(scopes)
+    }
+}
+
+// https://developers.google.com/identity/protocols/OpenIDConnect#obtainuserinfo
+#[derive(Deserialize)]
+struct GoogleIdClaims {
+    iss: String,
+    /// Our client id, Google only issues ID tokens for a single audience
+    aud: String,
+    exp: i64,
+    /// Echoes the nonce of the login url
+    nonce: Option<String>,
+    /// Only present for Google Workspace accounts
+    hd: Option<String>,
+    /// The account's primary email, with the email scope
+    email: Option<String>,
+    #[serde(default)]
+    email_verified: bool,
+}
+
+pub struct GoogleProvider {
+    client_id: String,
+    client_secret: String,
+    endpoints: GoogleEndpoints,
+    allowlist: GoogleAllowlist,
+    scopes: GoogleScopes,
+}
+
+impl GoogleProvider {
+    pub fn new(
+        client_id: String,
+        client_secret: String,
+        endpoints: GoogleEndpoints,
+        allowlist: GoogleAllowlist,
+        scopes: GoogleScopes,
+    ) -> Self {
+        GoogleProvider {
+            client_id,
+            client_secret,
+            endpoints,
+            allowlist,
+            scopes,
+        }
+    }
+
+    fn hosted_domain_hint(&self) -> Option<&str> {
+        // Google only takes a hint for one domain
+        match self.allowlist.domains.as_slice() {
+            [domain] => Some(domain.as_str()),
+            _ => None,
+        }
+    }
+}
+
+impl IdentityProvider for GoogleProvider {
+    fn name(&self) -> &str {
+        GOOGLE_PROVIDER
+    }
+
+    fn login_prefix(&self) -> &str {
+        "goog"
+    }
+
+    fn login_url(
+        &self,
+        state: &str,
+        nonce: &str,
+        code_challenge: &str,
+        redirect_uri: &str,
+    ) -> AppFuture<String> {
+        Box::new(future::ok(google_oauth_client::get_login_url(
+            &self.endpoints.authorize_url,
+            state,
+            nonce,
+            code_challenge,
+            redirect_uri,
+            &self.client_id,
+            self.hosted_domain_hint(),
+            &self.scopes.login,
+        )))
+    }
+
+    /// The consent screen only asks for the new scopes, see [google_oauth_client::get_login_url]
+    fn upgrade_url(
+        &self,
+        state: &str,
+        nonce: &str,
+        code_challenge: &str,
+        redirect_uri: &str,
+        scope_names: &[String],
+    ) -> AppFuture<String> {
+        let mut scopes = match self.scopes.optional_scopes(scope_names) {
+            Ok(scopes) => scopes,
+            Err(err) => return Box::new(future::err(err)),
+        };
+        // still signing in, so we can tell the grant came from the same account
+        scopes.extend(self.scopes.login.iter().cloned());
+        Box::new(future::ok(google_oauth_client::get_login_url(
+            &self.endpoints.authorize_url,
+            state,
+            nonce,
+            code_challenge,
+            redirect_uri,
+            &self.client_id,
+            self.hosted_domain_hint(),
+            &scopes,
+        )))
+    }
+
+    fn exchange_code(
+        &self,
+        code: &str,
+        code_verifier: &str,
+        redirect_uri: &str,
+    ) -> AppFuture<ProviderTokens> {
+        Box::new(
+            google_oauth_client::exchange_code_for_token(
+                &self.endpoints.token_url,
+                code,
+                code_verifier,
+                redirect_uri,
+                &self.client_id,
+                &self.client_secret,
+            )
+            .map(
+                |exchange: google_oauth_client::ExchangeResult| ProviderTokens {
+                    access_token: exchange.access_token().access_token.clone(),
+                    id_token: exchange.access_token().id_token.clone(),
+                    granted_scopes: exchange.access_token().scope.clone(),
+                },
+            )
+            .map_err(|err| provider_error(GOOGLE_PROVIDER, err)),
+        )
+    }
+
+    /// Checks the ID token answers our own login url before asking the People API
+    fn who_am_i(&self, tokens: &ProviderTokens, nonce: &str) -> AppFuture<IAm> {
+        if let Err(err) = id_token_claims(tokens, &self.client_id, nonce) {
+            return Box::new(future::err(provider_error(GOOGLE_PROVIDER, err)));
+        }
+        Box::new(
+            google_people_client::who_am_i(&self.endpoints.people_url, &tokens.access_token)
+                .map(|i_am: google_people_client::IAm| IAm {
+                    email: Some(i_am.email_address),
+                    full_name: Some(i_am.display_name),
+                    given_name: Some(i_am.given_name),
+                    photo_url: Some(i_am.photo_url),
+                    resource_name: i_am.resource_name,
+                    provider: GOOGLE_PROVIDER.to_string(),
+                    granted_scopes: None,
+                })
+                .map_err(|err| provider_error(GOOGLE_PROVIDER, err)),
+        )
+    }
+
+    /// The `hd` hint only picks the account, so the domain is checked again here. Both the domain
+    /// and the email come from the ID token, since the People API lists unverified addresses too.
+    fn check_allowed(&self, tokens: &ProviderTokens, nonce: &str, _i_am: &IAm) -> Result<()> {
+        let (hosted_domain, verified_email) = match id_token_claims(tokens, &self.client_id, nonce)
+        {
+            Ok(GoogleIdClaims {
+                hd,
+                email,
+                email_verified,
+                ..
+            }) => (hd, email.filter(|_| email_verified)),
+            Err(err) => {
+                return Err(Error::Forbidden(format!(
+                    "This Google account can't be checked: {}",
+                    err
+                )))
+            }
+        };
+        if self.allowlist.allows(
+            hosted_domain.as_ref().map(String::as_str),
+            verified_email.as_ref().map(String::as_str),
+        ) {
+            Ok(())
+        } else {
+            Err(Error::Forbidden(String::from(
+                "This Google account is not allowed to sign in.",
+            )))
+        }
+    }
+}
+
+/// The claims of the ID token, once they show Google issued it to us for the login url with `nonce`
+fn id_token_claims(
+    tokens: &ProviderTokens,
+    client_id: &str,
+    nonce: &str,
+) -> Result<GoogleIdClaims, String> {
+    let id_token = tokens.id_token.as_ref().ok_or("ID token missing")?;
+    // the ID token came straight from Google's token endpoint
+    let claims = jwt::unverified_claims::<GoogleIdClaims>(id_token)
+        .map_err(|err| format!("ID token invalid: {:?}", err))?;
+    if !GOOGLE_ISSUERS.contains(&claims.iss.as_str()) {
+        return Err(format!("ID token from another issuer: {}", claims.iss));
+    }
+    if claims.aud != client_id {
+        return Err(String::from("ID token for another audience"));
+    }
+    if claims.exp + EXPIRATION_LEEWAY_SECS < Utc::now().timestamp() {
+        return Err(String::from("ID token expired"));
+    }
+    if claims.nonce.as_ref().map(String::as_str) != Some(nonce) {
+        return Err(String::from("ID token nonce mismatch"));
+    }
+    Ok(claims)
+}
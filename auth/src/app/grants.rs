@@ -0,0 +1,193 @@
+//! Incremental authorization, where a signed in user grants a provider's optional scopes
+//! after signing in, like access to their calendar
+use actix::prelude::*;
+use actix_web::{HttpRequest, HttpResponse, Path, Query};
+use futures::{
+    future::{self, Either},
+    Future,
+};
+use std::sync::Arc;
+
+use super::login_errors::LoginError;
+use super::providers::IdentityProvider;
+use super::sessions::{provider_redirect_uri, provider_start_url, ProviderPath};
+use super::{AppState, Config};
+use crate::auth;
+use crate::db::{self, grants, users, DbExecutor};
+use crate::mem::{models, sessions, MemExecutor};
+use crate::prelude::*;
+
+#[derive(Deserialize)]
+pub struct GrantUrlQuery {
+    /// Space delimited names of the provider's optional scopes, like "calendar"
+    scope: String,
+    redirect_uri: Option<String>,
+    /// Name of the client app, for its entries in the redirect allowlist
+    client: Option<String>,
+}
+
+/// Where to send the user to grant more scopes, coming back through the provider's callback
+pub fn create_grant_url(
+    (user, req, path, query): (
+        auth::AuthUser,
+        HttpRequest<AppState>,
+        Path<ProviderPath>,
+        Query<GrantUrlQuery>,
+    ),
+) -> AppFuture<HttpResponse> {
+    let settings: Arc<Config> = req.state().config.clone();
+    let mem: MemExecutor = req.state().mem.clone();
+    let provider = match req.state().providers.require(&path.provider) {
+        Ok(provider) => provider,
+        Err(err) => return Box::new(future::err(err)),
+    };
+
+    let scope_names: Vec<String> = query.scope.split_whitespace().map(String::from).collect();
+    if scope_names.is_empty() {
+        return Box::new(future::err(Error::BadRequest(String::from(
+            "scope must name at least one scope to grant",
+        ))));
+    }
+    let redirect_uri_opt = query.redirect_uri.as_ref();
+    if let Some(redirect_uri) = redirect_uri_opt {
+        let client = query.client.as_ref().map(String::as_str);
+        if !settings
+            .login_redirect_allowlist
+            .allows(client, redirect_uri)
+        {
+            return Box::new(future::err(Error::BadRequest(format!(
+                "{} is not an allowed redirect_uri",
+                redirect_uri
+            ))));
+        }
+    }
+
+    Box::new(
+        sessions::create_grant_handoff(
+            &mem,
+            user.user.user_id,
+            scope_names.join(" "),
+            redirect_uri_opt,
+        )
+        .and_then({
+            let settings = settings.clone();
+            let provider = provider.clone();
+            // unknown scopes are refused now, rather than once the browser is on its way
+            move |handoff_state: sessions::HandoffState| {
+                provider
+                    .upgrade_url(
+                        &handoff_state.state,
+                        &handoff_state.nonce,
+                        &handoff_state.code_challenge,
+                        &provider_redirect_uri(&settings.http_public_url, provider.name()),
+                        &scope_names,
+                    )
+                    .map(move |_| handoff_state)
+            }
+        })
+        .map(move |handoff_state| {
+            HttpResponse::Ok().json(json!({
+                "url": provider_start_url(
+                    &settings.http_public_url,
+                    provider.name(),
+                    &handoff_state.state,
+                ),
+            }))
+        }),
+    )
+}
+
+/// The scopes the user has granted each provider
+pub fn list_user_grants(
+    (user, db): (auth::AuthUser, Addr<DbExecutor>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    db.send(grants::GetUserGrants {
+        user_id: user.user.user_id,
+    })
+    .flatten()
+    .map(|user_grants: Vec<db::models::UserGrant>| {
+        let grants: Vec<_> = user_grants
+            .iter()
+            .map(|grant| {
+                json!({
+                    "provider": grant.provider,
+                    "scopes": grant.scopes.split_whitespace().collect::<Vec<_>>(),
+                    "updated_at": grant.updated_at,
+                })
+            })
+            .collect();
+        HttpResponse::Ok().json(json!({
+            "grants": grants,
+        }))
+    })
+}
+
+/// Record the scopes granted in a callback, as long as the provider account belongs to the user
+/// Record the scopes a provider reported granting at sign in, like a later grant would
+pub(super) fn record_login_grant(
+    db: &Addr<DbExecutor>,
+    user_id: String,
+    provider: String,
+    granted_scopes: Option<String>,
+) -> AppFuture<()> {
+    match granted_scopes {
+        Some(scopes) => Box::new(
+            db.send(grants::UpsertUserGrant {
+                user_id,
+                provider,
+                scopes,
+            })
+            .flatten()
+            .map(|_| ()),
+        ),
+        None => Box::new(future::ok(())),
+    }
+}
+
+pub(super) fn record_callback_grant(
+    db: Addr<DbExecutor>,
+    provider: &Arc<IdentityProvider>,
+    user_id: String,
+    scopes: String,
+    handoff: models::StateHandoff,
+    i_am: models::IAm,
+) -> AppFuture<sessions::LinkOutput, LoginError> {
+    let provider_name = provider.name().to_string();
+    Box::new(
+        db.send(users::GetLoginForResource(users::ExtResourceId::new(
+            provider.login_prefix(),
+            &i_am.resource_name,
+        )))
+        .flatten()
+        .map_err(|err| {
+            warn!("record_callback_grant: getting login failed: {:?}", err);
+            LoginError::ServerError
+        })
+        .and_then(
+            move |user_login_opt: Option<db::models::UserLogin>| match user_login_opt {
+                Some(ref user_login) if user_login.user_id == user_id => Either::A(
+                    db.send(grants::UpsertUserGrant {
+                        user_id,
+                        provider: provider_name,
+                        scopes,
+                    })
+                    .flatten()
+                    .map(move |_| sessions::LinkOutput {
+                        redirect_uri_opt: handoff.redirect_uri,
+                    })
+                    .map_err(|err| {
+                        warn!("record_callback_grant: saving grant failed: {:?}", err);
+                        LoginError::ServerError
+                    }),
+                ),
+                _ => {
+                    info!(
+                        "record_callback_grant: {} is not a login of user {}",
+                        i_am.resource_name, user_id
+                    );
+                    Either::B(future::err(LoginError::AccountMismatch))
+                }
+            },
+        ),
+    )
+}
@@ -0,0 +1,114 @@
+use actix_web::{http::header::AUTHORIZATION, HttpRequest, HttpResponse};
+use chrono::{Duration, Utc};
+use futures::{future, Future};
+
+use super::AppState;
+use crate::auth;
+use crate::cookies;
+use crate::prelude::*;
+
+const HASURA_CLAIMS_NAMESPACE: &str = "https://hasura.io/jwt/claims";
+const HASURA_ROLE_HEADER: &str = "X-Hasura-Role";
+
+#[derive(Serialize)]
+struct HasuraClaims<'a> {
+    #[serde(rename = "x-hasura-user-id")]
+    user_id: &'a str,
+    #[serde(rename = "x-hasura-default-role")]
+    default_role: &'a str,
+    #[serde(rename = "x-hasura-allowed-roles")]
+    allowed_roles: Vec<&'a str>,
+}
+
+// Route handlers ↓
+/// Exchange a user session for a short-lived JWT which Hasura can verify on its own
+pub fn create_hasura_jwt(
+    (user, req): (auth::AuthUser, HttpRequest<AppState>),
+) -> Result<HttpResponse> {
+    let state: &AppState = req.state();
+    let signing_key = state.jwt_keys.signing_key().ok_or_else(|| {
+        error!("create_hasura_jwt: JWT_PRIVATE_KEY_PATH is not configured");
+        Error::InternalServerError
+    })?;
+    let config = &state.config;
+
+    let issued_at = Utc::now();
+    let expires_at = issued_at + Duration::seconds(config.jwt_expiration_secs as i64);
+    let user_id = &user.user.user_id;
+    let claims = json!({
+        "iss": config.http_public_url,
+        "sub": user_id,
+        "iat": issued_at.timestamp(),
+        "exp": expires_at.timestamp(),
+        "name": user.user.display_name,
+        HASURA_CLAIMS_NAMESPACE: HasuraClaims {
+            user_id,
+            default_role: &config.hasura_default_role,
+            allowed_roles: config.hasura_allowed_roles.split_whitespace().collect(),
+        },
+    });
+
+    Ok(HttpResponse::Ok().json(json!({
+        "token": signing_key.sign(&claims),
+        "token_type": "Bearer",
+        "expires_in": config.jwt_expiration_secs,
+    })))
+}
+
+/// Hasura's webhook authentication mode, which forwards the client's headers to us.
+/// Hasura only distinguishes 200 from 401, so every rejection is reported as unauthorized.
+pub fn hasura_webhook(req: HttpRequest<AppState>) -> AppFuture<HttpResponse> {
+    let config = req.state().config.clone();
+    let user_cookie = config.session_cookies && req.cookie(cookies::USER_COOKIE).is_some();
+    if !req.headers().contains_key(AUTHORIZATION) && !user_cookie {
+        return Box::new(future::ok(HttpResponse::Ok().json(json!({
+            HASURA_ROLE_HEADER: config.hasura_anonymous_role,
+        }))));
+    }
+    if !req.headers().contains_key(AUTHORIZATION) {
+        // Hasura asks with a GET whatever the client sent, and GraphQL mutations change state
+        if let Err(err) = cookies::verify_csrf(&req) {
+            return Box::new(future::err(err));
+        }
+    }
+
+    let requested_role: Option<String> = req
+        .headers()
+        .get(HASURA_ROLE_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from);
+
+    Box::new(
+        auth::authenticate_user(&req)
+            .map_err(|err| match err {
+                Error::InternalServerError => err,
+                other => {
+                    debug!("hasura_webhook: Rejected \"{}\"", other);
+                    Error::Unauthorized(String::from("Invalid credentials"))
+                }
+            })
+            .and_then(move |user: auth::AuthUser| {
+                let role = match requested_role {
+                    None => config.hasura_default_role.clone(),
+                    Some(role) => {
+                        if !config
+                            .hasura_allowed_roles
+                            .split_whitespace()
+                            .any(|allowed| allowed == role)
+                        {
+                            return Err(Error::Unauthorized(format!(
+                                "Role {} is not allowed",
+                                role
+                            )));
+                        }
+                        role
+                    }
+                };
+
+                Ok(HttpResponse::Ok().json(json!({
+                    "X-Hasura-User-Id": user.user.user_id,
+                    HASURA_ROLE_HEADER: role,
+                })))
+            }),
+    )
+}
@@ -0,0 +1,158 @@
+//! Token introspection (RFC 7662), so other services can check our access tokens over http
+use actix::prelude::*;
+use actix_web::{http::header::AUTHORIZATION, Form, HttpRequest, HttpResponse};
+use futures::{
+    future::{self, Either},
+    Future,
+};
+use ring::constant_time;
+
+use super::AppState;
+use crate::auth;
+use crate::db::{users, DbExecutor};
+use crate::mem::models;
+use crate::prelude::*;
+
+const BASIC_AUTH_PREFIX: &str = "Basic ";
+
+#[derive(Deserialize)]
+pub struct IntrospectionForm {
+    token: String,
+}
+
+// Route handlers ↓
+/// Report whether an access token is active and who it belongs to, without counting as using it.
+/// Callers authenticate with a client id and secret from `INTROSPECTION_CLIENTS`.
+pub fn introspect_token(
+    (req, form): (HttpRequest<AppState>, Form<IntrospectionForm>),
+) -> AppFuture<HttpResponse> {
+    if let Err(err) = authenticate_client(&req) {
+        return Box::new(future::err(err));
+    }
+    let mem = req.state().mem.clone();
+    let db: Addr<DbExecutor> = req.state().db.clone();
+    let config = req.state().config.clone();
+    let access_token = auth::AccessToken::from(form.into_inner().token);
+
+    Box::new(
+        auth::peek_user_session_for_token(&mem, &config, &access_token)
+            .map(|user_session| user_introspection(&user_session))
+            .or_else(move |err| match err {
+                Error::InternalServerError => Either::A(future::err(err)),
+                _ => Either::B(
+                    auth::login_session_for_token(&mem, &config, &access_token)
+                        .and_then(move |login_session| login_introspection(db, login_session)),
+                ),
+            })
+            .or_else(|err| match err {
+                Error::InternalServerError => Err(err),
+                // expired, revoked, or not one of our tokens, which callers need not tell apart
+                _ => Ok(json!({
+                    "active": false,
+                })),
+            })
+            .map(|introspection| {
+                HttpResponse::Ok()
+                    .header("Cache-Control", "no-store")
+                    .json(introspection)
+            }),
+    )
+}
+
+fn user_introspection(user_session: &models::UserSession) -> serde_json::Value {
+    let user: auth::User = user_session.user.clone().into();
+    json!({
+        "active": true,
+        "token_type": "Bearer",
+        "kind": "user",
+        "sub": user.user_id,
+        "username": user.display_name,
+        "iat": user_session.created_at.timestamp(),
+        "exp": user_session.expires_at.timestamp(),
+    })
+}
+
+/// A login session whose user still owes their second factor is not signed in yet, so it's
+/// reported as inactive rather than as the user
+fn login_introspection(
+    db: Addr<DbExecutor>,
+    login_session: models::LoginSession,
+) -> AppFuture<serde_json::Value> {
+    let introspection = json!({
+        "active": true,
+        "token_type": "Bearer",
+        "kind": "login",
+    });
+    let user_id = match login_session.user_id {
+        Some(user_id) => user_id,
+        None => return Box::new(future::ok(introspection)),
+    };
+    let second_factor_owed = match login_session.second_factor {
+        models::SecondFactor::Satisfied => Either::A(future::ok(false)),
+        models::SecondFactor::Pending => Either::A(future::ok(true)),
+        // not asked for yet, which it will be if the user has a second factor
+        models::SecondFactor::None => Either::B(
+            db.send(users::GetSecondFactorMethods {
+                user_id: user_id.clone(),
+            })
+            .flatten()
+            .map(|methods| !methods.is_empty()),
+        ),
+    };
+    Box::new(second_factor_owed.map(move |owed| {
+        if owed {
+            json!({
+                "active": false,
+            })
+        } else {
+            let mut introspection = introspection;
+            introspection["sub"] = json!(user_id);
+            introspection
+        }
+    }))
+}
+
+/// Check the HTTP Basic client credentials against `INTROSPECTION_CLIENTS`
+fn authenticate_client(req: &HttpRequest<AppState>) -> Result<()> {
+    let invalid = || Error::Unauthorized(String::from("Invalid client credentials"));
+    let credentials = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| value.starts_with(BASIC_AUTH_PREFIX))
+        .and_then(|value| base64::decode(&value[BASIC_AUTH_PREFIX.len()..]).ok())
+        .and_then(|decoded| String::from_utf8(decoded).ok())
+        .ok_or_else(invalid)?;
+    let (client_id, client_secret) = split_credentials(&credentials).ok_or_else(invalid)?;
+
+    let config = &req.state().config;
+    let known_secret = config
+        .introspection_clients
+        .split_whitespace()
+        .filter_map(split_credentials)
+        .find(|(id, _)| *id == client_id)
+        .map(|(_, secret)| secret);
+    match known_secret {
+        Some(secret)
+            if constant_time::verify_slices_are_equal(
+                secret.as_bytes(),
+                client_secret.as_bytes(),
+            )
+            .is_ok() =>
+        {
+            Ok(())
+        }
+        _ => {
+            debug!("authenticate_client: Rejected client \"{}\"", client_id);
+            Err(invalid())
+        }
+    }
+}
+
+fn split_credentials(credentials: &str) -> Option<(&str, &str)> {
+    let mut parts = credentials.splitn(2, ':');
+    match (parts.next(), parts.next()) {
+        (Some(id), Some(secret)) if !id.is_empty() => Some((id, secret)),
+        _ => None,
+    }
+}
@@ -0,0 +1,96 @@
+//! Provider logins end with a browser redirect, so their failures are sent back to the client app
+//! as `error` and `error_description` query parameters, like OAuth2 error responses.
+use actix_web::HttpResponse;
+use url::Url;
+
+use crate::mem::sessions::HandoffError;
+
+#[derive(Debug)]
+pub enum LoginError {
+    /// The person declined at the provider
+    AccessDenied,
+    /// The provider account may not sign in here, like one outside the allowed domains
+    AccountNotAllowed,
+    /// A user granting more scopes signed in to an account not linked to them
+    AccountMismatch,
+    /// A user adding a login signed in to an account which already signs in to another user
+    AccountInUse,
+    /// The provider came back without a code or state
+    InvalidRequest,
+    ExpiredState,
+    ReusedState,
+    BrowserMismatch,
+    /// The provider failed to exchange the code or to tell us who signed in
+    ProviderUnavailable,
+    ServerError,
+}
+
+impl LoginError {
+    /// The `error` parameter reported by the provider itself
+    pub fn from_provider(error: &str) -> LoginError {
+        match error {
+            "access_denied" => LoginError::AccessDenied,
+            _ => LoginError::ProviderUnavailable,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            LoginError::AccessDenied => "access_denied",
+            LoginError::AccountNotAllowed => "account_not_allowed",
+            LoginError::AccountMismatch => "account_mismatch",
+            LoginError::AccountInUse => "account_in_use",
+            LoginError::InvalidRequest => "invalid_request",
+            LoginError::ExpiredState => "expired_state",
+            LoginError::ReusedState => "reused_state",
+            LoginError::BrowserMismatch => "browser_mismatch",
+            LoginError::ProviderUnavailable => "provider_unavailable",
+            LoginError::ServerError => "server_error",
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            LoginError::AccessDenied => "Sign in was cancelled.",
+            LoginError::AccountNotAllowed => "This account is not allowed to sign in.",
+            LoginError::AccountMismatch => {
+                "Access must be granted from an account you already sign in with."
+            }
+            LoginError::AccountInUse => "This account already signs in to someone else.",
+            LoginError::InvalidRequest => "The login provider sent back an incomplete response.",
+            LoginError::ExpiredState => "The login took too long, please try again.",
+            LoginError::ReusedState => "This login was already completed, please try again.",
+            LoginError::BrowserMismatch => {
+                "The login must be completed in the browser it was started in."
+            }
+            LoginError::ProviderUnavailable => {
+                "The login provider could not be reached, please try again later."
+            }
+            LoginError::ServerError => "Something went wrong, please try again.",
+        }
+    }
+}
+
+impl From<HandoffError> for LoginError {
+    fn from(error: HandoffError) -> Self {
+        match error {
+            HandoffError::Expired => LoginError::ExpiredState,
+            HandoffError::Reused => LoginError::ReusedState,
+            HandoffError::BrowserMismatch => LoginError::BrowserMismatch,
+        }
+    }
+}
+
+/// Send the person back to `redirect_to` with the error added to its query
+pub fn redirect_with_error(redirect_to: &str, error: LoginError) -> HttpResponse {
+    let location = match Url::parse(redirect_to) {
+        Ok(mut url) => {
+            url.query_pairs_mut()
+                .append_pair("error", error.code())
+                .append_pair("error_description", error.description());
+            url.into_string()
+        }
+        Err(_) => redirect_to.to_string(),
+    };
+    HttpResponse::Found().header("Location", location).finish()
+}
@@ -0,0 +1,164 @@
+//! Signed in users adding more provider accounts to sign in with, and removing them again
+use actix::prelude::*;
+use actix_web::{HttpRequest, HttpResponse, Path, Query};
+use futures::{future, Future};
+use std::sync::Arc;
+
+use super::email;
+use super::login_errors::LoginError;
+use super::providers::IdentityProvider;
+use super::sessions::{provider_start_url, ProviderPath};
+use super::{AppState, Config};
+use crate::auth;
+use crate::db::{self, users, DbExecutor};
+use crate::mem::{models, sessions, MemExecutor};
+use crate::prelude::*;
+
+#[derive(Deserialize)]
+pub struct LinkUrlQuery {
+    redirect_uri: Option<String>,
+    /// Name of the client app, for its entries in the redirect allowlist
+    client: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct UnlinkQuery {
+    /// A login's `id`, as listed
+    id: String,
+}
+
+/// Where to send the user to sign in to the provider account they are adding, coming back
+/// through the provider's callback
+pub fn create_link_url(
+    (user, req, path, query): (
+        auth::AuthUser,
+        HttpRequest<AppState>,
+        Path<ProviderPath>,
+        Query<LinkUrlQuery>,
+    ),
+) -> AppFuture<HttpResponse> {
+    let settings: Arc<Config> = req.state().config.clone();
+    let mem: MemExecutor = req.state().mem.clone();
+    let provider = match req.state().providers.require(&path.provider) {
+        Ok(provider) => provider,
+        Err(err) => return Box::new(future::err(err)),
+    };
+
+    let redirect_uri_opt = query.redirect_uri.as_ref();
+    if let Some(redirect_uri) = redirect_uri_opt {
+        let client = query.client.as_ref().map(String::as_str);
+        if !settings
+            .login_redirect_allowlist
+            .allows(client, redirect_uri)
+        {
+            return Box::new(future::err(Error::BadRequest(format!(
+                "{} is not an allowed redirect_uri",
+                redirect_uri
+            ))));
+        }
+    }
+
+    Box::new(
+        sessions::create_link_handoff(&mem, user.user.user_id, redirect_uri_opt).map(
+            move |handoff_state: sessions::HandoffState| {
+                HttpResponse::Ok().json(json!({
+                    "url": provider_start_url(
+                        &settings.http_public_url,
+                        provider.name(),
+                        &handoff_state.state,
+                    ),
+                }))
+            },
+        ),
+    )
+}
+
+/// The accounts the user signs in with
+pub fn list_user_logins(
+    (user, req): (auth::AuthUser, HttpRequest<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let db: Addr<DbExecutor> = req.state().db.clone();
+    let providers = req.state().providers.clone();
+    db.send(users::GetUserLogins {
+        user_id: user.user.user_id,
+    })
+    .flatten()
+    .map(move |user_logins: Vec<db::models::UserLogin>| {
+        let logins: Vec<_> = user_logins
+            .iter()
+            .map(|user_login| {
+                let mut parts = user_login.external_id.splitn(2, '|');
+                let login_prefix = parts.next().unwrap_or_default();
+                // logins of providers no longer configured are still listed, by prefix
+                let provider = if login_prefix == email::EMAIL_LOGIN_PREFIX {
+                    email::EMAIL_PROVIDER.to_string()
+                } else {
+                    providers
+                        .by_login_prefix(login_prefix)
+                        .map(|provider| provider.name().to_string())
+                        .unwrap_or_else(|| login_prefix.to_string())
+                };
+                json!({
+                    "id": user_login.external_id,
+                    "provider": provider,
+                    "resource_name": parts.next(),
+                })
+            })
+            .collect();
+        HttpResponse::Ok().json(json!({
+            "logins": logins,
+        }))
+    })
+}
+
+/// Stop signing in with one of the user's accounts, unless it's their only way to sign in
+pub fn unlink_user_login(
+    (user, req, query): (auth::AuthUser, HttpRequest<AppState>, Query<UnlinkQuery>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let db: Addr<DbExecutor> = req.state().db.clone();
+    db.send(users::RemoveUserLogin {
+        user_id: user.user.user_id,
+        external_id: query.into_inner().id,
+    })
+    .flatten()
+    .map(|_| {
+        HttpResponse::Ok().json(json!({
+            "success": "Login removed",
+        }))
+    })
+}
+
+/// Add the account signed in to in a callback to the user, as long as it isn't someone else's
+pub(super) fn record_callback_link(
+    db: Addr<DbExecutor>,
+    provider: &Arc<IdentityProvider>,
+    user_id: String,
+    handoff: models::StateHandoff,
+    i_am: models::IAm,
+) -> AppFuture<sessions::LinkOutput, LoginError> {
+    Box::new(
+        db.send(users::AddUserLogin {
+            user_id: user_id.clone(),
+            external_id: users::ExtResourceId::new(provider.login_prefix(), &i_am.resource_name),
+        })
+        .flatten()
+        .map_err(|err| {
+            warn!("record_callback_link: adding login failed: {:?}", err);
+            LoginError::ServerError
+        })
+        .and_then(move |added| match added {
+            users::AddedLogin::Added | users::AddedLogin::AlreadyLinked => {
+                Ok(sessions::LinkOutput {
+                    redirect_uri_opt: handoff.redirect_uri,
+                })
+            }
+            users::AddedLogin::OtherUser => {
+                info!(
+                    "record_callback_link: {} already signs in to another user than {}",
+                    i_am.resource_name, user_id
+                );
+                Err(LoginError::AccountInUse)
+            }
+        }),
+    )
+}
@@ -1,5 +1,9 @@
 use crate::db::{new_pool, DbExecutor};
+use crate::mail::{self, MailExecutor};
 use crate::mem::MemExecutor;
+use crate::passwords::PasswordHasher;
+use crate::totp::SecretSealer;
+use crate::webauthn::RelyingParty;
 use actix::prelude::{Addr, SyncArbiter};
 use actix_redis::RedisActor;
 use actix_web::{
@@ -9,17 +13,43 @@ use actix_web::{
 };
 use std::sync::Arc;
 
+mod email;
+mod github;
 mod google;
+mod grants;
+mod hasura;
+mod introspect;
+mod login_errors;
+mod logins;
+mod oidc;
+mod passwords;
+mod providers;
 mod sessions;
+mod totp;
+mod webauthn;
+mod well_known;
 
 use crate::config::{Config, NotEmpty};
+use crate::jwt::{JwtKeyring, JwtSigningKey};
+use providers::ProviderRegistry;
 
 const NUM_DB_THREADS: usize = 4;
+const NUM_MAIL_THREADS: usize = 2;
 
 pub struct AppState {
     pub db: Addr<DbExecutor>,
     pub mem: MemExecutor,
     pub config: Arc<Config>,
+    /// Keys for signing and publishing JWTs for downstream services
+    pub jwt_keys: Arc<JwtKeyring>,
+    /// Identity providers people can sign in with
+    pub providers: Arc<ProviderRegistry>,
+    pub mailer: Addr<MailExecutor>,
+    pub passwords: Arc<PasswordHasher>,
+    /// Seals TOTP secrets before they are stored
+    pub totp: Arc<SecretSealer>,
+    /// Where passkeys are registered for and used from
+    pub webauthn: Arc<RelyingParty>,
 }
 
 fn index(_req: &HttpRequest<AppState>) -> &'static str {
@@ -36,6 +66,9 @@ pub fn create(config: Config) -> App<AppState> {
     let database_address =
         SyncArbiter::start(NUM_DB_THREADS, move || DbExecutor(database_pool.clone()));
 
+    let new_mailer = mail::mailer_from_config(&config);
+    let mailer_address = SyncArbiter::start(NUM_MAIL_THREADS, move || MailExecutor(new_mailer()));
+
     let redis_addr = RedisActor::start(config.redis_url.clone());
     let mem_executor = MemExecutor::new(redis_addr);
 
@@ -46,26 +79,130 @@ pub fn create(config: Config) -> App<AppState> {
                 cors_builder.allowed_origin(origin);
             }
         }
+        if config.session_cookies {
+            // without an allowlist every origin is allowed, and could send requests with our cookies
+            if config.http_allowed_origins.trim().is_empty() {
+                warn!("SESSION_COOKIES is on without HTTP_ALLOWED_ORIGINS, so only same origin requests send cookies");
+            } else {
+                cors_builder.supports_credentials();
+            }
+        }
         cors_builder.finish()
     };
 
+    let jwt_keys = JwtKeyring::new(
+        config.jwt_private_key_path.not_empty().map(|path| {
+            JwtSigningKey::from_file(&path)
+                .unwrap_or_else(|err| panic!("JWT_PRIVATE_KEY_PATH is invalid: {}", err))
+        }),
+        config
+            .jwt_verification_key_paths
+            .split_whitespace()
+            .map(|path| {
+                JwtSigningKey::from_file(path)
+                    .unwrap_or_else(|err| panic!("JWT_VERIFICATION_KEY_PATHS is invalid: {}", err))
+            })
+            .collect(),
+    );
+
+    let mut providers = ProviderRegistry::default();
+    if let Some(client_id) = config.google_oauth_client_id.not_empty() {
+        providers.register(google::GoogleProvider::new(
+            client_id,
+            config.google_oauth_client_secret.clone(),
+            google::GoogleEndpoints {
+                authorize_url: config.google_authorize_url.clone(),
+                token_url: config.google_token_url.clone(),
+                people_url: config.google_people_url.clone(),
+            },
+            google::GoogleAllowlist {
+                domains: config
+                    .google_allowed_domains
+                    .split_whitespace()
+                    .map(String::from)
+                    .collect(),
+                emails: config
+                    .google_allowed_emails
+                    .split_whitespace()
+                    .map(String::from)
+                    .collect(),
+            },
+            google::GoogleScopes {
+                login: config
+                    .google_scopes
+                    .split_whitespace()
+                    .map(String::from)
+                    .collect(),
+                optional: config
+                    .google_optional_scopes
+                    .split_whitespace()
+                    .map(|entry| {
+                        let mut parts = entry.splitn(2, '=');
+                        match (parts.next(), parts.next()) {
+                            (Some(name), Some(scope)) => (name.to_string(), scope.to_string()),
+                            _ => panic!("GOOGLE_OPTIONAL_SCOPES entry {} is not name=scope", entry),
+                        }
+                    })
+                    .collect(),
+            },
+        ));
+    }
+    if let Some(client_id) = config.github_oauth_client_id.not_empty() {
+        providers.register(github::GithubProvider::new(
+            client_id,
+            config.github_oauth_client_secret.clone(),
+            github::GithubEndpoints {
+                authorize_url: config.github_authorize_url.clone(),
+                token_url: config.github_token_url.clone(),
+                api_url: config.github_api_url.clone(),
+            },
+        ));
+    }
+    if let Some(issuer) = config.oidc_issuer_url.not_empty() {
+        providers.register(oidc::OidcProvider::new(
+            config.oidc_provider_name.clone(),
+            issuer,
+            config.oidc_client_id.clone(),
+            config.oidc_client_secret.clone(),
+            config.oidc_scopes.clone(),
+        ));
+    }
+
+    let passwords = Arc::new(PasswordHasher::new(&config));
+    let totp = Arc::new(SecretSealer::new(&config.totp_encryption_key));
+    let webauthn = Arc::new(RelyingParty::new(&config));
+
     let state = AppState {
         db: database_address.clone(),
         mem: mem_executor,
         config: Arc::new(config),
+        jwt_keys: Arc::new(jwt_keys),
+        providers: Arc::new(providers),
+        mailer: mailer_address,
+        passwords,
+        totp,
+        webauthn,
     };
 
     App::with_state(state)
         .middleware(Logger::default())
         .middleware(cors)
         .resource("/", |r| r.f(index))
+        .resource("/.well-known/jwks.json", |r| {
+            r.method(Method::GET).f(well_known::jwks)
+        })
+        .resource("/.well-known/openid-configuration", |r| {
+            r.method(Method::GET).f(well_known::openid_configuration)
+        })
         .scope("/auth", |scope| {
             scope.nested("/v0", |scope| {
                 scope
                     .resource("login/session", |r| {
                         r.method(Method::POST)
                             .with_async(sessions::create_login_session);
-                        r.method(Method::GET).with(sessions::login_session_i_am)
+                        r.method(Method::GET).with(sessions::login_session_i_am);
+                        r.method(Method::DELETE)
+                            .with_async(sessions::delete_login_session)
                     })
                     .resource("login/session/register", |r| {
                         r.method(Method::POST)
@@ -75,15 +212,128 @@ pub fn create(config: Config) -> App<AppState> {
                         r.method(Method::POST)
                             .with_async(sessions::create_user_session)
                     })
+                    .resource("login/session/totp", |r| {
+                        r.method(Method::POST).with_async(totp::verify_login_totp)
+                    })
+                    .resource("login/session/recovery_code", |r| {
+                        r.method(Method::POST)
+                            .with_async(totp::verify_login_recovery_code)
+                    })
+                    .resource("login/session/webauthn/start", |r| {
+                        r.method(Method::POST)
+                            .with_async(webauthn::start_webauthn_login)
+                    })
+                    .resource("login/session/webauthn/finish", |r| {
+                        r.method(Method::POST)
+                            .with_async(webauthn::finish_webauthn_login)
+                    })
+                    .resource("token/refresh", |r| {
+                        r.method(Method::POST)
+                            .with_async(sessions::refresh_user_session)
+                    })
+                    .resource("introspect", |r| {
+                        r.method(Method::POST)
+                            .with_async(introspect::introspect_token)
+                    })
                     .resource("me", |r| {
-                        r.method(Method::GET).with(sessions::user_session_i_am)
+                        r.method(Method::GET).with(sessions::user_session_i_am);
+                        r.method(Method::DELETE)
+                            .with_async(sessions::delete_user_session)
+                    })
+                    .resource("me/sessions", |r| {
+                        r.method(Method::GET)
+                            .with_async(sessions::list_user_sessions);
+                        r.method(Method::DELETE)
+                            .with_async(sessions::delete_all_user_sessions)
+                    })
+                    .resource("me/sessions/{session_id}", |r| {
+                        r.method(Method::DELETE)
+                            .with_async(sessions::delete_user_session_by_id)
+                    })
+                    .resource("me/password", |r| {
+                        r.method(Method::POST)
+                            .with_async(passwords::start_set_password);
+                        r.method(Method::PUT).with_async(passwords::change_password)
                     })
-                    .resource("google/login_url", |r| {
+                    .resource("me/password/confirm", |r| {
+                        r.method(Method::POST).with_async(passwords::set_password)
+                    })
+                    .resource("me/totp", |r| {
+                        r.method(Method::POST)
+                            .with_async(totp::start_totp_enrollment);
+                        r.method(Method::DELETE).with_async(totp::disable_totp)
+                    })
+                    .resource("me/totp/confirm", |r| {
+                        r.method(Method::POST).with_async(totp::confirm_totp)
+                    })
+                    .resource("me/totp/recovery_codes", |r| {
+                        r.method(Method::POST)
+                            .with_async(totp::regenerate_recovery_codes)
+                    })
+                    .resource("me/webauthn", |r| {
+                        r.method(Method::GET)
+                            .with_async(webauthn::list_webauthn_credentials)
+                    })
+                    .resource("me/webauthn/register/start", |r| {
                         r.method(Method::POST)
-                            .with_async(sessions::create_google_login_url);
+                            .with_async(webauthn::start_webauthn_registration)
+                    })
+                    .resource("me/webauthn/register/finish", |r| {
+                        r.method(Method::POST)
+                            .with_async(webauthn::finish_webauthn_registration)
+                    })
+                    .resource("me/webauthn/{credential_id}", |r| {
+                        r.method(Method::DELETE)
+                            .with_async(webauthn::delete_webauthn_credential)
+                    })
+                    .resource("me/logins", |r| {
+                        r.method(Method::GET).with_async(logins::list_user_logins);
+                        r.method(Method::DELETE)
+                            .with_async(logins::unlink_user_login)
+                    })
+                    .resource("me/logins/{provider}", |r| {
+                        r.method(Method::POST).with_async(logins::create_link_url)
+                    })
+                    .resource("me/grants", |r| {
+                        r.method(Method::GET).with_async(grants::list_user_grants)
+                    })
+                    .resource("me/grants/{provider}", |r| {
+                        r.method(Method::POST).with_async(grants::create_grant_url)
+                    })
+                    .resource("me/hasura/jwt", |r| {
+                        r.method(Method::POST).with(hasura::create_hasura_jwt)
+                    })
+                    .resource("hasura/webhook", |r| {
+                        r.method(Method::GET).with_async(hasura::hasura_webhook)
+                    })
+                    .resource("email/start", |r| {
+                        r.method(Method::POST).with_async(email::start_email_login)
+                    })
+                    .resource("email/verify", |r| {
+                        r.method(Method::GET).with(email::email_login_page);
+                        r.method(Method::POST).with_async(email::verify_email_login)
+                    })
+                    .resource("password/login", |r| {
+                        r.method(Method::POST).with_async(passwords::password_login)
+                    })
+                    .resource("password/reset/start", |r| {
+                        r.method(Method::POST)
+                            .with_async(passwords::start_password_reset)
+                    })
+                    .resource("password/reset", |r| {
+                        r.method(Method::POST).with_async(passwords::reset_password)
+                    })
+                    .resource("{provider}/login_url", |r| {
+                        r.method(Method::POST)
+                            .with_async(sessions::create_provider_login_url);
+                    })
+                    .resource("{provider}/start", |r| {
+                        r.method(Method::GET)
+                            .with_async(sessions::start_provider_login);
                     })
-                    .resource("google/callback", |r| {
-                        r.method(Method::GET).with_async(sessions::google_callback);
+                    .resource("{provider}/callback", |r| {
+                        r.method(Method::GET)
+                            .with_async(sessions::provider_callback);
                     })
             })
         })
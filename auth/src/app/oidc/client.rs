@@ -0,0 +1,120 @@
+use actix_web::{client, error, FutureResponse, HttpMessage};
+use futures::{future, Future};
+
+use crate::jwt::JwkSet;
+
+// https://openid.net/specs/openid-connect-discovery-1_0.html#ProviderMetadata
+#[derive(Deserialize, Debug)]
+pub struct DiscoveryDocument {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub jwks_uri: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct OidcTokenJson {
+    // success
+    pub access_token: Option<String>,
+    pub id_token: Option<String>,
+    // error
+    pub error: Option<String>,
+    pub error_description: Option<String>,
+}
+
+pub fn get_discovery_document(issuer: &str) -> FutureResponse<DiscoveryDocument> {
+    let url = format!(
+        "{}/.well-known/openid-configuration",
+        issuer.trim_end_matches('/')
+    );
+    get_json(&url)
+}
+
+pub fn get_jwks(jwks_uri: &str) -> FutureResponse<JwkSet> {
+    get_json(jwks_uri)
+}
+
+/// Resolves with the access token and ID token
+pub fn exchange_code_for_token(
+    token_endpoint: &str,
+    code: &str,
+    code_verifier: &str,
+    redirect_uri: &str,
+    client_id: &str,
+    client_secret: &str,
+) -> FutureResponse<(String, String)> {
+    let params = [
+        ("code", code),
+        ("code_verifier", code_verifier),
+        ("client_id", client_id),
+        ("client_secret", client_secret),
+        ("redirect_uri", redirect_uri),
+        ("grant_type", "authorization_code"),
+    ];
+
+    Box::new(
+        client::post(token_endpoint)
+            .header("User-Agent", "Actix-web")
+            .header("Accept-Encoding", "identity")
+            .header("Accept", "application/json")
+            .form(&params)
+            .unwrap()
+            .send()
+            .timeout(std::time::Duration::from_secs(10))
+            .map_err(|e| {
+                warn!("Failed to send code params for Token exchange: {:?}", e);
+                error::ErrorFailedDependency("Code exchange send error")
+            })
+            .and_then(|resp: actix_web::client::ClientResponse| {
+                // errors come back as json with a 400 status
+                resp.json::<OidcTokenJson>().map_err(|e| {
+                    warn!("Failed to parse OidcTokenJson {:?}", e);
+                    error::ErrorFailedDependency("Code exchange json parse error")
+                })
+            })
+            .and_then(|token_json: OidcTokenJson| {
+                match (token_json.access_token, token_json.id_token) {
+                    (Some(access_token), Some(id_token)) => Ok((access_token, id_token)),
+                    _ => Err(error::ErrorInternalServerError(format!(
+                        "Error with received tokens: {}",
+                        token_json
+                            .error_description
+                            .or(token_json.error)
+                            .unwrap_or("ID token missing".to_string())
+                    ))),
+                }
+            }),
+    )
+}
+
+fn get_json<T>(url: &str) -> FutureResponse<T>
+where
+    T: serde::de::DeserializeOwned + 'static,
+{
+    Box::new(
+        client::get(url)
+            .header("User-Agent", "Actix-web")
+            .header("Accept-Encoding", "identity")
+            .finish()
+            .unwrap()
+            .send()
+            .timeout(std::time::Duration::from_secs(10))
+            .map_err(|e| {
+                warn!("Failed to send OpenID Connect metadata request {:?}", e);
+                error::ErrorInternalServerError("Metadata send error")
+            })
+            .and_then(|resp: actix_web::client::ClientResponse| {
+                if resp.status().is_success() {
+                    future::Either::A(resp.json::<T>().map_err(|e| {
+                        warn!("Failed to parse OpenID Connect metadata {:?}", e);
+                        error::ErrorInternalServerError("Metadata json parse error")
+                    }))
+                } else {
+                    future::Either::B(future::err(error::ErrorInternalServerError(format!(
+                        "Metadata request error [{}]",
+                        resp.status()
+                    ))))
+                }
+            }),
+    )
+}
@@ -0,0 +1,4 @@
+//! Signing in with any OpenID Connect provider, like Keycloak, Okta or a local test provider
+mod client;
+mod provider;
+pub use provider::OidcProvider;
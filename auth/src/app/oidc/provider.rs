@@ -0,0 +1,253 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use futures::{future, Future};
+use url::Url;
+
+use super::client::{self, DiscoveryDocument};
+use crate::app::providers::{provider_error, IdentityProvider, ProviderTokens};
+use crate::jwt::{JwkSet, JwtVerifyError};
+use crate::mem::models::IAm;
+use crate::prelude::*;
+
+/// How long to keep the discovery document and signing keys before fetching them again
+const METADATA_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Clock skew allowed when checking the ID token's expiration
+const EXPIRATION_LEEWAY_SECS: i64 = 60;
+
+struct OidcMetadata {
+    discovery: DiscoveryDocument,
+    keys: JwkSet,
+}
+
+struct OidcInner {
+    name: String,
+    issuer: String,
+    client_id: String,
+    client_secret: String,
+    scopes: String,
+    metadata: Mutex<Option<(Instant, Arc<OidcMetadata>)>>,
+}
+
+/// Any provider implementing OpenID Connect discovery, with people identified by the `sub` of their ID token
+pub struct OidcProvider {
+    inner: Arc<OidcInner>,
+}
+
+impl OidcProvider {
+    pub fn new(
+        name: String,
+        issuer: String,
+        client_id: String,
+        client_secret: String,
+        scopes: String,
+    ) -> Self {
+        OidcProvider {
+            inner: Arc::new(OidcInner {
+                name,
+                issuer,
+                client_id,
+                client_secret,
+                scopes,
+                metadata: Mutex::new(None),
+            }),
+        }
+    }
+}
+
+// https://openid.net/specs/openid-connect-core-1_0.html#IDToken
+#[derive(Deserialize, Debug)]
+struct IdTokenClaims {
+    iss: String,
+    sub: String,
+    aud: Audience,
+    exp: i64,
+    nonce: Option<String>,
+    email: Option<String>,
+    email_verified: Option<bool>,
+    name: Option<String>,
+    given_name: Option<String>,
+    picture: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum Audience {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Audience {
+    fn contains(&self, client_id: &str) -> bool {
+        match self {
+            Audience::One(aud) => aud == client_id,
+            Audience::Many(auds) => auds.iter().any(|aud| aud == client_id),
+        }
+    }
+}
+
+impl IdentityProvider for OidcProvider {
+    fn name(&self) -> &str {
+        &self.inner.name
+    }
+
+    fn login_prefix(&self) -> &str {
+        &self.inner.name
+    }
+
+    fn login_url(
+        &self,
+        state: &str,
+        nonce: &str,
+        code_challenge: &str,
+        redirect_uri: &str,
+    ) -> AppFuture<String> {
+        let inner = self.inner.clone();
+        let state = state.to_string();
+        let nonce = nonce.to_string();
+        let code_challenge = code_challenge.to_string();
+        let redirect_uri = redirect_uri.to_string();
+        Box::new(metadata(self.inner.clone(), false).and_then(move |cached| {
+            let mut url = Url::parse(&cached.discovery.authorization_endpoint)
+                .map_err(|err| provider_error(&inner.name, err))?;
+            url.query_pairs_mut()
+                .append_pair("response_type", "code")
+                .append_pair("client_id", &inner.client_id)
+                .append_pair("redirect_uri", &redirect_uri)
+                .append_pair("scope", &inner.scopes)
+                .append_pair("state", &state)
+                .append_pair("nonce", &nonce)
+                .append_pair("code_challenge", &code_challenge)
+                .append_pair("code_challenge_method", "S256");
+            Ok(url.into_string())
+        }))
+    }
+
+    fn exchange_code(
+        &self,
+        code: &str,
+        code_verifier: &str,
+        redirect_uri: &str,
+    ) -> AppFuture<ProviderTokens> {
+        let inner = self.inner.clone();
+        let code = code.to_string();
+        let code_verifier = code_verifier.to_string();
+        let redirect_uri = redirect_uri.to_string();
+        Box::new(metadata(self.inner.clone(), false).and_then(move |cached| {
+            client::exchange_code_for_token(
+                &cached.discovery.token_endpoint,
+                &code,
+                &code_verifier,
+                &redirect_uri,
+                &inner.client_id,
+                &inner.client_secret,
+            )
+            .map(|(access_token, id_token)| ProviderTokens {
+                access_token,
+                id_token: Some(id_token),
+                granted_scopes: None,
+            })
+            .map_err(move |err| provider_error(&inner.name, err))
+        }))
+    }
+
+    fn who_am_i(&self, tokens: &ProviderTokens, nonce: &str) -> AppFuture<IAm> {
+        let id_token = match &tokens.id_token {
+            Some(id_token) => id_token.clone(),
+            None => {
+                return Box::new(future::err(provider_error(
+                    &self.inner.name,
+                    "ID token missing",
+                )))
+            }
+        };
+
+        let inner = self.inner.clone();
+        let nonce = nonce.to_string();
+        Box::new(
+            metadata(self.inner.clone(), false)
+                .and_then(move |cached| {
+                    match cached.keys.verify::<IdTokenClaims>(&id_token) {
+                        // the provider may have rotated its keys since we fetched them
+                        Err(JwtVerifyError::UnknownKey) => future::Either::A(
+                            metadata(inner.clone(), true).and_then(move |fresh| {
+                                let claims = fresh
+                                    .keys
+                                    .verify::<IdTokenClaims>(&id_token)
+                                    .map_err(|err| provider_error(&inner.name, err))?;
+                                Ok((inner, claims))
+                            }),
+                        ),
+                        result => future::Either::B(future::result(
+                            result
+                                .map_err(|err| provider_error(&inner.name, err))
+                                .map(|claims| (inner, claims)),
+                        )),
+                    }
+                })
+                .and_then(move |(inner, claims)| i_am_from_claims(&inner, claims, &nonce)),
+        )
+    }
+}
+
+/// Check the claims are meant for us, then take the profile from them
+fn i_am_from_claims(inner: &OidcInner, claims: IdTokenClaims, nonce: &str) -> Result<IAm> {
+    if claims.iss != inner.issuer {
+        return Err(provider_error(&inner.name, "ID token from another issuer"));
+    }
+    if !claims.aud.contains(&inner.client_id) {
+        return Err(provider_error(&inner.name, "ID token for another audience"));
+    }
+    if claims.exp + EXPIRATION_LEEWAY_SECS < Utc::now().timestamp() {
+        return Err(provider_error(&inner.name, "ID token expired"));
+    }
+    if claims.nonce.as_ref().map(String::as_str) != Some(nonce) {
+        return Err(provider_error(&inner.name, "ID token nonce mismatch"));
+    }
+
+    // unverified emails could belong to anyone, and providers may leave the claim out
+    let email_verified = claims.email_verified == Some(true);
+    Ok(IAm {
+        email: claims.email.filter(|_| email_verified),
+        full_name: claims.name,
+        given_name: claims.given_name,
+        photo_url: claims.picture,
+        resource_name: claims.sub,
+        provider: inner.name.clone(),
+        granted_scopes: None,
+    })
+}
+
+/// The provider's discovery document and signing keys, cached for [METADATA_TTL]
+fn metadata(inner: Arc<OidcInner>, refresh: bool) -> AppFuture<Arc<OidcMetadata>> {
+    if !refresh {
+        if let Some((fetched_at, metadata)) = &*inner.metadata.lock().unwrap() {
+            if fetched_at.elapsed() < METADATA_TTL {
+                return Box::new(future::ok(metadata.clone()));
+            }
+        }
+    }
+
+    let name = inner.name.clone();
+    Box::new(
+        client::get_discovery_document(&inner.issuer)
+            .and_then(move |discovery: DiscoveryDocument| {
+                if discovery.issuer != inner.issuer {
+                    return future::Either::B(future::err(
+                        actix_web::error::ErrorInternalServerError(format!(
+                            "Discovery document is for issuer {}",
+                            discovery.issuer
+                        )),
+                    ));
+                }
+                future::Either::A(client::get_jwks(&discovery.jwks_uri).map(move |keys| {
+                    let metadata = Arc::new(OidcMetadata { discovery, keys });
+                    *inner.metadata.lock().unwrap() = Some((Instant::now(), metadata.clone()));
+                    metadata
+                }))
+            })
+            .map_err(move |err| provider_error(&name, err)),
+    )
+}
@@ -0,0 +1,371 @@
+//! Signing in with a username and password, for deployments without a login provider.
+//! Usernames are email addresses, so forgotten passwords can be reset by email.
+use actix::prelude::*;
+use actix_web::{HttpRequest, HttpResponse, Json};
+use futures::{future, Future};
+use std::sync::Arc;
+
+use super::email::normalize_email;
+use super::{AppState, Config};
+use crate::auth;
+use crate::config::NotEmpty;
+use crate::db::{passwords, DbExecutor};
+use crate::mail::{Email, SendEmail};
+use crate::mem::{sessions, MemExecutor};
+use crate::passwords::PasswordHasher;
+use crate::prelude::*;
+
+#[derive(Deserialize)]
+pub struct PasswordLoginBody {
+    username: String,
+    password: String,
+}
+
+/// Sign in the login session, like a provider's callback does. A username is locked out for
+/// [sessions::PASSWORD_LOCKOUT] after [sessions::MAX_PASSWORD_ATTEMPTS] wrong passwords.
+pub fn password_login(
+    (login, req, body): (
+        auth::AuthLogin,
+        HttpRequest<AppState>,
+        Json<PasswordLoginBody>,
+    ),
+) -> AppFuture<HttpResponse> {
+    let settings: Arc<Config> = req.state().config.clone();
+    let mem: MemExecutor = req.state().mem.clone();
+    let db: Addr<DbExecutor> = req.state().db.clone();
+    let hasher: Arc<PasswordHasher> = req.state().passwords.clone();
+    if let Err(err) = check_enabled(&settings) {
+        return Box::new(future::err(err));
+    }
+    let body = body.into_inner();
+    // an invalid username is verified like an unknown one, to take as long
+    let username = normalize_email(&body.username).unwrap_or_default();
+
+    Box::new(
+        sessions::start_password_attempt(&mem, &username)
+            .and_then({
+                let username = username.clone();
+                move |allowed| {
+                    if !allowed {
+                        return future::Either::A(future::err(Error::Unauthorized(String::from(
+                            "Too many incorrect passwords, try again later",
+                        ))));
+                    }
+                    future::Either::B(
+                        db.send(passwords::VerifyPassword {
+                            username,
+                            password: body.password,
+                            hasher,
+                        })
+                        .flatten(),
+                    )
+                }
+            })
+            .and_then(|user_id_opt| {
+                user_id_opt.ok_or_else(|| {
+                    Error::Unauthorized(String::from("Username or password is incorrect"))
+                })
+            })
+            .and_then(move |user_id| {
+                sessions::password_attempt_succeeded(&mem, &username).and_then(move |_| {
+                    sessions::link_login_session_to_user_id(
+                        &mem,
+                        &login.access_key,
+                        user_id,
+                        &settings.login_session_expiration(),
+                    )
+                })
+            })
+            .map(|_| {
+                HttpResponse::Ok().json(json!({
+                    "success": "Signed in",
+                }))
+            }),
+    )
+}
+
+#[derive(Deserialize)]
+pub struct StartSetPasswordBody {
+    username: String,
+}
+
+/// Email a link to the username a user who signed in some other way wants for their password,
+/// since anyone who signs in with it must be able to reset it by email
+pub fn start_set_password(
+    (user, req, body): (
+        auth::AuthUser,
+        HttpRequest<AppState>,
+        Json<StartSetPasswordBody>,
+    ),
+) -> AppFuture<HttpResponse> {
+    let settings: Arc<Config> = req.state().config.clone();
+    let mem: MemExecutor = req.state().mem.clone();
+    let mailer = req.state().mailer.clone();
+    let checked = check_enabled(&settings).and_then(|_| {
+        normalize_email(&body.username)
+            .ok_or_else(|| Error::BadRequest(String::from("username must be an email address")))
+    });
+    let username = match checked {
+        Ok(username) => username,
+        Err(err) => return Box::new(future::err(err)),
+    };
+
+    Box::new(
+        sessions::create_password_setup(
+            &mem,
+            user.user.user_id,
+            username.clone(),
+            &settings.password_reset_expiration(),
+        )
+        .and_then(move |token| {
+            mailer
+                .send(SendEmail(password_setup_email(&settings, username, &token)))
+                .flatten()
+        })
+        .map(|_| {
+            HttpResponse::Ok().json(json!({
+                "success": "A link to set your password was sent to the username",
+            }))
+        }),
+    )
+}
+
+#[derive(Deserialize)]
+pub struct SetPasswordBody {
+    token: String,
+    password: String,
+}
+
+/// Give the user the password, with the username confirmed by the token from their email
+pub fn set_password(
+    (user, req, body): (auth::AuthUser, HttpRequest<AppState>, Json<SetPasswordBody>),
+) -> AppFuture<HttpResponse> {
+    let mem: MemExecutor = req.state().mem.clone();
+    let db: Addr<DbExecutor> = req.state().db.clone();
+    let hasher: Arc<PasswordHasher> = req.state().passwords.clone();
+    let body = body.into_inner();
+    if let Err(err) =
+        check_enabled(&req.state().config).and_then(|_| hasher.check_length(&body.password))
+    {
+        return Box::new(future::err(err));
+    }
+    let user_id = user.user.user_id;
+
+    Box::new(
+        sessions::take_password_setup(&mem, &body.token)
+            .and_then(|taken| {
+                taken.map_err(|_| {
+                    Error::BadRequest(String::from(
+                        "Link expired or was already used, please request a new one",
+                    ))
+                })
+            })
+            .and_then(move |password_setup| {
+                // the link only confirms the username for the user who asked for it
+                if password_setup.user_id != user_id {
+                    return future::Either::A(future::err(Error::Forbidden(String::from(
+                        "Link was sent to a different user",
+                    ))));
+                }
+                future::Either::B(
+                    db.send(passwords::CreatePassword {
+                        user_id,
+                        username: password_setup.username,
+                        password: body.password,
+                        hasher,
+                    })
+                    .flatten(),
+                )
+            })
+            .map(|_| {
+                HttpResponse::Ok().json(json!({
+                    "success": "Password set",
+                }))
+            }),
+    )
+}
+
+#[derive(Deserialize)]
+pub struct ChangePasswordBody {
+    current_password: String,
+    password: String,
+}
+
+pub fn change_password(
+    (user, req, body): (
+        auth::AuthUser,
+        HttpRequest<AppState>,
+        Json<ChangePasswordBody>,
+    ),
+) -> AppFuture<HttpResponse> {
+    let db: Addr<DbExecutor> = req.state().db.clone();
+    let hasher: Arc<PasswordHasher> = req.state().passwords.clone();
+    let body = body.into_inner();
+    if let Err(err) =
+        check_enabled(&req.state().config).and_then(|_| hasher.check_length(&body.password))
+    {
+        return Box::new(future::err(err));
+    }
+
+    Box::new(
+        db.send(passwords::ChangePassword {
+            user_id: user.user.user_id,
+            current_password: body.current_password,
+            password: body.password,
+            hasher,
+        })
+        .flatten()
+        .map(|_| {
+            HttpResponse::Ok().json(json!({
+                "success": "Password changed",
+            }))
+        }),
+    )
+}
+
+#[derive(Deserialize)]
+pub struct StartPasswordResetBody {
+    username: String,
+}
+
+/// Email a reset link if the username has a password. Responds the same either way, and
+/// without waiting for the email, so it can't be used to find out which usernames exist.
+pub fn start_password_reset(
+    (req, body): (HttpRequest<AppState>, Json<StartPasswordResetBody>),
+) -> AppFuture<HttpResponse> {
+    let settings: Arc<Config> = req.state().config.clone();
+    let mem: MemExecutor = req.state().mem.clone();
+    let db: Addr<DbExecutor> = req.state().db.clone();
+    let mailer = req.state().mailer.clone();
+    if let Err(err) = check_enabled(&settings) {
+        return Box::new(future::err(err));
+    }
+    let username = match normalize_email(&body.username) {
+        Some(username) => username,
+        None => {
+            return Box::new(future::err(Error::BadRequest(String::from(
+                "username must be an email address",
+            ))))
+        }
+    };
+
+    let reset = db
+        .send(passwords::GetPasswordUser {
+            username: username.clone(),
+        })
+        .flatten()
+        .and_then(move |user_id_opt| match user_id_opt {
+            Some(user_id) => future::Either::A(
+                sessions::create_password_reset(
+                    &mem,
+                    user_id,
+                    &settings.password_reset_expiration(),
+                )
+                .map(move |token| {
+                    mailer.do_send(SendEmail(password_reset_email(&settings, username, &token)))
+                }),
+            ),
+            None => future::Either::B(future::ok(())),
+        })
+        .map_err(|err| warn!("start_password_reset: Error starting reset: {:?}", err));
+    Arbiter::spawn(reset);
+
+    Box::new(future::ok(HttpResponse::Ok().json(json!({
+        "success": "If that username has a password, a reset link was sent to it",
+    }))))
+}
+
+#[derive(Deserialize)]
+pub struct ResetPasswordBody {
+    token: String,
+    password: String,
+}
+
+/// Set a new password with the token from a reset link, ending every session of the user
+pub fn reset_password(
+    (req, body): (HttpRequest<AppState>, Json<ResetPasswordBody>),
+) -> AppFuture<HttpResponse> {
+    let mem: MemExecutor = req.state().mem.clone();
+    let db: Addr<DbExecutor> = req.state().db.clone();
+    let hasher: Arc<PasswordHasher> = req.state().passwords.clone();
+    let body = body.into_inner();
+    if let Err(err) =
+        check_enabled(&req.state().config).and_then(|_| hasher.check_length(&body.password))
+    {
+        return Box::new(future::err(err));
+    }
+
+    Box::new(
+        sessions::take_password_reset(&mem, &body.token)
+            .and_then(|taken| {
+                taken.map_err(|_| {
+                    Error::BadRequest(String::from(
+                        "Reset link expired or was already used, please request a new one",
+                    ))
+                })
+            })
+            .and_then(move |password_reset| {
+                let user_id = password_reset.user_id;
+                db.send(passwords::ResetPassword {
+                    user_id: user_id.clone(),
+                    password: body.password,
+                    hasher,
+                })
+                .flatten()
+                .and_then(move |_| sessions::delete_user_sessions_for_user(&mem, user_id))
+            })
+            .map(|_| {
+                HttpResponse::Ok().json(json!({
+                    "success": "Password reset, please sign in again",
+                }))
+            }),
+    )
+}
+
+fn check_enabled(settings: &Config) -> Result<()> {
+    if settings.password_login {
+        Ok(())
+    } else {
+        Err(Error::BadRequest(String::from(
+            "Signing in with a password is not enabled",
+        )))
+    }
+}
+
+fn password_reset_email(settings: &Config, to: String, token: &str) -> Email {
+    let reset_url = settings
+        .password_reset_url
+        .not_empty()
+        .unwrap_or_else(|| format!("{}reset-password", settings.default_login_redirect()));
+    let separator = if reset_url.contains('?') { '&' } else { '?' };
+    Email {
+        to,
+        subject: String::from("Reset your password"),
+        text: format!(
+            "Open this link to choose a new password:\n\n{}{}token={}\n\nIt expires in {} minutes and works once. If you did not ask to reset your password, you can ignore this email.\n",
+            reset_url,
+            separator,
+            token,
+            settings.password_reset_expiration_secs / 60
+        ),
+    }
+}
+
+fn password_setup_email(settings: &Config, to: String, token: &str) -> Email {
+    let setup_url = settings
+        .password_setup_url
+        .not_empty()
+        .unwrap_or_else(|| format!("{}set-password", settings.default_login_redirect()));
+    let separator = if setup_url.contains('?') { '&' } else { '?' };
+    Email {
+        to,
+        subject: String::from("Set your password"),
+        text: format!(
+            "Open this link to choose a password, which you will sign in with using this email address:\n\n{}{}token={}\n\nIt expires in {} minutes and works once. If you did not ask to set a password, you can ignore this email.\n",
+            setup_url,
+            separator,
+            token,
+            settings.password_reset_expiration_secs / 60
+        ),
+    }
+}
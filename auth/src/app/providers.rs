@@ -0,0 +1,104 @@
+//! Identity providers people can sign in with, looked up by the name in their routes
+use futures::future;
+use std::sync::Arc;
+
+use crate::mem::models::IAm;
+use crate::prelude::*;
+
+/// Tokens from exchanging the authorization code of a callback
+pub struct ProviderTokens {
+    pub access_token: String,
+    /// Only from OpenID Connect providers
+    pub id_token: Option<String>,
+    /// Space delimited, when the provider reports which scopes were granted
+    pub granted_scopes: Option<String>,
+}
+
+/// An OAuth2 provider which can tell us who signed in
+pub trait IdentityProvider {
+    /// Used in the provider's routes and recorded as [IAm::provider]
+    fn name(&self) -> &str;
+
+    /// Prefix for the external ids of this provider's logins, see [crate::db::users::ExtResourceId]
+    fn login_prefix(&self) -> &str;
+
+    /// Where to send the person to sign in, coming back to `redirect_uri` with `state`
+    fn login_url(
+        &self,
+        state: &str,
+        nonce: &str,
+        code_challenge: &str,
+        redirect_uri: &str,
+    ) -> AppFuture<String>;
+
+    /// Like [IdentityProvider::login_url], but asking the person to also grant the named
+    /// optional scopes, on top of the ones they granted before
+    fn upgrade_url(
+        &self,
+        _state: &str,
+        _nonce: &str,
+        _code_challenge: &str,
+        _redirect_uri: &str,
+        _scope_names: &[String],
+    ) -> AppFuture<String> {
+        Box::new(future::err(Error::BadRequest(format!(
+            "{} has no optional scopes to grant",
+            self.name()
+        ))))
+    }
+
+    /// Exchange the code from the callback for tokens, proving we asked for it with the PKCE `code_verifier`
+    fn exchange_code(
+        &self,
+        code: &str,
+        code_verifier: &str,
+        redirect_uri: &str,
+    ) -> AppFuture<ProviderTokens>;
+
+    /// Fetch the profile of whoever signed in, where `nonce` was sent along with the login url
+    fn who_am_i(&self, tokens: &ProviderTokens, nonce: &str) -> AppFuture<IAm>;
+
+    /// Whether whoever signed in may use this service, checked before their login is linked.
+    /// `nonce` is the same as for [IdentityProvider::who_am_i].
+    fn check_allowed(&self, _tokens: &ProviderTokens, _nonce: &str, _i_am: &IAm) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct ProviderRegistry {
+    providers: Vec<Arc<IdentityProvider>>,
+}
+
+impl ProviderRegistry {
+    pub fn register<P: IdentityProvider + 'static>(&mut self, provider: P) {
+        self.providers.push(Arc::new(provider));
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<IdentityProvider>> {
+        self.providers
+            .iter()
+            .find(|provider| provider.name() == name)
+            .cloned()
+    }
+
+    /// The provider whose logins are stored with `login_prefix`
+    pub fn by_login_prefix(&self, login_prefix: &str) -> Option<Arc<IdentityProvider>> {
+        self.providers
+            .iter()
+            .find(|provider| provider.login_prefix() == login_prefix)
+            .cloned()
+    }
+
+    /// Like [ProviderRegistry::get], but an unknown name is the requester's mistake
+    pub fn require(&self, name: &str) -> Result<Arc<IdentityProvider>> {
+        self.get(name)
+            .ok_or_else(|| Error::BadRequest(format!("{} is not a supported login provider", name)))
+    }
+}
+
+/// Providers' own errors are logged rather than passed on to the person signing in
+pub fn provider_error<E: std::fmt::Debug>(provider: &str, err: E) -> Error {
+    warn!("provider_error: {} failed: {:?}", provider, err);
+    Error::InternalServerError
+}
@@ -1,5 +1,6 @@
 use actix::prelude::*;
-use actix_web::{HttpRequest, HttpResponse, Query};
+use actix_web::http::header::USER_AGENT;
+use actix_web::{dev::HttpResponseBuilder, HttpRequest, HttpResponse, Path, Query};
 use futures::{
     future::{self, Either},
     Future,
@@ -8,23 +9,66 @@ use std::sync::Arc;
 
 use super::{AppState, Config};
 use crate::auth;
+use crate::cookies;
 use crate::mem::{models, sessions, MemExecutor};
 use crate::prelude::*;
 
 use crate::db::{self, users, DbExecutor};
 
-use super::google::clients::{google_oauth_client, google_people_client};
+use super::email;
+use super::grants;
+use super::login_errors::{self, LoginError};
+use super::logins;
+use super::providers::{IdentityProvider, ProviderTokens};
 
 // Route handlers ↓
 pub fn create_login_session(
     req: HttpRequest<AppState>,
 ) -> impl Future<Item = HttpResponse, Error = Error> {
     let mem: MemExecutor = req.state().mem.clone();
-    let pepper = req.state().config.pepper_0.clone();
-    sessions::create_login_access_key(&mem).map(move |login_access_key| {
-        let access_key = auth::AccessKey::new_login_key(login_access_key);
-        HttpResponse::Ok().json(json!({
-            "access_token": auth::AccessToken::encrypt(access_key, &pepper),
+    let config: Arc<Config> = req.state().config.clone();
+    let pepper_id = config.peppers.active_id();
+    sessions::create_login_access_key(&mem, pepper_id, &config.login_session_expiration()).map(
+        move |login_access_key| {
+            let access_key = auth::AccessKey::new_login_key(pepper_id, login_access_key);
+            let access_token = auth::AccessToken::encrypt(access_key, &config.peppers);
+            if config.session_cookies {
+                let csrf_token = cookies::new_csrf_token();
+                HttpResponse::Ok()
+                    .cookie(cookies::token_cookie(
+                        &config,
+                        cookies::LOGIN_COOKIE,
+                        &access_token,
+                        &config.login_session_expiration(),
+                    ))
+                    .cookie(cookies::csrf_cookie(
+                        &config,
+                        &csrf_token,
+                        &config.login_session_expiration(),
+                    ))
+                    .json(json!({
+                        "csrf_token": csrf_token,
+                    }))
+            } else {
+                HttpResponse::Ok().json(json!({
+                    "access_token": access_token,
+                }))
+            }
+        },
+    )
+}
+
+pub fn delete_login_session(
+    (login, req, mem): (auth::AuthLogin, HttpRequest<AppState>, MemExecutor),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let config: Arc<Config> = req.state().config.clone();
+    sessions::delete_login_session(&mem, &login.access_key).map(move |_| {
+        let mut response = HttpResponse::Ok();
+        if config.session_cookies {
+            response.del_cookie(&cookies::removal_cookie(&config, cookies::LOGIN_COOKIE));
+        }
+        response.json(json!({
+            "success": "Login session ended",
         }))
     })
 }
@@ -33,12 +77,20 @@ pub fn login_session_i_am(login: auth::AuthLogin) -> HttpResponse {
     HttpResponse::Ok().json(json!({
         "i_am": login.i_am,
         "user_id": login.user_id,
+        "second_factor": login.second_factor.status(),
     }))
 }
 
 pub fn register_login_session(
-    (login, db, mem): (auth::AuthLogin, Addr<DbExecutor>, MemExecutor),
+    (login, req, db, mem): (
+        auth::AuthLogin,
+        HttpRequest<AppState>,
+        Addr<DbExecutor>,
+        MemExecutor,
+    ),
 ) -> impl Future<Item = HttpResponse, Error = Error> {
+    let login_session_expiration = req.state().config.login_session_expiration();
+    let providers = req.state().providers.clone();
     if login.user_id.is_some() {
         Either::A(future::ok(HttpResponse::Ok().json(json!({
             "success": "You already have a user!",
@@ -47,15 +99,23 @@ pub fn register_login_session(
         let login_key = login.access_key;
         Either::B(if let Some(i_am) = login.i_am {
             let full_name = i_am.full_name.clone();
-            Either::A(if i_am.provider != "google" {
-                Either::A(future::err(Error::BadRequest(format!(
+            let (provider_name, granted_scopes) =
+                (i_am.provider.clone(), i_am.granted_scopes.clone());
+            let login_prefix_opt = if i_am.provider == email::EMAIL_PROVIDER {
+                Some(email::EMAIL_LOGIN_PREFIX.to_string())
+            } else {
+                providers
+                    .get(&i_am.provider)
+                    .map(|provider| provider.login_prefix().to_string())
+            };
+            Either::A(match login_prefix_opt {
+                None => Either::A(future::err(Error::BadRequest(format!(
                     "{} as a login provider is not fully supported",
                     i_am.provider
-                ))))
-            } else {
-                Either::B(
+                )))),
+                Some(login_prefix) => Either::B(
                     db.send(users::CreateUser {
-                        external_id: users::ExtResourceId::google(&i_am.resource_name),
+                        external_id: users::ExtResourceId::new(&login_prefix, &i_am.resource_name),
                         display_name: i_am
                             .given_name
                             .or(i_am.full_name)
@@ -64,10 +124,24 @@ pub fn register_login_session(
                         photo_url: i_am.photo_url,
                     })
                     .flatten()
+                    .and_then(move |user| {
+                        grants::record_login_grant(
+                            &db,
+                            user.id.clone(),
+                            provider_name,
+                            granted_scopes,
+                        )
+                        .map(move |_| user)
+                    })
                     .and_then(move |user| {
                         let user_id = user.id.clone();
-                        sessions::link_login_session_to_user_id(&mem, &login_key, user_id)
-                            .map(move |_| user)
+                        sessions::link_login_session_to_user_id(
+                            &mem,
+                            &login_key,
+                            user_id,
+                            &login_session_expiration,
+                        )
+                        .map(move |_| user)
                     })
                     .map(|user| {
                         HttpResponse::Ok().json(json!({
@@ -75,7 +149,7 @@ pub fn register_login_session(
                             "user": user,
                         }))
                     }),
-                )
+                ),
             })
         } else {
             Either::B(future::err(Error::BadRequest(String::from(
@@ -88,32 +162,98 @@ pub fn register_login_session(
 pub fn create_user_session(
     (login, req, db): (auth::AuthLogin, HttpRequest<AppState>, Addr<DbExecutor>),
 ) -> impl Future<Item = HttpResponse, Error = Error> {
+    let login_key = login.access_key;
+    let second_factor_satisfied = login.second_factor == models::SecondFactor::Satisfied;
     match login.user_id {
         None => Either::A(future::err(Error::Unauthorized(String::from(
             "Login session is not associated with a user",
         )))),
         Some(user_id) => Either::B(
-            db.send(users::GetUserById { user_id })
-                .flatten()
-                .and_then(|db_user_opt| {
-                    db_user_opt.ok_or(Error::BadRequest(String::from(
-                        "User linked no longer exists",
-                    )))
-                })
-                .and_then(move |db_user| {
-                    let mem: MemExecutor = req.state().mem.clone();
-                    let pepper = req.state().config.pepper_0.clone();
-                    sessions::create_user_access_key(&mem, db_user).map(move |user_access_key| {
-                        let access_key = auth::AccessKey::new_user_key(user_access_key);
-                        HttpResponse::Ok().json(json!({
-                            "access_token": auth::AccessToken::encrypt(access_key, &pepper),
-                        }))
-                    })
-                }),
+            db.send(users::GetUserById {
+                user_id: user_id.clone(),
+            })
+            .flatten()
+            .and_then(|db_user_opt| {
+                db_user_opt.ok_or(Error::BadRequest(String::from(
+                    "User linked no longer exists",
+                )))
+            })
+            .and_then(move |db_user| {
+                if second_factor_satisfied {
+                    Either::A(future::ok((db_user, Vec::new())))
+                } else {
+                    Either::B(
+                        db.send(users::GetSecondFactorMethods { user_id })
+                            .flatten()
+                            .map(|methods| (db_user, methods)),
+                    )
+                }
+            })
+            .and_then(move |(db_user, second_factor_methods)| {
+                let mem: MemExecutor = req.state().mem.clone();
+                let config: Arc<Config> = req.state().config.clone();
+                if !second_factor_methods.is_empty() {
+                    return Either::A(
+                        sessions::require_second_factor(
+                            &mem,
+                            &login_key,
+                            &config.login_session_expiration(),
+                        )
+                        .map(move |_| {
+                            HttpResponse::Unauthorized().json(json!({
+                                "error": "second_factor_required",
+                                "methods": second_factor_methods,
+                            }))
+                        }),
+                    );
+                }
+                let pepper_id = config.peppers.active_id();
+                Either::B(
+                    sessions::create_user_access_key(
+                        &mem,
+                        db_user,
+                        pepper_id,
+                        session_client(&req),
+                        &config.user_session_expiration(),
+                        &config.refresh_token_expiration(),
+                    )
+                    .map(move |user_access_keys| {
+                        user_access_keys_response(&config, pepper_id, user_access_keys)
+                    }),
+                )
+            }),
         ),
     }
 }
 
+/// Exchange the refresh token from the Authorization header for a new user session
+/// and the next refresh token. Each refresh token can only be used once.
+pub fn refresh_user_session(
+    req: HttpRequest<AppState>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let mem: MemExecutor = req.state().mem.clone();
+    let config: Arc<Config> = req.state().config.clone();
+    let pepper_id = config.peppers.active_id();
+    let client = session_client(&req);
+    future::result(auth::authenticate_refresh(&req))
+        .and_then({
+            let config = config.clone();
+            move |refresh_key| {
+                sessions::refresh_user_access_key(
+                    &mem,
+                    &refresh_key,
+                    pepper_id,
+                    client,
+                    &config.user_session_expiration(),
+                    &config.refresh_token_expiration(),
+                )
+            }
+        })
+        .map(move |user_access_keys| {
+            user_access_keys_response(&config, pepper_id, user_access_keys)
+        })
+}
+
 pub fn user_session_i_am(user: auth::AuthUser) -> HttpResponse {
     HttpResponse::Ok().json(json!({
         "user_id": &user.user.user_id,
@@ -121,121 +261,502 @@ pub fn user_session_i_am(user: auth::AuthUser) -> HttpResponse {
     }))
 }
 
+pub fn delete_user_session(
+    (user, req, mem): (auth::AuthUser, HttpRequest<AppState>, MemExecutor),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let config: Arc<Config> = req.state().config.clone();
+    sessions::delete_user_session(&mem, &user.user.user_id, &user.access_key).map(move |_| {
+        logged_out_response(&config).json(json!({
+            "success": "Logged out",
+        }))
+    })
+}
+
+/// List the user's active sessions, so they can spot ones they don't recognize
+pub fn list_user_sessions(
+    (user, mem): (auth::AuthUser, MemExecutor),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let current_key = user.access_key.0;
+    sessions::get_user_sessions(&mem, &user.user.user_id).map(move |user_sessions| {
+        let sessions: Vec<_> = user_sessions
+            .iter()
+            .map(|user_session| {
+                json!({
+                    "id": user_session.public_id(),
+                    "created_at": user_session.created_at,
+                    "last_seen_at": user_session.last_seen_at,
+                    "expires_at": user_session.expires_at,
+                    "ip": user_session.client.ip,
+                    "user_agent": user_session.client.user_agent,
+                    "current": user_session.key == current_key,
+                })
+            })
+            .collect();
+        HttpResponse::Ok().json(json!({
+            "sessions": sessions,
+        }))
+    })
+}
+
+#[derive(Deserialize)]
+pub struct UserSessionPath {
+    session_id: String,
+}
+
+/// Revoke one of the user's sessions by the id from [list_user_sessions]
+pub fn delete_user_session_by_id(
+    (user, mem, path): (auth::AuthUser, MemExecutor, Path<UserSessionPath>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let session_id = path.into_inner().session_id;
+    sessions::delete_user_session_by_public_id(&mem, &user.user.user_id, session_id).and_then(
+        |found| {
+            if found {
+                Ok(HttpResponse::Ok().json(json!({
+                    "success": "Session revoked",
+                })))
+            } else {
+                Err(Error::BadRequest(String::from("Session does not exist")))
+            }
+        },
+    )
+}
+
+/// Log out everywhere, including the session making this request
+pub fn delete_all_user_sessions(
+    (user, req, mem): (auth::AuthUser, HttpRequest<AppState>, MemExecutor),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let config: Arc<Config> = req.state().config.clone();
+    sessions::delete_user_sessions_for_user(&mem, user.user.user_id).map(move |deleted_count| {
+        logged_out_response(&config).json(json!({
+            "success": "Logged out everywhere",
+            "deleted_sessions": deleted_count,
+        }))
+    })
+}
+
 #[derive(Deserialize)]
 pub struct LoginUrlQuery {
     redirect_uri: Option<String>,
+    /// Name of the client app, for its entries in the redirect allowlist
+    client: Option<String>,
 }
 
-pub fn create_google_login_url(
-    (login, req, query): (auth::AuthLogin, HttpRequest<AppState>, Query<LoginUrlQuery>),
-) -> impl Future<Item = HttpResponse, Error = Error> {
+#[derive(Deserialize)]
+pub struct ProviderPath {
+    pub(super) provider: String,
+}
+
+pub fn create_provider_login_url(
+    (login, req, path, query): (
+        auth::AuthLogin,
+        HttpRequest<AppState>,
+        Path<ProviderPath>,
+        Query<LoginUrlQuery>,
+    ),
+) -> AppFuture<HttpResponse> {
     let settings: Arc<Config> = req.state().config.clone();
     let mem: MemExecutor = req.state().mem.clone();
+    let provider = match req.state().providers.require(&path.provider) {
+        Ok(provider) => provider,
+        Err(err) => return Box::new(future::err(err)),
+    };
 
     let redirect_uri_opt = query.redirect_uri.as_ref();
+    if let Some(redirect_uri) = redirect_uri_opt {
+        let client = query.client.as_ref().map(String::as_str);
+        if !settings
+            .login_redirect_allowlist
+            .allows(client, redirect_uri)
+        {
+            return Box::new(future::err(Error::BadRequest(format!(
+                "{} is not an allowed redirect_uri",
+                redirect_uri
+            ))));
+        }
+    }
 
-    sessions::create_login_handoff(&mem, &login.access_key, redirect_uri_opt)
+    Box::new(
+        sessions::create_login_handoff(
+            &mem,
+            &login.access_key,
+            redirect_uri_opt,
+            &settings.login_session_expiration(),
+        )
         .map(move |handoff_state: sessions::HandoffState| {
-            google_oauth_client::get_login_url(
-                &handoff_state.0,
-                &google_redirect_uri(&settings.http_public_url),
-                &settings.google_oauth_client_id,
-                None,
-            )
-        })
-        .map(|login_url| {
             HttpResponse::Ok().json(json!({
-                "url": login_url,
+                "url": provider_start_url(
+                    &settings.http_public_url,
+                    provider.name(),
+                    &handoff_state.state,
+                ),
             }))
-        })
+        }),
+    )
+}
+
+#[derive(Deserialize)]
+pub struct ProviderStartQuery {
+    state: String,
+}
+
+/// Where the urls for signing in, granting and linking lead. The browser navigates here, so the
+/// handoff cookie set here is kept even when the app's own requests are sent without cookies.
+/// The handoff is bound to this browser before the browser is sent on to the provider.
+pub fn start_provider_login(
+    (path, query, req): (
+        Path<ProviderPath>,
+        Query<ProviderStartQuery>,
+        HttpRequest<AppState>,
+    ),
+) -> AppFuture<HttpResponse> {
+    let provider = match req.state().providers.require(&path.provider) {
+        Ok(provider) => provider,
+        Err(err) => return Box::new(future::err(err)),
+    };
+    let settings: Arc<Config> = req.state().config.clone();
+    let mem: MemExecutor = req.state().mem.clone();
+    let default_redirect = settings.default_login_redirect();
+    let browser_binding = cookies::browser_binding(&req);
+
+    let redirect_uri = provider_redirect_uri(&settings.http_public_url, provider.name());
+    Box::new(
+        sessions::bind_login_handoff(&mem, &query.state, &browser_binding)
+            .then(|bound| match bound {
+                Ok(Ok(handoff)) => Ok(handoff),
+                Ok(Err(handoff_error)) => Err((LoginError::from(handoff_error), None)),
+                Err(err) => {
+                    warn!("start_provider_login: binding handoff failed: {:?}", err);
+                    Err((LoginError::ServerError, None))
+                }
+            })
+            .and_then(move |handoff: models::StateHandoff| {
+                let failure_redirect = handoff.redirect_uri.clone();
+                handoff_provider_url(&*provider, &handoff, &redirect_uri).map_err(move |err| {
+                    warn!("start_provider_login: provider url failed: {:?}", err);
+                    (LoginError::ProviderUnavailable, failure_redirect)
+                })
+            })
+            .then(move |result| {
+                Ok::<_, Error>(match result {
+                    Ok(provider_url) => HttpResponse::Found()
+                        .cookie(cookies::handoff_cookie(
+                            &settings,
+                            &browser_binding,
+                            &sessions::HANDOFF_EXPIRATION,
+                        ))
+                        .header("Location", provider_url)
+                        .finish(),
+                    Err((login_error, failure_redirect)) => login_errors::redirect_with_error(
+                        &failure_redirect.unwrap_or(default_redirect),
+                        login_error,
+                    ),
+                })
+            }),
+    )
+}
+
+/// Where the provider asks the person to sign in, or to grant more scopes, for the handoff
+fn handoff_provider_url(
+    provider: &IdentityProvider,
+    handoff: &models::StateHandoff,
+    redirect_uri: &str,
+) -> AppFuture<String> {
+    match handoff.purpose {
+        models::HandoffPurpose::Grant {
+            ref requested_scopes,
+            ..
+        } => provider.upgrade_url(
+            &handoff.key,
+            &handoff.nonce,
+            &handoff.code_challenge(),
+            redirect_uri,
+            &requested_scopes
+                .split_whitespace()
+                .map(String::from)
+                .collect::<Vec<_>>(),
+        ),
+        models::HandoffPurpose::Login | models::HandoffPurpose::Link { .. } => provider.login_url(
+            &handoff.key,
+            &handoff.nonce,
+            &handoff.code_challenge(),
+            redirect_uri,
+        ),
+    }
 }
 
 #[derive(Debug, Deserialize)]
-pub struct GoogleCallbackQuery {
+pub struct ProviderCallbackQuery {
     error: Option<String>,
     code: Option<String>,
     state: Option<String>,
 }
 
-pub fn google_callback(
-    (query, req): (Query<GoogleCallbackQuery>, HttpRequest<AppState>),
+pub fn provider_callback(
+    (path, query, req): (
+        Path<ProviderPath>,
+        Query<ProviderCallbackQuery>,
+        HttpRequest<AppState>,
+    ),
 ) -> AppFuture<HttpResponse> {
-    if let Some(ref cause) = query.error {
-        return Box::new(future::err(Error::BadRequest(format!(
-            "Error during login: {:?}",
-            cause
-        ))));
-    }
-    if let Some(ref code) = query.code {
-        if let Some(ref state) = query.state {
-            // with code and state, we can make the exchange
-            let settings: Arc<Config> = req.state().config.clone();
-            let mem = req.state().mem.clone();
-            let db: Addr<DbExecutor> = req.state().db.clone();
-
-            let (code, state) = (code.to_string(), state.to_string());
-
-            let redirect_uri = google_redirect_uri(&settings.http_public_url);
-            Box::new(
-                google_oauth_client::exchange_code_for_token(
-                    &code.clone(),
-                    &redirect_uri,
-                    &settings.google_oauth_client_id,
-                    &settings.google_oauth_client_secret,
-                )
-                .and_then(|access_result: google_oauth_client::ExchangeResult| {
-                    google_people_client::who_am_i(access_result.access_token())
-                })
-                .map_err(|_| Error::InternalServerError)
-                .and_then(move |i_am: google_people_client::IAm| {
-                    db.send(users::GetLoginForResource(users::ExtResourceId::google(
-                        &i_am.resource_name,
-                    )))
-                    .flatten()
-                    .join(future::ok(i_am))
+    let provider = match req.state().providers.require(&path.provider) {
+        Ok(provider) => provider,
+        Err(err) => return Box::new(future::err(err)),
+    };
+    let settings: Arc<Config> = req.state().config.clone();
+    let mem = req.state().mem.clone();
+    let db: Addr<DbExecutor> = req.state().db.clone();
+    let login_session_expiration = settings.login_session_expiration();
+    // failures without a handoff to redirect back to land on our own page
+    let default_redirect = settings.default_login_redirect();
+
+    let query = query.into_inner();
+    let state = match query.state {
+        Some(ref state) => state.to_string(),
+        None => {
+            return Box::new(future::ok(login_errors::redirect_with_error(
+                &default_redirect,
+                LoginError::InvalidRequest,
+            )))
+        }
+    };
+    let browser_binding = req
+        .cookie(cookies::HANDOFF_COOKIE)
+        .map(|cookie| cookie.value().to_string());
+
+    let redirect_uri = provider_redirect_uri(&settings.http_public_url, provider.name());
+    Box::new(
+        sessions::take_login_handoff(&mem, &state, browser_binding)
+            .then(|taken| match taken {
+                Ok(Ok(handoff)) => Ok(handoff),
+                Ok(Err(handoff_error)) => Err((LoginError::from(handoff_error), None)),
+                Err(err) => {
+                    warn!("provider_callback: taking handoff failed: {:?}", err);
+                    Err((LoginError::ServerError, None))
+                }
+            })
+            .and_then(move |handoff: models::StateHandoff| {
+                let failure_redirect = handoff.redirect_uri.clone();
+                let code = match (query.error, query.code) {
+                    (Some(ref error), _) => Err(LoginError::from_provider(error)),
+                    (None, Some(code)) => Ok(code),
+                    (None, None) => Err(LoginError::InvalidRequest),
+                };
+                future::result(code)
+                    .and_then(move |code| {
+                        provider
+                            .exchange_code(&code, &handoff.code_verifier, &redirect_uri)
+                            .and_then({
+                                let provider = provider.clone();
+                                move |tokens: ProviderTokens| {
+                                    provider
+                                        .who_am_i(&tokens, &handoff.nonce)
+                                        .map(move |i_am| (handoff, tokens, i_am))
+                                }
+                            })
+                            .map_err(|_| LoginError::ProviderUnavailable)
+                            .and_then({
+                                let provider = provider.clone();
+                                move |(handoff, tokens, i_am)| match provider.check_allowed(
+                                    &tokens,
+                                    &handoff.nonce,
+                                    &i_am,
+                                ) {
+                                    Ok(()) => Ok((handoff, tokens.granted_scopes, i_am)),
+                                    Err(err) => {
+                                        info!(
+                                            "provider_callback: {} not allowed: {}",
+                                            i_am.resource_name, err
+                                        );
+                                        Err(LoginError::AccountNotAllowed)
+                                    }
+                                }
+                            })
+                            .and_then(move |(handoff, granted_scopes, i_am)| {
+                                match handoff.purpose.clone() {
+                                    models::HandoffPurpose::Login => Either::A(
+                                        link_callback_handoff(
+                                            db,
+                                            mem,
+                                            provider.login_prefix(),
+                                            handoff,
+                                            models::IAm {
+                                                granted_scopes,
+                                                ..i_am
+                                            },
+                                            login_session_expiration,
+                                        )
+                                        .map_err(|err| {
+                                            warn!(
+                                                "provider_callback: linking handoff failed: {:?}",
+                                                err
+                                            );
+                                            LoginError::ServerError
+                                        }),
+                                    ),
+                                    models::HandoffPurpose::Grant {
+                                        user_id,
+                                        requested_scopes,
+                                    } => Either::B(grants::record_callback_grant(
+                                        db,
+                                        &provider,
+                                        user_id,
+                                        granted_scopes.unwrap_or(requested_scopes),
+                                        handoff,
+                                        i_am,
+                                    )),
+                                    models::HandoffPurpose::Link { user_id } => {
+                                        Either::B(logins::record_callback_link(
+                                            db, &provider, user_id, handoff, i_am,
+                                        ))
+                                    }
+                                }
+                            })
+                    })
+                    .map_err(move |login_error| (login_error, failure_redirect))
+            })
+            .then(move |result| {
+                Ok::<_, Error>(match result {
+                    Ok(link_output) => {
+                        let redirect_to = link_output.redirect_uri_opt.unwrap_or(default_redirect);
+                        HttpResponse::Found()
+                            .header("Location", redirect_to)
+                            .finish()
+                    }
+                    Err((login_error, failure_redirect)) => login_errors::redirect_with_error(
+                        &failure_redirect.unwrap_or(default_redirect),
+                        login_error,
+                    ),
                 })
-                .and_then(
-                    move |(user_login_opt, i_am): (
-                        Option<db::models::UserLogin>,
-                        google_people_client::IAm,
-                    )| {
-                        if let Some(user_login) = user_login_opt {
-                            Either::A(sessions::link_state_to_user_id(
-                                &mem,
-                                state.to_string(),
-                                user_login.user_id,
-                            ))
-                        } else {
-                            Either::B(sessions::link_state_to_i_am(
-                                &mem,
-                                state.to_string(),
-                                models::IAm {
-                                    email: Some(i_am.email_address),
-                                    full_name: Some(i_am.display_name),
-                                    given_name: Some(i_am.given_name),
-                                    photo_url: Some(i_am.photo_url),
-                                    resource_name: i_am.resource_name,
-                                    provider: "google".to_string(),
-                                },
-                            ))
-                        }
-                    },
+            }),
+    )
+}
+
+/// Attach whoever signed in to the login session of the handoff, recording the scopes they
+/// granted if they are already a user
+fn link_callback_handoff(
+    db: Addr<DbExecutor>,
+    mem: MemExecutor,
+    login_prefix: &str,
+    handoff: models::StateHandoff,
+    i_am: models::IAm,
+    login_session_expiration: std::time::Duration,
+) -> AppFuture<sessions::LinkOutput> {
+    Box::new(
+        db.send(users::GetLoginForResource(users::ExtResourceId::new(
+            login_prefix,
+            &i_am.resource_name,
+        )))
+        .flatten()
+        .and_then(move |user_login_opt: Option<db::models::UserLogin>| {
+            if let Some(user_login) = user_login_opt {
+                Either::A(
+                    grants::record_login_grant(
+                        &db,
+                        user_login.user_id.clone(),
+                        i_am.provider,
+                        i_am.granted_scopes,
+                    )
+                    .and_then(move |_| {
+                        sessions::link_handoff_to_user_id(
+                            &mem,
+                            handoff,
+                            user_login.user_id,
+                            &login_session_expiration,
+                        )
+                    }),
                 )
-                .map(|link_output: sessions::LinkOutput| {
-                    let redirect_to = link_output.redirect_uri_opt.unwrap_or(String::from("/"));
-                    HttpResponse::Found()
-                        .header("Location", redirect_to)
-                        .finish()
-                }),
-            )
-        } else {
-            Box::new(future::err(Error::BadRequest(format!("Missing state"))))
-        }
+            } else {
+                Either::B(sessions::link_handoff_to_i_am(
+                    &mem,
+                    handoff,
+                    i_am,
+                    &login_session_expiration,
+                ))
+            }
+        }),
+    )
+}
+
+/// Where the session is being created from, for listing sessions later
+fn session_client(req: &HttpRequest<AppState>) -> sessions::SessionClient {
+    sessions::SessionClient {
+        ip: req.connection_info().remote().map(String::from),
+        user_agent: req
+            .headers()
+            .get(USER_AGENT)
+            .and_then(|user_agent| user_agent.to_str().ok())
+            .map(String::from),
+    }
+}
+
+fn user_access_keys_response(
+    config: &Config,
+    pepper_id: u8,
+    user_access_keys: sessions::UserAccessKeys,
+) -> HttpResponse {
+    let sessions::UserAccessKeys {
+        user_key,
+        refresh_key,
+    } = user_access_keys;
+    let access_token = auth::AccessToken::encrypt(
+        auth::AccessKey::new_user_key(pepper_id, user_key),
+        &config.peppers,
+    );
+    let refresh_token = auth::AccessToken::encrypt(
+        auth::AccessKey::new_refresh_key(pepper_id, refresh_key),
+        &config.peppers,
+    );
+    if config.session_cookies {
+        // the cookies last as long as the session could be refreshed, the tokens expire sooner
+        let cookie_max_age = config.refresh_token_expiration();
+        let csrf_token = cookies::new_csrf_token();
+        HttpResponse::Ok()
+            .cookie(cookies::token_cookie(
+                config,
+                cookies::USER_COOKIE,
+                &access_token,
+                &cookie_max_age,
+            ))
+            .cookie(cookies::token_cookie(
+                config,
+                cookies::REFRESH_COOKIE,
+                &refresh_token,
+                &cookie_max_age,
+            ))
+            .cookie(cookies::csrf_cookie(config, &csrf_token, &cookie_max_age))
+            .json(json!({
+                "csrf_token": csrf_token,
+                "expires_in": config.user_session_expiration_secs,
+            }))
     } else {
-        Box::new(future::err(Error::BadRequest(format!("Missing code"))))
+        HttpResponse::Ok().json(json!({
+            "access_token": access_token,
+            "refresh_token": refresh_token,
+            "expires_in": config.user_session_expiration_secs,
+        }))
     }
 }
 
-fn google_redirect_uri(public_url: &str) -> String {
-    format!("{}/auth/v0/google/callback", public_url)
+/// Clears the session cookies in cookie mode
+fn logged_out_response(config: &Config) -> HttpResponseBuilder {
+    let mut response = HttpResponse::Ok();
+    if config.session_cookies {
+        for &name in &[
+            cookies::USER_COOKIE,
+            cookies::REFRESH_COOKIE,
+            cookies::CSRF_COOKIE,
+        ] {
+            response.del_cookie(&cookies::removal_cookie(config, name));
+        }
+    }
+    response
+}
+
+pub(super) fn provider_redirect_uri(public_url: &str, provider: &str) -> String {
+    format!("{}/auth/v0/{}/callback", public_url, provider)
+}
+
+/// The url the app sends the browser to, see [start_provider_login]
+pub(super) fn provider_start_url(public_url: &str, provider: &str, state: &str) -> String {
+    format!("{}/auth/v0/{}/start?state={}", public_url, provider, state)
 }
@@ -0,0 +1,225 @@
+//! Two factor authentication with an authenticator app. Once set up, a login session linked to
+//! the user has to show a code, or one of the user's recovery codes, before it can create a
+//! user session.
+use actix::prelude::*;
+use actix_web::{HttpRequest, HttpResponse, Json};
+use futures::{
+    future::{self, Either},
+    Future,
+};
+use std::sync::Arc;
+
+use super::{AppState, Config};
+use crate::auth;
+use crate::db::{totp, DbExecutor};
+use crate::mem::{sessions, MemExecutor};
+use crate::prelude::*;
+use crate::totp::{encode_secret, provisioning_uri, SecretSealer};
+
+#[derive(Deserialize)]
+pub struct CodeBody {
+    code: String,
+}
+
+/// A new secret for the user to add to their authenticator app, which isn't required at sign in
+/// until it's confirmed with a code
+pub fn start_totp_enrollment(
+    (user, req): (auth::AuthUser, HttpRequest<AppState>),
+) -> AppFuture<HttpResponse> {
+    let db: Addr<DbExecutor> = req.state().db.clone();
+    let settings: Arc<Config> = req.state().config.clone();
+    let sealer: Arc<SecretSealer> = req.state().totp.clone();
+    if !sealer.is_enabled() {
+        return Box::new(future::err(Error::BadRequest(String::from(
+            "Two factor authentication is not enabled",
+        ))));
+    }
+    let account_name = user.user.display_name;
+
+    Box::new(
+        db.send(totp::StartTotpEnrollment {
+            user_id: user.user.user_id,
+            sealer,
+        })
+        .flatten()
+        .map(move |secret| {
+            HttpResponse::Ok().json(json!({
+                "secret": encode_secret(&secret),
+                "otpauth_uri": provisioning_uri(&settings.totp_issuer, &account_name, &secret),
+            }))
+        }),
+    )
+}
+
+/// Turn on two factor authentication, responding with recovery codes to be shown once
+pub fn confirm_totp(
+    (user, req, body): (auth::AuthUser, HttpRequest<AppState>, Json<CodeBody>),
+) -> AppFuture<HttpResponse> {
+    let db: Addr<DbExecutor> = req.state().db.clone();
+    Box::new(
+        db.send(totp::ConfirmTotp {
+            user_id: user.user.user_id,
+            code: body.into_inner().code,
+            sealer: req.state().totp.clone(),
+        })
+        .flatten()
+        .map(|recovery_codes| {
+            HttpResponse::Ok().json(json!({
+                "success": "Two factor authentication is on",
+                "recovery_codes": recovery_codes,
+            }))
+        }),
+    )
+}
+
+/// Turn off two factor authentication, which takes a current code
+pub fn disable_totp(
+    (user, req, body): (auth::AuthUser, HttpRequest<AppState>, Json<CodeBody>),
+) -> AppFuture<HttpResponse> {
+    let db: Addr<DbExecutor> = req.state().db.clone();
+    Box::new(
+        db.send(totp::DisableTotp {
+            user_id: user.user.user_id,
+            code: body.into_inner().code,
+            sealer: req.state().totp.clone(),
+        })
+        .flatten()
+        .map(|_| {
+            HttpResponse::Ok().json(json!({
+                "success": "Two factor authentication is off",
+            }))
+        }),
+    )
+}
+
+/// Replace the user's recovery codes, which takes a current code
+pub fn regenerate_recovery_codes(
+    (user, req, body): (auth::AuthUser, HttpRequest<AppState>, Json<CodeBody>),
+) -> AppFuture<HttpResponse> {
+    let db: Addr<DbExecutor> = req.state().db.clone();
+    Box::new(
+        db.send(totp::RegenerateRecoveryCodes {
+            user_id: user.user.user_id,
+            code: body.into_inner().code,
+            sealer: req.state().totp.clone(),
+        })
+        .flatten()
+        .map(|recovery_codes| {
+            HttpResponse::Ok().json(json!({
+                "recovery_codes": recovery_codes,
+            }))
+        }),
+    )
+}
+
+/// Satisfy the login session's second factor with a code from the user's app
+pub fn verify_login_totp(
+    (login, req, body): (auth::AuthLogin, HttpRequest<AppState>, Json<CodeBody>),
+) -> AppFuture<HttpResponse> {
+    let db: Addr<DbExecutor> = req.state().db.clone();
+    let sealer: Arc<SecretSealer> = req.state().totp.clone();
+    let code = body.into_inner().code;
+    verify_login_second_factor(login, &req, move |user_id| {
+        db.send(totp::VerifyTotp {
+            user_id,
+            code,
+            sealer,
+        })
+        .flatten()
+    })
+}
+
+/// Satisfy the login session's second factor with one of the user's recovery codes
+pub fn verify_login_recovery_code(
+    (login, req, body): (auth::AuthLogin, HttpRequest<AppState>, Json<CodeBody>),
+) -> AppFuture<HttpResponse> {
+    let db: Addr<DbExecutor> = req.state().db.clone();
+    let code = body.into_inner().code;
+    verify_login_second_factor(login, &req, move |user_id| {
+        db.send(totp::UseRecoveryCode { user_id, code }).flatten()
+    })
+}
+
+fn verify_login_second_factor<F, V>(
+    login: auth::AuthLogin,
+    req: &HttpRequest<AppState>,
+    verify: V,
+) -> AppFuture<HttpResponse>
+where
+    F: Future<Item = bool, Error = Error> + 'static,
+    V: FnOnce(String) -> F + 'static,
+{
+    let mem: MemExecutor = req.state().mem.clone();
+    let login_session_expiration = req.state().config.login_session_expiration();
+    let user_id = match login.user_id {
+        Some(user_id) => user_id,
+        None => {
+            return Box::new(future::err(Error::Unauthorized(String::from(
+                "Login session is not associated with a user",
+            ))))
+        }
+    };
+    let login_key = login.access_key;
+
+    Box::new(
+        check_second_factor(mem, login_key, user_id, login_session_expiration, verify).and_then(
+            |satisfied| {
+                if satisfied {
+                    Ok(HttpResponse::Ok().json(json!({
+                        "success": "Second factor verified",
+                    })))
+                } else {
+                    Err(Error::Unauthorized(String::from("Code is incorrect")))
+                }
+            },
+        ),
+    )
+}
+
+/// Count the attempt, then `verify` the second factor and satisfy the login session with it.
+/// Resolves with whether it was satisfied, or fails once there were too many wrong attempts.
+pub(super) fn check_second_factor<F, V>(
+    mem: MemExecutor,
+    login_key: auth::LoginAccessKey,
+    user_id: String,
+    login_session_expiration: std::time::Duration,
+    verify: V,
+) -> AppFuture<bool>
+where
+    F: Future<Item = bool, Error = Error> + 'static,
+    V: FnOnce(String) -> F + 'static,
+{
+    Box::new(
+        sessions::start_second_factor_attempt(
+            &mem,
+            &login_key,
+            &user_id,
+            &login_session_expiration,
+        )
+        .and_then(move |attempt| match attempt {
+            sessions::SecondFactorAttempt::Allowed => {
+                Either::A(verify(user_id.clone()).and_then(move |verified| {
+                    if verified {
+                        Either::A(
+                            sessions::satisfy_second_factor(
+                                &mem,
+                                &login_key,
+                                &user_id,
+                                &login_session_expiration,
+                            )
+                            .map(|_| true),
+                        )
+                    } else {
+                        Either::B(future::ok(false))
+                    }
+                }))
+            }
+            sessions::SecondFactorAttempt::UserLockedOut => Either::B(future::err(
+                Error::Unauthorized(String::from("Too many incorrect codes, try again later")),
+            )),
+            sessions::SecondFactorAttempt::LoginSessionEnded => Either::B(future::err(
+                Error::Unauthorized(String::from("Too many incorrect codes, sign in again")),
+            )),
+        }),
+    )
+}
@@ -0,0 +1,427 @@
+//! Passkeys and security keys. Signed in users register them, and then they can sign in with
+//! one instead of a provider or password, or use one as their second factor.
+//!
+//! Each ceremony is a start request, whose options go to `navigator.credentials.create()` or
+//! `navigator.credentials.get()`, and a finish request with the credential that resolves to.
+use actix::prelude::*;
+use actix_web::{HttpRequest, HttpResponse, Json, Path};
+use futures::{
+    future::{self, Either},
+    Future,
+};
+use std::sync::Arc;
+
+use super::totp;
+use super::{AppState, Config};
+use crate::auth;
+use crate::db::{webauthn as db_webauthn, DbExecutor};
+use crate::mem::{models, sessions, MemExecutor};
+use crate::prelude::*;
+use crate::webauthn::{self, RelyingParty, SUPPORTED_ALGS, TIMEOUT_MILLIS};
+
+#[derive(Deserialize)]
+pub struct AttestationResponse {
+    #[serde(rename = "clientDataJSON")]
+    client_data_json: String,
+    #[serde(rename = "attestationObject")]
+    attestation_object: String,
+}
+
+/// A `PublicKeyCredential` from `navigator.credentials.create()`, with its fields base64url
+#[derive(Deserialize)]
+pub struct RegistrationBody {
+    id: String,
+    response: AttestationResponse,
+    /// To tell the user's passkeys apart
+    name: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct AssertionResponse {
+    #[serde(rename = "clientDataJSON")]
+    client_data_json: String,
+    #[serde(rename = "authenticatorData")]
+    authenticator_data: String,
+    signature: String,
+    #[serde(rename = "userHandle")]
+    user_handle: Option<String>,
+}
+
+/// A `PublicKeyCredential` from `navigator.credentials.get()`, with its fields base64url
+#[derive(Deserialize)]
+pub struct AssertionBody {
+    id: String,
+    response: AssertionResponse,
+}
+
+#[derive(Deserialize)]
+pub struct CredentialPath {
+    credential_id: String,
+}
+
+/// Options for registering a new passkey for the signed in user
+pub fn start_webauthn_registration(
+    (user, req): (auth::AuthUser, HttpRequest<AppState>),
+) -> AppFuture<HttpResponse> {
+    let mem: MemExecutor = req.state().mem.clone();
+    let db: Addr<DbExecutor> = req.state().db.clone();
+    let rp: Arc<RelyingParty> = req.state().webauthn.clone();
+    if let Err(err) = check_enabled(&req.state().config) {
+        return Box::new(future::err(err));
+    }
+    let user_id = user.user.user_id.clone();
+
+    Box::new(
+        db.send(db_webauthn::GetWebauthnCredentials {
+            user_id: user_id.clone(),
+        })
+        .flatten()
+        .and_then(move |credentials| {
+            sessions::create_webauthn_registration(&mem, &user.access_key, user_id).map(
+                move |challenge| {
+                    HttpResponse::Ok().json(json!({
+                        "publicKey": {
+                            "rp": {
+                                "id": rp.id,
+                                "name": rp.name,
+                            },
+                            "user": {
+                                "id": webauthn::encode(user.user.user_id.as_bytes()),
+                                "name": user.user.display_name,
+                                "displayName": user.user.full_name.as_ref().unwrap_or(&user.user.display_name),
+                            },
+                            "challenge": challenge,
+                            "pubKeyCredParams": SUPPORTED_ALGS
+                                .iter()
+                                .map(|alg| json!({ "type": "public-key", "alg": alg }))
+                                .collect::<Vec<_>>(),
+                            "timeout": TIMEOUT_MILLIS,
+                            "attestation": "none",
+                            "authenticatorSelection": {
+                                // discoverable, so it can sign in without a username
+                                "residentKey": "preferred",
+                                "requireResidentKey": false,
+                                "userVerification": "preferred",
+                            },
+                            "excludeCredentials": allow_list(&credentials),
+                        },
+                    }))
+                },
+            )
+        }),
+    )
+}
+
+/// Store the passkey the browser created from [start_webauthn_registration]'s options
+pub fn finish_webauthn_registration(
+    (user, req, body): (
+        auth::AuthUser,
+        HttpRequest<AppState>,
+        Json<RegistrationBody>,
+    ),
+) -> AppFuture<HttpResponse> {
+    let mem: MemExecutor = req.state().mem.clone();
+    let db: Addr<DbExecutor> = req.state().db.clone();
+    let rp: Arc<RelyingParty> = req.state().webauthn.clone();
+    if let Err(err) = check_enabled(&req.state().config) {
+        return Box::new(future::err(err));
+    }
+    let body = body.into_inner();
+    let user_id = user.user.user_id;
+
+    Box::new(
+        sessions::take_webauthn_registration(&mem, &user.access_key)
+            .and_then(move |registration_opt| {
+                let registration = match registration_opt {
+                    Some(ref registration) if registration.user_id == user_id => registration,
+                    _ => {
+                        return Err(Error::BadRequest(String::from(
+                            "Start registering the passkey again",
+                        )))
+                    }
+                };
+                let credential = rp.verify_registration(
+                    &registration.challenge,
+                    &webauthn::decode(&body.response.client_data_json)?,
+                    &webauthn::decode(&body.response.attestation_object)?,
+                    false,
+                )?;
+                let credential_id = webauthn::encode(&credential.credential_id);
+                if credential_id != body.id.trim_end_matches('=') {
+                    return Err(Error::BadRequest(String::from(
+                        "Invalid passkey response: id does not match authData",
+                    )));
+                }
+                Ok(db_webauthn::CreateWebauthnCredential {
+                    user_id,
+                    credential_id,
+                    public_key: credential.public_key,
+                    sign_count: credential.sign_count,
+                    name: body
+                        .name
+                        .filter(|name| !name.trim().is_empty())
+                        .unwrap_or_else(|| String::from("Passkey")),
+                })
+            })
+            .and_then(move |create| db.send(create).flatten())
+            .map(|credential| {
+                HttpResponse::Ok().json(json!({
+                    "success": "Passkey registered",
+                    "credential": credential,
+                }))
+            }),
+    )
+}
+
+pub fn list_webauthn_credentials(
+    (user, req): (auth::AuthUser, HttpRequest<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let db: Addr<DbExecutor> = req.state().db.clone();
+    db.send(db_webauthn::GetWebauthnCredentials {
+        user_id: user.user.user_id,
+    })
+    .flatten()
+    .map(|credentials| {
+        HttpResponse::Ok().json(json!({
+            "credentials": credentials,
+        }))
+    })
+}
+
+pub fn delete_webauthn_credential(
+    (user, req, path): (auth::AuthUser, HttpRequest<AppState>, Path<CredentialPath>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let db: Addr<DbExecutor> = req.state().db.clone();
+    db.send(db_webauthn::DeleteWebauthnCredential {
+        user_id: user.user.user_id,
+        credential_id: path.into_inner().credential_id,
+    })
+    .flatten()
+    .and_then(|deleted| {
+        if deleted {
+            Ok(HttpResponse::Ok().json(json!({
+                "success": "Passkey removed",
+            })))
+        } else {
+            Err(Error::BadRequest(String::from("No such passkey")))
+        }
+    })
+}
+
+/// Options for signing in with a passkey. When the login session is already linked to a
+/// user, the passkey is their second factor and has to be one of theirs.
+pub fn start_webauthn_login(
+    (login, req): (auth::AuthLogin, HttpRequest<AppState>),
+) -> AppFuture<HttpResponse> {
+    let mem: MemExecutor = req.state().mem.clone();
+    let db: Addr<DbExecutor> = req.state().db.clone();
+    let rp: Arc<RelyingParty> = req.state().webauthn.clone();
+    if let Err(err) = check_enabled(&req.state().config) {
+        return Box::new(future::err(err));
+    }
+    let login_key = login.access_key;
+    let second_factor = login.user_id.is_some();
+
+    let credentials = match login.user_id {
+        // discoverable credentials are offered by the browser without a list
+        None => Either::A(future::ok(Vec::new())),
+        Some(user_id) => Either::B(
+            db.send(db_webauthn::GetWebauthnCredentials { user_id })
+                .flatten()
+                .and_then(|credentials| {
+                    if credentials.is_empty() {
+                        Err(Error::BadRequest(String::from("User has no passkeys")))
+                    } else {
+                        Ok(credentials)
+                    }
+                }),
+        ),
+    };
+    Box::new(credentials.and_then(move |credentials| {
+        sessions::create_webauthn_challenge(&mem, &login_key, second_factor).map(move |challenge| {
+            HttpResponse::Ok().json(json!({
+                "publicKey": {
+                    "challenge": challenge,
+                    "rpId": rp.id,
+                    "timeout": TIMEOUT_MILLIS,
+                    // on its own, the passkey has to be both factors
+                    "userVerification": if second_factor { "discouraged" } else { "required" },
+                    "allowCredentials": allow_list(&credentials),
+                },
+            }))
+        })
+    }))
+}
+
+/// Check the passkey's assertion, and sign in the login session or satisfy its second factor
+pub fn finish_webauthn_login(
+    (login, req, body): (auth::AuthLogin, HttpRequest<AppState>, Json<AssertionBody>),
+) -> AppFuture<HttpResponse> {
+    let mem: MemExecutor = req.state().mem.clone();
+    let db: Addr<DbExecutor> = req.state().db.clone();
+    let rp: Arc<RelyingParty> = req.state().webauthn.clone();
+    let login_session_expiration = req.state().config.login_session_expiration();
+    if let Err(err) = check_enabled(&req.state().config) {
+        return Box::new(future::err(err));
+    }
+    let body = body.into_inner();
+    let login_key = login.access_key;
+    let login_user_id = login.user_id;
+
+    let challenge_and_credential = sessions::take_webauthn_challenge(&mem, &login_key)
+        .and_then(|challenge_opt| {
+            challenge_opt.ok_or_else(|| {
+                Error::BadRequest(String::from("Start signing in with the passkey again"))
+            })
+        })
+        .join(
+            db.send(db_webauthn::GetWebauthnCredential {
+                credential_id: body.id.trim_end_matches('=').to_string(),
+            })
+            .flatten()
+            .and_then(|credential_opt| {
+                credential_opt
+                    .ok_or_else(|| Error::Unauthorized(String::from("Passkey is not registered")))
+            }),
+        );
+
+    Box::new(challenge_and_credential.and_then(
+        move |(challenge, credential): (models::WebauthnChallenge, _)| {
+            let checked = check_assertion(&rp, &challenge, &credential, &body.response);
+            if challenge.second_factor {
+                // the second factor's user is whoever the login session was linked to
+                let user_id = match login_user_id {
+                    Some(user_id) => user_id,
+                    None => {
+                        return Either::B(Either::A(future::err(Error::Unauthorized(
+                            String::from("Login session is not associated with a user"),
+                        ))))
+                    }
+                };
+                let checked = checked.and_then(|sign_count| {
+                    if credential.user_id == user_id {
+                        Ok(sign_count)
+                    } else {
+                        Err(Error::Unauthorized(String::from(
+                            "Passkey belongs to someone else",
+                        )))
+                    }
+                });
+                let credential_id = credential.credential_id;
+                let verify = move |_: String| match checked {
+                    Ok(sign_count) => Either::A(
+                        db.send(db_webauthn::UseWebauthnCredential {
+                            credential_id,
+                            sign_count,
+                        })
+                        .flatten()
+                        .then(|used| match used {
+                            Ok(()) => Ok::<_, Error>(true),
+                            Err(err) => {
+                                warn!("finish_webauthn_login: Second factor failed: {:?}", err);
+                                Ok(false)
+                            }
+                        }),
+                    ),
+                    Err(err) => {
+                        warn!("finish_webauthn_login: Second factor failed: {:?}", err);
+                        Either::B(future::ok(false))
+                    }
+                };
+                Either::A(
+                    totp::check_second_factor(
+                        mem,
+                        login_key,
+                        user_id,
+                        login_session_expiration,
+                        verify,
+                    )
+                    .and_then(|satisfied| {
+                        if satisfied {
+                            Ok(HttpResponse::Ok().json(json!({
+                                "success": "Second factor verified",
+                            })))
+                        } else {
+                            Err(Error::Unauthorized(String::from(
+                                "Passkey could not be verified",
+                            )))
+                        }
+                    }),
+                )
+            } else {
+                let sign_count = match checked {
+                    Ok(sign_count) => sign_count,
+                    Err(err) => return Either::B(Either::A(future::err(err))),
+                };
+                let user_id = credential.user_id;
+                Either::B(Either::B(
+                    db.send(db_webauthn::UseWebauthnCredential {
+                        credential_id: credential.credential_id,
+                        sign_count,
+                    })
+                    .flatten()
+                    .and_then(move |_| {
+                        sessions::link_login_session_to_passkey_user(
+                            &mem,
+                            &login_key,
+                            user_id,
+                            &login_session_expiration,
+                        )
+                    })
+                    .map(|_| {
+                        HttpResponse::Ok().json(json!({
+                            "success": "Signed in",
+                        }))
+                    }),
+                ))
+            }
+        },
+    ))
+}
+
+/// Verify the assertion against the stored credential, resolving with its signature counter
+fn check_assertion(
+    rp: &RelyingParty,
+    challenge: &models::WebauthnChallenge,
+    credential: &crate::db::models::UserWebauthnCredential,
+    response: &AssertionResponse,
+) -> Result<u32> {
+    if let Some(ref user_handle) = response.user_handle {
+        if webauthn::decode(user_handle)? != credential.user_id.as_bytes() {
+            return Err(Error::Unauthorized(String::from(
+                "Passkey belongs to someone else",
+            )));
+        }
+    }
+    let assertion = rp.verify_assertion(
+        &challenge.challenge,
+        &credential.public_key,
+        &webauthn::decode(&response.client_data_json)?,
+        &webauthn::decode(&response.authenticator_data)?,
+        &webauthn::decode(&response.signature)?,
+        !challenge.second_factor,
+    )?;
+    Ok(assertion.sign_count)
+}
+
+fn allow_list(credentials: &[crate::db::models::UserWebauthnCredential]) -> Vec<serde_json::Value> {
+    credentials
+        .iter()
+        .map(|credential| {
+            json!({
+                "type": "public-key",
+                "id": credential.credential_id,
+            })
+        })
+        .collect()
+}
+
+fn check_enabled(settings: &Config) -> Result<()> {
+    if settings.webauthn {
+        Ok(())
+    } else {
+        Err(Error::BadRequest(String::from(
+            "Signing in with a passkey is not enabled",
+        )))
+    }
+}
@@ -0,0 +1,28 @@
+use actix_web::{HttpRequest, HttpResponse};
+
+use super::AppState;
+
+/// Verifiers may cache the documents for this long
+const WELL_KNOWN_CACHE_CONTROL: &str = "public, max-age=300";
+
+// Route handlers ↓
+pub fn jwks(req: &HttpRequest<AppState>) -> HttpResponse {
+    HttpResponse::Ok()
+        .header("Cache-Control", WELL_KNOWN_CACHE_CONTROL)
+        .json(req.state().jwt_keys.jwks())
+}
+
+/// Only the parts of the discovery document which verifiers of our tokens depend on
+pub fn openid_configuration(req: &HttpRequest<AppState>) -> HttpResponse {
+    let state: &AppState = req.state();
+    let public_url = &state.config.http_public_url;
+    HttpResponse::Ok()
+        .header("Cache-Control", WELL_KNOWN_CACHE_CONTROL)
+        .json(json!({
+            "issuer": public_url,
+            "jwks_uri": format!("{}/.well-known/jwks.json", public_url),
+            "subject_types_supported": ["public"],
+            "id_token_signing_alg_values_supported": state.jwt_keys.algs(),
+            "claims_supported": ["iss", "sub", "iat", "exp", "name"],
+        }))
+}
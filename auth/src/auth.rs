@@ -1,8 +1,10 @@
 use actix_web::{FromRequest, HttpRequest};
-use futures::future::{result, Future};
+use futures::future::{self, result, Future};
 use std::convert::From;
 
 use super::app::AppState;
+use crate::config::{Config, PepperKeyring};
+use crate::cookies;
 use crate::mem::{models, sessions, MemExecutor};
 use crate::prelude::*;
 
@@ -13,15 +15,19 @@ const BEARER_TOKEN_PREFIX: &str = "Bearer ";
 pub struct UserAccessKey(pub String);
 #[derive(Debug, Clone)]
 pub struct LoginAccessKey(pub String);
+#[derive(Debug, Clone)]
+pub struct RefreshAccessKey(pub String);
 
 pub struct AccessKey {
-    salt: String,
+    /// Which pepper from the keyring this key was (or will be) sealed with
+    pepper_id: u8,
     key: AccessKeyInner,
 }
 
 enum AccessKeyInner {
     Login(LoginAccessKey),
     User(UserAccessKey),
+    Refresh(RefreshAccessKey),
 }
 
 /// The Access token is an opaque value which can be decrypted to find the user's session key
@@ -30,35 +36,133 @@ enum AccessKeyInner {
 #[serde(transparent)]
 pub struct AccessToken(String);
 
-use crate::utils::{dec, enc, hex, secure_rand};
+impl AccessToken {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for AccessToken {
+    fn from(token: String) -> Self {
+        AccessToken(token)
+    }
+}
 
-const SALT_LENGTH: usize = 16;
-const SALT_LENGTH_HEX: usize = 32;
+use crate::utils::{dec, secure_rand};
+use ring::{aead, digest, hkdf, hmac};
+
+/// Tokens are laid out as `version || pepper id || nonce || sealed(kind || session key)` and
+/// base64url encoded. The header is authenticated as additional data, so it cannot be swapped out.
+const ACCESS_TOKEN_V2: u8 = 2;
+/// Like v2, but without a pepper id in the header, these were always sealed with `PEPPER_0`.
+const ACCESS_TOKEN_V1: u8 = 1;
+static ACCESS_TOKEN_AEAD: &aead::Algorithm = &aead::CHACHA20_POLY1305;
+const ACCESS_TOKEN_KDF_SALT: &[u8] = b"knot access token";
+const ACCESS_TOKEN_KDF_INFO: &[u8] = b"v1";
+const NONCE_LENGTH: usize = 12;
+
+const ACCESS_KEY_LOGIN_KIND: u8 = b'L';
+const ACCESS_KEY_USER_KIND: u8 = b'U';
+const ACCESS_KEY_REFRESH_KIND: u8 = b'R';
 
-const ACCESS_KEY_LOGIN_PREFIX: &str = "Login ";
-const ACCESS_KEY_USER_PREFIX: &str = "User ";
 impl AccessToken {
-    pub fn encrypt(access_key: AccessKey, pepper: &str) -> Self {
-        let AccessKey { salt, key } = access_key;
-        let fm = match key {
-            AccessKeyInner::Login(login_key) => {
-                format!("{}{}", ACCESS_KEY_LOGIN_PREFIX, login_key.0)
-            }
-            AccessKeyInner::User(user_key) => format!("{}{}", ACCESS_KEY_USER_PREFIX, user_key.0),
+    /// Seal the access key with the pepper it was created for, see [PepperKeyring::active_id]
+    pub fn encrypt(access_key: AccessKey, peppers: &PepperKeyring) -> Self {
+        let AccessKey { pepper_id, key } = access_key;
+        let pepper = peppers
+            .get(pepper_id)
+            .expect("Access keys are only created for configured peppers");
+        let (kind, key) = match key {
+            AccessKeyInner::Login(login_key) => (ACCESS_KEY_LOGIN_KIND, login_key.0),
+            AccessKeyInner::User(user_key) => (ACCESS_KEY_USER_KIND, user_key.0),
+            AccessKeyInner::Refresh(refresh_key) => (ACCESS_KEY_REFRESH_KIND, refresh_key.0),
         };
-        let salt_and_pepper = format!("{}{}", salt, pepper);
-        let sp_encrypted = enc(fm.as_bytes(), &salt_and_pepper);
-        let salt_and_sp_encrypted = format!("{}{}", salt, sp_encrypted);
-        AccessToken(enc(salt_and_sp_encrypted.as_bytes(), "access_token"))
+        let tag_len = ACCESS_TOKEN_AEAD.tag_len();
+        let header = [ACCESS_TOKEN_V2, pepper_id];
+        let nonce = secure_rand(NONCE_LENGTH);
+
+        let mut in_out = Vec::with_capacity(1 + key.len() + tag_len);
+        in_out.push(kind);
+        in_out.extend_from_slice(key.as_bytes());
+        in_out.resize(in_out.len() + tag_len, 0);
+
+        let sealing_key = aead::SealingKey::new(ACCESS_TOKEN_AEAD, &token_key(pepper))
+            .expect("Access token key has the algorithm's key length");
+        let sealed_len = aead::seal_in_place(&sealing_key, &nonce, &header, &mut in_out, tag_len)
+            .expect("Sealing access token");
+
+        let mut token = Vec::with_capacity(header.len() + NONCE_LENGTH + sealed_len);
+        token.extend_from_slice(&header);
+        token.extend_from_slice(&nonce);
+        token.extend_from_slice(&in_out[..sealed_len]);
+        AccessToken(base64::encode_config(&token, base64::URL_SAFE_NO_PAD))
+    }
+
+    /// Decrypts and verifies the token with the pepper it names.
+    /// When `accept_legacy` is set, tokens minted in the old ShortCrypt format are still accepted.
+    pub fn decrypt(
+        &self,
+        peppers: &PepperKeyring,
+        accept_legacy: bool,
+    ) -> Result<AccessKey, &'static str> {
+        match self.decrypt_versioned(peppers) {
+            Ok(access_key) => Ok(access_key),
+            Err(err) if accept_legacy => self.decrypt_legacy(peppers).map_err(|legacy_err| {
+                debug!(
+                    "AccessToken::decrypt: Legacy decrypt error \"{}\"",
+                    legacy_err
+                );
+                err
+            }),
+            Err(err) => Err(err),
+        }
     }
 
-    pub fn decrypt(&self, pepper: &str) -> Result<AccessKey, &'static str> {
+    fn decrypt_versioned(&self, peppers: &PepperKeyring) -> Result<AccessKey, &'static str> {
+        let mut token = base64::decode_config(&self.0, base64::URL_SAFE_NO_PAD)
+            .map_err(|_| "Invalid token encoding")?;
+        let (header_len, pepper_id) = match token.first() {
+            Some(&ACCESS_TOKEN_V2) if token.len() > 1 => (2, token[1]),
+            Some(&ACCESS_TOKEN_V1) => (1, 0),
+            _ => return Err("Unknown token version"),
+        };
+        // header, nonce, at least the kind byte, and the tag
+        if token.len() < header_len + NONCE_LENGTH + 1 + ACCESS_TOKEN_AEAD.tag_len() {
+            return Err("Token too short");
+        }
+        let pepper = peppers.get(pepper_id).ok_or("Unknown pepper")?;
+
+        let (header, rest) = token.split_at_mut(header_len);
+        let (nonce, sealed) = rest.split_at_mut(NONCE_LENGTH);
+        let opening_key = aead::OpeningKey::new(ACCESS_TOKEN_AEAD, &token_key(pepper))
+            .expect("Access token key has the algorithm's key length");
+        let plain = aead::open_in_place(&opening_key, nonce, header, 0, sealed)
+            .map_err(|_| "Token failed verification")?;
+
+        let (kind, key) = plain.split_first().ok_or("Token too short")?;
+        let key = std::str::from_utf8(key)
+            .map_err(|_| "Invalid utf8")?
+            .to_string();
+        let key = match *kind {
+            ACCESS_KEY_LOGIN_KIND => AccessKeyInner::Login(LoginAccessKey(key)),
+            ACCESS_KEY_USER_KIND => AccessKeyInner::User(UserAccessKey(key)),
+            ACCESS_KEY_REFRESH_KIND => AccessKeyInner::Refresh(RefreshAccessKey(key)),
+            _ => return Err("Unknown login key kind"),
+        };
+
+        Ok(AccessKey { pepper_id, key })
+    }
+
+    /// Tokens issued before the versioned format, kept decodable for the migration window.
+    /// These were all minted with `PEPPER_0`.
+    fn decrypt_legacy(&self, peppers: &PepperKeyring) -> Result<AccessKey, &'static str> {
+        let pepper = peppers.get(0).ok_or("Unknown pepper")?;
         let salt_and_sp_encrypted = dec(&self.0, "access_token")?;
-        if salt_and_sp_encrypted.len() <= SALT_LENGTH_HEX {
+        if salt_and_sp_encrypted.len() <= LEGACY_SALT_LENGTH_HEX {
             return Err("Token too short");
         }
         use std::str::from_utf8;
-        let (salt_utf8, sp_encrypted_utf8) = salt_and_sp_encrypted.split_at(SALT_LENGTH_HEX);
+        let (salt_utf8, sp_encrypted_utf8) = salt_and_sp_encrypted.split_at(LEGACY_SALT_LENGTH_HEX);
         let (salt, sp_encrypted) = (
             from_utf8(salt_utf8).map_err(|_| "Invalid utf8")?,
             from_utf8(&sp_encrypted_utf8).map_err(|_| "Invalid utf8")?,
@@ -66,40 +170,54 @@ impl AccessToken {
         let salt_and_pepper = format!("{}{}", salt, pepper);
         let fm_utf8 = dec(sp_encrypted, &salt_and_pepper)?;
         let fm = from_utf8(&fm_utf8).map_err(|_| "Invalid utf8")?;
-        let key = if fm.starts_with(ACCESS_KEY_LOGIN_PREFIX) {
-            let (_, key) = fm.split_at(ACCESS_KEY_LOGIN_PREFIX.len());
+        let key = if fm.starts_with(LEGACY_ACCESS_KEY_LOGIN_PREFIX) {
+            let (_, key) = fm.split_at(LEGACY_ACCESS_KEY_LOGIN_PREFIX.len());
             AccessKeyInner::Login(LoginAccessKey(key.to_string()))
-        } else if fm.starts_with(ACCESS_KEY_USER_PREFIX) {
-            let (_, key) = fm.split_at(ACCESS_KEY_USER_PREFIX.len());
+        } else if fm.starts_with(LEGACY_ACCESS_KEY_USER_PREFIX) {
+            let (_, key) = fm.split_at(LEGACY_ACCESS_KEY_USER_PREFIX.len());
             AccessKeyInner::User(UserAccessKey(key.to_string()))
         } else {
             return Err("Unknown login key kind");
         };
 
-        Ok(AccessKey {
-            salt: salt.to_string(),
-            key,
-        })
+        Ok(AccessKey { pepper_id: 0, key })
     }
 }
 
+const LEGACY_SALT_LENGTH_HEX: usize = 32;
+const LEGACY_ACCESS_KEY_LOGIN_PREFIX: &str = "Login ";
+const LEGACY_ACCESS_KEY_USER_PREFIX: &str = "User ";
+
+/// Derive the symmetric token key from the pepper, so the pepper itself is never used as a key
+fn token_key(pepper: &str) -> Vec<u8> {
+    let salt = hmac::SigningKey::new(&digest::SHA256, ACCESS_TOKEN_KDF_SALT);
+    let mut key = vec![0; ACCESS_TOKEN_AEAD.key_len()];
+    hkdf::extract_and_expand(&salt, pepper.as_bytes(), ACCESS_TOKEN_KDF_INFO, &mut key);
+    key
+}
+
 impl AccessKey {
-    pub fn new_user_key(key: UserAccessKey) -> Self {
-        let new_salt = hex(&secure_rand(SALT_LENGTH));
+    pub fn new_user_key(pepper_id: u8, key: UserAccessKey) -> Self {
         AccessKey {
-            salt: new_salt,
+            pepper_id,
             key: AccessKeyInner::User(key),
         }
     }
 
-    pub fn new_login_key(key: LoginAccessKey) -> Self {
-        let new_salt = hex(&secure_rand(SALT_LENGTH));
+    pub fn new_login_key(pepper_id: u8, key: LoginAccessKey) -> Self {
         AccessKey {
-            salt: new_salt,
+            pepper_id,
             key: AccessKeyInner::Login(key),
         }
     }
 
+    pub fn new_refresh_key(pepper_id: u8, key: RefreshAccessKey) -> Self {
+        AccessKey {
+            pepper_id,
+            key: AccessKeyInner::Refresh(key),
+        }
+    }
+
     fn login_key(&self) -> Option<&LoginAccessKey> {
         match self.key {
             AccessKeyInner::Login(ref l) => Some(&l),
@@ -113,6 +231,13 @@ impl AccessKey {
             _ => None,
         }
     }
+
+    fn refresh_key(&self) -> Option<&RefreshAccessKey> {
+        match self.key {
+            AccessKeyInner::Refresh(ref l) => Some(&l),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -127,6 +252,7 @@ pub struct AuthLogin {
     pub access_key: LoginAccessKey,
     pub i_am: Option<models::IAm>,
     pub user_id: Option<String>,
+    pub second_factor: models::SecondFactor,
 }
 
 pub struct AuthUser {
@@ -162,69 +288,131 @@ impl FromRequest<AppState> for AuthUser {
 
 fn authenticate_login(req: &HttpRequest<AppState>) -> impl Future<Item = AuthLogin, Error = Error> {
     let mem: MemExecutor = req.state().mem.clone();
-    let pepper = req.state().config.pepper_0.clone();
-
-    result(preprocess_authz_token(req))
-        .and_then(move |access_token| {
-            access_token.decrypt(&pepper).map_err(|err| {
-                debug!("authenticate_login: Decrypt error \"{}\"", err);
-                Error::BadRequest(format!("Authentication value error"))
-            })
-        })
-        .and_then(move |access_key: AccessKey| {
-            access_key
-                .login_key()
-                .cloned()
-                .ok_or(Error::BadRequest("Not a login access token".to_string()))
-        })
-        .and_then(move |login_access_key: LoginAccessKey| {
-            sessions::get_login_session_opt(&mem, &login_access_key).from_err()
-        })
-        .and_then(|login_session_opt| {
-            login_session_opt
-                .ok_or(Error::Unauthorized(String::from("Invalid credentials")))
-                .map(|login_session: models::LoginSession| AuthLogin {
-                    access_key: LoginAccessKey(login_session.key),
-                    i_am: login_session.i_am,
-                    user_id: login_session.user_id,
-                })
+    let config = req.state().config.clone();
+
+    result(preprocess_authz_token(req, cookies::LOGIN_COOKIE))
+        .and_then(move |access_token| login_session_for_token(&mem, &config, &access_token))
+        .map(|login_session: models::LoginSession| AuthLogin {
+            access_key: LoginAccessKey(login_session.key),
+            i_am: login_session.i_am,
+            user_id: login_session.user_id,
+            second_factor: login_session.second_factor,
         })
 }
 
-fn authenticate_user(req: &HttpRequest<AppState>) -> impl Future<Item = AuthUser, Error = Error> {
+pub fn authenticate_user(
+    req: &HttpRequest<AppState>,
+) -> impl Future<Item = AuthUser, Error = Error> {
     let mem: MemExecutor = req.state().mem.clone();
-    let pepper = req.state().config.pepper_0.clone();
-
-    result(preprocess_authz_token(req))
-        .and_then(move |access_token| {
-            access_token.decrypt(&pepper).map_err(|err| {
-                debug!("authenticate_login: Decrypt error \"{}\"", err);
-                Error::BadRequest(format!("Authentication value error"))
-            })
-        })
-        .and_then(move |access_key: AccessKey| {
-            access_key
-                .user_key()
-                .map(std::clone::Clone::clone)
-                .ok_or(Error::BadRequest("Not a user access token".to_string()))
-        })
-        .and_then(move |user_key: UserAccessKey| {
-            sessions::get_user_session_opt(&mem, &user_key).from_err()
+    let config = req.state().config.clone();
+
+    result(preprocess_authz_token(req, cookies::USER_COOKIE))
+        .and_then(move |access_token| user_session_for_token(&mem, &config, &access_token))
+        .map(|user_session: models::UserSession| AuthUser {
+            access_key: UserAccessKey(user_session.key),
+            user: user_session.user.into(),
         })
-        .and_then(|user_session_opt| {
-            user_session_opt
-                .ok_or(Error::Unauthorized(String::from("Invalid credentials")))
-                .map(|user_session: models::UserSession| AuthUser {
-                    access_key: UserAccessKey(user_session.key),
-                    user: user_session.user.into(),
-                })
+}
+
+/// Find the live login session for an access token, however the token was presented
+pub fn login_session_for_token(
+    mem: &MemExecutor,
+    config: &Config,
+    access_token: &AccessToken,
+) -> AppFuture<models::LoginSession> {
+    let login_access_key = match decrypt_access_token(config, access_token).and_then(|access_key| {
+        access_key
+            .login_key()
+            .cloned()
+            .ok_or(Error::BadRequest("Not a login access token".to_string()))
+    }) {
+        Ok(login_access_key) => login_access_key,
+        Err(err) => return Box::new(future::err(err)),
+    };
+    Box::new(
+        sessions::get_login_session_opt(mem, &login_access_key).and_then(|login_session_opt| {
+            login_session_opt.ok_or(Error::Unauthorized(String::from("Invalid credentials")))
+        }),
+    )
+}
+
+/// Find the live user session for an access token, however the token was presented.
+/// This counts as using the session, which pushes its expiration back.
+pub fn user_session_for_token(
+    mem: &MemExecutor,
+    config: &Config,
+    access_token: &AccessToken,
+) -> AppFuture<models::UserSession> {
+    let mem: MemExecutor = mem.clone();
+    let user_session_expiration = config.user_session_expiration();
+    Box::new(
+        peek_user_session_for_token(&mem, config, access_token).and_then(
+            move |user_session: models::UserSession| {
+                sessions::touch_user_session(&mem, user_session, &user_session_expiration)
+            },
+        ),
+    )
+}
+
+/// Like [user_session_for_token], but without counting as using the session, for when someone
+/// other than the session's user is asking about it
+pub fn peek_user_session_for_token(
+    mem: &MemExecutor,
+    config: &Config,
+    access_token: &AccessToken,
+) -> AppFuture<models::UserSession> {
+    let user_key = match decrypt_access_token(config, access_token).and_then(|access_key| {
+        access_key
+            .user_key()
+            .cloned()
+            .ok_or(Error::BadRequest("Not a user access token".to_string()))
+    }) {
+        Ok(user_key) => user_key,
+        Err(err) => return Box::new(future::err(err)),
+    };
+    Box::new(
+        sessions::get_user_session_opt(mem, &user_key).and_then(|user_session_opt| {
+            user_session_opt.ok_or(Error::Unauthorized(String::from("Invalid credentials")))
+        }),
+    )
+}
+
+fn decrypt_access_token(config: &Config, access_token: &AccessToken) -> Result<AccessKey> {
+    access_token
+        .decrypt(&config.peppers, config.access_token_accept_legacy)
+        .map_err(|err| {
+            debug!("decrypt_access_token: Decrypt error \"{}\"", err);
+            Error::BadRequest(format!("Authentication value error"))
         })
 }
 
-fn preprocess_authz_token(req: &HttpRequest<AppState>) -> Result<AccessToken> {
+/// Refresh tokens are only checked for being well formed here, since using one
+/// is what rotates it, see [sessions::refresh_user_access_key]
+pub fn authenticate_refresh(req: &HttpRequest<AppState>) -> Result<RefreshAccessKey> {
+    let config = req.state().config.clone();
+    let access_key = preprocess_authz_token(req, cookies::REFRESH_COOKIE)?
+        .decrypt(&config.peppers, false)
+        .map_err(|err| {
+            debug!("authenticate_refresh: Decrypt error \"{}\"", err);
+            Error::BadRequest(format!("Authentication value error"))
+        })?;
+    access_key
+        .refresh_key()
+        .cloned()
+        .ok_or(Error::BadRequest("Not a refresh token".to_string()))
+}
+
+/// Read the token from the Authorization header, or from its cookie in cookie mode
+fn preprocess_authz_token(req: &HttpRequest<AppState>, cookie_name: &str) -> Result<AccessToken> {
     let token = match req.headers().get(AUTHORIZATION) {
         Some(token) => token.to_str().unwrap(),
         None => {
+            if req.state().config.session_cookies {
+                if let Some(cookie) = req.cookie(cookie_name) {
+                    cookies::verify_csrf_for_method(req)?;
+                    return Ok(AccessToken(cookie.value().to_string()));
+                }
+            }
             return Err(Error::Unauthorized(
                 "No authorization was provided".to_string(),
             ));
@@ -240,3 +428,161 @@ fn preprocess_authz_token(req: &HttpRequest<AppState>) -> Result<AccessToken> {
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use short_crypt::ShortCrypt;
+
+    fn peppers() -> PepperKeyring {
+        PepperKeyring::from_peppers(&[(0, "pepper zero"), (1, "pepper one")], 1)
+    }
+
+    /// A token in the ShortCrypt format used before the versioned one
+    fn legacy_token(pepper: &str, plain: &str) -> AccessToken {
+        let salt = "0123456789abcdef0123456789abcdef";
+        let sp_encrypted =
+            ShortCrypt::new(format!("{}{}", salt, pepper)).encrypt_to_url_component(plain);
+        AccessToken(
+            ShortCrypt::new("access_token")
+                .encrypt_to_url_component(&format!("{}{}", salt, sp_encrypted)),
+        )
+    }
+
+    fn tampered(token: &AccessToken, index: usize) -> AccessToken {
+        let mut bytes = base64::decode_config(token.as_str(), base64::URL_SAFE_NO_PAD).unwrap();
+        bytes[index] ^= 0x01;
+        AccessToken(base64::encode_config(&bytes, base64::URL_SAFE_NO_PAD))
+    }
+
+    #[test]
+    fn round_trips_each_kind_with_its_pepper() {
+        let peppers = peppers();
+        let login = AccessToken::encrypt(
+            AccessKey::new_login_key(1, LoginAccessKey(String::from("login key"))),
+            &peppers,
+        );
+        let user = AccessToken::encrypt(
+            AccessKey::new_user_key(0, UserAccessKey(String::from("user key"))),
+            &peppers,
+        );
+        let refresh = AccessToken::encrypt(
+            AccessKey::new_refresh_key(1, RefreshAccessKey(String::from("refresh key"))),
+            &peppers,
+        );
+
+        let login = login.decrypt(&peppers, false).unwrap();
+        assert_eq!(login.pepper_id, 1);
+        assert_eq!(login.login_key().unwrap().0, "login key");
+        assert!(login.user_key().is_none());
+        let user = user.decrypt(&peppers, false).unwrap();
+        assert_eq!(user.pepper_id, 0);
+        assert_eq!(user.user_key().unwrap().0, "user key");
+        let refresh = refresh.decrypt(&peppers, false).unwrap();
+        assert_eq!(refresh.refresh_key().unwrap().0, "refresh key");
+    }
+
+    #[test]
+    fn tokens_are_not_deterministic() {
+        let peppers = peppers();
+        let seal = || {
+            AccessToken::encrypt(
+                AccessKey::new_user_key(1, UserAccessKey(String::from("user key"))),
+                &peppers,
+            )
+        };
+        assert_ne!(seal().as_str(), seal().as_str());
+    }
+
+    #[test]
+    fn rejects_a_tampered_header() {
+        let peppers = peppers();
+        let token = AccessToken::encrypt(
+            AccessKey::new_user_key(1, UserAccessKey(String::from("user key"))),
+            &peppers,
+        );
+        // pepper id 1 becomes 0, which is configured, but the header is authenticated
+        assert_eq!(
+            tampered(&token, 1).decrypt(&peppers, false).err(),
+            Some("Token failed verification")
+        );
+        // version 2 becomes 3
+        assert_eq!(
+            tampered(&token, 0).decrypt(&peppers, false).err(),
+            Some("Unknown token version")
+        );
+    }
+
+    #[test]
+    fn rejects_a_tampered_nonce_or_ciphertext() {
+        let peppers = peppers();
+        let token = AccessToken::encrypt(
+            AccessKey::new_user_key(1, UserAccessKey(String::from("user key"))),
+            &peppers,
+        );
+        let len = base64::decode_config(token.as_str(), base64::URL_SAFE_NO_PAD)
+            .unwrap()
+            .len();
+        for &index in &[2, 2 + NONCE_LENGTH, len - 1] {
+            assert_eq!(
+                tampered(&token, index).decrypt(&peppers, false).err(),
+                Some("Token failed verification")
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_a_token_sealed_with_another_pepper() {
+        let token = AccessToken::encrypt(
+            AccessKey::new_user_key(1, UserAccessKey(String::from("user key"))),
+            &peppers(),
+        );
+        let rotated = PepperKeyring::from_peppers(&[(0, "pepper zero"), (1, "another")], 1);
+        assert_eq!(
+            token.decrypt(&rotated, false).err(),
+            Some("Token failed verification")
+        );
+        let retired = PepperKeyring::from_peppers(&[(0, "pepper zero")], 0);
+        assert_eq!(token.decrypt(&retired, false).err(), Some("Unknown pepper"));
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        let peppers = peppers();
+        assert!(AccessToken(String::from("not base64!"))
+            .decrypt(&peppers, true)
+            .is_err());
+        // version 2 and pepper 1, but nothing sealed
+        assert_eq!(
+            AccessToken(String::from("AgEAAA"))
+                .decrypt(&peppers, false)
+                .err(),
+            Some("Token too short")
+        );
+    }
+
+    #[test]
+    fn legacy_tokens_are_only_accepted_when_enabled() {
+        let peppers = peppers();
+        let user = legacy_token("pepper zero", "User user key");
+        let login = legacy_token("pepper zero", "Login login key");
+
+        assert!(user.decrypt(&peppers, false).is_err());
+        let user = user.decrypt(&peppers, true).unwrap();
+        assert_eq!(user.pepper_id, 0);
+        assert_eq!(user.user_key().unwrap().0, "user key");
+        let login = login.decrypt(&peppers, true).unwrap();
+        assert_eq!(login.login_key().unwrap().0, "login key");
+    }
+
+    #[test]
+    fn legacy_tokens_are_read_with_pepper_zero() {
+        let peppers = peppers();
+        assert!(legacy_token("pepper one", "User user key")
+            .decrypt(&peppers, true)
+            .is_err());
+        assert!(legacy_token("pepper zero", "Refresh key")
+            .decrypt(&peppers, true)
+            .is_err());
+    }
+}
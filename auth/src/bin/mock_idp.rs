@@ -0,0 +1,337 @@
+//! A stand-in for Google and GitHub, so the whole login flow can run without a network.
+//!
+//! It signs in scripted users from `MOCK_IDP_USERS` (default `fixtures/mock_idp_users.json`)
+//! without asking anything. Pick one with the `login_hint` parameter of the login url, otherwise
+//! the first user signs in. Point the auth server at it with:
+//!
+//! ```sh
+//! GOOGLE_AUTHORIZE_URL=http://127.0.0.1:8090/authorize
+//! GOOGLE_TOKEN_URL=http://127.0.0.1:8090/token
+//! GOOGLE_PEOPLE_URL=http://127.0.0.1:8090/people/me
+//! GITHUB_AUTHORIZE_URL=http://127.0.0.1:8090/authorize
+//! GITHUB_TOKEN_URL=http://127.0.0.1:8090/token
+//! GITHUB_API_URL=http://127.0.0.1:8090
+//! ```
+#[macro_use]
+extern crate log;
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
+
+use actix_web::{
+    http::{header, Method},
+    server, App, Form, HttpRequest, HttpResponse, Query, State,
+};
+use ring::{digest, rand::SecureRandom, rand::SystemRandom};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// A person the mock provider signs in, as read from the users file
+#[derive(Clone, Debug, Deserialize)]
+struct MockUser {
+    id: String,
+    email: String,
+    name: String,
+    #[serde(default)]
+    given_name: Option<String>,
+    /// GitHub login, defaults to the id
+    #[serde(default)]
+    login: Option<String>,
+    #[serde(default)]
+    picture: Option<String>,
+    /// Google Workspace domain, sent as `hd` in the ID token
+    #[serde(default)]
+    hd: Option<String>,
+    /// Sent as `email_verified` in the ID token, defaults to true
+    #[serde(default = "verified")]
+    email_verified: bool,
+    /// Script a failed sign in, like "access_denied"
+    #[serde(default)]
+    error: Option<String>,
+}
+
+/// An authorization code waiting to be exchanged
+struct Grant {
+    user: MockUser,
+    redirect_uri: String,
+    code_challenge: Option<String>,
+    /// Sent back in the ID token, as its audience and nonce
+    client_id: Option<String>,
+    nonce: Option<String>,
+    /// Everything asked for is granted, and reported back like Google does
+    scope: Option<String>,
+}
+
+#[derive(Clone)]
+struct MockState {
+    users: Arc<Vec<MockUser>>,
+    grants: Arc<Mutex<HashMap<String, Grant>>>,
+    access_tokens: Arc<Mutex<HashMap<String, MockUser>>>,
+}
+
+#[derive(Deserialize)]
+struct AuthorizeQuery {
+    redirect_uri: String,
+    state: String,
+    client_id: Option<String>,
+    code_challenge: Option<String>,
+    nonce: Option<String>,
+    login_hint: Option<String>,
+    scope: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenForm {
+    code: String,
+    redirect_uri: String,
+    code_verifier: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct PeopleQuery {
+    access_token: String,
+}
+
+fn main() {
+    kankyo::load().ok();
+    if std::env::var("RUST_LOG").ok().is_none() {
+        std::env::set_var("RUST_LOG", "mock_idp=debug,actix_web=info");
+    }
+    env_logger::init();
+
+    let bind_address =
+        std::env::var("MOCK_IDP_BIND_ADDRESS").unwrap_or_else(|_| String::from("127.0.0.1:8090"));
+    let users_path = std::env::var("MOCK_IDP_USERS")
+        .unwrap_or_else(|_| String::from("fixtures/mock_idp_users.json"));
+    let users: Vec<MockUser> = serde_json::from_reader(
+        std::fs::File::open(&users_path)
+            .unwrap_or_else(|err| panic!("MOCK_IDP_USERS {} can't be read: {}", users_path, err)),
+    )
+    .unwrap_or_else(|err| panic!("MOCK_IDP_USERS {} is invalid: {}", users_path, err));
+    assert!(!users.is_empty(), "MOCK_IDP_USERS must have a user");
+
+    let state = MockState {
+        users: Arc::new(users),
+        grants: Arc::new(Mutex::new(HashMap::new())),
+        access_tokens: Arc::new(Mutex::new(HashMap::new())),
+    };
+
+    let sys = actix::System::new("mock_idp");
+    server::new(move || {
+        App::with_state(state.clone())
+            .resource("/authorize", |r| r.method(Method::GET).with(authorize))
+            .resource("/token", |r| r.method(Method::POST).with(token))
+            .resource("/people/me", |r| r.method(Method::GET).with(people_me))
+            .resource("/user", |r| r.method(Method::GET).f(github_user))
+            .resource("/user/emails", |r| {
+                r.method(Method::GET).f(github_user_emails)
+            })
+    })
+    .bind(&bind_address)
+    .unwrap_or_else(|err| panic!("Can't bind to {}: {}", bind_address, err))
+    .start();
+
+    info!(
+        "Mock identity provider listening on http://{}",
+        bind_address
+    );
+    let _ = sys.run();
+}
+
+/// Sign the scripted user in straight away and send them back with a code
+fn authorize((query, state): (Query<AuthorizeQuery>, State<MockState>)) -> HttpResponse {
+    let user = query
+        .login_hint
+        .as_ref()
+        .and_then(|hint| {
+            state
+                .users
+                .iter()
+                .find(|user| &user.id == hint || &user.email == hint)
+        })
+        .unwrap_or(&state.users[0])
+        .clone();
+
+    let separator = if query.redirect_uri.contains('?') {
+        '&'
+    } else {
+        '?'
+    };
+    let location = match user.error.clone() {
+        Some(error) => format!(
+            "{}{}error={}&state={}",
+            query.redirect_uri, separator, error, query.state
+        ),
+        None => {
+            let code = random_hex();
+            debug!("authorize: {} signed in with code {}", user.id, code);
+            state.grants.lock().unwrap().insert(
+                code.clone(),
+                Grant {
+                    user,
+                    redirect_uri: query.redirect_uri.clone(),
+                    code_challenge: query.code_challenge.clone(),
+                    client_id: query.client_id.clone(),
+                    nonce: query.nonce.clone(),
+                    scope: query.scope.clone(),
+                },
+            );
+            format!(
+                "{}{}code={}&state={}",
+                query.redirect_uri, separator, code, query.state
+            )
+        }
+    };
+    HttpResponse::Found()
+        .header(header::LOCATION, location)
+        .finish()
+}
+
+/// Exchange a code once, checking the redirect uri and PKCE verifier like a real provider
+fn token((form, state): (Form<TokenForm>, State<MockState>)) -> HttpResponse {
+    let grant = match state.grants.lock().unwrap().remove(&form.code) {
+        Some(grant) => grant,
+        None => return token_error("invalid_grant", "Unknown or already used code"),
+    };
+    if grant.redirect_uri != form.redirect_uri {
+        return token_error("invalid_grant", "redirect_uri does not match");
+    }
+    if let Some(ref code_challenge) = grant.code_challenge {
+        let verified = form
+            .code_verifier
+            .as_ref()
+            .map(|verifier| {
+                let hash = digest::digest(&digest::SHA256, verifier.as_bytes());
+                &base64::encode_config(hash.as_ref(), base64::URL_SAFE_NO_PAD) == code_challenge
+            })
+            .unwrap_or(false);
+        if !verified {
+            return token_error("invalid_grant", "code_verifier does not match");
+        }
+    }
+
+    let access_token = random_hex();
+    let id_token = unsigned_id_token(&grant);
+    state
+        .access_tokens
+        .lock()
+        .unwrap()
+        .insert(access_token.clone(), grant.user);
+    HttpResponse::Ok().json(json!({
+        "access_token": access_token,
+        "token_type": "Bearer",
+        "expires_in": 3600,
+        "id_token": id_token,
+        "scope": grant.scope,
+    }))
+}
+
+/// Google People API, https://developers.google.com/people/api/rest/v1/people/get
+fn people_me((query, state): (Query<PeopleQuery>, State<MockState>)) -> HttpResponse {
+    let user = match signed_in_user(&state, &query.access_token) {
+        Some(user) => user,
+        None => return HttpResponse::Unauthorized().finish(),
+    };
+    let metadata = json!({ "primary": true, "verified": true, "source": { "type": "PROFILE", "id": user.id } });
+    HttpResponse::Ok().json(json!({
+        "resourceName": format!("people/{}", user.id),
+        "names": [{
+            "metadata": metadata,
+            "displayName": user.name,
+            "givenName": user.given_name,
+        }],
+        "emailAddresses": [{ "metadata": metadata, "value": user.email }],
+        "photos": [{
+            "metadata": metadata,
+            "url": user.picture.clone().unwrap_or_else(|| String::from("http://127.0.0.1/photo.jpg")),
+        }],
+    }))
+}
+
+/// GitHub REST API, https://developer.github.com/v3/users/#get-the-authenticated-user
+fn github_user(req: &HttpRequest<MockState>) -> HttpResponse {
+    match github_token_user(req) {
+        Some(user) => HttpResponse::Ok().json(json!({
+            "id": user.id.parse::<u64>().unwrap_or(0),
+            "login": user.login.clone().unwrap_or_else(|| user.id.clone()),
+            "name": user.name,
+            "avatar_url": user.picture,
+        })),
+        None => HttpResponse::Unauthorized().finish(),
+    }
+}
+
+fn github_user_emails(req: &HttpRequest<MockState>) -> HttpResponse {
+    match github_token_user(req) {
+        Some(user) => HttpResponse::Ok().json(json!([{
+            "email": user.email,
+            "primary": true,
+            "verified": true,
+        }])),
+        None => HttpResponse::Unauthorized().finish(),
+    }
+}
+
+fn github_token_user(req: &HttpRequest<MockState>) -> Option<MockUser> {
+    let access_token = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| {
+            if value.starts_with("token ") {
+                Some(value["token ".len()..].to_string())
+            } else {
+                None
+            }
+        })?;
+    signed_in_user(req.state(), &access_token)
+}
+
+fn signed_in_user(state: &MockState, access_token: &str) -> Option<MockUser> {
+    state
+        .access_tokens
+        .lock()
+        .unwrap()
+        .get(access_token)
+        .cloned()
+}
+
+/// Enough of an ID token for the claims Google's provider reads, without a signature
+fn unsigned_id_token(grant: &Grant) -> String {
+    let user = &grant.user;
+    let claims = json!({
+        "iss": "https://accounts.google.com",
+        "aud": grant.client_id,
+        "exp": chrono::Utc::now().timestamp() + 3600,
+        "sub": user.id,
+        "email": user.email,
+        "email_verified": user.email_verified,
+        "hd": user.hd,
+        "nonce": grant.nonce,
+    });
+    format!(
+        "{}.{}.",
+        base64::encode_config(br#"{"alg":"none"}"#, base64::URL_SAFE_NO_PAD),
+        base64::encode_config(claims.to_string().as_bytes(), base64::URL_SAFE_NO_PAD)
+    )
+}
+
+fn verified() -> bool {
+    true
+}
+
+fn token_error(error: &str, description: &str) -> HttpResponse {
+    HttpResponse::BadRequest().json(json!({
+        "error": error,
+        "error_description": description,
+    }))
+}
+
+fn random_hex() -> String {
+    let mut bytes = [0u8; 16];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .expect("Sucessful system random");
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
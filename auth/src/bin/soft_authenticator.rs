@@ -0,0 +1,218 @@
+//! A software passkey, so WebAuthn registration and sign in can be scripted in integration tests
+//! without a browser or a security key.
+//!
+//! It reads the options the auth server responds with on stdin, and prints the body for the
+//! matching finish request, the way the browser would build it. Keys are Ed25519 and are kept in
+//! `SOFT_AUTHENTICATOR_KEYS` (default `soft_authenticator_keys.json`) between runs.
+//!
+//! ```sh
+//! curl -X POST -H "Authorization: Bearer $USER_TOKEN" $AUTH/me/webauthn/register/start \
+//!   | cargo run --bin soft_authenticator -- create http://127.0.0.1:8088 \
+//!   | curl -X POST -H "Authorization: Bearer $USER_TOKEN" -H "Content-Type: application/json" \
+//!       -d @- $AUTH/me/webauthn/register/finish
+//! curl -X POST -H "Authorization: Bearer $LOGIN_TOKEN" $AUTH/login/session/webauthn/start \
+//!   | cargo run --bin soft_authenticator -- get http://127.0.0.1:8088 \
+//!   | curl -X POST -H "Authorization: Bearer $LOGIN_TOKEN" -H "Content-Type: application/json" \
+//!       -d @- $AUTH/login/session/webauthn/finish
+//! ```
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
+
+use ring::{digest, rand::SecureRandom, rand::SystemRandom, signature};
+use serde_cbor::Value;
+use std::collections::BTreeMap;
+
+/// Stands for "none" attestation, like a platform authenticator which doesn't say what it is
+const AAGUID: [u8; 16] = [0; 16];
+
+// Authenticator data flags: user present, user verified and attested credential data
+const FLAGS_ASSERTION: u8 = 0x01 | 0x04;
+const FLAGS_REGISTRATION: u8 = FLAGS_ASSERTION | 0x40;
+
+/// A credential the authenticator created
+#[derive(Serialize, Deserialize)]
+struct StoredKey {
+    credential_id: String,
+    rp_id: String,
+    user_handle: String,
+    /// PKCS#8 of the Ed25519 key pair, in base64url
+    pkcs8: String,
+    sign_count: u32,
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let (ceremony, origin) = match (args.get(1), args.get(2)) {
+        (Some(ceremony), Some(origin)) => (ceremony.as_str(), origin.as_str()),
+        _ => {
+            eprintln!("usage: soft_authenticator create|get <origin> < options.json");
+            std::process::exit(2);
+        }
+    };
+    let keys_path = std::env::var("SOFT_AUTHENTICATOR_KEYS")
+        .unwrap_or_else(|_| String::from("soft_authenticator_keys.json"));
+    let mut keys: Vec<StoredKey> = std::fs::File::open(&keys_path)
+        .ok()
+        .map(|file| {
+            serde_json::from_reader(file).unwrap_or_else(|err| {
+                panic!("SOFT_AUTHENTICATOR_KEYS {} is invalid: {}", keys_path, err)
+            })
+        })
+        .unwrap_or_default();
+    let options: serde_json::Value =
+        serde_json::from_reader(std::io::stdin()).expect("Options on stdin are JSON");
+    let options = &options["publicKey"];
+
+    let credential = match ceremony {
+        "create" => create(&mut keys, options, origin),
+        "get" => get(&mut keys, options, origin),
+        other => panic!("Unknown ceremony {}, expected create or get", other),
+    };
+
+    std::fs::write(&keys_path, serde_json::to_vec_pretty(&keys).unwrap()).unwrap_or_else(|err| {
+        panic!(
+            "SOFT_AUTHENTICATOR_KEYS {} can't be written: {}",
+            keys_path, err
+        )
+    });
+    println!("{}", credential);
+}
+
+/// Like `navigator.credentials.create()`
+fn create(
+    keys: &mut Vec<StoredKey>,
+    options: &serde_json::Value,
+    origin: &str,
+) -> serde_json::Value {
+    let rp_id = options["rp"]["id"].as_str().expect("Options have rp.id");
+    let user_handle = options["user"]["id"]
+        .as_str()
+        .expect("Options have user.id");
+    let rng = SystemRandom::new();
+    let pkcs8 = signature::Ed25519KeyPair::generate_pkcs8(&rng).expect("Key pair generated");
+    let key_pair = key_pair(&pkcs8);
+    let mut credential_id = [0u8; 16];
+    rng.fill(&mut credential_id)
+        .expect("Sucessful system random");
+
+    // COSE_Key: kty OKP, alg EdDSA, crv Ed25519, x
+    let mut cose_key = BTreeMap::new();
+    cose_key.insert(Value::Integer(1), Value::Integer(1));
+    cose_key.insert(Value::Integer(3), Value::Integer(-8));
+    cose_key.insert(Value::Integer(-1), Value::Integer(6));
+    cose_key.insert(
+        Value::Integer(-2),
+        Value::Bytes(key_pair.public_key_bytes().to_vec()),
+    );
+
+    let mut auth_data = authenticator_data(rp_id, FLAGS_REGISTRATION, 0);
+    auth_data.extend_from_slice(&AAGUID);
+    auth_data.extend_from_slice(&[0, credential_id.len() as u8]);
+    auth_data.extend_from_slice(&credential_id);
+    auth_data.extend(serde_cbor::to_vec(&Value::Map(cose_key)).unwrap());
+
+    let mut attestation = BTreeMap::new();
+    attestation.insert(
+        Value::Text(String::from("fmt")),
+        Value::Text(String::from("none")),
+    );
+    attestation.insert(
+        Value::Text(String::from("attStmt")),
+        Value::Map(BTreeMap::new()),
+    );
+    attestation.insert(
+        Value::Text(String::from("authData")),
+        Value::Bytes(auth_data),
+    );
+
+    let id = encode(&credential_id);
+    keys.push(StoredKey {
+        credential_id: id.clone(),
+        rp_id: rp_id.to_string(),
+        user_handle: user_handle.to_string(),
+        pkcs8: encode(&pkcs8[..]),
+        sign_count: 0,
+    });
+    json!({
+        "id": id,
+        "type": "public-key",
+        "name": "Software authenticator",
+        "response": {
+            "clientDataJSON": encode(client_data("webauthn.create", options, origin).as_bytes()),
+            "attestationObject": encode(&serde_cbor::to_vec(&Value::Map(attestation)).unwrap()),
+        },
+    })
+}
+
+/// Like `navigator.credentials.get()`, using the newest allowed key for the relying party
+fn get(keys: &mut Vec<StoredKey>, options: &serde_json::Value, origin: &str) -> serde_json::Value {
+    let rp_id = options["rpId"].as_str().expect("Options have rpId");
+    let allowed: Vec<&str> = options["allowCredentials"]
+        .as_array()
+        .map(|allowed| {
+            allowed
+                .iter()
+                .filter_map(|credential| credential["id"].as_str())
+                .collect()
+        })
+        .unwrap_or_default();
+    let stored = keys
+        .iter_mut()
+        .rev()
+        .find(|key| {
+            key.rp_id == rp_id
+                && (allowed.is_empty() || allowed.contains(&key.credential_id.as_str()))
+        })
+        .unwrap_or_else(|| panic!("No key for {} was created yet", rp_id));
+    stored.sign_count += 1;
+
+    let auth_data = authenticator_data(rp_id, FLAGS_ASSERTION, stored.sign_count);
+    let client_data_json = client_data("webauthn.get", options, origin);
+    let mut signed = auth_data.clone();
+    signed.extend_from_slice(digest::digest(&digest::SHA256, client_data_json.as_bytes()).as_ref());
+    let signature = key_pair(&decode(&stored.pkcs8)).sign(&signed);
+
+    json!({
+        "id": stored.credential_id,
+        "type": "public-key",
+        "response": {
+            "clientDataJSON": encode(client_data_json.as_bytes()),
+            "authenticatorData": encode(&auth_data),
+            "signature": encode(signature.as_ref()),
+            "userHandle": stored.user_handle,
+        },
+    })
+}
+
+fn authenticator_data(rp_id: &str, flags: u8, sign_count: u32) -> Vec<u8> {
+    let mut data = digest::digest(&digest::SHA256, rp_id.as_bytes())
+        .as_ref()
+        .to_vec();
+    data.push(flags);
+    data.extend_from_slice(&sign_count.to_be_bytes());
+    data
+}
+
+fn client_data(ceremony: &str, options: &serde_json::Value, origin: &str) -> String {
+    json!({
+        "type": ceremony,
+        "challenge": options["challenge"].as_str().expect("Options have a challenge"),
+        "origin": origin,
+        "crossOrigin": false,
+    })
+    .to_string()
+}
+
+fn key_pair(pkcs8: &[u8]) -> signature::Ed25519KeyPair {
+    signature::Ed25519KeyPair::from_pkcs8(untrusted::Input::from(pkcs8)).expect("Valid PKCS#8")
+}
+
+fn encode(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+fn decode(value: &str) -> Vec<u8> {
+    base64::decode_config(value, base64::URL_SAFE_NO_PAD).expect("Valid base64url")
+}
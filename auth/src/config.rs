@@ -1,15 +1,70 @@
 use std::env;
+use std::time::Duration;
+use url::Url;
 
 #[derive(Clone, Debug)]
 pub struct Config {
     pub database_url: String,
     pub google_oauth_client_id: String,
     pub google_oauth_client_secret: String,
+    pub google_authorize_url: String,
+    pub google_token_url: String,
+    pub google_people_url: String,
+    pub google_allowed_domains: String,
+    pub google_allowed_emails: String,
+    pub google_scopes: String,
+    pub google_optional_scopes: String,
+    pub github_oauth_client_id: String,
+    pub github_oauth_client_secret: String,
+    pub github_authorize_url: String,
+    pub github_token_url: String,
+    pub github_api_url: String,
     pub http_allowed_origins: String,
     pub http_bind_address: String,
     pub http_public_url: String,
+    pub hasura_allowed_roles: String,
+    pub hasura_anonymous_role: String,
+    pub hasura_default_role: String,
+    pub introspection_clients: String,
+    pub jwt_expiration_secs: u64,
+    pub jwt_private_key_path: String,
+    pub jwt_verification_key_paths: String,
+    pub oidc_provider_name: String,
+    pub oidc_issuer_url: String,
+    pub oidc_client_id: String,
+    pub oidc_client_secret: String,
+    pub oidc_scopes: String,
+    pub login_redirect_allowlist: RedirectAllowlist,
+    pub email_login: bool,
+    pub email_login_expiration_secs: u64,
+    pub mailer: String,
+    pub mail_from: String,
+    pub mail_file_dir: String,
+    pub smtp_host: String,
+    pub smtp_username: String,
+    pub smtp_password: String,
+    pub password_login: bool,
+    pub password_min_length: usize,
+    pub password_hash_memory_kib: u32,
+    pub password_hash_iterations: u32,
+    pub password_hash_parallelism: u32,
+    pub password_reset_url: String,
+    pub password_reset_expiration_secs: u64,
+    pub password_setup_url: String,
+    pub totp_encryption_key: String,
+    pub totp_issuer: String,
+    pub webauthn: bool,
+    pub webauthn_rp_id: String,
+    pub webauthn_rp_name: String,
+    pub webauthn_origins: String,
+    pub login_session_expiration_secs: u64,
+    pub user_session_expiration_secs: u64,
+    pub refresh_token_expiration_secs: u64,
     pub redis_url: String,
-    pub pepper_0: String,
+    pub peppers: PepperKeyring,
+    pub access_token_accept_legacy: bool,
+    pub session_cookies: bool,
+    pub session_cookie_secure: bool,
 }
 
 impl Default for Config {
@@ -18,11 +73,69 @@ impl Default for Config {
             database_url: String::from("postgres://postgres:@localhost/app"),
             google_oauth_client_id: String::from(""),
             google_oauth_client_secret: String::from(""),
+            google_authorize_url: String::from("https://accounts.google.com/o/oauth2/v2/auth"),
+            google_token_url: String::from("https://www.googleapis.com/oauth2/v4/token"),
+            google_people_url: String::from("https://people.googleapis.com/v1/people/me"),
+            google_allowed_domains: String::from(""),
+            google_allowed_emails: String::from(""),
+            google_scopes: String::from(
+                "openid email https://www.googleapis.com/auth/userinfo.profile",
+            ),
+            google_optional_scopes: String::from(
+                "calendar=https://www.googleapis.com/auth/calendar",
+            ),
+            github_oauth_client_id: String::from(""),
+            github_oauth_client_secret: String::from(""),
+            github_authorize_url: String::from("https://github.com/login/oauth/authorize"),
+            github_token_url: String::from("https://github.com/login/oauth/access_token"),
+            github_api_url: String::from("https://api.github.com"),
             http_allowed_origins: String::from(""),
             http_bind_address: String::from("127.0.0.1:8088"),
             http_public_url: String::from("http://127.0.0.1:8088"),
+            hasura_allowed_roles: String::from("user"),
+            hasura_anonymous_role: String::from("anonymous"),
+            hasura_default_role: String::from("user"),
+            introspection_clients: String::from(""),
+            jwt_expiration_secs: 60 * 15,
+            jwt_private_key_path: String::from(""),
+            jwt_verification_key_paths: String::from(""),
+            oidc_provider_name: String::from("oidc"),
+            oidc_issuer_url: String::from(""),
+            oidc_client_id: String::from(""),
+            oidc_client_secret: String::from(""),
+            oidc_scopes: String::from("openid email profile"),
+            login_redirect_allowlist: RedirectAllowlist::default(),
+            email_login: false,
+            email_login_expiration_secs: 60 * 15,
+            mailer: String::from("file"),
+            mail_from: String::from("no-reply@localhost"),
+            mail_file_dir: String::from("mail"),
+            smtp_host: String::from(""),
+            smtp_username: String::from(""),
+            smtp_password: String::from(""),
+            password_login: false,
+            password_min_length: 10,
+            // OWASP's minimum for Argon2id
+            password_hash_memory_kib: 19 * 1024,
+            password_hash_iterations: 2,
+            password_hash_parallelism: 1,
+            password_reset_url: String::from(""),
+            password_reset_expiration_secs: 60 * 60,
+            password_setup_url: String::from(""),
+            totp_encryption_key: String::from(""),
+            totp_issuer: String::from("Knot"),
+            webauthn: false,
+            webauthn_rp_id: String::from(""),
+            webauthn_rp_name: String::from("Knot"),
+            webauthn_origins: String::from(""),
+            login_session_expiration_secs: 60 * 120,
+            user_session_expiration_secs: 60 * 60,
+            refresh_token_expiration_secs: 60 * 60 * 24 * 30,
             redis_url: String::from("127.0.0.1:6379"),
-            pepper_0: String::from(""),
+            peppers: PepperKeyring::default(),
+            access_token_accept_legacy: false,
+            session_cookies: false,
+            session_cookie_secure: true,
         }
     }
 }
@@ -49,6 +162,24 @@ fn env_or(name: &str, default: &str) -> String {
     env::var(name).ok().unwrap_or_else(|| default.to_string())
 }
 
+/// Helper function for numbers and other parsed values, which must be valid when set
+fn env_parse_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    match env::var(name).ok() {
+        Some(value) => value
+            .parse()
+            .unwrap_or_else(|_| panic!("{} has an invalid value", name)),
+        None => default,
+    }
+}
+
+/// Helper function for flags, where "true" or "1" turn the flag on
+fn env_flag_or(name: &str, default: bool) -> bool {
+    match env::var(name).ok() {
+        Some(value) => value == "true" || value == "1",
+        None => default,
+    }
+}
+
 impl Config {
     pub fn with_environment(&self) -> Config {
         Config {
@@ -58,11 +189,398 @@ impl Config {
                 "GOOGLE_OAUTH_CLIENT_SECRET",
                 &self.google_oauth_client_secret,
             ),
+            google_authorize_url: env_or("GOOGLE_AUTHORIZE_URL", &self.google_authorize_url),
+            google_token_url: env_or("GOOGLE_TOKEN_URL", &self.google_token_url),
+            google_people_url: env_or("GOOGLE_PEOPLE_URL", &self.google_people_url),
+            google_allowed_domains: env_or("GOOGLE_ALLOWED_DOMAINS", &self.google_allowed_domains),
+            google_allowed_emails: env_or("GOOGLE_ALLOWED_EMAILS", &self.google_allowed_emails),
+            google_scopes: env_or("GOOGLE_SCOPES", &self.google_scopes),
+            google_optional_scopes: env_or("GOOGLE_OPTIONAL_SCOPES", &self.google_optional_scopes),
+            github_oauth_client_id: env_or("GITHUB_OAUTH_CLIENT_ID", &self.github_oauth_client_id),
+            github_oauth_client_secret: env_or(
+                "GITHUB_OAUTH_CLIENT_SECRET",
+                &self.github_oauth_client_secret,
+            ),
+            github_authorize_url: env_or("GITHUB_AUTHORIZE_URL", &self.github_authorize_url),
+            github_token_url: env_or("GITHUB_TOKEN_URL", &self.github_token_url),
+            github_api_url: env_or("GITHUB_API_URL", &self.github_api_url),
             http_bind_address: env_or("HTTP_BIND_ADDRESS", &self.http_bind_address),
             http_public_url: env_or("PUBLIC_URL", &self.http_public_url),
             http_allowed_origins: env_or("HTTP_ALLOWED_ORIGINS", &self.http_allowed_origins),
+            hasura_allowed_roles: env_or("HASURA_ALLOWED_ROLES", &self.hasura_allowed_roles),
+            hasura_anonymous_role: env_or("HASURA_ANONYMOUS_ROLE", &self.hasura_anonymous_role),
+            hasura_default_role: env_or("HASURA_DEFAULT_ROLE", &self.hasura_default_role),
+            introspection_clients: env_or("INTROSPECTION_CLIENTS", &self.introspection_clients),
+            jwt_expiration_secs: env_parse_or("JWT_EXPIRATION_SECS", self.jwt_expiration_secs),
+            jwt_private_key_path: env_or("JWT_PRIVATE_KEY_PATH", &self.jwt_private_key_path),
+            jwt_verification_key_paths: env_or(
+                "JWT_VERIFICATION_KEY_PATHS",
+                &self.jwt_verification_key_paths,
+            ),
+            oidc_provider_name: env_or("OIDC_PROVIDER_NAME", &self.oidc_provider_name),
+            oidc_issuer_url: env_or("OIDC_ISSUER_URL", &self.oidc_issuer_url),
+            oidc_client_id: env_or("OIDC_CLIENT_ID", &self.oidc_client_id),
+            oidc_client_secret: env_or("OIDC_CLIENT_SECRET", &self.oidc_client_secret),
+            oidc_scopes: env_or("OIDC_SCOPES", &self.oidc_scopes),
+            login_redirect_allowlist: self.login_redirect_allowlist.with_environment(),
+            email_login: env_flag_or("EMAIL_LOGIN", self.email_login),
+            email_login_expiration_secs: env_parse_or(
+                "EMAIL_LOGIN_EXPIRATION_SECS",
+                self.email_login_expiration_secs,
+            ),
+            mailer: env_or("MAILER", &self.mailer),
+            mail_from: env_or("MAIL_FROM", &self.mail_from),
+            mail_file_dir: env_or("MAIL_FILE_DIR", &self.mail_file_dir),
+            smtp_host: env_or("SMTP_HOST", &self.smtp_host),
+            smtp_username: env_or("SMTP_USERNAME", &self.smtp_username),
+            smtp_password: env_or("SMTP_PASSWORD", &self.smtp_password),
+            password_login: env_flag_or("PASSWORD_LOGIN", self.password_login),
+            password_min_length: env_parse_or("PASSWORD_MIN_LENGTH", self.password_min_length),
+            password_hash_memory_kib: env_parse_or(
+                "PASSWORD_HASH_MEMORY_KIB",
+                self.password_hash_memory_kib,
+            ),
+            password_hash_iterations: env_parse_or(
+                "PASSWORD_HASH_ITERATIONS",
+                self.password_hash_iterations,
+            ),
+            password_hash_parallelism: env_parse_or(
+                "PASSWORD_HASH_PARALLELISM",
+                self.password_hash_parallelism,
+            ),
+            password_reset_url: env_or("PASSWORD_RESET_URL", &self.password_reset_url),
+            password_reset_expiration_secs: env_parse_or(
+                "PASSWORD_RESET_EXPIRATION_SECS",
+                self.password_reset_expiration_secs,
+            ),
+            password_setup_url: env_or("PASSWORD_SETUP_URL", &self.password_setup_url),
+            totp_encryption_key: env_or("TOTP_ENCRYPTION_KEY", &self.totp_encryption_key),
+            totp_issuer: env_or("TOTP_ISSUER", &self.totp_issuer),
+            webauthn: env_flag_or("WEBAUTHN", self.webauthn),
+            webauthn_rp_id: env_or("WEBAUTHN_RP_ID", &self.webauthn_rp_id),
+            webauthn_rp_name: env_or("WEBAUTHN_RP_NAME", &self.webauthn_rp_name),
+            webauthn_origins: env_or("WEBAUTHN_ORIGINS", &self.webauthn_origins),
+            login_session_expiration_secs: env_parse_or(
+                "LOGIN_SESSION_EXPIRATION_SECS",
+                self.login_session_expiration_secs,
+            ),
+            user_session_expiration_secs: env_parse_or(
+                "USER_SESSION_EXPIRATION_SECS",
+                self.user_session_expiration_secs,
+            ),
+            refresh_token_expiration_secs: env_parse_or(
+                "REFRESH_TOKEN_EXPIRATION_SECS",
+                self.refresh_token_expiration_secs,
+            ),
             redis_url: env_or("REDIS_URL", &self.redis_url),
-            pepper_0: env_or("PEPPER_0", &self.pepper_0),
+            peppers: self.peppers.with_environment(),
+            access_token_accept_legacy: env_flag_or(
+                "ACCESS_TOKEN_ACCEPT_LEGACY",
+                self.access_token_accept_legacy,
+            ),
+            session_cookies: env_flag_or("SESSION_COOKIES", self.session_cookies),
+            session_cookie_secure: env_flag_or("SESSION_COOKIE_SECURE", self.session_cookie_secure),
+        }
+    }
+
+    /// Login sessions only need to last through signing in
+    pub fn login_session_expiration(&self) -> Duration {
+        Duration::from_secs(self.login_session_expiration_secs)
+    }
+
+    /// Sign in links sent by email can be used once within this long
+    pub fn email_login_expiration(&self) -> Duration {
+        Duration::from_secs(self.email_login_expiration_secs)
+    }
+
+    /// Password reset and setup links can be used once within this long
+    pub fn password_reset_expiration(&self) -> Duration {
+        Duration::from_secs(self.password_reset_expiration_secs)
+    }
+
+    /// User sessions expire after this long without being used
+    pub fn user_session_expiration(&self) -> Duration {
+        Duration::from_secs(self.user_session_expiration_secs)
+    }
+
+    /// Refresh tokens expire after this long without being exchanged
+    pub fn refresh_token_expiration(&self) -> Duration {
+        Duration::from_secs(self.refresh_token_expiration_secs)
+    }
+
+    /// Where people land after signing in when their app did not ask for a `redirect_uri`
+    pub fn default_login_redirect(&self) -> String {
+        format!("{}/", self.http_public_url.trim_end_matches('/'))
+    }
+}
+
+/// Where login flows may send people back to, from `LOGIN_REDIRECT_ALLOWLIST`: a space delimited
+/// list of exact URLs like `https://example.com/welcome`, or origins ending in `/*` like
+/// `https://*.example.com/*`.
+///
+/// Entries prefixed with a client app's name, like `mobile=com.example.app:/callback`, are only
+/// allowed for logins which pass that `client`. Entries without a name are allowed for every client.
+#[derive(Clone, Debug, Default)]
+pub struct RedirectAllowlist {
+    entries: Vec<(Option<String>, RedirectPattern)>,
+}
+
+#[derive(Clone, Debug)]
+enum RedirectPattern {
+    Exact(Url),
+    Origin {
+        scheme: String,
+        /// Matches subdomains of `host` only, for `*.` patterns
+        any_subdomain: bool,
+        host: String,
+        port: Option<u16>,
+    },
+}
+
+impl RedirectAllowlist {
+    fn with_environment(&self) -> RedirectAllowlist {
+        match env::var("LOGIN_REDIRECT_ALLOWLIST").ok() {
+            Some(value) => RedirectAllowlist::parse(&value),
+            None => self.clone(),
+        }
+    }
+
+    fn parse(value: &str) -> RedirectAllowlist {
+        RedirectAllowlist {
+            entries: value
+                .split_whitespace()
+                .map(|entry| {
+                    parse_redirect_entry(entry).unwrap_or_else(|| {
+                        panic!("LOGIN_REDIRECT_ALLOWLIST has an invalid entry: {}", entry)
+                    })
+                })
+                .collect(),
+        }
+    }
+
+    /// Whether `client` may send people to `redirect_uri`
+    pub fn allows(&self, client: Option<&str>, redirect_uri: &str) -> bool {
+        let url = match Url::parse(redirect_uri) {
+            Ok(url) => url,
+            Err(_) => return false,
+        };
+        self.entries
+            .iter()
+            .filter(|(entry_client, _)| {
+                entry_client.is_none() || entry_client.as_ref().map(String::as_str) == client
+            })
+            .any(|(_, pattern)| pattern.matches(&url))
+    }
+}
+
+impl RedirectPattern {
+    fn matches(&self, url: &Url) -> bool {
+        match self {
+            RedirectPattern::Exact(allowed) => allowed == url,
+            RedirectPattern::Origin {
+                scheme,
+                any_subdomain,
+                host,
+                port,
+            } => {
+                let url_host = match url.host_str() {
+                    Some(url_host) => url_host,
+                    None => return false,
+                };
+                let host_matches = if *any_subdomain {
+                    url_host.ends_with(&format!(".{}", host))
+                } else {
+                    url_host == host
+                };
+                url.scheme() == scheme && host_matches && url.port_or_known_default() == *port
+            }
         }
     }
 }
+
+fn parse_redirect_entry(entry: &str) -> Option<(Option<String>, RedirectPattern)> {
+    let (client, pattern) = match entry.find('=') {
+        Some(index) => (Some(entry[..index].to_string()), &entry[index + 1..]),
+        None => (None, entry),
+    };
+    let pattern = if pattern.ends_with("/*") {
+        let origin = &pattern[..pattern.len() - 2];
+        let any_subdomain = origin.contains("://*.");
+        let url = Url::parse(&origin.replacen("://*.", "://", 1)).ok()?;
+        RedirectPattern::Origin {
+            scheme: url.scheme().to_string(),
+            any_subdomain,
+            host: url.host_str()?.to_string(),
+            port: url.port_or_known_default(),
+        }
+    } else {
+        RedirectPattern::Exact(Url::parse(pattern).ok()?)
+    };
+    Some((client, pattern))
+}
+
+/// Additional secrets which are not stored in the database, numbered by their environment
+/// variable: `PEPPER_0`, `PEPPER_1`, …
+///
+/// Tokens remember which pepper they were sealed with, so a pepper can be retired by minting with
+/// a newer one (`PEPPER_ACTIVE`) and only removing it once no live sessions depend on it.
+#[derive(Clone, Debug)]
+pub struct PepperKeyring {
+    peppers: Vec<(u8, String)>,
+    active_id: u8,
+}
+
+impl Default for PepperKeyring {
+    fn default() -> PepperKeyring {
+        PepperKeyring {
+            peppers: vec![(0, String::from(""))],
+            active_id: 0,
+        }
+    }
+}
+
+impl PepperKeyring {
+    fn with_environment(&self) -> PepperKeyring {
+        self.with_vars(|name| env::var(name).ok())
+    }
+
+    fn with_vars<F: Fn(&str) -> Option<String>>(&self, var: F) -> PepperKeyring {
+        let peppers: Vec<(u8, String)> = (0..=u8::max_value())
+            .filter_map(|id| var(&format!("PEPPER_{}", id)).map(|pepper| (id, pepper)))
+            .collect();
+        let active_id = var("PEPPER_ACTIVE")
+            .map(|id| id.parse().expect("PEPPER_ACTIVE must be a pepper number"))
+            .unwrap_or(self.active_id);
+
+        PepperKeyring {
+            peppers: if peppers.is_empty() {
+                self.peppers.clone()
+            } else {
+                peppers
+            },
+            active_id,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn from_peppers(peppers: &[(u8, &str)], active_id: u8) -> PepperKeyring {
+        PepperKeyring {
+            peppers: peppers
+                .iter()
+                .map(|(id, pepper)| (*id, pepper.to_string()))
+                .collect(),
+            active_id,
+        }
+    }
+
+    pub fn get(&self, id: u8) -> Option<&str> {
+        self.peppers
+            .iter()
+            .find(|(pepper_id, _)| *pepper_id == id)
+            .map(|(_, pepper)| pepper.as_str())
+    }
+
+    /// The pepper new tokens are minted with
+    pub fn active_id(&self) -> u8 {
+        self.active_id
+    }
+
+    pub fn ids(&self) -> Vec<u8> {
+        self.peppers.iter().map(|(id, _)| *id).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn keyring(vars: &[(&str, &str)]) -> PepperKeyring {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        PepperKeyring::default().with_vars(|name| vars.get(name).cloned())
+    }
+
+    #[test]
+    fn pepper_keyring_reads_numbered_peppers() {
+        let peppers = keyring(&[
+            ("PEPPER_0", "zero"),
+            ("PEPPER_2", "two"),
+            ("PEPPER_ACTIVE", "2"),
+        ]);
+        assert_eq!(peppers.ids(), vec![0, 2]);
+        assert_eq!(peppers.get(0), Some("zero"));
+        assert_eq!(peppers.get(1), None);
+        assert_eq!(peppers.get(2), Some("two"));
+        assert_eq!(peppers.active_id(), 2);
+    }
+
+    #[test]
+    fn pepper_keyring_defaults_to_pepper_zero() {
+        let peppers = keyring(&[("PEPPER_0", "zero")]);
+        assert_eq!(peppers.active_id(), 0);
+        let peppers = keyring(&[]);
+        assert_eq!(peppers.ids(), vec![0]);
+        assert_eq!(peppers.get(0), Some(""));
+    }
+
+    #[test]
+    #[should_panic(expected = "PEPPER_ACTIVE must be a pepper number")]
+    fn pepper_keyring_rejects_an_invalid_active_id() {
+        keyring(&[("PEPPER_0", "zero"), ("PEPPER_ACTIVE", "newest")]);
+    }
+
+    #[test]
+    fn redirect_allowlist_matches_exact_urls() {
+        let allowlist = RedirectAllowlist::parse("https://example.com/welcome");
+        assert!(allowlist.allows(None, "https://example.com/welcome"));
+        assert!(!allowlist.allows(None, "https://example.com/welcome/more"));
+        assert!(!allowlist.allows(None, "https://example.com/"));
+        assert!(!allowlist.allows(None, "not a url"));
+    }
+
+    #[test]
+    fn redirect_allowlist_matches_origins() {
+        let allowlist = RedirectAllowlist::parse("https://example.com/* http://localhost:3000/*");
+        assert!(allowlist.allows(None, "https://example.com/any/path?query"));
+        assert!(allowlist.allows(None, "https://example.com:443/"));
+        assert!(allowlist.allows(None, "http://localhost:3000/"));
+        assert!(!allowlist.allows(None, "http://example.com/"));
+        assert!(!allowlist.allows(None, "https://example.com.evil.com/"));
+        assert!(!allowlist.allows(None, "https://evil.example.com/"));
+        assert!(!allowlist.allows(None, "http://localhost:3001/"));
+    }
+
+    #[test]
+    fn redirect_allowlist_matches_subdomains() {
+        let allowlist = RedirectAllowlist::parse("https://*.example.com/*");
+        assert!(allowlist.allows(None, "https://app.example.com/"));
+        assert!(allowlist.allows(None, "https://a.b.example.com/"));
+        assert!(!allowlist.allows(None, "https://example.com/"));
+        assert!(!allowlist.allows(None, "https://evilexample.com/"));
+    }
+
+    #[test]
+    fn redirect_allowlist_scopes_entries_to_clients() {
+        let allowlist =
+            RedirectAllowlist::parse("https://example.com/* mobile=com.example.app:/login");
+        assert!(allowlist.allows(Some("mobile"), "com.example.app:/login"));
+        assert!(!allowlist.allows(None, "com.example.app:/login"));
+        assert!(!allowlist.allows(Some("desktop"), "com.example.app:/login"));
+        // entries without a client are allowed for every client
+        assert!(allowlist.allows(Some("mobile"), "https://example.com/"));
+    }
+
+    #[test]
+    fn redirect_allowlist_allows_nothing_when_empty() {
+        assert!(!RedirectAllowlist::parse("").allows(None, "https://example.com/"));
+    }
+
+    #[test]
+    #[should_panic(expected = "LOGIN_REDIRECT_ALLOWLIST has an invalid entry")]
+    fn redirect_allowlist_rejects_invalid_entries() {
+        RedirectAllowlist::parse("https://example.com/* not-a-url");
+    }
+}
@@ -0,0 +1,121 @@
+//! Session cookies for browser apps, so tokens don't have to be kept where scripts can read them.
+//!
+//! Cookies are sent along with requests other sites trigger, so requests which change anything
+//! must also repeat the CSRF cookie in a header ("double submit"). Other sites cannot read our
+//! cookies, so they cannot forge the header.
+use actix_web::{http::Method, HttpRequest};
+use cookie::{Cookie, SameSite};
+use ring::constant_time;
+use std::time::Duration;
+
+use crate::auth::AccessToken;
+use crate::config::Config;
+use crate::prelude::*;
+use crate::utils::secure_rand_hex;
+
+pub const LOGIN_COOKIE: &str = "knot_login";
+pub const USER_COOKIE: &str = "knot_user";
+pub const REFRESH_COOKIE: &str = "knot_refresh";
+/// Not HttpOnly, since scripts need to read it to send it back in [CSRF_HEADER]
+pub const CSRF_COOKIE: &str = "knot_csrf";
+pub const CSRF_HEADER: &str = "X-CSRF-Token";
+/// Binds provider and email logins to the browser which started them, in both session modes.
+/// Provider logins set it while the browser navigates to their start url, so it's kept even when
+/// the app's requests don't send cookies.
+pub const HANDOFF_COOKIE: &str = "knot_handoff";
+
+/// The refresh token is only sent to the endpoint which exchanges it
+const REFRESH_COOKIE_PATH: &str = "/auth/v0/token/refresh";
+
+/// The handoff cookie is only sent to the provider routes
+const HANDOFF_COOKIE_PATH: &str = "/auth/v0";
+
+pub fn token_cookie(
+    config: &Config,
+    name: &'static str,
+    token: &AccessToken,
+    max_age: &Duration,
+) -> Cookie<'static> {
+    base_cookie(config, name, token.as_str().to_string())
+        .http_only(true)
+        .max_age(time::Duration::seconds(max_age.as_secs() as i64))
+        .finish()
+}
+
+pub fn csrf_cookie(config: &Config, csrf_token: &str, max_age: &Duration) -> Cookie<'static> {
+    base_cookie(config, CSRF_COOKIE, csrf_token.to_string())
+        .max_age(time::Duration::seconds(max_age.as_secs() as i64))
+        .finish()
+}
+
+/// A cookie to pass to `del_cookie`, which must match the path the cookie was set with
+pub fn removal_cookie(config: &Config, name: &'static str) -> Cookie<'static> {
+    base_cookie(config, name, String::new()).finish()
+}
+
+pub fn new_csrf_token() -> String {
+    secure_rand_hex(16)
+}
+
+pub fn handoff_cookie(
+    config: &Config,
+    browser_binding: &str,
+    max_age: &Duration,
+) -> Cookie<'static> {
+    base_cookie(config, HANDOFF_COOKIE, browser_binding.to_string())
+        .http_only(true)
+        .max_age(time::Duration::seconds(max_age.as_secs() as i64))
+        .finish()
+}
+
+/// Keep the browser's existing binding, so logins started in several tabs can all complete
+pub fn browser_binding<S>(req: &HttpRequest<S>) -> String {
+    req.cookie(HANDOFF_COOKIE)
+        .map(|cookie| cookie.value().to_string())
+        .filter(|value| !value.is_empty())
+        .unwrap_or_else(|| secure_rand_hex(16))
+}
+
+fn base_cookie(config: &Config, name: &'static str, value: String) -> cookie::CookieBuilder {
+    let path = match name {
+        REFRESH_COOKIE => REFRESH_COOKIE_PATH,
+        HANDOFF_COOKIE => HANDOFF_COOKIE_PATH,
+        _ => "/",
+    };
+    Cookie::build(name, value)
+        .path(path)
+        .secure(config.session_cookie_secure)
+        .same_site(SameSite::Lax)
+}
+
+/// Check the CSRF header for requests which may change state
+pub fn verify_csrf_for_method<S>(req: &HttpRequest<S>) -> Result<()> {
+    match *req.method() {
+        Method::GET | Method::HEAD | Method::OPTIONS => Ok(()),
+        _ => verify_csrf(req),
+    }
+}
+
+/// Check the CSRF header matches the CSRF cookie
+pub fn verify_csrf<S>(req: &HttpRequest<S>) -> Result<()> {
+    let header = req
+        .headers()
+        .get(CSRF_HEADER)
+        .and_then(|value| value.to_str().ok());
+    match (req.cookie(CSRF_COOKIE), header) {
+        (Some(ref cookie), Some(header))
+            if !cookie.value().is_empty()
+                && constant_time::verify_slices_are_equal(
+                    cookie.value().as_bytes(),
+                    header.as_bytes(),
+                )
+                .is_ok() =>
+        {
+            Ok(())
+        }
+        _ => Err(Error::Forbidden(format!(
+            "{} header does not match the {} cookie",
+            CSRF_HEADER, CSRF_COOKIE
+        ))),
+    }
+}
@@ -0,0 +1,60 @@
+use super::{models, schema, users::db_error, DbExecutor};
+use crate::prelude::*;
+use actix::prelude::*;
+use chrono::Utc;
+use diesel::prelude::*;
+
+/// Replaces the scopes recorded for the user's provider, since providers report every scope granted so far
+pub struct UpsertUserGrant {
+    pub user_id: String,
+    pub provider: String,
+    pub scopes: String,
+}
+
+impl Message for UpsertUserGrant {
+    type Result = Result<models::UserGrant>;
+}
+
+impl Handler<UpsertUserGrant> for DbExecutor {
+    type Result = Result<models::UserGrant>;
+
+    fn handle(&mut self, msg: UpsertUserGrant, _: &mut Self::Context) -> Self::Result {
+        let conn = self.conn()?;
+
+        use schema::user_grants::dsl::*;
+        diesel::insert_into(user_grants)
+            .values(models::NewUserGrant {
+                user_id: &msg.user_id,
+                provider: &msg.provider,
+                scopes: &msg.scopes,
+            })
+            .on_conflict((user_id, provider))
+            .do_update()
+            .set((scopes.eq(&msg.scopes), updated_at.eq(Utc::now())))
+            .get_result(&conn)
+            .map_err(|e| db_error("UpsertUserGrant: Error upserting user grant", e))
+    }
+}
+
+pub struct GetUserGrants {
+    pub user_id: String,
+}
+
+impl Message for GetUserGrants {
+    type Result = Result<Vec<models::UserGrant>>;
+}
+
+impl Handler<GetUserGrants> for DbExecutor {
+    type Result = Result<Vec<models::UserGrant>>;
+
+    fn handle(&mut self, msg: GetUserGrants, _: &mut Self::Context) -> Self::Result {
+        let conn = self.conn()?;
+
+        use schema::user_grants::dsl::*;
+        user_grants
+            .filter(user_id.eq(&msg.user_id))
+            .order(provider)
+            .load(&conn)
+            .map_err(|e| db_error("GetUserGrants: load error", e))
+    }
+}
@@ -1,6 +1,10 @@
+pub mod grants;
 pub mod models;
+pub mod passwords;
 mod schema;
+pub mod totp;
 pub mod users;
+pub mod webauthn;
 use crate::prelude::*;
 
 use actix::prelude::{Actor, SyncContext};
@@ -36,3 +36,94 @@ pub struct NewUser<'a> {
     pub photo_url: Option<&'a String>,
     pub is_person: bool,
 }
+
+use super::schema::user_grants;
+
+#[derive(Insertable)]
+#[table_name = "user_grants"]
+pub struct NewUserGrant<'a> {
+    pub user_id: &'a str,
+    pub provider: &'a str,
+    pub scopes: &'a str,
+}
+
+#[derive(Queryable, PartialEq, Debug, Deserialize, Serialize, Clone)]
+pub struct UserGrant {
+    pub user_id: String,
+    pub provider: String,
+    pub scopes: String,
+    pub updated_at: DateTime<Utc>,
+}
+
+use super::schema::user_passwords;
+
+#[derive(Insertable)]
+#[table_name = "user_passwords"]
+pub struct NewUserPassword<'a> {
+    pub user_id: &'a str,
+    pub username: &'a str,
+    pub password_hash: &'a str,
+    pub pepper_id: i16,
+}
+
+/// Never serialized, so the hash can't end up in a response
+#[derive(Queryable)]
+pub struct UserPassword {
+    pub user_id: String,
+    pub username: String,
+    pub password_hash: String,
+    pub pepper_id: i16,
+    pub updated_at: DateTime<Utc>,
+}
+
+use super::schema::user_totp;
+
+#[derive(Insertable)]
+#[table_name = "user_totp"]
+pub struct NewUserTotp<'a> {
+    pub user_id: &'a str,
+    pub secret_ciphertext: &'a str,
+}
+
+#[derive(Queryable)]
+pub struct UserTotp {
+    pub user_id: String,
+    pub secret_ciphertext: String,
+    pub confirmed: bool,
+    pub last_used_step: i64,
+    pub created_at: DateTime<Utc>,
+}
+
+use super::schema::user_recovery_codes;
+
+#[derive(Insertable)]
+#[table_name = "user_recovery_codes"]
+pub struct NewUserRecoveryCode<'a> {
+    pub user_id: &'a str,
+    pub code_hash: &'a str,
+}
+
+use super::schema::user_webauthn_credentials;
+
+#[derive(Insertable)]
+#[table_name = "user_webauthn_credentials"]
+pub struct NewUserWebauthnCredential<'a> {
+    pub credential_id: &'a str,
+    pub user_id: &'a str,
+    pub public_key: &'a [u8],
+    pub sign_count: i64,
+    pub name: &'a str,
+}
+
+#[derive(Queryable, Serialize)]
+pub struct UserWebauthnCredential {
+    pub credential_id: String,
+    pub user_id: String,
+    #[serde(skip)]
+    pub public_key: Vec<u8>,
+    #[serde(skip)]
+    pub sign_count: i64,
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
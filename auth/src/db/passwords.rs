@@ -0,0 +1,218 @@
+use super::{models, schema, users::db_error, DbExecutor};
+use crate::passwords::PasswordHasher;
+use crate::prelude::*;
+use actix::prelude::*;
+use chrono::Utc;
+use diesel::prelude::*;
+use std::sync::Arc;
+
+// Hashing is slow on purpose, so it happens here on the database threads rather than in handlers
+
+fn get_password_by_user_id(
+    conn: &PgConnection,
+    by_user_id: &str,
+) -> Result<Option<models::UserPassword>> {
+    use schema::user_passwords::dsl::*;
+
+    user_passwords
+        .filter(user_id.eq(by_user_id))
+        .get_result(conn)
+        .optional()
+        .map_err(|e| db_error("get_password_by_user_id: get_result error", e))
+}
+
+fn get_password_by_username(
+    conn: &PgConnection,
+    by_username: &str,
+) -> Result<Option<models::UserPassword>> {
+    use schema::user_passwords::dsl::*;
+
+    user_passwords
+        .filter(username.eq(by_username))
+        .get_result(conn)
+        .optional()
+        .map_err(|e| db_error("get_password_by_username: get_result error", e))
+}
+
+fn update_password_hash(
+    conn: &PgConnection,
+    hasher: &PasswordHasher,
+    of_user_id: &str,
+    password: &str,
+) -> Result<()> {
+    let new_hash = hasher.hash(password)?;
+
+    use schema::user_passwords::dsl::*;
+    diesel::update(user_passwords.filter(user_id.eq(of_user_id)))
+        .set((
+            password_hash.eq(&new_hash.hash),
+            pepper_id.eq(i16::from(new_hash.pepper_id)),
+            updated_at.eq(Utc::now()),
+        ))
+        .execute(conn)
+        .map(|_| ())
+        .map_err(|e| db_error("update_password_hash: Error updating password", e))
+}
+
+/// The first password of a user, who signs in with it by `username`
+pub struct CreatePassword {
+    pub user_id: String,
+    pub username: String,
+    pub password: String,
+    pub hasher: Arc<PasswordHasher>,
+}
+
+impl Message for CreatePassword {
+    type Result = Result<()>;
+}
+
+impl Handler<CreatePassword> for DbExecutor {
+    type Result = Result<()>;
+
+    fn handle(&mut self, msg: CreatePassword, _: &mut Self::Context) -> Self::Result {
+        let conn = self.conn()?;
+
+        if get_password_by_user_id(&conn, &msg.user_id)?.is_some() {
+            return Err(Error::BadRequest(String::from(
+                "User already has a password, change it instead",
+            )));
+        }
+        let new_hash = msg.hasher.hash(&msg.password)?;
+
+        // a taken username is a unique violation
+        diesel::insert_into(schema::user_passwords::table)
+            .values(models::NewUserPassword {
+                user_id: &msg.user_id,
+                username: &msg.username,
+                password_hash: &new_hash.hash,
+                pepper_id: i16::from(new_hash.pepper_id),
+            })
+            .execute(&conn)?;
+        Ok(())
+    }
+}
+
+/// Replace the user's password, after checking they know the current one
+pub struct ChangePassword {
+    pub user_id: String,
+    pub current_password: String,
+    pub password: String,
+    pub hasher: Arc<PasswordHasher>,
+}
+
+impl Message for ChangePassword {
+    type Result = Result<()>;
+}
+
+impl Handler<ChangePassword> for DbExecutor {
+    type Result = Result<()>;
+
+    fn handle(&mut self, msg: ChangePassword, _: &mut Self::Context) -> Self::Result {
+        let conn = self.conn()?;
+
+        let verified = match get_password_by_user_id(&conn, &msg.user_id)? {
+            Some(user_password) => msg.hasher.verify(
+                &user_password.password_hash,
+                user_password.pepper_id as u8,
+                &msg.current_password,
+            ),
+            None => {
+                return Err(Error::BadRequest(String::from(
+                    "User does not have a password yet",
+                )))
+            }
+        };
+        if !verified {
+            return Err(Error::Unauthorized(String::from(
+                "Current password is incorrect",
+            )));
+        }
+        update_password_hash(&conn, &msg.hasher, &msg.user_id, &msg.password)
+    }
+}
+
+/// Replace the user's password without the current one, once a reset link proved who they are
+pub struct ResetPassword {
+    pub user_id: String,
+    pub password: String,
+    pub hasher: Arc<PasswordHasher>,
+}
+
+impl Message for ResetPassword {
+    type Result = Result<()>;
+}
+
+impl Handler<ResetPassword> for DbExecutor {
+    type Result = Result<()>;
+
+    fn handle(&mut self, msg: ResetPassword, _: &mut Self::Context) -> Self::Result {
+        let conn = self.conn()?;
+        update_password_hash(&conn, &msg.hasher, &msg.user_id, &msg.password)
+    }
+}
+
+/// Resolves with the user id when the password is theirs. Takes as long whether or not the
+/// username exists, so failures don't reveal which usernames do.
+pub struct VerifyPassword {
+    pub username: String,
+    pub password: String,
+    pub hasher: Arc<PasswordHasher>,
+}
+
+impl Message for VerifyPassword {
+    type Result = Result<Option<String>>;
+}
+
+impl Handler<VerifyPassword> for DbExecutor {
+    type Result = Result<Option<String>>;
+
+    fn handle(&mut self, msg: VerifyPassword, _: &mut Self::Context) -> Self::Result {
+        let conn = self.conn()?;
+
+        let user_password = match get_password_by_username(&conn, &msg.username)? {
+            Some(user_password) => user_password,
+            None => {
+                msg.hasher.verify_dummy(&msg.password);
+                return Ok(None);
+            }
+        };
+        let pepper_id = user_password.pepper_id as u8;
+        if !msg
+            .hasher
+            .verify(&user_password.password_hash, pepper_id, &msg.password)
+        {
+            return Ok(None);
+        }
+        if msg
+            .hasher
+            .needs_rehash(&user_password.password_hash, pepper_id)
+        {
+            // signing in still works if this fails, it's tried again next time
+            if let Err(err) =
+                update_password_hash(&conn, &msg.hasher, &user_password.user_id, &msg.password)
+            {
+                warn!("VerifyPassword: Error rehashing password: {:?}", err);
+            }
+        }
+        Ok(Some(user_password.user_id))
+    }
+}
+
+/// The user with a password for `username`, for sending them a reset link
+pub struct GetPasswordUser {
+    pub username: String,
+}
+
+impl Message for GetPasswordUser {
+    type Result = Result<Option<String>>;
+}
+
+impl Handler<GetPasswordUser> for DbExecutor {
+    type Result = Result<Option<String>>;
+
+    fn handle(&mut self, msg: GetPasswordUser, _: &mut Self::Context) -> Self::Result {
+        let conn = self.conn()?;
+        Ok(get_password_by_username(&conn, &msg.username)?
+            .map(|user_password| user_password.user_id))
+    }
+}
@@ -1,3 +1,12 @@
+table! {
+    user_grants (user_id, provider) {
+        user_id -> Text,
+        provider -> Text,
+        scopes -> Text,
+        updated_at -> Timestamptz,
+    }
+}
+
 table! {
     user_logins (external_id) {
         external_id -> Text,
@@ -5,6 +14,46 @@ table! {
     }
 }
 
+table! {
+    user_passwords (user_id) {
+        user_id -> Text,
+        username -> Text,
+        password_hash -> Text,
+        pepper_id -> Int2,
+        updated_at -> Timestamptz,
+    }
+}
+
+table! {
+    user_recovery_codes (user_id, code_hash) {
+        user_id -> Text,
+        code_hash -> Text,
+        used_at -> Nullable<Timestamptz>,
+    }
+}
+
+table! {
+    user_totp (user_id) {
+        user_id -> Text,
+        secret_ciphertext -> Text,
+        confirmed -> Bool,
+        last_used_step -> Int8,
+        created_at -> Timestamptz,
+    }
+}
+
+table! {
+    user_webauthn_credentials (credential_id) {
+        credential_id -> Text,
+        user_id -> Text,
+        public_key -> Bytea,
+        sign_count -> Int8,
+        name -> Text,
+        created_at -> Timestamptz,
+        last_used_at -> Nullable<Timestamptz>,
+    }
+}
+
 table! {
     users (id) {
         id -> Text,
@@ -16,6 +65,19 @@ table! {
     }
 }
 
+joinable!(user_grants -> users (user_id));
 joinable!(user_logins -> users (user_id));
+joinable!(user_passwords -> users (user_id));
+joinable!(user_recovery_codes -> users (user_id));
+joinable!(user_totp -> users (user_id));
+joinable!(user_webauthn_credentials -> users (user_id));
 
-allow_tables_to_appear_in_same_query!(user_logins, users,);
+allow_tables_to_appear_in_same_query!(
+    user_grants,
+    user_logins,
+    user_passwords,
+    user_recovery_codes,
+    user_totp,
+    user_webauthn_credentials,
+    users,
+);
@@ -0,0 +1,293 @@
+use super::{models, schema, users::db_error, DbExecutor};
+use crate::prelude::*;
+use crate::totp::{self, SecretSealer};
+use actix::prelude::*;
+use chrono::Utc;
+use diesel::prelude::*;
+use std::sync::Arc;
+
+/// How many recovery codes a user gets at a time
+const RECOVERY_CODE_COUNT: usize = 10;
+
+fn get_totp_by_user_id(conn: &PgConnection, by_user_id: &str) -> Result<Option<models::UserTotp>> {
+    use schema::user_totp::dsl::*;
+
+    user_totp
+        .filter(user_id.eq(by_user_id))
+        .get_result(conn)
+        .optional()
+        .map_err(|e| db_error("get_totp_by_user_id: get_result error", e))
+}
+
+/// Check a code against the user's secret, using up its time step
+fn verify_and_use_code(
+    conn: &PgConnection,
+    sealer: &SecretSealer,
+    user_totp_row: &models::UserTotp,
+    code: &str,
+) -> Result<bool> {
+    let secret = sealer.open(&user_totp_row.secret_ciphertext)?;
+    let now_step = totp::step_at(Utc::now().timestamp() as u64);
+    let step = match totp::verify_code(&secret, code, now_step, user_totp_row.last_used_step as u64)
+    {
+        Some(step) => step as i64,
+        None => return Ok(false),
+    };
+
+    use schema::user_totp::dsl::*;
+    // only one request can use the step, even when they race
+    let updated = diesel::update(
+        user_totp
+            .filter(user_id.eq(&user_totp_row.user_id))
+            .filter(last_used_step.lt(step)),
+    )
+    .set(last_used_step.eq(step))
+    .execute(conn)
+    .map_err(|e| db_error("verify_and_use_code: Error updating last used step", e))?;
+    Ok(updated == 1)
+}
+
+/// A confirmed secret, and whether `code` was valid for it
+fn verify_confirmed_code(
+    conn: &PgConnection,
+    sealer: &SecretSealer,
+    of_user_id: &str,
+    code: &str,
+) -> Result<bool> {
+    match get_totp_by_user_id(conn, of_user_id)? {
+        Some(ref user_totp_row) if user_totp_row.confirmed => {
+            verify_and_use_code(conn, sealer, user_totp_row, code)
+        }
+        _ => Err(Error::BadRequest(String::from(
+            "Two factor authentication is not set up",
+        ))),
+    }
+}
+
+/// Replace the user's recovery codes with new ones, returning them to be shown once
+fn replace_recovery_codes(conn: &PgConnection, of_user_id: &str) -> Result<Vec<String>> {
+    use schema::user_recovery_codes::dsl::*;
+
+    diesel::delete(user_recovery_codes.filter(user_id.eq(of_user_id)))
+        .execute(conn)
+        .map_err(|e| db_error("replace_recovery_codes: Error deleting old codes", e))?;
+    let codes = totp::new_recovery_codes(RECOVERY_CODE_COUNT);
+    let hashes: Vec<String> = codes
+        .iter()
+        .map(|code| totp::recovery_code_hash(code))
+        .collect();
+    let new_codes: Vec<_> = hashes
+        .iter()
+        .map(|hash| models::NewUserRecoveryCode {
+            user_id: of_user_id,
+            code_hash: hash,
+        })
+        .collect();
+    diesel::insert_into(user_recovery_codes)
+        .values(&new_codes)
+        .execute(conn)
+        .map_err(|e| db_error("replace_recovery_codes: Error inserting codes", e))?;
+    Ok(codes)
+}
+
+/// A new secret for the user's authenticator app, which only takes effect once confirmed
+pub struct StartTotpEnrollment {
+    pub user_id: String,
+    pub sealer: Arc<SecretSealer>,
+}
+
+impl Message for StartTotpEnrollment {
+    type Result = Result<Vec<u8>>;
+}
+
+impl Handler<StartTotpEnrollment> for DbExecutor {
+    type Result = Result<Vec<u8>>;
+
+    fn handle(&mut self, msg: StartTotpEnrollment, _: &mut Self::Context) -> Self::Result {
+        let conn = self.conn()?;
+
+        if let Some(existing) = get_totp_by_user_id(&conn, &msg.user_id)? {
+            if existing.confirmed {
+                return Err(Error::BadRequest(String::from(
+                    "Two factor authentication is already set up, remove it first",
+                )));
+            }
+        }
+        let secret = totp::new_secret();
+        let sealed = msg.sealer.seal(&secret)?;
+
+        use schema::user_totp::dsl::*;
+        // starting over replaces an unconfirmed secret
+        diesel::insert_into(user_totp)
+            .values(models::NewUserTotp {
+                user_id: &msg.user_id,
+                secret_ciphertext: &sealed,
+            })
+            .on_conflict(user_id)
+            .do_update()
+            .set((
+                secret_ciphertext.eq(&sealed),
+                last_used_step.eq(0),
+                created_at.eq(Utc::now()),
+            ))
+            .execute(&conn)
+            .map_err(|e| db_error("StartTotpEnrollment: Error saving secret", e))?;
+        Ok(secret)
+    }
+}
+
+/// Turn on the second factor once the user shows a code from their app, resolving with
+/// their recovery codes
+pub struct ConfirmTotp {
+    pub user_id: String,
+    pub code: String,
+    pub sealer: Arc<SecretSealer>,
+}
+
+impl Message for ConfirmTotp {
+    type Result = Result<Vec<String>>;
+}
+
+impl Handler<ConfirmTotp> for DbExecutor {
+    type Result = Result<Vec<String>>;
+
+    fn handle(&mut self, msg: ConfirmTotp, _: &mut Self::Context) -> Self::Result {
+        let conn = self.conn()?;
+
+        let user_totp_row = match get_totp_by_user_id(&conn, &msg.user_id)? {
+            Some(ref row) if row.confirmed => {
+                return Err(Error::BadRequest(String::from(
+                    "Two factor authentication is already set up",
+                )))
+            }
+            Some(row) => row,
+            None => {
+                return Err(Error::BadRequest(String::from(
+                    "Start setting up two factor authentication first",
+                )))
+            }
+        };
+        conn.transaction(|| {
+            if !verify_and_use_code(&conn, &msg.sealer, &user_totp_row, &msg.code)? {
+                return Err(Error::Unauthorized(String::from("Code is incorrect")));
+            }
+            use schema::user_totp::dsl::*;
+            diesel::update(user_totp.filter(user_id.eq(&msg.user_id)))
+                .set(confirmed.eq(true))
+                .execute(&conn)
+                .map_err(|e| db_error("ConfirmTotp: Error confirming", e))?;
+            replace_recovery_codes(&conn, &msg.user_id)
+        })
+    }
+}
+
+/// Check a code while signing in. Each code works once.
+pub struct VerifyTotp {
+    pub user_id: String,
+    pub code: String,
+    pub sealer: Arc<SecretSealer>,
+}
+
+impl Message for VerifyTotp {
+    type Result = Result<bool>;
+}
+
+impl Handler<VerifyTotp> for DbExecutor {
+    type Result = Result<bool>;
+
+    fn handle(&mut self, msg: VerifyTotp, _: &mut Self::Context) -> Self::Result {
+        let conn = self.conn()?;
+        verify_confirmed_code(&conn, &msg.sealer, &msg.user_id, &msg.code)
+    }
+}
+
+/// Use up one of the user's recovery codes instead of a code from their app
+pub struct UseRecoveryCode {
+    pub user_id: String,
+    pub code: String,
+}
+
+impl Message for UseRecoveryCode {
+    type Result = Result<bool>;
+}
+
+impl Handler<UseRecoveryCode> for DbExecutor {
+    type Result = Result<bool>;
+
+    fn handle(&mut self, msg: UseRecoveryCode, _: &mut Self::Context) -> Self::Result {
+        let conn = self.conn()?;
+
+        use schema::user_recovery_codes::dsl::*;
+        let updated = diesel::update(
+            user_recovery_codes
+                .filter(user_id.eq(&msg.user_id))
+                .filter(code_hash.eq(totp::recovery_code_hash(&msg.code)))
+                .filter(used_at.is_null()),
+        )
+        .set(used_at.eq(Utc::now()))
+        .execute(&conn)
+        .map_err(|e| db_error("UseRecoveryCode: Error using code", e))?;
+        Ok(updated == 1)
+    }
+}
+
+/// New recovery codes, replacing the old ones, after checking a code from the user's app
+pub struct RegenerateRecoveryCodes {
+    pub user_id: String,
+    pub code: String,
+    pub sealer: Arc<SecretSealer>,
+}
+
+impl Message for RegenerateRecoveryCodes {
+    type Result = Result<Vec<String>>;
+}
+
+impl Handler<RegenerateRecoveryCodes> for DbExecutor {
+    type Result = Result<Vec<String>>;
+
+    fn handle(&mut self, msg: RegenerateRecoveryCodes, _: &mut Self::Context) -> Self::Result {
+        let conn = self.conn()?;
+
+        if !verify_confirmed_code(&conn, &msg.sealer, &msg.user_id, &msg.code)? {
+            return Err(Error::Unauthorized(String::from("Code is incorrect")));
+        }
+        conn.transaction(|| replace_recovery_codes(&conn, &msg.user_id))
+    }
+}
+
+/// Turn off the second factor, after checking a code from the user's app
+pub struct DisableTotp {
+    pub user_id: String,
+    pub code: String,
+    pub sealer: Arc<SecretSealer>,
+}
+
+impl Message for DisableTotp {
+    type Result = Result<()>;
+}
+
+impl Handler<DisableTotp> for DbExecutor {
+    type Result = Result<()>;
+
+    fn handle(&mut self, msg: DisableTotp, _: &mut Self::Context) -> Self::Result {
+        let conn = self.conn()?;
+
+        if !verify_confirmed_code(&conn, &msg.sealer, &msg.user_id, &msg.code)? {
+            return Err(Error::Unauthorized(String::from("Code is incorrect")));
+        }
+        conn.transaction(|| {
+            diesel::delete(
+                schema::user_recovery_codes::table
+                    .filter(schema::user_recovery_codes::user_id.eq(&msg.user_id)),
+            )
+            .execute(&conn)
+            .map_err(|e| db_error("DisableTotp: Error deleting recovery codes", e))?;
+            diesel::delete(
+                schema::user_totp::table.filter(schema::user_totp::user_id.eq(&msg.user_id)),
+            )
+            .execute(&conn)
+            .map_err(|e| db_error("DisableTotp: Error deleting secret", e))?;
+            Ok(())
+        })
+    }
+}
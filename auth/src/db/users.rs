@@ -32,9 +32,10 @@ pub struct ExtResourceId {
 }
 
 impl ExtResourceId {
-    pub fn google(resource_name: &str) -> Self {
+    /// `provider` is the login prefix of the identity provider, like "goog"
+    pub fn new(provider: &str, resource_name: &str) -> Self {
         ExtResourceId {
-            provider: String::from("goog"),
+            provider: provider.to_string(),
             resource_name: resource_name.to_string(),
         }
     }
@@ -60,6 +61,150 @@ impl Handler<GetLoginForResource> for DbExecutor {
     }
 }
 
+/// What adding a login to a user came to
+#[derive(Debug, PartialEq)]
+pub enum AddedLogin {
+    Added,
+    /// The user already signs in with it
+    AlreadyLinked,
+    /// Someone else signs in with it, so it stays theirs
+    OtherUser,
+}
+
+/// Add another way for an existing user to sign in
+pub struct AddUserLogin {
+    pub user_id: String,
+    pub external_id: ExtResourceId,
+}
+
+impl Message for AddUserLogin {
+    type Result = Result<AddedLogin>;
+}
+
+impl Handler<AddUserLogin> for DbExecutor {
+    type Result = Result<AddedLogin>;
+
+    fn handle(&mut self, msg: AddUserLogin, _: &mut Self::Context) -> Self::Result {
+        let conn = self.conn()?;
+
+        match get_user_login_by_ext_id(&conn, &msg.external_id)? {
+            Some(ref user_login) if user_login.user_id == msg.user_id => {
+                Ok(AddedLogin::AlreadyLinked)
+            }
+            Some(_) => Ok(AddedLogin::OtherUser),
+            None => {
+                diesel::insert_into(schema::user_logins::table)
+                    .values(models::NewUserLogin {
+                        external_id: &msg.external_id.to_string(),
+                        user_id: &msg.user_id,
+                    })
+                    .execute(&conn)
+                    .map_err(|e| db_error("AddUserLogin: Error inserting login", e))?;
+                Ok(AddedLogin::Added)
+            }
+        }
+    }
+}
+
+pub struct GetUserLogins {
+    pub user_id: String,
+}
+
+impl Message for GetUserLogins {
+    type Result = Result<Vec<models::UserLogin>>;
+}
+
+impl Handler<GetUserLogins> for DbExecutor {
+    type Result = Result<Vec<models::UserLogin>>;
+
+    fn handle(&mut self, msg: GetUserLogins, _: &mut Self::Context) -> Self::Result {
+        use schema::user_logins::dsl::*;
+        let conn = self.conn()?;
+
+        user_logins
+            .filter(user_id.eq(&msg.user_id))
+            .order(external_id.asc())
+            .load(&conn)
+            .map_err(|e| db_error("GetUserLogins: Error loading logins", e))
+    }
+}
+
+/// Remove one of the user's logins, as long as they have another way to sign in
+pub struct RemoveUserLogin {
+    pub user_id: String,
+    /// As listed by [GetUserLogins]
+    pub external_id: String,
+}
+
+impl Message for RemoveUserLogin {
+    type Result = Result<()>;
+}
+
+impl Handler<RemoveUserLogin> for DbExecutor {
+    type Result = Result<()>;
+
+    fn handle(&mut self, msg: RemoveUserLogin, _: &mut Self::Context) -> Self::Result {
+        let conn = self.conn()?;
+
+        conn.transaction(|| {
+            use schema::user_logins::dsl::*;
+            let logins: Vec<models::UserLogin> = user_logins
+                .filter(user_id.eq(&msg.user_id))
+                .load(&conn)
+                .map_err(|e| db_error("RemoveUserLogin: Error loading logins", e))?;
+            if !logins
+                .iter()
+                .any(|login| login.external_id == msg.external_id)
+            {
+                return Err(Error::BadRequest(String::from("No such login")));
+            }
+            if count_sign_in_methods(&conn, &msg.user_id)? <= 1 {
+                return Err(Error::BadRequest(String::from(
+                    "Can't remove the last way to sign in",
+                )));
+            }
+            diesel::delete(
+                user_logins
+                    .filter(user_id.eq(&msg.user_id))
+                    .filter(external_id.eq(&msg.external_id)),
+            )
+            .execute(&conn)
+            .map_err(|e| db_error("RemoveUserLogin: Error deleting login", e))?;
+            Ok(())
+        })
+    }
+}
+
+/// Every way the user can sign in: provider logins, their password and their passkeys.
+/// In a transaction, the user stays locked until it ends, so that two removals at once can't
+/// both count the other one.
+pub(super) fn count_sign_in_methods(conn: &PgConnection, of_user_id: &str) -> Result<i64> {
+    use diesel::dsl::count_star;
+
+    schema::users::table
+        .filter(schema::users::id.eq(of_user_id))
+        .select(schema::users::id)
+        .for_update()
+        .get_result::<String>(conn)
+        .map_err(|e| db_error("count_sign_in_methods: Error locking user", e))?;
+    let logins: i64 = schema::user_logins::table
+        .filter(schema::user_logins::user_id.eq(of_user_id))
+        .select(count_star())
+        .get_result(conn)
+        .map_err(|e| db_error("count_sign_in_methods: Error counting logins", e))?;
+    let passwords: i64 = schema::user_passwords::table
+        .filter(schema::user_passwords::user_id.eq(of_user_id))
+        .select(count_star())
+        .get_result(conn)
+        .map_err(|e| db_error("count_sign_in_methods: Error counting passwords", e))?;
+    let passkeys: i64 = schema::user_webauthn_credentials::table
+        .filter(schema::user_webauthn_credentials::user_id.eq(of_user_id))
+        .select(count_star())
+        .get_result(conn)
+        .map_err(|e| db_error("count_sign_in_methods: Error counting passkeys", e))?;
+    Ok(logins + passwords + passkeys)
+}
+
 pub struct CreateUser {
     pub external_id: ExtResourceId,
     pub display_name: String,
@@ -112,7 +257,7 @@ impl Handler<CreateUser> for DbExecutor {
     }
 }
 
-fn db_error<T: Into<String>, U: std::fmt::Debug>(message: T, err: U) -> Error {
+pub(super) fn db_error<T: Into<String>, U: std::fmt::Debug>(message: T, err: U) -> Error {
     let mstr = message.into();
     error!("db_error: {}; {:?}", mstr, err);
     Error::InternalServerError
@@ -175,3 +320,44 @@ impl Handler<GetUserById> for DbExecutor {
         get_user_by_id(&conn, &msg.user_id)
     }
 }
+
+/// How the user can prove a second factor, which they have to when signing in if there are any
+pub struct GetSecondFactorMethods {
+    pub user_id: String,
+}
+
+impl Message for GetSecondFactorMethods {
+    type Result = Result<Vec<&'static str>>;
+}
+
+impl Handler<GetSecondFactorMethods> for DbExecutor {
+    type Result = Result<Vec<&'static str>>;
+
+    fn handle(&mut self, msg: GetSecondFactorMethods, _: &mut Self::Context) -> Self::Result {
+        let conn = self.conn()?;
+
+        let has_totp: bool = diesel::select(diesel::dsl::exists(
+            schema::user_totp::table
+                .filter(schema::user_totp::user_id.eq(&msg.user_id))
+                .filter(schema::user_totp::confirmed.eq(true)),
+        ))
+        .get_result(&conn)
+        .map_err(|e| db_error("GetSecondFactorMethods: Error checking totp", e))?;
+        let has_webauthn: bool = diesel::select(diesel::dsl::exists(
+            schema::user_webauthn_credentials::table
+                .filter(schema::user_webauthn_credentials::user_id.eq(&msg.user_id)),
+        ))
+        .get_result(&conn)
+        .map_err(|e| db_error("GetSecondFactorMethods: Error checking webauthn", e))?;
+
+        let mut methods = Vec::new();
+        if has_totp {
+            methods.push("totp");
+            methods.push("recovery_code");
+        }
+        if has_webauthn {
+            methods.push("webauthn");
+        }
+        Ok(methods)
+    }
+}
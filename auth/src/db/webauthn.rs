@@ -0,0 +1,181 @@
+use super::users::{count_sign_in_methods, db_error};
+use super::{models, schema, DbExecutor};
+use crate::prelude::*;
+use actix::prelude::*;
+use chrono::Utc;
+use diesel::prelude::*;
+
+pub struct CreateWebauthnCredential {
+    pub user_id: String,
+    pub credential_id: String,
+    pub public_key: Vec<u8>,
+    pub sign_count: u32,
+    pub name: String,
+}
+
+impl Message for CreateWebauthnCredential {
+    type Result = Result<models::UserWebauthnCredential>;
+}
+
+impl Handler<CreateWebauthnCredential> for DbExecutor {
+    type Result = Result<models::UserWebauthnCredential>;
+
+    fn handle(&mut self, msg: CreateWebauthnCredential, _: &mut Self::Context) -> Self::Result {
+        use schema::user_webauthn_credentials::dsl::*;
+        let conn = self.conn()?;
+
+        // a credential registered before is a unique violation
+        let credential = diesel::insert_into(user_webauthn_credentials)
+            .values(models::NewUserWebauthnCredential {
+                credential_id: &msg.credential_id,
+                user_id: &msg.user_id,
+                public_key: &msg.public_key,
+                sign_count: i64::from(msg.sign_count),
+                name: &msg.name,
+            })
+            .get_result(&conn)?;
+        Ok(credential)
+    }
+}
+
+/// A user's credentials, to list or to offer when asking for one
+pub struct GetWebauthnCredentials {
+    pub user_id: String,
+}
+
+impl Message for GetWebauthnCredentials {
+    type Result = Result<Vec<models::UserWebauthnCredential>>;
+}
+
+impl Handler<GetWebauthnCredentials> for DbExecutor {
+    type Result = Result<Vec<models::UserWebauthnCredential>>;
+
+    fn handle(&mut self, msg: GetWebauthnCredentials, _: &mut Self::Context) -> Self::Result {
+        use schema::user_webauthn_credentials::dsl::*;
+        let conn = self.conn()?;
+
+        user_webauthn_credentials
+            .filter(user_id.eq(&msg.user_id))
+            .order(created_at.asc())
+            .load(&conn)
+            .map_err(|e| db_error("GetWebauthnCredentials: Error loading credentials", e))
+    }
+}
+
+/// The credential an assertion was made with, whoever it belongs to
+pub struct GetWebauthnCredential {
+    pub credential_id: String,
+}
+
+impl Message for GetWebauthnCredential {
+    type Result = Result<Option<models::UserWebauthnCredential>>;
+}
+
+impl Handler<GetWebauthnCredential> for DbExecutor {
+    type Result = Result<Option<models::UserWebauthnCredential>>;
+
+    fn handle(&mut self, msg: GetWebauthnCredential, _: &mut Self::Context) -> Self::Result {
+        use schema::user_webauthn_credentials::dsl::*;
+        let conn = self.conn()?;
+
+        user_webauthn_credentials
+            .filter(credential_id.eq(&msg.credential_id))
+            .get_result(&conn)
+            .optional()
+            .map_err(|e| db_error("GetWebauthnCredential: get_result error", e))
+    }
+}
+
+/// Record a use of the credential. The signature counter has to move forward, unless the
+/// authenticator doesn't keep one, or else the credential was cloned.
+pub struct UseWebauthnCredential {
+    pub credential_id: String,
+    pub sign_count: u32,
+}
+
+impl Message for UseWebauthnCredential {
+    type Result = Result<()>;
+}
+
+impl Handler<UseWebauthnCredential> for DbExecutor {
+    type Result = Result<()>;
+
+    fn handle(&mut self, msg: UseWebauthnCredential, _: &mut Self::Context) -> Self::Result {
+        use schema::user_webauthn_credentials::dsl::*;
+        let conn = self.conn()?;
+        let new_sign_count = i64::from(msg.sign_count);
+
+        let updated = if new_sign_count == 0 {
+            diesel::update(
+                user_webauthn_credentials
+                    .filter(credential_id.eq(&msg.credential_id))
+                    .filter(sign_count.eq(0)),
+            )
+            .set(last_used_at.eq(Utc::now()))
+            .execute(&conn)
+        } else {
+            diesel::update(
+                user_webauthn_credentials
+                    .filter(credential_id.eq(&msg.credential_id))
+                    .filter(sign_count.lt(new_sign_count)),
+            )
+            .set((sign_count.eq(new_sign_count), last_used_at.eq(Utc::now())))
+            .execute(&conn)
+        }
+        .map_err(|e| db_error("UseWebauthnCredential: Error updating credential", e))?;
+
+        if updated == 1 {
+            Ok(())
+        } else {
+            warn!(
+                "UseWebauthnCredential: Signature counter of {} went backwards",
+                msg.credential_id
+            );
+            Err(Error::Unauthorized(String::from(
+                "Passkey may have been copied, register it again",
+            )))
+        }
+    }
+}
+
+pub struct DeleteWebauthnCredential {
+    pub user_id: String,
+    pub credential_id: String,
+}
+
+impl Message for DeleteWebauthnCredential {
+    type Result = Result<bool>;
+}
+
+impl Handler<DeleteWebauthnCredential> for DbExecutor {
+    type Result = Result<bool>;
+
+    fn handle(&mut self, msg: DeleteWebauthnCredential, _: &mut Self::Context) -> Self::Result {
+        use schema::user_webauthn_credentials::dsl::*;
+        let conn = self.conn()?;
+
+        conn.transaction(|| {
+            let owned: Vec<String> = user_webauthn_credentials
+                .filter(user_id.eq(&msg.user_id))
+                .select(credential_id)
+                .load(&conn)
+                .map_err(|e| db_error("DeleteWebauthnCredential: Error loading credentials", e))?;
+            if !owned.contains(&msg.credential_id) {
+                return Ok(false);
+            }
+            if count_sign_in_methods(&conn, &msg.user_id)? <= 1 {
+                return Err(Error::BadRequest(String::from(
+                    "Can't remove the last way to sign in",
+                )));
+            }
+            diesel::delete(
+                user_webauthn_credentials
+                    .filter(user_id.eq(&msg.user_id))
+                    .filter(credential_id.eq(&msg.credential_id)),
+            )
+            .execute(&conn)
+            .map_err(|e| db_error("DeleteWebauthnCredential: Error deleting credential", e))?;
+            Ok(true)
+        })
+    }
+}
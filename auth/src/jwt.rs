@@ -0,0 +1,353 @@
+//! Signed JSON Web Tokens, so other services (like Hasura) can verify users without asking us,
+//! and so we can verify tokens other issuers signed
+use ring::{der, digest, error::Unspecified, rand::SystemRandom, signature};
+use serde::{de::DeserializeOwned, Serialize};
+use std::sync::Arc;
+
+use crate::utils::hex;
+
+const PEM_BEGIN: &str = "-----BEGIN";
+
+/// Every key verifiers should trust, and the one new tokens are signed with.
+///
+/// Keys are rotated by first publishing the next key as a verification key, then switching the
+/// signing key once verifiers have picked it up, and finally dropping the old key after the
+/// tokens it signed have expired.
+pub struct JwtKeyring {
+    signing_key: Option<JwtSigningKey>,
+    verification_keys: Vec<JwtSigningKey>,
+}
+
+impl JwtKeyring {
+    pub fn new(signing_key: Option<JwtSigningKey>, verification_keys: Vec<JwtSigningKey>) -> Self {
+        JwtKeyring {
+            signing_key,
+            verification_keys,
+        }
+    }
+
+    pub fn signing_key(&self) -> Option<&JwtSigningKey> {
+        self.signing_key.as_ref()
+    }
+
+    /// All public keys as a JSON Web Key Set
+    pub fn jwks(&self) -> serde_json::Value {
+        json!({
+            "keys": self.published_keys().map(|key| &key.public_jwk).collect::<Vec<_>>(),
+        })
+    }
+
+    /// Algorithms verifiers may encounter
+    pub fn algs(&self) -> Vec<&'static str> {
+        let mut algs: Vec<&'static str> = self.published_keys().map(JwtSigningKey::alg).collect();
+        algs.sort();
+        algs.dedup();
+        algs
+    }
+
+    fn published_keys(&self) -> impl Iterator<Item = &JwtSigningKey> {
+        self.signing_key.iter().chain(self.verification_keys.iter())
+    }
+}
+
+pub struct JwtSigningKey {
+    kid: String,
+    key_pair: KeyPair,
+    public_jwk: serde_json::Value,
+}
+
+enum KeyPair {
+    Ed25519(signature::Ed25519KeyPair),
+    Rsa(Arc<signature::RSAKeyPair>),
+}
+
+#[derive(Serialize)]
+struct JwtHeader<'a> {
+    alg: &'a str,
+    typ: &'a str,
+    kid: &'a str,
+}
+
+impl JwtSigningKey {
+    /// Load an unencrypted PKCS#8 private key from a PEM or DER file.
+    /// Ed25519 keys sign with `EdDSA`, RSA keys sign with `RS256`.
+    pub fn from_file(path: &str) -> Result<Self, String> {
+        let contents =
+            std::fs::read(path).map_err(|e| format!("Unable to read key {}: {}", path, e))?;
+        let der = if contents.starts_with(PEM_BEGIN.as_bytes()) {
+            pem_to_der(&contents).ok_or(format!("Invalid PEM in {}", path))?
+        } else {
+            contents
+        };
+        JwtSigningKey::from_pkcs8(&der)
+    }
+
+    pub fn from_pkcs8(der: &[u8]) -> Result<Self, String> {
+        let input = untrusted::Input::from(der);
+        // Identify the key without revealing anything about it
+        let kid = hex(&digest::digest(&digest::SHA256, der).as_ref()[..8]);
+
+        let (key_pair, public_jwk) =
+            if let Ok(ed) = signature::Ed25519KeyPair::from_pkcs8_maybe_unchecked(input) {
+                let public_jwk = json!({
+                    "kty": "OKP",
+                    "crv": "Ed25519",
+                    "x": base64::encode_config(ed.public_key_bytes(), base64::URL_SAFE_NO_PAD),
+                    "alg": "EdDSA",
+                    "use": "sig",
+                    "kid": kid,
+                });
+                (KeyPair::Ed25519(ed), public_jwk)
+            } else if let Ok(rsa) = signature::RSAKeyPair::from_pkcs8(input) {
+                let (n, e) = rsa_public_components(input)
+                    .ok_or(String::from("Unable to read the RSA public key"))?;
+                let public_jwk = json!({
+                    "kty": "RSA",
+                    "n": base64::encode_config(&n, base64::URL_SAFE_NO_PAD),
+                    "e": base64::encode_config(&e, base64::URL_SAFE_NO_PAD),
+                    "alg": "RS256",
+                    "use": "sig",
+                    "kid": kid,
+                });
+                (KeyPair::Rsa(Arc::new(rsa)), public_jwk)
+            } else {
+                return Err(String::from(
+                    "Expected an unencrypted Ed25519 or RSA PKCS#8 private key",
+                ));
+            };
+
+        Ok(JwtSigningKey {
+            kid,
+            key_pair,
+            public_jwk,
+        })
+    }
+
+    pub fn kid(&self) -> &str {
+        &self.kid
+    }
+
+    /// JWS algorithm name
+    pub fn alg(&self) -> &'static str {
+        match self.key_pair {
+            KeyPair::Ed25519(_) => "EdDSA",
+            KeyPair::Rsa(_) => "RS256",
+        }
+    }
+
+    /// Create a compact serialized JWT from the claims
+    pub fn sign<T: Serialize>(&self, claims: &T) -> String {
+        let header = JwtHeader {
+            alg: self.alg(),
+            typ: "JWT",
+            kid: &self.kid,
+        };
+        let signing_input = format!("{}.{}", base64_json(&header), base64_json(claims));
+
+        let signature = match self.key_pair {
+            KeyPair::Ed25519(ref ed) => ed.sign(signing_input.as_bytes()).as_ref().to_vec(),
+            KeyPair::Rsa(ref rsa) => {
+                let mut signature = vec![0; rsa.public_modulus_len()];
+                signature::RSASigningState::new(rsa.clone())
+                    .and_then(|mut state| {
+                        state.sign(
+                            &signature::RSA_PKCS1_SHA256,
+                            &SystemRandom::new(),
+                            signing_input.as_bytes(),
+                            &mut signature,
+                        )
+                    })
+                    .expect("RSA signature has the modulus length");
+                signature
+            }
+        };
+
+        format!(
+            "{}.{}",
+            signing_input,
+            base64::encode_config(&signature, base64::URL_SAFE_NO_PAD)
+        )
+    }
+}
+
+/// Public keys published by another issuer, for verifying the tokens it signed
+#[derive(Clone, Debug, Deserialize)]
+pub struct JwkSet {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct Jwk {
+    kty: String,
+    kid: Option<String>,
+    // RSA
+    n: Option<String>,
+    e: Option<String>,
+    // OKP and EC
+    crv: Option<String>,
+    x: Option<String>,
+    y: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct VerifyingHeader {
+    alg: String,
+    kid: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum JwtVerifyError {
+    Malformed,
+    /// The key may have been rotated, so the key set is worth fetching again
+    UnknownKey,
+    UnsupportedAlgorithm,
+    BadSignature,
+}
+
+impl JwkSet {
+    /// Check the signature of a compact serialized JWT and decode its claims.
+    /// Checking claims like `exp` and `aud` is left to the caller.
+    pub fn verify<T: DeserializeOwned>(&self, token: &str) -> Result<T, JwtVerifyError> {
+        let mut parts = token.split('.');
+        let (header_b64, claims_b64, signature_b64) =
+            match (parts.next(), parts.next(), parts.next(), parts.next()) {
+                (Some(header), Some(claims), Some(signature), None) => (header, claims, signature),
+                _ => return Err(JwtVerifyError::Malformed),
+            };
+        let header: VerifyingHeader = decode_base64_json(header_b64)?;
+        let signature = base64::decode_config(signature_b64, base64::URL_SAFE_NO_PAD)
+            .map_err(|_| JwtVerifyError::Malformed)?;
+        let signing_input = &token[..header_b64.len() + 1 + claims_b64.len()];
+
+        let key = self
+            .find(header.kid.as_ref().map(String::as_str))
+            .ok_or(JwtVerifyError::UnknownKey)?;
+        key.verify(&header.alg, signing_input.as_bytes(), &signature)?;
+        decode_base64_json(claims_b64)
+    }
+
+    fn find(&self, kid: Option<&str>) -> Option<&Jwk> {
+        match kid {
+            Some(kid) => self
+                .keys
+                .iter()
+                .find(|key| key.kid.as_ref().map(String::as_str) == Some(kid)),
+            // without a kid, only an unambiguous key will do
+            None if self.keys.len() == 1 => self.keys.first(),
+            None => None,
+        }
+    }
+}
+
+impl Jwk {
+    fn verify(&self, alg: &str, message: &[u8], sig: &[u8]) -> Result<(), JwtVerifyError> {
+        let (message, sig) = (untrusted::Input::from(message), untrusted::Input::from(sig));
+        let crv = self.crv.as_ref().map(String::as_str);
+        let verified = match (alg, self.kty.as_str()) {
+            ("RS256", "RSA") => {
+                let (n, e) = (jwk_param(&self.n)?, jwk_param(&self.e)?);
+                signature::primitive::verify_rsa(
+                    &signature::RSA_PKCS1_2048_8192_SHA256,
+                    (untrusted::Input::from(&n), untrusted::Input::from(&e)),
+                    message,
+                    sig,
+                )
+            }
+            ("EdDSA", "OKP") if crv == Some("Ed25519") => {
+                let x = jwk_param(&self.x)?;
+                signature::verify(
+                    &signature::ED25519,
+                    untrusted::Input::from(&x),
+                    message,
+                    sig,
+                )
+            }
+            ("ES256", "EC") if crv == Some("P-256") => {
+                // uncompressed point
+                let mut point = vec![4];
+                point.extend(jwk_param(&self.x)?);
+                point.extend(jwk_param(&self.y)?);
+                signature::verify(
+                    &signature::ECDSA_P256_SHA256_FIXED,
+                    untrusted::Input::from(&point),
+                    message,
+                    sig,
+                )
+            }
+            _ => return Err(JwtVerifyError::UnsupportedAlgorithm),
+        };
+        verified.map_err(|_| JwtVerifyError::BadSignature)
+    }
+}
+
+/// Decode the claims of a JWT without checking its signature. Only for ID tokens received
+/// directly from the issuer's token endpoint, where TLS already authenticates the issuer.
+pub fn unverified_claims<T: DeserializeOwned>(token: &str) -> Result<T, JwtVerifyError> {
+    match token.split('.').nth(1) {
+        Some(claims_b64) => decode_base64_json(claims_b64),
+        None => Err(JwtVerifyError::Malformed),
+    }
+}
+
+fn jwk_param(param: &Option<String>) -> Result<Vec<u8>, JwtVerifyError> {
+    param
+        .as_ref()
+        .and_then(|value| base64::decode_config(value, base64::URL_SAFE_NO_PAD).ok())
+        .ok_or(JwtVerifyError::Malformed)
+}
+
+fn decode_base64_json<T: DeserializeOwned>(value: &str) -> Result<T, JwtVerifyError> {
+    base64::decode_config(value, base64::URL_SAFE_NO_PAD)
+        .ok()
+        .and_then(|json| serde_json::from_slice(&json).ok())
+        .ok_or(JwtVerifyError::Malformed)
+}
+
+fn base64_json<T: Serialize>(value: &T) -> String {
+    let json = serde_json::to_vec(value).expect("JWT parts serialize to json");
+    base64::encode_config(&json, base64::URL_SAFE_NO_PAD)
+}
+
+/// Read the public modulus and exponent from a PKCS#8 wrapped `RSAPrivateKey`,
+/// which ring already validated when loading the key pair
+fn rsa_public_components(pkcs8: untrusted::Input) -> Option<(Vec<u8>, Vec<u8>)> {
+    pkcs8
+        .read_all(Unspecified, |input| {
+            der::nested(input, der::Tag::Sequence, Unspecified, |private_key_info| {
+                let _version = der::small_nonnegative_integer(private_key_info)?;
+                let _algorithm =
+                    der::expect_tag_and_get_value(private_key_info, der::Tag::Sequence)?;
+                let private_key =
+                    der::expect_tag_and_get_value(private_key_info, der::Tag::OctetString)?;
+                // optional attributes
+                let _ = private_key_info.skip_to_end();
+
+                private_key.read_all(Unspecified, |input| {
+                    der::nested(input, der::Tag::Sequence, Unspecified, |rsa_private_key| {
+                        let _version = der::small_nonnegative_integer(rsa_private_key)?;
+                        let n = der::positive_integer(rsa_private_key)?;
+                        let e = der::positive_integer(rsa_private_key)?;
+                        let _ = rsa_private_key.skip_to_end();
+                        Ok((
+                            n.as_slice_less_safe().to_vec(),
+                            e.as_slice_less_safe().to_vec(),
+                        ))
+                    })
+                })
+            })
+        })
+        .ok()
+}
+
+/// Decode the body of the first PEM block
+fn pem_to_der(pem: &[u8]) -> Option<Vec<u8>> {
+    let pem = std::str::from_utf8(pem).ok()?;
+    let body: String = pem
+        .lines()
+        .map(str::trim)
+        .skip_while(|line| !line.starts_with(PEM_BEGIN))
+        .skip(1)
+        .take_while(|line| !line.starts_with("-----END"))
+        .collect();
+    base64::decode(&body).ok()
+}
@@ -0,0 +1,74 @@
+//! Sending email, like sign in links, through whichever [Mailer] is configured with `MAILER`
+use actix::prelude::*;
+
+use crate::config::Config;
+use crate::prelude::*;
+
+mod outbox;
+mod smtp;
+
+pub use outbox::{FileMailer, MemoryMailer};
+pub use smtp::SmtpMailer;
+
+/// A plain text email, sent from the configured `MAIL_FROM`
+#[derive(Clone, Debug, Serialize)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub text: String,
+}
+
+pub trait Mailer {
+    /// Blocks until the email is handed off, so mailers run on [MailExecutor]'s own threads
+    fn send(&mut self, email: &Email) -> Result<()>;
+}
+
+pub struct MailExecutor(pub Box<Mailer + Send>);
+
+impl Actor for MailExecutor {
+    type Context = SyncContext<Self>;
+}
+
+pub struct SendEmail(pub Email);
+
+impl Message for SendEmail {
+    type Result = Result<()>;
+}
+
+impl Handler<SendEmail> for MailExecutor {
+    type Result = Result<()>;
+
+    fn handle(&mut self, msg: SendEmail, _: &mut Self::Context) -> Self::Result {
+        self.0.send(&msg.0)
+    }
+}
+
+/// Makes a mailer for each [MailExecutor] thread
+pub type MailerFactory = Box<Fn() -> Box<Mailer + Send> + Send + Sync>;
+
+/// The mailer named by `MAILER`: "smtp", "file" or "memory".
+/// Each thread gets its own SMTP connection, while memory mailers all share one outbox.
+pub fn mailer_from_config(config: &Config) -> MailerFactory {
+    match config.mailer.as_str() {
+        "smtp" => {
+            let config = config.clone();
+            Box::new(move || {
+                Box::new(SmtpMailer::new(
+                    &config.smtp_host,
+                    &config.smtp_username,
+                    &config.smtp_password,
+                    &config.mail_from,
+                ))
+            })
+        }
+        "file" => {
+            let mailer = FileMailer::new(&config.mail_file_dir);
+            Box::new(move || Box::new(mailer.clone()))
+        }
+        "memory" => {
+            let mailer = MemoryMailer::default();
+            Box::new(move || Box::new(mailer.clone()))
+        }
+        other => panic!("MAILER {} is not smtp, file or memory", other),
+    }
+}
@@ -0,0 +1,93 @@
+//! Mailers which keep emails instead of sending them, for local development and tests
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use super::{Email, Mailer};
+use crate::prelude::*;
+use crate::utils::secure_rand_hex;
+
+/// Writes each email to its own JSON file in a directory
+#[derive(Clone)]
+pub struct FileMailer {
+    dir: PathBuf,
+}
+
+impl FileMailer {
+    pub fn new(dir: &str) -> Self {
+        std::fs::create_dir_all(dir)
+            .unwrap_or_else(|err| panic!("MAIL_FILE_DIR {} can't be created: {}", dir, err));
+        FileMailer {
+            dir: PathBuf::from(dir),
+        }
+    }
+}
+
+impl Mailer for FileMailer {
+    fn send(&mut self, email: &Email) -> Result<()> {
+        // sorts in the order they were sent
+        let path = self.dir.join(format!(
+            "{}-{}.json",
+            chrono::Utc::now().format("%Y%m%dT%H%M%S%.3f"),
+            secure_rand_hex(4)
+        ));
+        let contents = serde_json::to_vec_pretty(email).map_err(|err| {
+            error!("FileMailer: Error serializing email: {:?}", err);
+            Error::InternalServerError
+        })?;
+        std::fs::write(&path, contents).map_err(|err| {
+            error!("FileMailer: Error writing {:?}: {:?}", path, err);
+            Error::InternalServerError
+        })?;
+        info!("FileMailer: Email to {} written to {:?}", email.to, path);
+        Ok(())
+    }
+}
+
+/// Logs emails and keeps them in memory, where clones share the same outbox
+#[derive(Clone, Default)]
+pub struct MemoryMailer {
+    sent: Arc<Mutex<Vec<Email>>>,
+}
+
+impl MemoryMailer {
+    /// Every email sent so far, oldest first
+    #[cfg(test)]
+    pub fn sent(&self) -> Vec<Email> {
+        self.sent.lock().expect("Unpoisoned outbox").clone()
+    }
+}
+
+impl Mailer for MemoryMailer {
+    fn send(&mut self, email: &Email) -> Result<()> {
+        debug!("MemoryMailer: Email to {}: {}", email.to, email.text);
+        self.sent
+            .lock()
+            .expect("Unpoisoned outbox")
+            .push(email.clone());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn email(to: &str) -> Email {
+        Email {
+            to: to.to_string(),
+            subject: String::from("Subject"),
+            text: String::from("Text"),
+        }
+    }
+
+    #[test]
+    fn memory_mailer_clones_share_outbox() {
+        let outbox = MemoryMailer::default();
+        let mut first = outbox.clone();
+        let mut second = outbox.clone();
+        first.send(&email("first@example.com")).unwrap();
+        second.send(&email("second@example.com")).unwrap();
+        let sent: Vec<String> = outbox.sent().into_iter().map(|email| email.to).collect();
+        assert_eq!(sent, vec!["first@example.com", "second@example.com"]);
+    }
+}
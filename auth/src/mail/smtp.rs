@@ -0,0 +1,55 @@
+use lettre::smtp::authentication::Credentials;
+use lettre::{SmtpClient, SmtpTransport, Transport};
+use lettre_email::EmailBuilder;
+
+use super::{Email, Mailer};
+use crate::prelude::*;
+
+/// Sends through an SMTP relay on the submission port, requiring STARTTLS
+pub struct SmtpMailer {
+    transport: SmtpTransport,
+    from: String,
+}
+
+impl SmtpMailer {
+    pub fn new(host: &str, username: &str, password: &str, from: &str) -> Self {
+        let mut client = SmtpClient::new_simple(host)
+            .unwrap_or_else(|err| panic!("SMTP_HOST {} is invalid: {}", host, err));
+        if !username.is_empty() {
+            client =
+                client.credentials(Credentials::new(username.to_string(), password.to_string()));
+        }
+        SmtpMailer {
+            // reuses the connection between emails
+            transport: client
+                .connection_reuse(lettre::smtp::ConnectionReuseParameters::ReuseUnlimited)
+                .transport(),
+            from: from.to_string(),
+        }
+    }
+}
+
+impl Mailer for SmtpMailer {
+    fn send(&mut self, email: &Email) -> Result<()> {
+        let message = EmailBuilder::new()
+            .to(email.to.as_str())
+            .from(self.from.as_str())
+            .subject(email.subject.as_str())
+            .text(email.text.as_str())
+            .build()
+            .map_err(|err| {
+                warn!(
+                    "SmtpMailer: Error building email to {}: {:?}",
+                    email.to, err
+                );
+                Error::BadRequest(String::from("Email address is invalid"))
+            })?;
+        self.transport
+            .send(message.into())
+            .map(|_| ())
+            .map_err(|err| {
+                error!("SmtpMailer: Error sending email to {}: {:?}", email.to, err);
+                Error::InternalServerError
+            })
+    }
+}
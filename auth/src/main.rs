@@ -11,14 +11,21 @@ extern crate serde_json;
 #[macro_use]
 extern crate failure;
 
+mod admin;
 mod app;
 mod auth;
 mod config;
+mod cookies;
 mod db;
 mod error;
+mod jwt;
+mod mail;
 mod mem;
+mod passwords;
 mod prelude;
+mod totp;
 mod utils;
+mod webauthn;
 
 use config::{Config, NotEmpty};
 
@@ -32,6 +39,15 @@ fn main() {
 
     let config = Config::default().with_environment();
 
+    config
+        .peppers
+        .get(config.peppers.active_id())
+        .expect("PEPPER_ACTIVE must refer to a configured PEPPER_<n>");
+
+    if let Some("pepper-report") = std::env::args().nth(1).as_ref().map(String::as_str) {
+        return admin::pepper_report(config);
+    }
+
     let bind_address = config
         .http_bind_address
         .not_empty()
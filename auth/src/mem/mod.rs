@@ -1,15 +1,89 @@
 use actix_redis::{Command, RedisActor, RespValue};
-use futures::future::{self, Future};
+use futures::future::{self, Either, Future};
 
 use crate::prelude::*;
 
 pub mod models;
 pub mod sessions;
 
+/// Left in place of a value taken with [MemExecutor::take_json] until the value would have expired
+const TAKEN_MARKER: &str = "!taken";
+
+/// Read the value and leave [TAKEN_MARKER] in its place, as one step so it is taken at most once
+const TAKE_SCRIPT: &str = r#"
+local value = redis.call('GET', KEYS[1])
+if value and value ~= ARGV[1] then
+    local ttl = redis.call('PTTL', KEYS[1])
+    if ttl > 0 then
+        redis.call('SET', KEYS[1], ARGV[1], 'PX', ttl)
+    else
+        redis.call('DEL', KEYS[1])
+    end
+end
+return value
+"#;
+
+/// Like [TAKE_SCRIPT], but only while the JSON field ARGV[2] holds ARGV[3]. Otherwise the value
+/// is left in place and 0 is returned.
+const TAKE_IF_SCRIPT: &str = r#"
+local value = redis.call('GET', KEYS[1])
+if value and value ~= ARGV[1] then
+    if cjson.decode(value)[ARGV[2]] ~= ARGV[3] then
+        return 0
+    end
+    local ttl = redis.call('PTTL', KEYS[1])
+    if ttl > 0 then
+        redis.call('SET', KEYS[1], ARGV[1], 'PX', ttl)
+    else
+        redis.call('DEL', KEYS[1])
+    end
+end
+return value
+"#;
+
+/// Replace the value only while the JSON field ARGV[1] still holds ARGV[2]. A value which was
+/// taken is not JSON anymore, so it's never replaced.
+const COMPARE_AND_SET_SCRIPT: &str = r#"
+local value = redis.call('GET', KEYS[1])
+if value then
+    local ok, decoded = pcall(cjson.decode, value)
+    if ok and type(decoded) == 'table' and decoded[ARGV[1]] == ARGV[2] then
+        redis.call('SET', KEYS[1], ARGV[3], 'EX', ARGV[4])
+        return 1
+    end
+end
+return 0
+"#;
+
+/// Add ARGV[1] to the counter, which expires ARGV[2] seconds after it was first added to
+const INCREMENT_SCRIPT: &str = r#"
+local count = redis.call('INCRBY', KEYS[1], ARGV[1])
+if redis.call('TTL', KEYS[1]) < 0 then
+    redis.call('EXPIRE', KEYS[1], ARGV[2])
+end
+return count
+"#;
+
+/// Delete the value and return what it was, as one step
+const DELETE_SCRIPT: &str = r#"
+local value = redis.call('GET', KEYS[1])
+redis.call('DEL', KEYS[1])
+return value
+"#;
+
 /// This is memory executor actor
 #[derive(Clone)]
 pub struct MemExecutor(Addr<RedisActor>);
 
+/// Outcome of [MemExecutor::take_json] and [MemExecutor::peek_json]
+pub enum Taken<T> {
+    Value(T),
+    /// Someone else took it first
+    AlreadyTaken,
+    /// It never existed or it expired
+    Missing,
+}
+
 pub trait MemModel {
     fn table_prefix() -> &'static str;
     fn table_key(&self) -> &str;
@@ -134,9 +208,184 @@ impl MemExecutor {
         )
     }
 
+    /// Like [MemExecutor::set_json], but only replaces a value which is still there.
+    /// Resolves with whether it was set, which it isn't once the value was deleted or expired.
+    pub fn set_json_if_exists<T>(
+        &self,
+        value: &T,
+        expires_in: &std::time::Duration,
+    ) -> AppFuture<bool>
+    where
+        T: serde::ser::Serialize + MemModel,
+    {
+        let value_str = match serde_json::to_string(value) {
+            Ok(v) => v,
+            Err(err) => {
+                return Box::new(future::err(mem_error(
+                    "set_json_if_exists error: serialization",
+                    err,
+                )));
+            }
+        };
+        Box::new(
+            self.command(
+                resp_array![
+                    "SET",
+                    value.named_key(),
+                    value_str,
+                    "EX",
+                    expires_in.as_secs().to_string(),
+                    "XX"
+                ],
+                "set_json_if_exists error",
+            )
+            .and_then(|res| match res {
+                RespValue::SimpleString(_) => Ok(true),
+                RespValue::Nil => Ok(false),
+                other => Err(mem_error(
+                    "set_json_if_exists error: unknown response",
+                    other,
+                )),
+            }),
+        )
+    }
+
+    /// Like [MemExecutor::get_json], but the value can only be read once
+    pub fn take_json<T>(&self, key: &str) -> AppFuture<Taken<T>>
+    where
+        T: serde::de::DeserializeOwned + MemModel + 'static,
+    {
+        let named_key = format!("{}#{}", T::table_prefix(), key);
+        Box::new(
+            self.command(
+                resp_array!["EVAL", TAKE_SCRIPT, "1", named_key, TAKEN_MARKER],
+                "take_json error",
+            )
+            .and_then(taken_from_response),
+        )
+    }
+
+    /// Like [MemExecutor::take_json], but only while the stored value's `field` is `expected`.
+    /// Resolves with `None` when it isn't, and then the value is left in place.
+    pub fn take_json_if<T>(
+        &self,
+        key: &str,
+        field: &str,
+        expected: &str,
+    ) -> AppFuture<Option<Taken<T>>>
+    where
+        T: serde::de::DeserializeOwned + MemModel + 'static,
+    {
+        let named_key = format!("{}#{}", T::table_prefix(), key);
+        Box::new(
+            self.command(
+                resp_array![
+                    "EVAL",
+                    TAKE_IF_SCRIPT,
+                    "1",
+                    named_key,
+                    TAKEN_MARKER,
+                    field,
+                    expected
+                ],
+                "take_json_if error",
+            )
+            .and_then(|res| match res {
+                RespValue::Integer(0) => Ok(None),
+                res => taken_from_response(res).map(Some),
+            }),
+        )
+    }
+
+    /// Like [MemExecutor::get_json], but tells a value which was taken apart from a missing one
+    pub fn peek_json<T>(&self, key: &str) -> AppFuture<Taken<T>>
+    where
+        T: serde::de::DeserializeOwned + MemModel + 'static,
+    {
+        let named_key = format!("{}#{}", T::table_prefix(), key);
+        Box::new(
+            self.command(resp_array!["GET", named_key], "peek_json error")
+                .and_then(taken_from_response),
+        )
+    }
+
+    /// Like [MemExecutor::set_json], but only while the stored value's `field` is `expected`.
+    /// Resolves with whether it was set, which it isn't once someone else replaced or removed it.
+    pub fn compare_and_set_json<T>(
+        &self,
+        value: &T,
+        field: &str,
+        expected: &str,
+        expires_in: &std::time::Duration,
+    ) -> AppFuture<bool>
+    where
+        T: serde::ser::Serialize + MemModel,
+    {
+        let value_str = match serde_json::to_string(value) {
+            Ok(v) => v,
+            Err(err) => {
+                return Box::new(future::err(mem_error(
+                    "compare_and_set_json error: serialization",
+                    err,
+                )));
+            }
+        };
+        Box::new(
+            self.command(
+                resp_array![
+                    "EVAL",
+                    COMPARE_AND_SET_SCRIPT,
+                    "1",
+                    value.named_key(),
+                    field,
+                    expected,
+                    value_str,
+                    expires_in.as_secs().to_string()
+                ],
+                "compare_and_set_json error",
+            )
+            .and_then(|res| match res {
+                RespValue::Integer(set) => Ok(set == 1),
+                other => Err(mem_error(
+                    "compare_and_set_json error: unknown response",
+                    other,
+                )),
+            }),
+        )
+    }
+
+    /// Delete the value, resolving with what it was, so only one caller gets it
+    pub fn delete_json<T>(&self, key: &str) -> AppFuture<Option<T>>
+    where
+        T: serde::de::DeserializeOwned + MemModel + 'static,
+    {
+        let named_key = format!("{}#{}", T::table_prefix(), key);
+        Box::new(
+            self.command(
+                resp_array!["EVAL", DELETE_SCRIPT, "1", named_key],
+                "delete_json error",
+            )
+            .and_then(|res| match res {
+                RespValue::BulkString(s) => {
+                    let value = String::from_utf8(s)
+                        .map_err(|e| mem_error("Redis returned invalid utf8", e))?;
+                    serde_json::from_str::<T>(&value)
+                        .map_err(|err| mem_error("delete_json error: deserialization", err))
+                        .map(Some)
+                }
+                RespValue::Nil => Ok(None),
+                other => Err(mem_error("delete_json error: unknown response", other)),
+            }),
+        )
+    }
+
     pub fn delete<T: MemModel>(&self, key: &str) -> impl Future<Item = (), Error = Error> {
+        self.delete_named(&format!("{}#{}", T::table_prefix(), key))
+    }
+
+    /// Delete a key which is not a [MemModel], like an index
+    pub fn delete_named(&self, named_key: &str) -> impl Future<Item = (), Error = Error> {
         let redis = &self.0;
-        let named_key = format!("{}#{}", T::table_prefix(), key);
         Box::new(
             redis
                 .send(Command(resp_array!["DEL", named_key]))
@@ -151,6 +400,202 @@ impl MemExecutor {
                 }),
         )
     }
+
+    /// Add or update a member of a sorted set
+    pub fn sorted_set_add(&self, named_key: &str, score: i64, member: &str) -> AppFuture<()> {
+        Box::new(
+            self.command(
+                resp_array!["ZADD", named_key, score.to_string(), member],
+                "sorted_set_add error",
+            )
+            .map(|_| ()),
+        )
+    }
+
+    pub fn sorted_set_remove(&self, named_key: &str, member: &str) -> AppFuture<()> {
+        Box::new(
+            self.command(
+                resp_array!["ZREM", named_key, member],
+                "sorted_set_remove error",
+            )
+            .map(|_| ()),
+        )
+    }
+
+    /// Remove every member scored at or below `max_score`
+    pub fn sorted_set_remove_to(&self, named_key: &str, max_score: i64) -> AppFuture<()> {
+        Box::new(
+            self.command(
+                resp_array!["ZREMRANGEBYSCORE", named_key, "-inf", max_score.to_string()],
+                "sorted_set_remove_to error",
+            )
+            .map(|_| ()),
+        )
+    }
+
+    pub fn sorted_set_members(&self, named_key: &str) -> AppFuture<Vec<String>> {
+        Box::new(
+            self.command(
+                resp_array!["ZRANGE", named_key, "0", "-1"],
+                "sorted_set_members error",
+            )
+            .and_then(|res| match res {
+                RespValue::Array(members) => members
+                    .into_iter()
+                    .map(|member| match member {
+                        RespValue::BulkString(member) => String::from_utf8(member)
+                            .map_err(|e| mem_error("Redis returned invalid utf8", e)),
+                        other => Err(mem_error("sorted_set_members: unknown member", other)),
+                    })
+                    .collect(),
+                other => Err(mem_error("sorted_set_members: unknown response", other)),
+            }),
+        )
+    }
+
+    /// Add `by` to a counter and resolve with its new count, as one step so parallel callers all
+    /// count.
+    /// A new counter expires after `expires_in`, which later additions don't extend.
+    pub fn increment(
+        &self,
+        named_key: &str,
+        by: i64,
+        expires_in: &std::time::Duration,
+    ) -> AppFuture<i64> {
+        Box::new(
+            self.command(
+                resp_array![
+                    "EVAL",
+                    INCREMENT_SCRIPT,
+                    "1",
+                    named_key,
+                    by.to_string(),
+                    expires_in.as_secs().to_string()
+                ],
+                "increment error",
+            )
+            .and_then(|res| match res {
+                RespValue::Integer(count) => Ok(count),
+                other => Err(mem_error("increment error: unknown response", other)),
+            }),
+        )
+    }
+
+    /// Expire the key at a unix timestamp
+    pub fn expire_at(&self, named_key: &str, timestamp: i64) -> AppFuture<()> {
+        Box::new(
+            self.command(
+                resp_array!["EXPIREAT", named_key, timestamp.to_string()],
+                "expire_at error",
+            )
+            .map(|_| ()),
+        )
+    }
+
+    /// Send a command, treating Redis error replies as errors
+    fn command(&self, command: RespValue, context: &'static str) -> AppFuture<RespValue> {
+        Box::new(self.0.send(Command(command)).map_err(Error::from).and_then(
+            move |res| match res {
+                Ok(RespValue::Error(err)) => Err(mem_error(context, err)),
+                Ok(val) => Ok(val),
+                Err(err) => Err(mem_error(context, err)),
+            },
+        ))
+    }
+
+    /// List the table keys of every stored `T`, using `SCAN` so Redis is not blocked
+    pub fn keys<T: MemModel + 'static>(&self) -> AppFuture<Vec<String>> {
+        let prefix = format!("{}#", T::table_prefix());
+        Box::new(
+            scan_r(
+                self.0.clone(),
+                format!("{}*", prefix),
+                String::from("0"),
+                Vec::new(),
+            )
+            .map(move |named_keys| {
+                named_keys
+                    .into_iter()
+                    .map(|named_key| named_key[prefix.len()..].to_string())
+                    .collect()
+            }),
+        )
+    }
+}
+
+fn scan_r(
+    redis: Addr<RedisActor>,
+    pattern: String,
+    cursor: String,
+    mut found: Vec<String>,
+) -> AppFuture<Vec<String>> {
+    Box::new(
+        redis
+            .send(Command(resp_array![
+                "SCAN",
+                cursor.as_str(),
+                "MATCH",
+                pattern.as_str(),
+                "COUNT",
+                "100"
+            ]))
+            .map_err(Error::from)
+            .and_then(|res| match res {
+                Ok(RespValue::Array(mut parts)) => {
+                    let keys = parts.pop();
+                    let next_cursor = parts.pop();
+                    match (next_cursor, keys) {
+                        (
+                            Some(RespValue::BulkString(next_cursor)),
+                            Some(RespValue::Array(keys)),
+                        ) => {
+                            let next_cursor = String::from_utf8(next_cursor)
+                                .map_err(|e| mem_error("Redis returned invalid utf8", e))?;
+                            let keys = keys
+                                .into_iter()
+                                .map(|key| match key {
+                                    RespValue::BulkString(key) => String::from_utf8(key)
+                                        .map_err(|e| mem_error("Redis returned invalid utf8", e)),
+                                    other => Err(mem_error("scan error: unknown key", other)),
+                                })
+                                .collect::<Result<Vec<String>>>()?;
+                            Ok((next_cursor, keys))
+                        }
+                        other => Err(mem_error("scan error: unknown response", other)),
+                    }
+                }
+                Ok(RespValue::Error(err)) => Err(mem_error("scan error", err)),
+                Ok(other) => Err(mem_error("scan error: unknown response", other)),
+                Err(err) => Err(mem_error("scan redis error", err)),
+            })
+            .and_then(move |(next_cursor, keys)| {
+                found.extend(keys);
+                if next_cursor == "0" {
+                    Either::A(future::ok(found))
+                } else {
+                    Either::B(scan_r(redis, pattern, next_cursor, found))
+                }
+            }),
+    )
+}
+
+/// Read a value, or [TAKEN_MARKER] in place of one, as returned by `GET` and the take scripts
+fn taken_from_response<T: serde::de::DeserializeOwned>(res: RespValue) -> Result<Taken<T>> {
+    match res {
+        RespValue::BulkString(s) => {
+            let value =
+                String::from_utf8(s).map_err(|e| mem_error("Redis returned invalid utf8", e))?;
+            if value == TAKEN_MARKER {
+                Ok(Taken::AlreadyTaken)
+            } else {
+                serde_json::from_str::<T>(&value)
+                    .map_err(|err| mem_error("take_json error: deserialization", err))
+                    .map(Taken::Value)
+            }
+        }
+        RespValue::Nil => Ok(Taken::Missing),
+        other => Err(mem_error("take_json error: unknown response", other)),
+    }
 }
 
 fn mem_error<T: Into<String>, U: std::fmt::Debug>(message: T, err: U) -> Error {
@@ -1,4 +1,8 @@
+use chrono::{DateTime, Utc};
+use ring::digest;
+
 use super::MemModel;
+use crate::utils::hex;
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct MemUser {
@@ -12,6 +16,21 @@ pub struct MemUser {
     photo_url: Option<String>,
 }
 
+impl MemUser {
+    pub fn user_id(&self) -> &str {
+        &self.user_id
+    }
+}
+
+/// Where a session was created from, so users can recognize their sessions
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct SessionClient {
+    #[serde(rename = "ip", skip_serializing_if = "Option::is_none")]
+    pub ip: Option<String>,
+    #[serde(rename = "ua", skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<String>,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct UserSession {
     /// User's state key for associating login with session
@@ -19,15 +38,62 @@ pub struct UserSession {
     pub key: String,
     #[serde(rename = "u")]
     pub user: MemUser,
+    /// Pepper the access token for this session was sealed with
+    #[serde(rename = "p", default)]
+    pub pepper_id: u8,
+    #[serde(rename = "c", default = "Utc::now")]
+    pub created_at: DateTime<Utc>,
+    #[serde(rename = "l", default = "Utc::now")]
+    pub last_seen_at: DateTime<Utc>,
+    #[serde(rename = "e", default = "Utc::now")]
+    pub expires_at: DateTime<Utc>,
+    #[serde(rename = "cl", default)]
+    pub client: SessionClient,
+    /// Refresh token family this session was issued with, revoked along with the session
+    #[serde(rename = "rf", default, skip_serializing_if = "Option::is_none")]
+    pub refresh_family: Option<String>,
 }
 
 impl UserSession {
-    pub fn from_key_and_user(key: String, mem_user: MemUser) -> Self {
+    pub fn from_key_and_user(
+        key: String,
+        mem_user: MemUser,
+        pepper_id: u8,
+        client: SessionClient,
+        refresh_family: String,
+        expires_in: &std::time::Duration,
+    ) -> Self {
+        let now = Utc::now();
         UserSession {
             key: key,
             user: mem_user,
+            pepper_id: pepper_id,
+            created_at: now,
+            last_seen_at: now,
+            expires_at: now
+                + chrono::Duration::from_std(*expires_in).expect("Session expiration in range"),
+            client: client,
+            refresh_family: Some(refresh_family),
         }
     }
+
+    /// Push the expiration back, since the session is still in use
+    pub fn slide_expiration(&mut self, expires_in: &std::time::Duration) {
+        let now = Utc::now();
+        self.last_seen_at = now;
+        self.expires_at =
+            now + chrono::Duration::from_std(*expires_in).expect("Session expiration in range");
+    }
+
+    /// Identifies the session to its user without revealing the key, which is a credential
+    pub fn public_id(&self) -> String {
+        hex(&digest::digest(&digest::SHA256, self.key.as_bytes()).as_ref()[..8])
+    }
+
+    /// Sorted set of a user's session keys, scored by when each session expires
+    pub fn index_named_key(user_id: &str) -> String {
+        format!("usi#{}", user_id)
+    }
 }
 
 impl MemModel for UserSession {
@@ -39,6 +105,70 @@ impl MemModel for UserSession {
     }
 }
 
+/// A single use token for getting a new user session once the current one expires
+#[derive(Serialize, Deserialize)]
+pub struct RefreshToken {
+    #[serde(rename = "k")]
+    pub key: String,
+    /// The [RefreshFamily] this token was issued in
+    #[serde(rename = "f")]
+    pub family: String,
+    /// Pepper the refresh token was sealed with
+    #[serde(rename = "p", default)]
+    pub pepper_id: u8,
+}
+
+impl MemModel for RefreshToken {
+    fn table_prefix() -> &'static str {
+        "rt"
+    }
+    fn table_key(&self) -> &str {
+        &self.key
+    }
+}
+
+impl RefreshToken {
+    /// Keys start with the family, so a token still names its family once it's used and deleted
+    pub fn new_key(family: &str, random: &str) -> String {
+        format!("{}.{}", family, random)
+    }
+
+    /// The family named by the key, for tokens issued with [RefreshToken::new_key]
+    pub fn family_of(key: &str) -> Option<&str> {
+        key.find('.').map(|index| &key[..index])
+    }
+}
+
+/// Every refresh token descended from a single sign in. Only the newest token may be used, so
+/// using an older one means a token was stolen, and the whole family is revoked.
+#[derive(Serialize, Deserialize)]
+pub struct RefreshFamily {
+    #[serde(rename = "k")]
+    pub key: String,
+    #[serde(rename = "u")]
+    pub user: MemUser,
+    /// The one refresh token which may still be used, see [RefreshFamily::CURRENT_TOKEN_FIELD]
+    #[serde(rename = "t")]
+    pub current_token: String,
+    /// The user session issued along with the current token
+    #[serde(rename = "s")]
+    pub session_key: String,
+}
+
+impl RefreshFamily {
+    /// How `current_token` is serialized, for comparing it in Redis
+    pub const CURRENT_TOKEN_FIELD: &'static str = "t";
+}
+
+impl MemModel for RefreshFamily {
+    fn table_prefix() -> &'static str {
+        "rf"
+    }
+    fn table_key(&self) -> &str {
+        &self.key
+    }
+}
+
 /// Information that could have been filled in by the exchange
 #[derive(Serialize, Deserialize, Clone)]
 pub struct IAm {
@@ -48,6 +178,9 @@ pub struct IAm {
     pub given_name: Option<String>,
     pub full_name: Option<String>,
     pub photo_url: Option<String>,
+    /// Scopes the provider reported granting at sign in, recorded once there is a user
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub granted_scopes: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -56,18 +189,89 @@ pub struct LoginSession {
     pub key: String,
     #[serde(rename = "a", skip_serializing_if = "Option::is_none")]
     pub i_am: Option<IAm>,
+    /// The user signed in as, see [LoginSession::USER_ID_FIELD]
     #[serde(rename = "u", skip_serializing_if = "Option::is_none")]
     pub user_id: Option<String>,
+    /// Pepper the access token for this session was sealed with
+    #[serde(rename = "p", default)]
+    pub pepper_id: u8,
+    /// Whether the linked user still has to prove a second factor
+    #[serde(rename = "f", default)]
+    pub second_factor: SecondFactor,
 }
 
 impl LoginSession {
-    pub fn from_key(key: String) -> Self {
+    /// How `user_id` is serialized, for comparing it in Redis
+    pub const USER_ID_FIELD: &'static str = "u";
+
+    /// Counter of the login session's attempts at its second factor
+    pub fn second_factor_attempts_named_key(key: &str) -> String {
+        format!("sfa#{}", key)
+    }
+
+    pub fn from_key(key: String, pepper_id: u8) -> Self {
         LoginSession {
             key: key,
             i_am: None,
             user_id: None,
+            pepper_id: pepper_id,
+            second_factor: SecondFactor::None,
+        }
+    }
+}
+
+/// Users with two factor authentication get their user session only once it's satisfied
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "t")]
+pub enum SecondFactor {
+    /// Not asked for yet
+    None,
+    /// Asked for, and not proved yet
+    Pending,
+    Satisfied,
+}
+
+/// A challenge for signing in with a passkey, stored under the key of the login session asking,
+/// and taken by the assertion answering it
+#[derive(Serialize, Deserialize, Clone)]
+pub struct WebauthnChallenge {
+    #[serde(rename = "k")]
+    pub key: String,
+    #[serde(rename = "c")]
+    pub challenge: String,
+    /// The passkey is the second factor for the linked user, rather than how they sign in
+    #[serde(rename = "s")]
+    pub second_factor: bool,
+}
+
+impl MemModel for WebauthnChallenge {
+    fn table_prefix() -> &'static str {
+        "wc"
+    }
+    fn table_key(&self) -> &str {
+        &self.key
+    }
+}
+
+impl SecondFactor {
+    pub fn status(&self) -> &'static str {
+        match *self {
+            SecondFactor::None => "none",
+            SecondFactor::Pending => "pending",
+            SecondFactor::Satisfied => "satisfied",
         }
     }
+
+    /// Counter of a user's unsuccessful attempts at their second factor, across login sessions
+    pub fn user_attempts_named_key(user_id: &str) -> String {
+        format!("sfu#{}", user_id)
+    }
+}
+
+impl Default for SecondFactor {
+    fn default() -> Self {
+        SecondFactor::None
+    }
 }
 
 impl MemModel for LoginSession {
@@ -88,16 +292,186 @@ pub struct StateHandoff {
     pub session_key: String,
     #[serde(rename = "ru", skip_serializing_if = "Option::is_none")]
     pub redirect_uri: Option<String>,
+    /// Sent to the identity provider, which must repeat it in the ID token
+    #[serde(rename = "n", default)]
+    pub nonce: String,
+    /// PKCE secret, only sent to the identity provider with the authorization code
+    #[serde(rename = "cv", default)]
+    pub code_verifier: String,
+    /// Hash of the secret in the browser which started the login, see
+    /// [StateHandoff::browser_hash]. Empty until a browser navigates to the start url.
+    #[serde(rename = "b", default)]
+    pub browser_hash: String,
+    #[serde(rename = "p", default)]
+    pub purpose: HandoffPurpose,
+}
+
+/// What the callback does with whoever signed in
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "t")]
+pub enum HandoffPurpose {
+    /// Sign in to the login session of [StateHandoff::session_key]
+    #[serde(rename = "l")]
+    Login,
+    /// Record the scopes a signed in user granted us
+    #[serde(rename = "g")]
+    Grant {
+        #[serde(rename = "u")]
+        user_id: String,
+        /// Space delimited, in case the provider doesn't report what was granted
+        #[serde(rename = "s")]
+        requested_scopes: String,
+    },
+    /// Add the provider account as another way for a signed in user to sign in
+    #[serde(rename = "lk")]
+    Link {
+        #[serde(rename = "u")]
+        user_id: String,
+    },
+}
+
+impl Default for HandoffPurpose {
+    fn default() -> Self {
+        HandoffPurpose::Login
+    }
 }
 
 impl StateHandoff {
-    pub fn login(key: &str, login_access_key: &str, redirect_uri: Option<&String>) -> Self {
+    /// How `browser_hash` is serialized, for comparing it in Redis
+    pub const BROWSER_HASH_FIELD: &'static str = "b";
+
+    pub fn new(
+        key: &str,
+        login_access_key: &str,
+        redirect_uri: Option<&String>,
+        nonce: &str,
+        code_verifier: &str,
+        purpose: HandoffPurpose,
+    ) -> Self {
         StateHandoff {
             key: key.to_string(),
             session_key: login_access_key.to_string(),
             redirect_uri: redirect_uri.cloned(),
+            nonce: nonce.to_string(),
+            code_verifier: code_verifier.to_string(),
+            browser_hash: String::new(),
+            purpose,
         }
     }
+
+    /// The browser's binding secret is kept in a cookie, so we only store its hash
+    pub fn browser_hash(browser_binding: &str) -> String {
+        hex(digest::digest(&digest::SHA256, browser_binding.as_bytes()).as_ref())
+    }
+
+    /// The S256 PKCE challenge sent along with the login url (RFC 7636)
+    pub fn code_challenge(&self) -> String {
+        base64::encode_config(
+            digest::digest(&digest::SHA256, self.code_verifier.as_bytes()).as_ref(),
+            base64::URL_SAFE_NO_PAD,
+        )
+    }
+}
+
+/// Key for values found by a token sent to someone, so reading the store is not enough to use them
+pub fn token_hash(token: &str) -> String {
+    hex(digest::digest(&digest::SHA256, token.as_bytes()).as_ref())
+}
+
+/// A sign in link sent by email, stored under the [token_hash] of the link's token
+#[derive(Serialize, Deserialize)]
+pub struct EmailLogin {
+    /// See [token_hash]
+    #[serde(rename = "k")]
+    pub key: String,
+    #[serde(rename = "sk")]
+    pub session_key: String,
+    #[serde(rename = "e")]
+    pub email: String,
+    #[serde(rename = "ru", skip_serializing_if = "Option::is_none")]
+    pub redirect_uri: Option<String>,
+    /// Only the browser which asked for the link can use it, see [StateHandoff::browser_hash]
+    #[serde(rename = "b", default)]
+    pub browser_hash: String,
+}
+
+impl EmailLogin {
+    /// How `browser_hash` is serialized, for comparing it in Redis
+    pub const BROWSER_HASH_FIELD: &'static str = "b";
+}
+
+impl MemModel for EmailLogin {
+    fn table_prefix() -> &'static str {
+        "em"
+    }
+    fn table_key(&self) -> &str {
+        &self.key
+    }
+}
+
+/// A password reset link sent by email, stored under the [token_hash] of its token
+#[derive(Serialize, Deserialize)]
+pub struct PasswordReset {
+    #[serde(rename = "k")]
+    pub key: String,
+    #[serde(rename = "u")]
+    pub user_id: String,
+}
+
+impl MemModel for PasswordReset {
+    fn table_prefix() -> &'static str {
+        "pr"
+    }
+    fn table_key(&self) -> &str {
+        &self.key
+    }
+}
+
+/// Counter of a username's unsuccessful password sign ins, keyed by the [token_hash] of the
+/// username so the store doesn't list who was tried
+pub fn password_attempts_named_key(username: &str) -> String {
+    format!("pwa#{}", token_hash(username))
+}
+
+/// A link confirming a user can read the email address they want as their password username,
+/// stored under the [token_hash] of its token
+#[derive(Serialize, Deserialize)]
+pub struct PasswordSetup {
+    #[serde(rename = "k")]
+    pub key: String,
+    #[serde(rename = "u")]
+    pub user_id: String,
+    #[serde(rename = "n")]
+    pub username: String,
+}
+
+impl MemModel for PasswordSetup {
+    fn table_prefix() -> &'static str {
+        "ps"
+    }
+    fn table_key(&self) -> &str {
+        &self.key
+    }
+}
+
+/// A challenge for registering a passkey, stored under the key of the user session asking
+#[derive(Serialize, Deserialize)]
+pub struct WebauthnRegistration {
+    #[serde(rename = "k")]
+    pub key: String,
+    #[serde(rename = "u")]
+    pub user_id: String,
+    #[serde(rename = "c")]
+    pub challenge: String,
+}
+
+impl MemModel for WebauthnRegistration {
+    fn table_prefix() -> &'static str {
+        "wr"
+    }
+    fn table_key(&self) -> &str {
+        &self.key
+    }
 }
 
 impl MemModel for StateHandoff {
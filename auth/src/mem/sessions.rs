@@ -1,7 +1,9 @@
 use super::models;
-use super::MemExecutor;
+use super::{MemExecutor, MemModel, Taken};
 use crate::prelude::*;
 use crate::utils::secure_rand_hex;
+use crate::webauthn;
+use chrono::Utc;
 use futures::{
     future::{self, Either},
     Future,
@@ -9,46 +11,129 @@ use futures::{
 
 use std::convert::From;
 
-use crate::auth::{LoginAccessKey, UserAccessKey};
+use crate::auth::{LoginAccessKey, RefreshAccessKey, UserAccessKey};
 
-// 120 minutes
-const SIGNUP_SESSION_EXPIRATION: std::time::Duration = std::time::Duration::from_secs(60 * 120);
 // 10 minutes
-const HANDOFF_EXPIRATION: std::time::Duration = std::time::Duration::from_secs(60 * 10);
+pub const HANDOFF_EXPIRATION: std::time::Duration = std::time::Duration::from_secs(60 * 10);
 
-pub struct HandoffState(pub String);
+/// What to send along to the identity provider, to recognize the person when they come back
+pub struct HandoffState {
+    pub state: String,
+    pub nonce: String,
+    pub code_challenge: String,
+}
+
+/// Why the state from a provider callback can not be used
+#[derive(Debug)]
+pub enum HandoffError {
+    Expired,
+    /// The callback was already used, so this could be a replay
+    Reused,
+    /// The callback arrived in a different browser than the one which started the login
+    BrowserMismatch,
+}
 
 pub use models::IAm;
 
-/// Create a state which is associated with this signup session
+/// Create a state which is associated with this signup session, and is bound to a browser once
+/// one starts it with [bind_login_handoff]
 pub fn create_login_handoff(
     mem: &MemExecutor,
     login_access_key: &LoginAccessKey,
-    redirect_uri: Option<&String>
+    redirect_uri: Option<&String>,
+    expires_in: &std::time::Duration,
 ) -> AppFuture<HandoffState> {
     let mem: MemExecutor = mem.clone();
     let redirect_uri = redirect_uri.cloned();
+    let expires_in = *expires_in;
     Box::new(get_login_session(&mem, login_access_key).and_then(
         move |auth: models::LoginSession| {
             let signup_session_key = auth.key.clone();
-            create_login_handoff_r(mem.clone(), signup_session_key, redirect_uri, 5).and_then(
-                move |state_handoff| {
-                    let state = state_handoff.key.clone();
-                    mem.set_json(&auth, &SIGNUP_SESSION_EXPIRATION)
-                        .map(move |_| HandoffState(state))
-                },
+            create_login_handoff_r(
+                mem.clone(),
+                signup_session_key,
+                redirect_uri,
+                models::HandoffPurpose::Login,
+                5,
             )
+            .and_then(move |state_handoff| {
+                let handoff_state = HandoffState::from(state_handoff);
+                mem.set_json(&auth, &expires_in).map(move |_| handoff_state)
+            })
         },
     ))
 }
 
+/// Create a state for a signed in user granting more scopes, see [models::HandoffPurpose::Grant]
+pub fn create_grant_handoff(
+    mem: &MemExecutor,
+    user_id: String,
+    requested_scopes: String,
+    redirect_uri: Option<&String>,
+) -> AppFuture<HandoffState> {
+    let purpose = models::HandoffPurpose::Grant {
+        user_id,
+        requested_scopes,
+    };
+    // no login session is signed in by a grant
+    Box::new(
+        create_login_handoff_r(
+            mem.clone(),
+            String::new(),
+            redirect_uri.cloned(),
+            purpose,
+            5,
+        )
+        .map(HandoffState::from),
+    )
+}
+
+/// Create a state for a signed in user adding a login, see [models::HandoffPurpose::Link]
+pub fn create_link_handoff(
+    mem: &MemExecutor,
+    user_id: String,
+    redirect_uri: Option<&String>,
+) -> AppFuture<HandoffState> {
+    let purpose = models::HandoffPurpose::Link { user_id };
+    // like a grant, no login session is signed in
+    Box::new(
+        create_login_handoff_r(
+            mem.clone(),
+            String::new(),
+            redirect_uri.cloned(),
+            purpose,
+            5,
+        )
+        .map(HandoffState::from),
+    )
+}
+
+impl From<models::StateHandoff> for HandoffState {
+    fn from(state_handoff: models::StateHandoff) -> Self {
+        HandoffState {
+            code_challenge: state_handoff.code_challenge(),
+            state: state_handoff.key,
+            nonce: state_handoff.nonce,
+        }
+    }
+}
+
 fn create_login_handoff_r(
     mem: MemExecutor,
     session_key: String,
     redirect_uri: Option<String>,
+    purpose: models::HandoffPurpose,
     attempts_left: usize,
 ) -> AppFuture<models::StateHandoff> {
-    let handoff = models::StateHandoff::login(&secure_rand_hex(12), &session_key, redirect_uri.as_ref());
+    let handoff = models::StateHandoff::new(
+        &secure_rand_hex(12),
+        &session_key,
+        redirect_uri.as_ref(),
+        &secure_rand_hex(16),
+        // 64 characters, within the 43 to 128 required of verifiers
+        &secure_rand_hex(32),
+        purpose,
+    );
     Box::new(
         mem.set_json_if_not_exists(&handoff, &HANDOFF_EXPIRATION)
             .from_err()
@@ -57,106 +142,609 @@ fn create_login_handoff_r(
                     Either::A(future::ok(handoff))
                 } else if attempts_left <= 0 {
                     error!(
-                        "create_login_handoff_r: Ran out of attempts to create a new handoff! Last tried: {}",
+                        "create_login_handoff_r: Ran out of attempts! Last tried: {}",
                         handoff.key,
                     );
                     Either::B(Either::A(future::err(Error::InternalServerError)))
                 } else {
-                    Either::B(Either::B(create_login_handoff_r(mem, session_key, redirect_uri, attempts_left - 1)))
+                    Either::B(Either::B(create_login_handoff_r(
+                        mem,
+                        session_key,
+                        redirect_uri,
+                        handoff.purpose,
+                        attempts_left - 1,
+                    )))
                 }
             }),
     )
 }
 
-/// On callback, assign identity information to the signup session to be used for completing signup
-pub fn link_state_to_i_am(mem: &MemExecutor, state: String, i_am: models::IAm) -> AppFuture<LinkOutput> {
+/// Bind the handoff to the browser holding `browser_binding`, as the first browser to start it.
+/// Starting it again in the same browser is fine, but not in any other.
+pub fn bind_login_handoff(
+    mem: &MemExecutor,
+    state: &str,
+    browser_binding: &str,
+) -> AppFuture<Result<models::StateHandoff, HandoffError>> {
     let mem: MemExecutor = mem.clone();
+    let browser_hash = models::StateHandoff::browser_hash(browser_binding);
     Box::new(
-        mem.get_json::<models::StateHandoff>(&state)
-            .and_then(|handoff_opt| {
-                handoff_opt.ok_or(Error::BadRequest(String::from(
-                    "Login state handoff does not exist.",
-                )))
-            })
-            .and_then(move |handoff: models::StateHandoff| {
-                let redirect_uri_opt = handoff.redirect_uri;
-                get_login_session(&mem, &LoginAccessKey(handoff.session_key)).and_then(
-                    move |mut login_session: models::LoginSession| {
-                        login_session.i_am = Some(i_am);
-                        mem.set_json(&login_session, &SIGNUP_SESSION_EXPIRATION)
-                    },
-                ).map(|_| LinkOutput {
-                    redirect_uri_opt: redirect_uri_opt,
+        mem.peek_json::<models::StateHandoff>(state)
+            .and_then(move |peeked| match peeked {
+                Taken::Value(mut handoff) => {
+                    if handoff.browser_hash == browser_hash {
+                        Either::A(future::ok(Ok(handoff)))
+                    } else if !handoff.browser_hash.is_empty() {
+                        Either::A(future::ok(Err(HandoffError::BrowserMismatch)))
+                    } else {
+                        handoff.browser_hash = browser_hash;
+                        Either::B(
+                            mem.compare_and_set_json(
+                                &handoff,
+                                models::StateHandoff::BROWSER_HASH_FIELD,
+                                "",
+                                &HANDOFF_EXPIRATION,
+                            )
+                            .map(move |bound| {
+                                if bound {
+                                    Ok(handoff)
+                                } else {
+                                    // another browser started it at the same time
+                                    Err(HandoffError::BrowserMismatch)
+                                }
+                            }),
+                        )
+                    }
+                }
+                Taken::AlreadyTaken => Either::A(future::ok(Err(HandoffError::Reused))),
+                Taken::Missing => Either::A(future::ok(Err(HandoffError::Expired))),
+            }),
+    )
+}
+
+/// On callback, take the handoff for the state the identity provider passed back, so it can't be
+/// replayed
+pub fn take_login_handoff(
+    mem: &MemExecutor,
+    state: &str,
+    browser_binding: Option<String>,
+) -> AppFuture<Result<models::StateHandoff, HandoffError>> {
+    take_bound_json(
+        mem,
+        state,
+        models::StateHandoff::BROWSER_HASH_FIELD,
+        browser_binding,
+    )
+}
+
+/// Take a value which is bound to a browser, but only in the browser holding `browser_binding`.
+/// In any other browser it's left in place, so seeing its key is not enough to use it up.
+fn take_bound_json<T>(
+    mem: &MemExecutor,
+    key: &str,
+    browser_hash_field: &str,
+    browser_binding: Option<String>,
+) -> AppFuture<Result<T, HandoffError>>
+where
+    T: serde::de::DeserializeOwned + MemModel + 'static,
+{
+    match browser_binding {
+        Some(browser_binding) => Box::new(
+            mem.take_json_if::<T>(
+                key,
+                browser_hash_field,
+                &models::StateHandoff::browser_hash(&browser_binding),
+            )
+            .map(|taken_opt| match taken_opt {
+                Some(Taken::Value(value)) => Ok(value),
+                Some(Taken::AlreadyTaken) => Err(HandoffError::Reused),
+                Some(Taken::Missing) => Err(HandoffError::Expired),
+                None => Err(HandoffError::BrowserMismatch),
+            }),
+        ),
+        None => Box::new(mem.peek_json::<T>(key).map(|peeked| match peeked {
+            Taken::Value(_) => Err(HandoffError::BrowserMismatch),
+            Taken::AlreadyTaken => Err(HandoffError::Reused),
+            Taken::Missing => Err(HandoffError::Expired),
+        })),
+    }
+}
+
+/// Create a sign in link token for `email`, associated with this signup session.
+/// Only the hash of the token is stored, the token itself goes in the email.
+pub fn create_email_login(
+    mem: &MemExecutor,
+    login_access_key: &LoginAccessKey,
+    email: String,
+    redirect_uri: Option<&String>,
+    browser_binding: &str,
+    expires_in: &std::time::Duration,
+) -> AppFuture<String> {
+    let mem: MemExecutor = mem.clone();
+    let redirect_uri = redirect_uri.cloned();
+    let browser_hash = models::StateHandoff::browser_hash(browser_binding);
+    let expires_in = *expires_in;
+    Box::new(get_login_session(&mem, login_access_key).and_then(
+        move |login_session: models::LoginSession| {
+            let token = secure_rand_hex(24);
+            let email_login = models::EmailLogin {
+                key: models::token_hash(&token),
+                session_key: login_session.key,
+                email,
+                redirect_uri,
+                browser_hash,
+            };
+            mem.set_json_if_not_exists(&email_login, &expires_in)
+                .from_err()
+                .and_then(move |success_tf| {
+                    if success_tf {
+                        Ok(token)
+                    } else {
+                        error!(
+                            "create_email_login: Token collided with {}",
+                            email_login.key
+                        );
+                        Err(Error::InternalServerError)
+                    }
                 })
+        },
+    ))
+}
+
+/// Take the sign in link with this token, which can only be done once, and only in the browser
+/// which asked for it
+pub fn take_email_login(
+    mem: &MemExecutor,
+    token: &str,
+    browser_binding: Option<String>,
+) -> AppFuture<Result<models::EmailLogin, HandoffError>> {
+    take_bound_json(
+        mem,
+        &models::token_hash(token),
+        models::EmailLogin::BROWSER_HASH_FIELD,
+        browser_binding,
+    )
+}
+
+/// Create a password reset token for the user, only storing its hash
+pub fn create_password_reset(
+    mem: &MemExecutor,
+    user_id: String,
+    expires_in: &std::time::Duration,
+) -> AppFuture<String> {
+    let token = secure_rand_hex(24);
+    let password_reset = models::PasswordReset {
+        key: models::token_hash(&token),
+        user_id,
+    };
+    Box::new(
+        mem.set_json_if_not_exists(&password_reset, expires_in)
+            .and_then(move |success_tf| {
+                if success_tf {
+                    Ok(token)
+                } else {
+                    error!(
+                        "create_password_reset: Token collided with {}",
+                        password_reset.key
+                    );
+                    Err(Error::InternalServerError)
+                }
+            }),
+    )
+}
+
+/// Take the password reset with this token, which can only be done once
+pub fn take_password_reset(
+    mem: &MemExecutor,
+    token: &str,
+) -> AppFuture<Result<models::PasswordReset, HandoffError>> {
+    Box::new(
+        mem.take_json::<models::PasswordReset>(&models::token_hash(token))
+            .map(|taken| match taken {
+                Taken::Value(password_reset) => Ok(password_reset),
+                Taken::AlreadyTaken => Err(HandoffError::Reused),
+                Taken::Missing => Err(HandoffError::Expired),
+            }),
+    )
+}
+
+/// Create a token confirming `username` for the user's password, only storing its hash
+pub fn create_password_setup(
+    mem: &MemExecutor,
+    user_id: String,
+    username: String,
+    expires_in: &std::time::Duration,
+) -> AppFuture<String> {
+    let token = secure_rand_hex(24);
+    let password_setup = models::PasswordSetup {
+        key: models::token_hash(&token),
+        user_id,
+        username,
+    };
+    Box::new(
+        mem.set_json_if_not_exists(&password_setup, expires_in)
+            .and_then(move |success_tf| {
+                if success_tf {
+                    Ok(token)
+                } else {
+                    error!(
+                        "create_password_setup: Token collided with {}",
+                        password_setup.key
+                    );
+                    Err(Error::InternalServerError)
+                }
+            }),
+    )
+}
+
+/// Take the password setup with this token, which can only be done once
+pub fn take_password_setup(
+    mem: &MemExecutor,
+    token: &str,
+) -> AppFuture<Result<models::PasswordSetup, HandoffError>> {
+    Box::new(
+        mem.take_json::<models::PasswordSetup>(&models::token_hash(token))
+            .map(|taken| match taken {
+                Taken::Value(password_setup) => Ok(password_setup),
+                Taken::AlreadyTaken => Err(HandoffError::Reused),
+                Taken::Missing => Err(HandoffError::Expired),
             }),
     )
 }
 
+/// Wrong passwords allowed for a username, however many login sessions they are spread over
+pub const MAX_PASSWORD_ATTEMPTS: i64 = 10;
+/// How long a username's wrong passwords count towards [MAX_PASSWORD_ATTEMPTS]
+pub const PASSWORD_LOCKOUT: std::time::Duration = std::time::Duration::from_secs(60 * 15);
+
+/// Count an attempt at the username's password before it is checked, so guesses sent in parallel
+/// all count. Resolves with whether the password may be checked.
+/// Attempts which succeed are taken back with [password_attempt_succeeded].
+pub fn start_password_attempt(mem: &MemExecutor, username: &str) -> AppFuture<bool> {
+    Box::new(
+        mem.increment(
+            &models::password_attempts_named_key(username),
+            1,
+            &PASSWORD_LOCKOUT,
+        )
+        .map(|attempts| attempts <= MAX_PASSWORD_ATTEMPTS),
+    )
+}
+
+/// Take back the attempt counted by [start_password_attempt] once the password was right
+pub fn password_attempt_succeeded(mem: &MemExecutor, username: &str) -> AppFuture<()> {
+    Box::new(
+        mem.increment(
+            &models::password_attempts_named_key(username),
+            -1,
+            &PASSWORD_LOCKOUT,
+        )
+        .map(|_| ()),
+    )
+}
+
+/// On callback, assign identity information to the signup session to be used for completing signup
+pub fn link_handoff_to_i_am(
+    mem: &MemExecutor,
+    handoff: models::StateHandoff,
+    i_am: models::IAm,
+    expires_in: &std::time::Duration,
+) -> AppFuture<LinkOutput> {
+    let redirect_uri_opt = handoff.redirect_uri;
+    Box::new(
+        link_login_session_to_i_am(mem, &LoginAccessKey(handoff.session_key), i_am, expires_in)
+            .map(|_| LinkOutput {
+                redirect_uri_opt: redirect_uri_opt,
+            }),
+    )
+}
+
+/// Assign identity information to the signup session, used to complete signup
+pub fn link_login_session_to_i_am(
+    mem: &MemExecutor,
+    login: &LoginAccessKey,
+    i_am: models::IAm,
+    expires_in: &std::time::Duration,
+) -> AppFuture<()> {
+    let mem: MemExecutor = mem.clone();
+    let expires_in = *expires_in;
+    Box::new(get_login_session(&mem, login).and_then(
+        move |mut login_session: models::LoginSession| {
+            login_session.i_am = Some(i_am);
+            mem.set_json(&login_session, &expires_in)
+        },
+    ))
+}
+
 pub struct LinkOutput {
     pub redirect_uri_opt: Option<String>,
 }
 
+/// On callback, assign the user who already has this login to the signup session
+pub fn link_handoff_to_user_id(
+    mem: &MemExecutor,
+    handoff: models::StateHandoff,
+    user_id: String,
+    expires_in: &std::time::Duration,
+) -> AppFuture<LinkOutput> {
+    let redirect_uri_opt = handoff.redirect_uri;
+    Box::new(
+        link_login_session_to_user_id(
+            mem,
+            &LoginAccessKey(handoff.session_key),
+            user_id,
+            expires_in,
+        )
+        .map(|_| LinkOutput {
+            redirect_uri_opt: redirect_uri_opt,
+        }),
+    )
+}
+
 /// On callback, assign identity information to the signup session to be used for completing signup
-pub fn link_state_to_user_id(mem: &MemExecutor, state: String, user_id: String) -> AppFuture<LinkOutput> {
+pub fn link_login_session_to_user_id(
+    mem: &MemExecutor,
+    login: &LoginAccessKey,
+    user_id: String,
+    expires_in: &std::time::Duration,
+) -> AppFuture<()> {
     let mem: MemExecutor = mem.clone();
+    let expires_in = *expires_in;
+    Box::new(get_login_session(&mem, login).and_then(
+        move |mut login_session: models::LoginSession| {
+            // a second factor proved for someone else doesn't count
+            if login_session.user_id.as_ref() != Some(&user_id) {
+                login_session.second_factor = models::SecondFactor::None;
+            }
+            login_session.user_id = Some(user_id);
+            mem.set_json(&login_session, &expires_in)
+        },
+    ))
+}
+
+/// Wrong second factor codes allowed before the login session has to start over
+pub const MAX_SECOND_FACTOR_ATTEMPTS: i64 = 5;
+/// Wrong second factor codes allowed for a user, however many login sessions they are spread over
+pub const MAX_USER_SECOND_FACTOR_ATTEMPTS: i64 = 20;
+/// How long a user's wrong codes count towards [MAX_USER_SECOND_FACTOR_ATTEMPTS]
+pub const USER_SECOND_FACTOR_LOCKOUT: std::time::Duration = std::time::Duration::from_secs(60 * 15);
+
+/// Record that the login session's user was asked for their second factor
+pub fn require_second_factor(
+    mem: &MemExecutor,
+    login: &LoginAccessKey,
+    expires_in: &std::time::Duration,
+) -> AppFuture<()> {
+    let mem: MemExecutor = mem.clone();
+    let expires_in = *expires_in;
+    Box::new(get_login_session(&mem, login).and_then(
+        move |mut login_session: models::LoginSession| {
+            if let models::SecondFactor::None = login_session.second_factor {
+                login_session.second_factor = models::SecondFactor::Pending;
+                Either::A(mem.set_json(&login_session, &expires_in))
+            } else {
+                Either::B(future::ok(()))
+            }
+        },
+    ))
+}
+
+/// Whether a second factor may be checked, see [start_second_factor_attempt]
+#[derive(Debug, PartialEq)]
+pub enum SecondFactorAttempt {
+    Allowed,
+    /// Too many wrong codes for the login session, which was ended
+    LoginSessionEnded,
+    /// Too many wrong codes for the user, who has to wait for [USER_SECOND_FACTOR_LOCKOUT]
+    UserLockedOut,
+}
+
+/// Count an attempt at the second factor before it is checked, so guesses sent in parallel all
+/// count.
+/// Attempts which succeed are taken back from the user's count with [satisfy_second_factor].
+pub fn start_second_factor_attempt(
+    mem: &MemExecutor,
+    login: &LoginAccessKey,
+    user_id: &str,
+    expires_in: &std::time::Duration,
+) -> AppFuture<SecondFactorAttempt> {
+    let mem: MemExecutor = mem.clone();
+    let user_key = models::SecondFactor::user_attempts_named_key(user_id);
+    let expires_in = *expires_in;
     Box::new(
-        mem.get_json::<models::StateHandoff>(&state)
-            .and_then(|handoff_opt| {
-                handoff_opt.ok_or(Error::BadRequest(String::from(
-                    "Login state handoff does not exist.",
-                )))
-            })
-            .and_then(move |handoff: models::StateHandoff| {
-                let redirect_uri_opt = handoff.redirect_uri;
-                link_login_session_to_user_id(&mem, &LoginAccessKey(handoff.session_key), user_id).map(|_| LinkOutput {
-                    redirect_uri_opt: redirect_uri_opt,
+        get_login_session(&mem, login).and_then(move |login_session: models::LoginSession| {
+            let login_key =
+                models::LoginSession::second_factor_attempts_named_key(&login_session.key);
+            mem.increment(&login_key, 1, &expires_in)
+                .join(mem.increment(&user_key, 1, &USER_SECOND_FACTOR_LOCKOUT))
+                .and_then(move |(login_attempts, user_attempts)| {
+                    if login_attempts > MAX_SECOND_FACTOR_ATTEMPTS {
+                        Either::A(
+                            mem.delete::<models::LoginSession>(&login_session.key)
+                                .map(|_| SecondFactorAttempt::LoginSessionEnded),
+                        )
+                    } else if user_attempts > MAX_USER_SECOND_FACTOR_ATTEMPTS {
+                        Either::B(future::ok(SecondFactorAttempt::UserLockedOut))
+                    } else {
+                        Either::B(future::ok(SecondFactorAttempt::Allowed))
+                    }
                 })
+        }),
+    )
+}
+
+/// Record that `user_id` proved their second factor for the login session.
+/// Fails if the login session was linked to someone else while the second factor was checked.
+pub fn satisfy_second_factor(
+    mem: &MemExecutor,
+    login: &LoginAccessKey,
+    user_id: &str,
+    expires_in: &std::time::Duration,
+) -> AppFuture<()> {
+    let mem: MemExecutor = mem.clone();
+    let user_id = user_id.to_string();
+    let expires_in = *expires_in;
+    Box::new(get_login_session(&mem, login).and_then(
+        move |mut login_session: models::LoginSession| {
+            if login_session.user_id.as_ref() != Some(&user_id) {
+                return Either::A(future::err(relinked_error()));
+            }
+            login_session.second_factor = models::SecondFactor::Satisfied;
+            Either::B(
+                mem.compare_and_set_json(
+                    &login_session,
+                    models::LoginSession::USER_ID_FIELD,
+                    &user_id,
+                    &expires_in,
+                )
+                .and_then(move |satisfied| {
+                    if satisfied {
+                        let user_key = models::SecondFactor::user_attempts_named_key(&user_id);
+                        Either::A(
+                            mem.increment(&user_key, -1, &USER_SECOND_FACTOR_LOCKOUT)
+                                .map(|_| ()),
+                        )
+                    } else {
+                        Either::B(future::err(relinked_error()))
+                    }
+                }),
+            )
+        },
+    ))
+}
+
+fn relinked_error() -> Error {
+    Error::Unauthorized(String::from(
+        "Signed in as someone else while checking the second factor, try again",
+    ))
+}
+
+/// Challenges are short lived, unlike the login session asking for them
+const WEBAUTHN_CHALLENGE_EXPIRATION_SECS: u64 = 60 * 5;
+
+/// Start signing in with a passkey, replacing any earlier challenge of the login session
+pub fn create_webauthn_challenge(
+    mem: &MemExecutor,
+    login: &LoginAccessKey,
+    second_factor: bool,
+) -> AppFuture<String> {
+    let mem: MemExecutor = mem.clone();
+    Box::new(
+        get_login_session(&mem, login).and_then(move |login_session: models::LoginSession| {
+            let challenge = models::WebauthnChallenge {
+                key: login_session.key,
+                challenge: webauthn::new_challenge(),
+                second_factor: second_factor,
+            };
+            let expires_in = std::time::Duration::from_secs(WEBAUTHN_CHALLENGE_EXPIRATION_SECS);
+            mem.set_json(&challenge, &expires_in)
+                .map(move |_| challenge.challenge)
+        }),
+    )
+}
+
+/// Take the login session's challenge, so it's only answered once
+pub fn take_webauthn_challenge(
+    mem: &MemExecutor,
+    login: &LoginAccessKey,
+) -> AppFuture<Option<models::WebauthnChallenge>> {
+    Box::new(
+        mem.take_json::<models::WebauthnChallenge>(&login.0)
+            .map(|taken| match taken {
+                Taken::Value(challenge) => Some(challenge),
+                Taken::AlreadyTaken | Taken::Missing => None,
             }),
     )
 }
 
-/// On callback, assign identity information to the signup session to be used for completing signup
-pub fn link_login_session_to_user_id(
+/// Sign in the login session with a passkey, which counts as both factors
+pub fn link_login_session_to_passkey_user(
     mem: &MemExecutor,
     login: &LoginAccessKey,
     user_id: String,
+    expires_in: &std::time::Duration,
 ) -> AppFuture<()> {
     let mem: MemExecutor = mem.clone();
+    let expires_in = *expires_in;
     Box::new(get_login_session(&mem, login).and_then(
         move |mut login_session: models::LoginSession| {
             login_session.user_id = Some(user_id);
-            mem.set_json(&login_session, &SIGNUP_SESSION_EXPIRATION)
+            login_session.second_factor = models::SecondFactor::Satisfied;
+            mem.set_json(&login_session, &expires_in)
         },
     ))
 }
 
-pub fn create_login_access_key(mem: &MemExecutor) -> AppFuture<LoginAccessKey> {
+/// Start registering a passkey for the user, replacing any registration the session started before
+pub fn create_webauthn_registration(
+    mem: &MemExecutor,
+    user_access_key: &UserAccessKey,
+    user_id: String,
+) -> AppFuture<String> {
+    let registration = models::WebauthnRegistration {
+        key: user_access_key.0.clone(),
+        user_id: user_id,
+        challenge: webauthn::new_challenge(),
+    };
+    let expires_in = std::time::Duration::from_secs(WEBAUTHN_CHALLENGE_EXPIRATION_SECS);
     Box::new(
-        create_login_access_key_r(mem.clone(), 5).map(|signup_session: models::LoginSession| {
-            LoginAccessKey(signup_session.key.to_string())
-        }),
+        mem.set_json(&registration, &expires_in)
+            .map(move |_| registration.challenge),
+    )
+}
+
+/// Take the registration the session started, so its challenge is only answered once
+pub fn take_webauthn_registration(
+    mem: &MemExecutor,
+    user_access_key: &UserAccessKey,
+) -> AppFuture<Option<models::WebauthnRegistration>> {
+    Box::new(
+        mem.take_json::<models::WebauthnRegistration>(&user_access_key.0)
+            .map(|taken| match taken {
+                Taken::Value(registration) => Some(registration),
+                Taken::AlreadyTaken | Taken::Missing => None,
+            }),
+    )
+}
+
+pub fn create_login_access_key(
+    mem: &MemExecutor,
+    pepper_id: u8,
+    expires_in: &std::time::Duration,
+) -> AppFuture<LoginAccessKey> {
+    Box::new(
+        create_login_access_key_r(mem.clone(), pepper_id, *expires_in, 5).map(
+            |signup_session: models::LoginSession| LoginAccessKey(signup_session.key.to_string()),
+        ),
     )
 }
 
 fn create_login_access_key_r(
     mem: MemExecutor,
+    pepper_id: u8,
+    expires_in: std::time::Duration,
     attempts_left: usize,
 ) -> AppFuture<models::LoginSession> {
-    let signup_session = models::LoginSession::from_key(secure_rand_hex(12));
+    let signup_session = models::LoginSession::from_key(secure_rand_hex(12), pepper_id);
     Box::new(
-        mem.set_json_if_not_exists(&signup_session, &SIGNUP_SESSION_EXPIRATION)
+        mem.set_json_if_not_exists(&signup_session, &expires_in)
             .from_err()
             .and_then(move |success_tf| {
                 if success_tf {
                     Either::A(future::ok(signup_session))
                 } else if attempts_left <= 0 {
                     error!(
-                        "create_login_access_key_r: Ran out of attempts to create a new signup_session! Last tried: {:?}",
+                        "create_login_access_key_r: Ran out of attempts! Last tried: {:?}",
                         signup_session.key,
                     );
                     Either::B(Either::A(future::err(Error::InternalServerError)))
                 } else {
-                    Either::B(Either::B(create_login_access_key_r(mem, attempts_left - 1)))
+                    Either::B(Either::B(create_login_access_key_r(
+                        mem,
+                        pepper_id,
+                        expires_in,
+                        attempts_left - 1,
+                    )))
                 }
             }),
     )
@@ -164,33 +752,481 @@ fn create_login_access_key_r(
 
 use crate::db::models::User;
 
-pub fn create_user_access_key(mem: &MemExecutor, user: User) -> AppFuture<UserAccessKey> {
+pub use models::SessionClient;
+
+// Only record activity once a minute, rather than writing on every request
+const LAST_SEEN_RESOLUTION_SECS: i64 = 60;
+
+/// Access keys handed out when a user session is created or refreshed
+pub struct UserAccessKeys {
+    pub user_key: UserAccessKey,
+    pub refresh_key: RefreshAccessKey,
+}
+
+pub fn create_user_access_key(
+    mem: &MemExecutor,
+    user: User,
+    pepper_id: u8,
+    client: SessionClient,
+    session_expires_in: &std::time::Duration,
+    refresh_expires_in: &std::time::Duration,
+) -> AppFuture<UserAccessKeys> {
+    let mem: MemExecutor = mem.clone();
+    let refresh_expires_in = *refresh_expires_in;
+    Box::new(
+        issue_user_access_keys(
+            mem.clone(),
+            models::MemUser::from(user),
+            pepper_id,
+            client,
+            secure_rand_hex(12),
+            *session_expires_in,
+            refresh_expires_in,
+        )
+        .and_then(move |(family, user_access_keys)| {
+            mem.set_json(&family, &refresh_expires_in)
+                .map(move |_| user_access_keys)
+        }),
+    )
+}
+
+/// Exchange the newest refresh token of a family for a new user session and the next refresh token.
+/// Presenting any older token of the family revokes the family and its session, and so does
+/// presenting the newest one twice at once, since only one exchange can replace it.
+pub fn refresh_user_access_key(
+    mem: &MemExecutor,
+    refresh_key: &RefreshAccessKey,
+    pepper_id: u8,
+    client: SessionClient,
+    session_expires_in: &std::time::Duration,
+    refresh_expires_in: &std::time::Duration,
+) -> AppFuture<UserAccessKeys> {
+    let mem: MemExecutor = mem.clone();
+    let token_key = refresh_key.0.clone();
+    let (session_expires_in, refresh_expires_in) = (*session_expires_in, *refresh_expires_in);
     Box::new(
-        create_user_access_key_r(mem.clone(), models::MemUser::from(user), 5)
-            .map(|user_session: models::UserSession| UserAccessKey(user_session.key.to_string())),
+        mem.get_json::<models::RefreshToken>(&token_key)
+            .and_then({
+                let token_key = token_key.clone();
+                move |refresh_token_opt| match refresh_token_opt {
+                    Some(refresh_token) => Ok(refresh_token.family),
+                    // used tokens are deleted, but still name their family
+                    None => models::RefreshToken::family_of(&token_key)
+                        .map(String::from)
+                        .ok_or(Error::Unauthorized(String::from("Refresh token expired"))),
+                }
+            })
+            .and_then({
+                let mem = mem.clone();
+                move |family_key| mem.get_json::<models::RefreshFamily>(&family_key)
+            })
+            .and_then(|family_opt| {
+                family_opt.ok_or(Error::Unauthorized(String::from("Refresh token revoked")))
+            })
+            .and_then(move |replaced: models::RefreshFamily| {
+                if replaced.current_token != token_key {
+                    warn!(
+                        "refresh_user_access_key: Refresh token reused, revoking family {:?}",
+                        replaced.key,
+                    );
+                    Either::A(revoke_refresh_family(&mem, &replaced.key).and_then(|_| {
+                        Err::<UserAccessKeys, _>(Error::Unauthorized(String::from(
+                            "Refresh token was already used",
+                        )))
+                    }))
+                } else {
+                    Either::B(
+                        issue_user_access_keys(
+                            mem.clone(),
+                            replaced.user.clone(),
+                            pepper_id,
+                            client,
+                            replaced.key.clone(),
+                            session_expires_in,
+                            refresh_expires_in,
+                        )
+                        .and_then(move |(family, user_access_keys)| {
+                            advance_refresh_family(
+                                mem,
+                                family,
+                                replaced,
+                                user_access_keys,
+                                &refresh_expires_in,
+                            )
+                        }),
+                    )
+                }
+            }),
+    )
+}
+
+/// Create a user session along with the next refresh token of the family, resolving with the
+/// family pointing at them for the caller to store
+fn issue_user_access_keys(
+    mem: MemExecutor,
+    user: models::MemUser,
+    pepper_id: u8,
+    client: SessionClient,
+    family_key: String,
+    session_expires_in: std::time::Duration,
+    refresh_expires_in: std::time::Duration,
+) -> AppFuture<(models::RefreshFamily, UserAccessKeys)> {
+    Box::new(
+        create_user_access_key_r(
+            mem.clone(),
+            user.clone(),
+            pepper_id,
+            client,
+            family_key.clone(),
+            session_expires_in,
+            5,
+        )
+        .and_then({
+            let mem = mem.clone();
+            move |user_session: models::UserSession| {
+                index_user_session(&mem, &user_session).map(move |_| user_session)
+            }
+        })
+        .and_then(move |user_session: models::UserSession| {
+            create_refresh_token_r(mem, family_key.clone(), pepper_id, refresh_expires_in, 5).map(
+                move |refresh_token: models::RefreshToken| {
+                    let family = models::RefreshFamily {
+                        key: family_key,
+                        user: user,
+                        current_token: refresh_token.key.clone(),
+                        session_key: user_session.key.clone(),
+                    };
+                    let user_access_keys = UserAccessKeys {
+                        user_key: UserAccessKey(user_session.key),
+                        refresh_key: RefreshAccessKey(refresh_token.key),
+                    };
+                    (family, user_access_keys)
+                },
+            )
+        }),
+    )
+}
+
+/// Point the family at its next token, as long as nobody else did first. The replaced token and
+/// session are then deleted, or if someone else did, the whole family is revoked.
+fn advance_refresh_family(
+    mem: MemExecutor,
+    family: models::RefreshFamily,
+    replaced: models::RefreshFamily,
+    user_access_keys: UserAccessKeys,
+    refresh_expires_in: &std::time::Duration,
+) -> AppFuture<UserAccessKeys> {
+    Box::new(
+        mem.compare_and_set_json(
+            &family,
+            models::RefreshFamily::CURRENT_TOKEN_FIELD,
+            &replaced.current_token,
+            refresh_expires_in,
+        )
+        .and_then(move |advanced| {
+            let models::RefreshFamily {
+                user,
+                current_token,
+                session_key,
+                ..
+            } = replaced;
+            if advanced {
+                Either::A(
+                    remove_user_session(&mem, user.user_id(), &UserAccessKey(session_key))
+                        .and_then(move |_| mem.delete::<models::RefreshToken>(&current_token))
+                        .map(move |_| user_access_keys),
+                )
+            } else {
+                warn!(
+                    "advance_refresh_family: Refresh token used twice, revoking family {:?}",
+                    family.key,
+                );
+                Either::B(
+                    remove_user_session(&mem, user.user_id(), &user_access_keys.user_key)
+                        .and_then({
+                            let mem = mem.clone();
+                            move |_| {
+                                mem.delete::<models::RefreshToken>(&user_access_keys.refresh_key.0)
+                            }
+                        })
+                        .and_then(move |_| revoke_refresh_family(&mem, &family.key))
+                        .and_then(|_| {
+                            Err::<UserAccessKeys, _>(Error::Unauthorized(String::from(
+                                "Refresh token was already used",
+                            )))
+                        }),
+                )
+            }
+        }),
+    )
+}
+
+/// Delete the family along with the session of its newest token, so none of its tokens work
+fn revoke_refresh_family(mem: &MemExecutor, family_key: &str) -> AppFuture<()> {
+    let mem: MemExecutor = mem.clone();
+    Box::new(
+        mem.delete_json::<models::RefreshFamily>(family_key)
+            .and_then(move |family_opt| match family_opt {
+                Some(family) => Either::A(remove_user_session(
+                    &mem,
+                    family.user.user_id(),
+                    &UserAccessKey(family.session_key),
+                )),
+                None => Either::B(future::ok(())),
+            }),
     )
 }
 
 fn create_user_access_key_r(
     mem: MemExecutor,
     user: models::MemUser,
+    pepper_id: u8,
+    client: SessionClient,
+    refresh_family: String,
+    expires_in: std::time::Duration,
     attempts_left: usize,
 ) -> AppFuture<models::UserSession> {
-    let user_session = models::UserSession::from_key_and_user(secure_rand_hex(12), user.clone());
+    let user_session = models::UserSession::from_key_and_user(
+        secure_rand_hex(12),
+        user.clone(),
+        pepper_id,
+        client.clone(),
+        refresh_family.clone(),
+        &expires_in,
+    );
     Box::new(
-        mem.set_json_if_not_exists(&user_session, &SIGNUP_SESSION_EXPIRATION)
+        mem.set_json_if_not_exists(&user_session, &expires_in)
             .from_err()
             .and_then(move |success_tf| {
                 if success_tf {
                     Either::A(future::ok(user_session))
                 } else if attempts_left <= 0 {
                     error!(
-                        "create_user_access_key_r: Ran out of attempts to create a new user_session! Last tried: {:?}",
+                        "create_user_access_key_r: Ran out of attempts! Last tried: {:?}",
                         user_session.key,
                     );
                     Either::B(Either::A(future::err(Error::InternalServerError)))
                 } else {
-                    Either::B(Either::B(create_user_access_key_r(mem, user, attempts_left - 1)))
+                    Either::B(Either::B(create_user_access_key_r(
+                        mem,
+                        user,
+                        pepper_id,
+                        client,
+                        refresh_family,
+                        expires_in,
+                        attempts_left - 1,
+                    )))
+                }
+            }),
+    )
+}
+
+fn create_refresh_token_r(
+    mem: MemExecutor,
+    family: String,
+    pepper_id: u8,
+    expires_in: std::time::Duration,
+    attempts_left: usize,
+) -> AppFuture<models::RefreshToken> {
+    let refresh_token = models::RefreshToken {
+        key: models::RefreshToken::new_key(&family, &secure_rand_hex(12)),
+        family: family.clone(),
+        pepper_id: pepper_id,
+    };
+    Box::new(
+        mem.set_json_if_not_exists(&refresh_token, &expires_in)
+            .from_err()
+            .and_then(move |success_tf| {
+                if success_tf {
+                    Either::A(future::ok(refresh_token))
+                } else if attempts_left <= 0 {
+                    error!(
+                        "create_refresh_token_r: Ran out of attempts! Last tried: {:?}",
+                        refresh_token.key,
+                    );
+                    Either::B(Either::A(future::err(Error::InternalServerError)))
+                } else {
+                    Either::B(Either::B(create_refresh_token_r(
+                        mem,
+                        family,
+                        pepper_id,
+                        expires_in,
+                        attempts_left - 1,
+                    )))
+                }
+            }),
+    )
+}
+
+/// Add the session to its user's index, dropping sessions which have since expired
+fn index_user_session(mem: &MemExecutor, user_session: &models::UserSession) -> AppFuture<()> {
+    let mem: MemExecutor = mem.clone();
+    let index_key = models::UserSession::index_named_key(user_session.user.user_id());
+    let session_key = user_session.key.clone();
+    let expires_at = user_session.expires_at.timestamp();
+    Box::new(
+        mem.sorted_set_remove_to(&index_key, Utc::now().timestamp())
+            .and_then({
+                let mem = mem.clone();
+                let index_key = index_key.clone();
+                move |_| mem.sorted_set_add(&index_key, expires_at, &session_key)
+            })
+            // every session slides by the same amount, so the session just added or used
+            // expires last
+            .and_then(move |_| mem.expire_at(&index_key, expires_at)),
+    )
+}
+
+pub fn delete_login_session(mem: &MemExecutor, login_access_key: &LoginAccessKey) -> AppFuture<()> {
+    Box::new(mem.delete::<models::LoginSession>(&login_access_key.0))
+}
+
+/// Log out of a session, revoking the refresh token issued with it
+pub fn delete_user_session(
+    mem: &MemExecutor,
+    user_id: &str,
+    user_access_key: &UserAccessKey,
+) -> AppFuture<()> {
+    let mem: MemExecutor = mem.clone();
+    let user_id = user_id.to_string();
+    let user_access_key = user_access_key.clone();
+    Box::new(
+        get_user_session_opt(&mem, &user_access_key).and_then(move |user_session_opt| {
+            match user_session_opt {
+                Some(user_session) => Either::A(delete_user_session_and_family(&mem, user_session)),
+                // already expired, but it may still be in the index
+                None => Either::B(remove_user_session(&mem, &user_id, &user_access_key)),
+            }
+        }),
+    )
+}
+
+fn delete_user_session_and_family(
+    mem: &MemExecutor,
+    user_session: models::UserSession,
+) -> AppFuture<()> {
+    let mem: MemExecutor = mem.clone();
+    Box::new(
+        remove_user_session(
+            &mem,
+            user_session.user.user_id(),
+            &UserAccessKey(user_session.key),
+        )
+        .and_then(move |_| match user_session.refresh_family {
+            Some(family_key) => Either::A(mem.delete::<models::RefreshFamily>(&family_key)),
+            None => Either::B(future::ok(())),
+        }),
+    )
+}
+
+/// Delete only the session, for when its refresh token family lives on
+fn remove_user_session(
+    mem: &MemExecutor,
+    user_id: &str,
+    user_access_key: &UserAccessKey,
+) -> AppFuture<()> {
+    let mem: MemExecutor = mem.clone();
+    let index_key = models::UserSession::index_named_key(user_id);
+    let session_key = user_access_key.0.clone();
+    Box::new(
+        mem.delete::<models::UserSession>(&session_key)
+            .and_then(move |_| mem.sorted_set_remove(&index_key, &session_key)),
+    )
+}
+
+/// Every unexpired session of a user, oldest first
+pub fn get_user_sessions(mem: &MemExecutor, user_id: &str) -> AppFuture<Vec<models::UserSession>> {
+    let mem: MemExecutor = mem.clone();
+    let index_key = models::UserSession::index_named_key(user_id);
+    Box::new(
+        mem.sorted_set_remove_to(&index_key, Utc::now().timestamp())
+            .and_then({
+                let mem = mem.clone();
+                move |_| mem.sorted_set_members(&index_key)
+            })
+            .and_then(move |keys| {
+                future::join_all(
+                    keys.iter()
+                        .map(|key| mem.get_json::<models::UserSession>(key))
+                        .collect::<Vec<_>>(),
+                )
+            })
+            .map(|user_sessions| {
+                let mut user_sessions: Vec<models::UserSession> = user_sessions
+                    .into_iter()
+                    .filter_map(|user_session_opt| user_session_opt)
+                    .collect();
+                user_sessions.sort_by_key(|user_session| user_session.created_at);
+                user_sessions
+            }),
+    )
+}
+
+/// Revoke one of a user's sessions by its public id, resolving with whether it was found
+pub fn delete_user_session_by_public_id(
+    mem: &MemExecutor,
+    user_id: &str,
+    public_id: String,
+) -> AppFuture<bool> {
+    let mem: MemExecutor = mem.clone();
+    let user_id = user_id.to_string();
+    Box::new(
+        get_user_sessions(&mem, &user_id).and_then(move |user_sessions| {
+            match user_sessions
+                .into_iter()
+                .find(|user_session| user_session.public_id() == public_id)
+            {
+                Some(user_session) => {
+                    Either::A(delete_user_session_and_family(&mem, user_session).map(|_| true))
+                }
+                None => Either::B(future::ok(false)),
+            }
+        }),
+    )
+}
+
+/// Log a user out everywhere, resolving with the number of sessions which were deleted
+pub fn delete_user_sessions_for_user(mem: &MemExecutor, user_id: String) -> AppFuture<usize> {
+    let mem: MemExecutor = mem.clone();
+    let index_key = models::UserSession::index_named_key(&user_id);
+    Box::new(
+        get_user_sessions(&mem, &user_id)
+            .and_then({
+                let mem = mem.clone();
+                move |user_sessions| {
+                    future::join_all(
+                        user_sessions
+                            .into_iter()
+                            .map(|user_session| delete_user_session_and_family(&mem, user_session))
+                            .collect::<Vec<_>>(),
+                    )
+                }
+            })
+            .and_then(move |deleted| mem.delete_named(&index_key).map(move |_| deleted.len())),
+    )
+}
+
+/// Record that the session was just used, which pushes its expiration back.
+/// Fails if the session was logged out since it was read, rather than bringing it back.
+pub fn touch_user_session(
+    mem: &MemExecutor,
+    mut user_session: models::UserSession,
+    expires_in: &std::time::Duration,
+) -> AppFuture<models::UserSession> {
+    if (Utc::now() - user_session.last_seen_at).num_seconds() < LAST_SEEN_RESOLUTION_SECS {
+        return Box::new(future::ok(user_session));
+    }
+    let mem: MemExecutor = mem.clone();
+    user_session.slide_expiration(expires_in);
+    Box::new(
+        mem.set_json_if_exists(&user_session, expires_in)
+            .and_then(move |touched| {
+                if touched {
+                    Either::A(index_user_session(&mem, &user_session).map(move |_| user_session))
+                } else {
+                    Either::B(future::err(Error::Unauthorized(String::from(
+                        "Invalid credentials",
+                    ))))
                 }
             }),
     )
@@ -223,3 +1259,94 @@ pub fn get_user_session_opt(
 ) -> impl Future<Item = Option<models::UserSession>, Error = Error> {
     mem.get_json(&user_access_key.0)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix::{Arbiter, System};
+    use actix_redis::RedisActor;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// Run `test` against the Redis at TEST_REDIS_URL, like tests/support does
+    fn with_mem<T, F>(test: F) -> Result<T>
+    where
+        T: 'static,
+        F: FnOnce(MemExecutor) -> AppFuture<T>,
+    {
+        let sys = System::new("mem-sessions-test");
+        let redis_url =
+            std::env::var("TEST_REDIS_URL").unwrap_or_else(|_| String::from("127.0.0.1:6379"));
+        let mem = MemExecutor::new(RedisActor::start(redis_url));
+        let result = Rc::new(RefCell::new(None));
+        let stored = result.clone();
+        Arbiter::spawn(test(mem).then(move |res| {
+            *stored.borrow_mut() = Some(res);
+            System::current().stop();
+            Ok(())
+        }));
+        sys.run();
+        let res = result.borrow_mut().take();
+        res.expect("test future resolved")
+    }
+
+    fn new_user() -> User {
+        User {
+            id: secure_rand_hex(8),
+            display_name: String::from("Sessions Test"),
+            full_name: None,
+            photo_url: None,
+            is_person: true,
+            created_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    #[ignore]
+    fn touching_logged_out_session_does_not_bring_it_back() {
+        let user = new_user();
+        let user_id = user.id.clone();
+        let expires_in = std::time::Duration::from_secs(60 * 60);
+        let (touched, still_stored, indexed) = with_mem(move |mem| {
+            Box::new(
+                create_user_access_key(
+                    &mem,
+                    user,
+                    0,
+                    SessionClient::default(),
+                    &expires_in,
+                    &expires_in,
+                )
+                .and_then({
+                    let mem = mem.clone();
+                    move |keys| get_user_session_opt(&mem, &keys.user_key)
+                })
+                .and_then(move |user_session_opt| {
+                    // read by a request which was still running when the user logged out
+                    // everywhere, long enough after its last use to be written back
+                    let mut user_session = user_session_opt.expect("user session was created");
+                    user_session.last_seen_at =
+                        user_session.last_seen_at - chrono::Duration::minutes(5);
+                    let user_key = UserAccessKey(user_session.key.clone());
+                    delete_user_sessions_for_user(&mem, user_id.clone())
+                        .and_then({
+                            let mem = mem.clone();
+                            move |_| touch_user_session(&mem, user_session, &expires_in).then(Ok)
+                        })
+                        .and_then(move |touched| {
+                            let index_key = models::UserSession::index_named_key(&user_id);
+                            get_user_session_opt(&mem, &user_key)
+                                .join(mem.sorted_set_members(&index_key))
+                                .map(move |(user_session_opt, indexed)| {
+                                    (touched.is_ok(), user_session_opt.is_some(), indexed)
+                                })
+                        })
+                }),
+            )
+        })
+        .unwrap();
+        assert!(!touched);
+        assert!(!still_stored);
+        assert!(indexed.is_empty());
+    }
+}
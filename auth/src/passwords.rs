@@ -0,0 +1,126 @@
+//! Argon2id password hashing, with the active pepper as the hash's secret
+use argon2::{ThreadMode, Variant, Version};
+
+use crate::config::{Config, PepperKeyring};
+use crate::prelude::*;
+use crate::utils::{secure_rand, secure_rand_hex};
+
+/// Long enough for passphrases, short enough that hashing can't be used to tie up the server
+pub const MAX_PASSWORD_LENGTH: usize = 1024;
+
+pub struct PasswordHasher {
+    mem_cost: u32,
+    time_cost: u32,
+    lanes: u32,
+    min_length: usize,
+    peppers: PepperKeyring,
+    /// Verified against when there is no password to check, so that unknown usernames take
+    /// as long to reject as wrong passwords
+    dummy_hash: String,
+}
+
+/// A new hash, and the pepper it used
+pub struct PasswordHash {
+    pub hash: String,
+    pub pepper_id: u8,
+}
+
+impl PasswordHasher {
+    pub fn new(config: &Config) -> Self {
+        let mut hasher = PasswordHasher {
+            mem_cost: config.password_hash_memory_kib,
+            time_cost: config.password_hash_iterations,
+            lanes: config.password_hash_parallelism,
+            min_length: config.password_min_length,
+            peppers: config.peppers.clone(),
+            dummy_hash: String::new(),
+        };
+        hasher.dummy_hash = hasher
+            .hash(&secure_rand_hex(16))
+            .unwrap_or_else(|err| panic!("PASSWORD_HASH_* settings are invalid: {}", err))
+            .hash;
+        hasher
+    }
+
+    /// Passwords must be within these lengths, counted in characters
+    pub fn check_length(&self, password: &str) -> Result<()> {
+        let length = password.chars().count();
+        if length < self.min_length {
+            Err(Error::BadRequest(format!(
+                "Password must be at least {} characters",
+                self.min_length
+            )))
+        } else if password.len() > MAX_PASSWORD_LENGTH {
+            Err(Error::BadRequest(String::from("Password is too long")))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn hash(&self, password: &str) -> Result<PasswordHash> {
+        let pepper_id = self.peppers.active_id();
+        let pepper = self.pepper(pepper_id)?;
+        let config = self.argon2_config(pepper.as_bytes());
+        argon2::hash_encoded(password.as_bytes(), &secure_rand(16), &config)
+            .map(|hash| PasswordHash { hash, pepper_id })
+            .map_err(|err| {
+                error!("PasswordHasher::hash: Error hashing: {:?}", err);
+                Error::InternalServerError
+            })
+    }
+
+    /// Comparing in constant time, as argon2 does
+    pub fn verify(&self, hash: &str, pepper_id: u8, password: &str) -> bool {
+        if password.len() > MAX_PASSWORD_LENGTH {
+            return false;
+        }
+        let pepper = match self.pepper(pepper_id) {
+            Ok(pepper) => pepper,
+            Err(_) => return false,
+        };
+        argon2::verify_encoded_ext(hash, password.as_bytes(), pepper.as_bytes(), &[])
+            .unwrap_or_else(|err| {
+                warn!("PasswordHasher::verify: Error verifying: {:?}", err);
+                false
+            })
+    }
+
+    /// Spend the time of a verification without a password to verify, always failing
+    pub fn verify_dummy(&self, password: &str) -> bool {
+        let _ = self.verify(&self.dummy_hash, self.peppers.active_id(), password);
+        false
+    }
+
+    /// Whether a verified password should be hashed again, after the pepper or cost changed
+    pub fn needs_rehash(&self, hash: &str, pepper_id: u8) -> bool {
+        let params = format!(
+            "$m={},t={},p={}$",
+            self.mem_cost, self.time_cost, self.lanes
+        );
+        pepper_id != self.peppers.active_id() || !hash.contains(&params)
+    }
+
+    fn pepper(&self, pepper_id: u8) -> Result<&str> {
+        self.peppers.get(pepper_id).ok_or_else(|| {
+            error!(
+                "PasswordHasher: PEPPER_{} is no longer configured",
+                pepper_id
+            );
+            Error::InternalServerError
+        })
+    }
+
+    fn argon2_config<'a>(&self, secret: &'a [u8]) -> argon2::Config<'a> {
+        argon2::Config {
+            variant: Variant::Argon2id,
+            version: Version::Version13,
+            mem_cost: self.mem_cost,
+            time_cost: self.time_cost,
+            lanes: self.lanes,
+            thread_mode: ThreadMode::Sequential,
+            secret,
+            ad: &[],
+            hash_length: 32,
+        }
+    }
+}
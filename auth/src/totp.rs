@@ -0,0 +1,251 @@
+//! Time-based one time passwords (RFC 6238) as a second factor, and sealing their secrets so
+//! a copy of the database is not enough to generate codes
+use ring::{aead, constant_time, digest, hmac};
+
+use crate::prelude::*;
+use crate::utils::{hex, secure_rand, secure_rand_hex};
+
+/// What authenticator apps expect by default: HMAC-SHA1, 30 second steps and 6 digits
+const STEP_SECS: u64 = 30;
+const DIGITS: u32 = 6;
+/// 160 bits, as RFC 4226 recommends
+const SECRET_LEN: usize = 20;
+/// Codes from the steps just before and after are accepted, for clocks which drift
+const ALLOWED_DRIFT_STEPS: u64 = 1;
+
+const NONCE_LEN: usize = 12;
+
+pub fn new_secret() -> Vec<u8> {
+    secure_rand(SECRET_LEN)
+}
+
+/// How the secret is shown to people who type it in instead of scanning the QR code
+pub fn encode_secret(secret: &[u8]) -> String {
+    base32::encode(base32::Alphabet::RFC4648 { padding: false }, secret)
+}
+
+/// For the QR code, https://github.com/google/google-authenticator/wiki/Key-Uri-Format
+pub fn provisioning_uri(issuer: &str, account_name: &str, secret: &[u8]) -> String {
+    let encode =
+        |value: &str| url::form_urlencoded::byte_serialize(value.as_bytes()).collect::<String>();
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        encode(issuer),
+        encode(account_name),
+        encode_secret(secret),
+        encode(issuer),
+        DIGITS,
+        STEP_SECS
+    )
+}
+
+/// The time step of a unix timestamp
+pub fn step_at(unix_secs: u64) -> u64 {
+    unix_secs / STEP_SECS
+}
+
+/// The step `code` is valid for around `now_step`, as long as it comes after `last_used_step`,
+/// so each code only works once
+pub fn verify_code(secret: &[u8], code: &str, now_step: u64, last_used_step: u64) -> Option<u64> {
+    let code = code.trim();
+    if code.len() != DIGITS as usize || !code.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let first_step = now_step.saturating_sub(ALLOWED_DRIFT_STEPS);
+    let mut matched = None;
+    // every step is checked, so timing doesn't tell which one matched
+    for step in first_step..=now_step + ALLOWED_DRIFT_STEPS {
+        let expected = format!("{:0width$}", code_at(secret, step), width = DIGITS as usize);
+        if constant_time::verify_slices_are_equal(expected.as_bytes(), code.as_bytes()).is_ok()
+            && step > last_used_step
+        {
+            matched = Some(step);
+        }
+    }
+    matched
+}
+
+/// HOTP (RFC 4226) for the counter `step`
+fn code_at(secret: &[u8], step: u64) -> u32 {
+    let key = hmac::SigningKey::new(&digest::SHA1, secret);
+    let signature = hmac::sign(&key, &step.to_be_bytes());
+    let hash = signature.as_ref();
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let binary = (u32::from(hash[offset]) & 0x7f) << 24
+        | u32::from(hash[offset + 1]) << 16
+        | u32::from(hash[offset + 2]) << 8
+        | u32::from(hash[offset + 3]);
+    binary % 10u32.pow(DIGITS)
+}
+
+/// Single use codes for when the authenticator is lost, shown to the person once
+pub fn new_recovery_codes(count: usize) -> Vec<String> {
+    (0..count)
+        .map(|_| {
+            let code = secure_rand_hex(5);
+            format!("{}-{}", &code[..5], &code[5..])
+        })
+        .collect()
+}
+
+/// Only hashes of recovery codes are stored. They are typed in by hand, so dashes, spaces and
+/// case don't matter.
+pub fn recovery_code_hash(code: &str) -> String {
+    let normalized: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    hex(digest::digest(&digest::SHA256, normalized.as_bytes()).as_ref())
+}
+
+/// Encrypts TOTP secrets with `TOTP_ENCRYPTION_KEY` before they are stored
+pub struct SecretSealer {
+    key_bytes: Option<Vec<u8>>,
+}
+
+impl SecretSealer {
+    /// `key` is 32 bytes in base64, or empty to turn off TOTP
+    pub fn new(key: &str) -> Self {
+        if key.is_empty() {
+            return SecretSealer { key_bytes: None };
+        }
+        let key_bytes = base64::decode(key)
+            .ok()
+            .filter(|bytes| bytes.len() == aead::CHACHA20_POLY1305.key_len())
+            .expect("TOTP_ENCRYPTION_KEY must be 32 bytes in base64");
+        SecretSealer {
+            key_bytes: Some(key_bytes),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.key_bytes.is_some()
+    }
+
+    /// base64 of the random nonce followed by the ciphertext and tag
+    pub fn seal(&self, secret: &[u8]) -> Result<String> {
+        let key = aead::SealingKey::new(&aead::CHACHA20_POLY1305, self.key_bytes()?)
+            .map_err(|_| Error::InternalServerError)?;
+        let nonce = secure_rand(NONCE_LEN);
+        let tag_len = aead::CHACHA20_POLY1305.tag_len();
+        let mut in_out = secret.to_vec();
+        in_out.extend(vec![0u8; tag_len]);
+        let out_len = aead::seal_in_place(&key, &nonce, &[], &mut in_out, tag_len)
+            .map_err(|_| Error::InternalServerError)?;
+        let mut sealed = nonce;
+        sealed.extend_from_slice(&in_out[..out_len]);
+        Ok(base64::encode(&sealed))
+    }
+
+    pub fn open(&self, sealed: &str) -> Result<Vec<u8>> {
+        let key = aead::OpeningKey::new(&aead::CHACHA20_POLY1305, self.key_bytes()?)
+            .map_err(|_| Error::InternalServerError)?;
+        let mut sealed = base64::decode(sealed).map_err(|_| Error::InternalServerError)?;
+        if sealed.len() < NONCE_LEN {
+            return Err(Error::InternalServerError);
+        }
+        let mut ciphertext = sealed.split_off(NONCE_LEN);
+        aead::open_in_place(&key, &sealed, &[], 0, &mut ciphertext)
+            .map(|secret| secret.to_vec())
+            .map_err(|_| {
+                error!("SecretSealer::open: TOTP secret can't be opened, was TOTP_ENCRYPTION_KEY changed?");
+                Error::InternalServerError
+            })
+    }
+
+    fn key_bytes(&self) -> Result<&[u8]> {
+        self.key_bytes.as_ref().map(Vec::as_slice).ok_or_else(|| {
+            Error::BadRequest(String::from("Two factor authentication is not enabled"))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The SHA1 secret from RFC 6238's test vectors
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    fn code(unix_secs: u64) -> String {
+        format!("{:06}", code_at(RFC_SECRET, step_at(unix_secs)))
+    }
+
+    #[test]
+    fn codes_match_rfc_6238_vectors() {
+        // the RFC's 8 digit codes, of which apps show the last 6
+        let vectors = [
+            (59, "94287082"),
+            (1_111_111_109, "07081804"),
+            (1_111_111_111, "14050471"),
+            (1_234_567_890, "89005924"),
+            (2_000_000_000, "69279037"),
+            (20_000_000_000, "65353130"),
+        ];
+        for (unix_secs, expected) in vectors.iter() {
+            assert_eq!(code(*unix_secs), &expected[2..], "at {}", unix_secs);
+        }
+    }
+
+    #[test]
+    fn verify_code_allows_drift_of_one_step() {
+        let now_step = step_at(1_111_111_111);
+        for step in &[now_step - 1, now_step, now_step + 1] {
+            let code = format!("{:06}", code_at(RFC_SECRET, *step));
+            assert_eq!(verify_code(RFC_SECRET, &code, now_step, 0), Some(*step));
+        }
+        for step in &[now_step - 2, now_step + 2] {
+            let code = format!("{:06}", code_at(RFC_SECRET, *step));
+            assert_eq!(verify_code(RFC_SECRET, &code, now_step, 0), None);
+        }
+    }
+
+    #[test]
+    fn verify_code_only_works_once() {
+        let now_step = step_at(1_111_111_111);
+        let code = code(1_111_111_111);
+        assert_eq!(
+            verify_code(RFC_SECRET, &code, now_step, now_step - 1),
+            Some(now_step)
+        );
+        assert_eq!(verify_code(RFC_SECRET, &code, now_step, now_step), None);
+    }
+
+    #[test]
+    fn verify_code_rejects_malformed_codes() {
+        let now_step = step_at(1_111_111_111);
+        assert_eq!(
+            verify_code(RFC_SECRET, " 050471\n", now_step, 0),
+            Some(now_step)
+        );
+        for malformed in &["", "50471", "0504710", "05047a", "14050471"] {
+            assert_eq!(verify_code(RFC_SECRET, malformed, now_step, 0), None);
+        }
+    }
+
+    #[test]
+    fn recovery_code_hash_ignores_formatting() {
+        assert_eq!(
+            recovery_code_hash("abcde-12345"),
+            recovery_code_hash("ABCDE 12345")
+        );
+        assert_ne!(
+            recovery_code_hash("abcde-12345"),
+            recovery_code_hash("abcde-12346")
+        );
+    }
+
+    #[test]
+    fn sealer_round_trip() {
+        let sealer = SecretSealer::new(&base64::encode(&[7u8; 32]));
+        let sealed = sealer.seal(RFC_SECRET).unwrap();
+        assert_eq!(sealer.open(&sealed).unwrap(), RFC_SECRET);
+        assert_ne!(sealed, sealer.seal(RFC_SECRET).unwrap());
+
+        let other = SecretSealer::new(&base64::encode(&[8u8; 32]));
+        assert!(other.open(&sealed).is_err());
+        assert!(!SecretSealer::new("").is_enabled());
+        assert!(SecretSealer::new("").seal(RFC_SECRET).is_err());
+    }
+}
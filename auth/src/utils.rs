@@ -24,11 +24,7 @@ pub fn hex(bytes: &[u8]) -> String {
 
 use short_crypt::ShortCrypt;
 
-pub fn enc(src: &[u8], key: &str) -> String {
-    // I'm sorry
-    ShortCrypt::new(key).encrypt_to_url_component(src)
-}
-
+/// Only used for reading access tokens issued before the versioned format
 pub fn dec(enc: &str, key: &str) -> Result<Vec<u8>, &'static str> {
     ShortCrypt::new(key).decrypt_url_component(enc)
 }
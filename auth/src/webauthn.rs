@@ -0,0 +1,556 @@
+//! Checking WebAuthn registrations and assertions, for signing in with passkeys and security keys.
+//!
+//! Attestation isn't verified, since we ask authenticators for none, so any authenticator can
+//! register as long as it can sign with one of [SUPPORTED_ALGS].
+use ring::{digest, signature};
+use serde_cbor::Value;
+use std::collections::BTreeMap;
+
+use crate::config::Config;
+use crate::prelude::*;
+use crate::utils::secure_rand;
+
+/// COSE algorithms of the public keys we can verify: ES256, EdDSA and RS256
+pub const SUPPORTED_ALGS: [i64; 3] = [-7, -8, -257];
+
+/// How long the browser should give the person to use their authenticator
+pub const TIMEOUT_MILLIS: u64 = 5 * 60 * 1000;
+
+const CHALLENGE_LEN: usize = 32;
+
+// Authenticator data flags
+const USER_PRESENT: u8 = 0x01;
+const USER_VERIFIED: u8 = 0x04;
+const ATTESTED_CREDENTIAL_DATA: u8 = 0x40;
+
+/// The site credentials are scoped to, and the origins it's served from
+pub struct RelyingParty {
+    pub id: String,
+    pub name: String,
+    origins: Vec<String>,
+}
+
+/// A credential from a registration, to be stored for the user
+pub struct RegisteredCredential {
+    pub credential_id: Vec<u8>,
+    /// COSE_Key, as the authenticator encoded it
+    pub public_key: Vec<u8>,
+    pub sign_count: u32,
+}
+
+/// A checked assertion
+pub struct Assertion {
+    pub sign_count: u32,
+}
+
+#[derive(Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    ceremony: String,
+    challenge: String,
+    origin: String,
+}
+
+struct AuthenticatorData<'a> {
+    rp_id_hash: &'a [u8],
+    flags: u8,
+    sign_count: u32,
+    /// Credential id and public key, present on registration
+    attested: Option<(&'a [u8], &'a [u8])>,
+}
+
+impl RelyingParty {
+    /// Defaults to the host of PUBLIC_URL, served from the origin of PUBLIC_URL
+    pub fn new(settings: &Config) -> Self {
+        let public_url = url::Url::parse(&settings.http_public_url).expect("PUBLIC_URL is a URL");
+        RelyingParty {
+            id: if settings.webauthn_rp_id.is_empty() {
+                public_url.host_str().unwrap_or("localhost").to_string()
+            } else {
+                settings.webauthn_rp_id.clone()
+            },
+            name: settings.webauthn_rp_name.clone(),
+            origins: if settings.webauthn_origins.trim().is_empty() {
+                vec![public_url.origin().ascii_serialization()]
+            } else {
+                settings
+                    .webauthn_origins
+                    .split_whitespace()
+                    .map(String::from)
+                    .collect()
+            },
+        }
+    }
+
+    /// Check a new credential from `navigator.credentials.create()`
+    pub fn verify_registration(
+        &self,
+        challenge: &str,
+        client_data_json: &[u8],
+        attestation_object: &[u8],
+        require_user_verification: bool,
+    ) -> Result<RegisteredCredential> {
+        self.check_client_data(client_data_json, "webauthn.create", challenge)?;
+        let attestation: BTreeMap<Value, Value> = serde_cbor::from_slice(attestation_object)
+            .map_err(|_| invalid("attestationObject is not CBOR"))?;
+        let auth_data = match attestation.get(&Value::Text(String::from("authData"))) {
+            Some(Value::Bytes(auth_data)) => auth_data,
+            _ => return Err(invalid("attestationObject has no authData")),
+        };
+        let auth_data = self.check_authenticator_data(auth_data, require_user_verification)?;
+        let (credential_id, public_key) = auth_data
+            .attested
+            .ok_or_else(|| invalid("authData has no attested credential"))?;
+        // catches keys we couldn't verify assertions from, before they're stored
+        CoseKey::parse(public_key)?;
+        Ok(RegisteredCredential {
+            credential_id: credential_id.to_vec(),
+            public_key: public_key.to_vec(),
+            sign_count: auth_data.sign_count,
+        })
+    }
+
+    /// Check an assertion from `navigator.credentials.get()` against a stored credential's key
+    pub fn verify_assertion(
+        &self,
+        challenge: &str,
+        public_key: &[u8],
+        client_data_json: &[u8],
+        authenticator_data: &[u8],
+        signature: &[u8],
+        require_user_verification: bool,
+    ) -> Result<Assertion> {
+        self.check_client_data(client_data_json, "webauthn.get", challenge)?;
+        let auth_data =
+            self.check_authenticator_data(authenticator_data, require_user_verification)?;
+        let mut signed = authenticator_data.to_vec();
+        signed.extend_from_slice(digest::digest(&digest::SHA256, client_data_json).as_ref());
+        CoseKey::parse(public_key)?
+            .verify(&signed, signature)
+            .map_err(|_| Error::Unauthorized(String::from("Passkey signature is invalid")))?;
+        Ok(Assertion {
+            sign_count: auth_data.sign_count,
+        })
+    }
+
+    fn check_client_data(
+        &self,
+        client_data_json: &[u8],
+        ceremony: &str,
+        challenge: &str,
+    ) -> Result<()> {
+        let client_data: ClientData = serde_json::from_slice(client_data_json)
+            .map_err(|_| invalid("clientDataJSON is not valid"))?;
+        if client_data.ceremony != ceremony {
+            return Err(invalid(&format!("clientDataJSON type is not {}", ceremony)));
+        }
+        if client_data.challenge.trim_end_matches('=') != challenge {
+            return Err(invalid("clientDataJSON is for a different challenge"));
+        }
+        if !self.origins.contains(&client_data.origin) {
+            return Err(invalid(&format!(
+                "{} is not an allowed origin",
+                client_data.origin
+            )));
+        }
+        Ok(())
+    }
+
+    fn check_authenticator_data<'a>(
+        &self,
+        data: &'a [u8],
+        require_user_verification: bool,
+    ) -> Result<AuthenticatorData<'a>> {
+        let auth_data = AuthenticatorData::parse(data)?;
+        let rp_id_hash = digest::digest(&digest::SHA256, self.id.as_bytes());
+        if auth_data.rp_id_hash != rp_id_hash.as_ref() {
+            return Err(invalid("authData is for a different relying party"));
+        }
+        if auth_data.flags & USER_PRESENT == 0 {
+            return Err(invalid("The user was not present"));
+        }
+        if require_user_verification && auth_data.flags & USER_VERIFIED == 0 {
+            return Err(invalid("The authenticator did not verify the user"));
+        }
+        Ok(auth_data)
+    }
+}
+
+impl<'a> AuthenticatorData<'a> {
+    fn parse(data: &'a [u8]) -> Result<Self> {
+        if data.len() < 37 {
+            return Err(invalid("authData is too short"));
+        }
+        let flags = data[32];
+        let sign_count = u32::from(data[33]) << 24
+            | u32::from(data[34]) << 16
+            | u32::from(data[35]) << 8
+            | u32::from(data[36]);
+        let attested = if flags & ATTESTED_CREDENTIAL_DATA != 0 {
+            // 16 bytes of AAGUID, then the credential id with its length
+            let rest = data
+                .get(37 + 16..)
+                .ok_or_else(|| invalid("authData is too short"))?;
+            if rest.len() < 2 {
+                return Err(invalid("authData is too short"));
+            }
+            let id_len = (rest[0] as usize) << 8 | rest[1] as usize;
+            let credential_id = rest
+                .get(2..2 + id_len)
+                .ok_or_else(|| invalid("authData is too short"))?;
+            let key_and_extensions = &rest[2 + id_len..];
+            // the public key is followed by extensions, so find where it ends
+            let mut deserializer = serde_cbor::Deserializer::from_slice(key_and_extensions);
+            serde::Deserialize::deserialize(&mut deserializer)
+                .map(|_: Value| ())
+                .map_err(|_| invalid("authData public key is not CBOR"))?;
+            let public_key = &key_and_extensions[..deserializer.byte_offset()];
+            Some((credential_id, public_key))
+        } else {
+            None
+        };
+        Ok(AuthenticatorData {
+            rp_id_hash: &data[..32],
+            flags,
+            sign_count,
+            attested,
+        })
+    }
+}
+
+/// A credential public key (RFC 8152 section 7)
+enum CoseKey {
+    /// Uncompressed P-256 point
+    Es256(Vec<u8>),
+    Ed25519(Vec<u8>),
+    Rs256 {
+        n: Vec<u8>,
+        e: Vec<u8>,
+    },
+}
+
+impl CoseKey {
+    fn parse(cose_key: &[u8]) -> Result<Self> {
+        let map: BTreeMap<Value, Value> =
+            serde_cbor::from_slice(cose_key).map_err(|_| invalid("Public key is not CBOR"))?;
+        let int = |label: i128| match map.get(&Value::Integer(label)) {
+            Some(Value::Integer(value)) => Some(*value),
+            _ => None,
+        };
+        let bytes = |label: i128| match map.get(&Value::Integer(label)) {
+            Some(Value::Bytes(value)) => Ok(value.clone()),
+            _ => Err(invalid("Public key is missing a parameter")),
+        };
+        // labels: 1 kty, 3 alg, -1 crv or n, -2 x or e, -3 y
+        match (int(1), int(3), int(-1)) {
+            (Some(2), Some(-7), Some(1)) => {
+                let mut point = vec![0x04];
+                point.extend(bytes(-2)?);
+                point.extend(bytes(-3)?);
+                Ok(CoseKey::Es256(point))
+            }
+            (Some(1), Some(-8), Some(6)) => Ok(CoseKey::Ed25519(bytes(-2)?)),
+            (Some(3), Some(-257), _) => Ok(CoseKey::Rs256 {
+                n: bytes(-1)?,
+                e: bytes(-2)?,
+            }),
+            _ => Err(invalid("Public key algorithm is not supported")),
+        }
+    }
+
+    fn verify(
+        &self,
+        message: &[u8],
+        sig: &[u8],
+    ) -> std::result::Result<(), ring::error::Unspecified> {
+        let message = untrusted::Input::from(message);
+        let sig = untrusted::Input::from(sig);
+        match *self {
+            CoseKey::Es256(ref point) => signature::verify(
+                &signature::ECDSA_P256_SHA256_ASN1,
+                untrusted::Input::from(point),
+                message,
+                sig,
+            ),
+            CoseKey::Ed25519(ref public_key) => signature::verify(
+                &signature::ED25519,
+                untrusted::Input::from(public_key),
+                message,
+                sig,
+            ),
+            CoseKey::Rs256 { ref n, ref e } => signature::primitive::verify_rsa(
+                &signature::RSA_PKCS1_2048_8192_SHA256,
+                (untrusted::Input::from(n), untrusted::Input::from(e)),
+                message,
+                sig,
+            ),
+        }
+    }
+}
+
+/// base64url without padding, how WebAuthn encodes binary in JSON
+pub fn encode(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+pub fn decode(value: &str) -> Result<Vec<u8>> {
+    base64::decode_config(value.trim_end_matches('='), base64::URL_SAFE_NO_PAD)
+        .map_err(|_| invalid("Expected base64url"))
+}
+
+pub fn new_challenge() -> String {
+    encode(&secure_rand(CHALLENGE_LEN))
+}
+
+fn invalid(message: &str) -> Error {
+    Error::BadRequest(format!("Invalid passkey response: {}", message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::rand::SystemRandom;
+
+    const RP_ID: &str = "example.com";
+    const ORIGIN: &str = "https://example.com";
+
+    fn relying_party() -> RelyingParty {
+        RelyingParty {
+            id: RP_ID.to_string(),
+            name: String::from("Example"),
+            origins: vec![ORIGIN.to_string()],
+        }
+    }
+
+    fn key_pair() -> signature::Ed25519KeyPair {
+        let pkcs8 = signature::Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        signature::Ed25519KeyPair::from_pkcs8(untrusted::Input::from(&pkcs8[..])).unwrap()
+    }
+
+    fn cose_key(entries: &[(i128, Value)]) -> Vec<u8> {
+        let map: BTreeMap<Value, Value> = entries
+            .iter()
+            .map(|(label, value)| (Value::Integer(*label), value.clone()))
+            .collect();
+        serde_cbor::to_vec(&Value::Map(map)).unwrap()
+    }
+
+    fn ed25519_cose_key(key_pair: &signature::Ed25519KeyPair) -> Vec<u8> {
+        cose_key(&[
+            (1, Value::Integer(1)),
+            (3, Value::Integer(-8)),
+            (-1, Value::Integer(6)),
+            (-2, Value::Bytes(key_pair.public_key_bytes().to_vec())),
+        ])
+    }
+
+    fn authenticator_data(flags: u8, sign_count: u32) -> Vec<u8> {
+        let mut data = digest::digest(&digest::SHA256, RP_ID.as_bytes())
+            .as_ref()
+            .to_vec();
+        data.push(flags);
+        data.extend_from_slice(&sign_count.to_be_bytes());
+        data
+    }
+
+    fn attested_data(credential_id: &[u8], public_key: &[u8]) -> Vec<u8> {
+        let mut data = authenticator_data(USER_PRESENT | ATTESTED_CREDENTIAL_DATA, 0);
+        data.extend_from_slice(&[0; 16]);
+        data.extend_from_slice(&[0, credential_id.len() as u8]);
+        data.extend_from_slice(credential_id);
+        data.extend_from_slice(public_key);
+        data
+    }
+
+    fn client_data(ceremony: &str, challenge: &str, origin: &str) -> Vec<u8> {
+        json!({
+            "type": ceremony,
+            "challenge": challenge,
+            "origin": origin,
+        })
+        .to_string()
+        .into_bytes()
+    }
+
+    #[test]
+    fn parse_assertion_data() {
+        let data = authenticator_data(USER_PRESENT | USER_VERIFIED, 0x0102_0304);
+        let parsed = AuthenticatorData::parse(&data).unwrap();
+        assert_eq!(parsed.rp_id_hash, &data[..32]);
+        assert_eq!(parsed.flags, USER_PRESENT | USER_VERIFIED);
+        assert_eq!(parsed.sign_count, 0x0102_0304);
+        assert!(parsed.attested.is_none());
+    }
+
+    #[test]
+    fn parse_attested_data_finds_end_of_public_key() {
+        let public_key = ed25519_cose_key(&key_pair());
+        let mut data = attested_data(b"credential", &public_key);
+        // extensions follow the public key
+        let mut extensions = BTreeMap::new();
+        extensions.insert(Value::Text(String::from("credProtect")), Value::Integer(1));
+        data.extend(serde_cbor::to_vec(&Value::Map(extensions)).unwrap());
+
+        let parsed = AuthenticatorData::parse(&data).unwrap();
+        let (credential_id, parsed_key) = parsed.attested.unwrap();
+        assert_eq!(credential_id, b"credential");
+        assert_eq!(parsed_key, &public_key[..]);
+    }
+
+    #[test]
+    fn parse_rejects_truncated_data() {
+        let data = authenticator_data(USER_PRESENT, 0);
+        assert!(AuthenticatorData::parse(&data[..36]).is_err());
+
+        let public_key = ed25519_cose_key(&key_pair());
+        let data = attested_data(b"credential", &public_key);
+        // cut inside the AAGUID, the credential id and the public key
+        for len in &[40, 37 + 16 + 2 + 4, data.len() - 1] {
+            assert!(
+                AuthenticatorData::parse(&data[..*len]).is_err(),
+                "at {}",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn parse_supported_cose_keys() {
+        match CoseKey::parse(&ed25519_cose_key(&key_pair())).unwrap() {
+            CoseKey::Ed25519(public_key) => assert_eq!(public_key.len(), 32),
+            _ => panic!("Expected an Ed25519 key"),
+        }
+        let es256 = cose_key(&[
+            (1, Value::Integer(2)),
+            (3, Value::Integer(-7)),
+            (-1, Value::Integer(1)),
+            (-2, Value::Bytes(vec![1; 32])),
+            (-3, Value::Bytes(vec![2; 32])),
+        ]);
+        match CoseKey::parse(&es256).unwrap() {
+            CoseKey::Es256(point) => {
+                assert_eq!(point.len(), 65);
+                assert_eq!(point[0], 0x04);
+            }
+            _ => panic!("Expected an ES256 key"),
+        }
+        let rs256 = cose_key(&[
+            (1, Value::Integer(3)),
+            (3, Value::Integer(-257)),
+            (-1, Value::Bytes(vec![0xc5; 256])),
+            (-2, Value::Bytes(vec![1, 0, 1])),
+        ]);
+        match CoseKey::parse(&rs256).unwrap() {
+            CoseKey::Rs256 { n, e } => {
+                assert_eq!(n.len(), 256);
+                assert_eq!(e, vec![1, 0, 1]);
+            }
+            _ => panic!("Expected an RS256 key"),
+        }
+    }
+
+    #[test]
+    fn parse_rejects_unsupported_cose_keys() {
+        // ES256 without y
+        let missing = cose_key(&[
+            (1, Value::Integer(2)),
+            (3, Value::Integer(-7)),
+            (-1, Value::Integer(1)),
+            (-2, Value::Bytes(vec![1; 32])),
+        ]);
+        // ES384
+        let unsupported = cose_key(&[
+            (1, Value::Integer(2)),
+            (3, Value::Integer(-35)),
+            (-1, Value::Integer(2)),
+            (-2, Value::Bytes(vec![1; 48])),
+            (-3, Value::Bytes(vec![2; 48])),
+        ]);
+        for invalid_key in &[missing, unsupported, b"not cbor".to_vec()] {
+            assert!(CoseKey::parse(invalid_key).is_err());
+        }
+    }
+
+    #[test]
+    fn verify_registration_and_assertion() {
+        let rp = relying_party();
+        let key_pair = key_pair();
+        let challenge = new_challenge();
+        let mut attestation = BTreeMap::new();
+        attestation.insert(
+            Value::Text(String::from("authData")),
+            Value::Bytes(attested_data(b"credential", &ed25519_cose_key(&key_pair))),
+        );
+        let attestation = serde_cbor::to_vec(&Value::Map(attestation)).unwrap();
+        let registered = rp
+            .verify_registration(
+                &challenge,
+                &client_data("webauthn.create", &challenge, ORIGIN),
+                &attestation,
+                false,
+            )
+            .unwrap();
+        assert_eq!(registered.credential_id, b"credential");
+        // user verification wasn't flagged
+        assert!(rp
+            .verify_registration(
+                &challenge,
+                &client_data("webauthn.create", &challenge, ORIGIN),
+                &attestation,
+                true,
+            )
+            .is_err());
+
+        let challenge = new_challenge();
+        let auth_data = authenticator_data(USER_PRESENT, 7);
+        let sign = |client_data_json: &[u8]| {
+            let mut signed = auth_data.clone();
+            signed.extend_from_slice(digest::digest(&digest::SHA256, client_data_json).as_ref());
+            key_pair.sign(&signed).as_ref().to_vec()
+        };
+        let client_data_json = client_data("webauthn.get", &challenge, ORIGIN);
+        let signature = sign(&client_data_json);
+        let assertion = rp
+            .verify_assertion(
+                &challenge,
+                &registered.public_key,
+                &client_data_json,
+                &auth_data,
+                &signature,
+                false,
+            )
+            .unwrap();
+        assert_eq!(assertion.sign_count, 7);
+
+        // another challenge, ceremony or origin, even when signed
+        for client_data_json in &[
+            client_data("webauthn.get", &new_challenge(), ORIGIN),
+            client_data("webauthn.create", &challenge, ORIGIN),
+            client_data("webauthn.get", &challenge, "https://evil.example"),
+        ] {
+            let signature = sign(client_data_json);
+            assert!(rp
+                .verify_assertion(
+                    &challenge,
+                    &registered.public_key,
+                    client_data_json,
+                    &auth_data,
+                    &signature,
+                    false,
+                )
+                .is_err());
+        }
+        let mut tampered = signature.clone();
+        tampered[0] ^= 1;
+        assert!(rp
+            .verify_assertion(
+                &challenge,
+                &registered.public_key,
+                &client_data_json,
+                &auth_data,
+                &tampered,
+                false,
+            )
+            .is_err());
+    }
+}
@@ -0,0 +1,122 @@
+//! Signing in with a provider from login_url to the user session, with src/bin/mock_idp.rs in
+//! place of Google. See tests/support for what these tests need to run.
+mod support;
+
+use serde_json::Value;
+
+use support::{mock_user, query_param, with_query_param, TestEnv};
+
+#[test]
+#[ignore]
+fn sign_in_and_register() {
+    let user = mock_user("provider");
+    let env = TestEnv::start(&[user.clone()]);
+
+    let login_token = env.login_session();
+    let (login_url, handoff) = env.login_url(&login_token);
+    assert!(query_param(&login_url, "state").is_some());
+    assert_eq!(
+        query_param(&login_url, "code_challenge_method")
+            .as_ref()
+            .map(String::as_str),
+        Some("S256")
+    );
+    let callback_url = env.authorize(&login_url);
+    assert!(callback_url.starts_with(&env.url("google/callback")));
+    let landed = env.callback(&callback_url, Some(&handoff));
+    assert_eq!(landed, format!("{}/", env.public_url));
+
+    let session = env.get(&login_token, "login/session").json();
+    assert_eq!(session["i_am"]["provider"], "google");
+    assert_eq!(session["i_am"]["email"], user["email"]);
+    assert_eq!(session["user_id"], Value::Null);
+
+    let registered = env.post(&login_token, "login/session/register", None);
+    assert_eq!(registered.status, 200, "{}", registered.text());
+    let user_token = env.user_session(&login_token);
+    let me = env.get(&user_token, "me");
+    assert_eq!(me.status, 200, "{}", me.text());
+    assert_eq!(me.json()["user_id"], registered.json()["user"]["id"]);
+}
+
+#[test]
+#[ignore]
+fn wrong_state_is_refused() {
+    let env = TestEnv::start(&[mock_user("state")]);
+
+    // a state we never handed out
+    let login_token = env.login_session();
+    let (login_url, handoff) = env.login_url(&login_token);
+    let callback_url = env.authorize(&login_url);
+    let forged = with_query_param(&callback_url, "state", "0123456789abcdef");
+    let landed = env.callback(&forged, Some(&handoff));
+    assert_eq!(
+        query_param(&landed, "error").as_ref().map(String::as_str),
+        Some("expired_state")
+    );
+
+    // the right state, in browsers which didn't start the login
+    for other_browser in &[Some("another-browser"), None] {
+        let landed = env.callback(&callback_url, *other_browser);
+        assert_eq!(
+            query_param(&landed, "error").as_ref().map(String::as_str),
+            Some("browser_mismatch")
+        );
+    }
+    let session = env.get(&login_token, "login/session").json();
+    assert_eq!(session["i_am"], Value::Null);
+
+    // which leaves it for the browser which did
+    let landed = env.callback(&callback_url, Some(&handoff));
+    assert_eq!(landed, format!("{}/", env.public_url));
+}
+
+#[test]
+#[ignore]
+fn start_url_is_bound_to_first_browser() {
+    let env = TestEnv::start(&[mock_user("start")]);
+
+    let login_token = env.login_session();
+    let start_url = env.start_url(&login_token);
+    let started = env.start(&start_url, None);
+    assert_eq!(started.status, 302, "{}", started.text());
+    let handoff = started.cookie("knot_handoff").unwrap();
+
+    // starting again in the same browser is fine, in another one it isn't
+    let again = env.start(&start_url, Some(&handoff));
+    assert_eq!(again.status, 302, "{}", again.text());
+    assert_eq!(again.header("location"), started.header("location"));
+    let elsewhere = env.start(&start_url, None);
+    assert_eq!(elsewhere.status, 302, "{}", elsewhere.text());
+    assert_eq!(
+        query_param(elsewhere.header("location").unwrap(), "error")
+            .as_ref()
+            .map(String::as_str),
+        Some("browser_mismatch")
+    );
+
+    let callback_url = env.authorize(started.header("location").unwrap());
+    let landed = env.callback(&callback_url, Some(&handoff));
+    assert_eq!(landed, format!("{}/", env.public_url));
+}
+
+#[test]
+#[ignore]
+fn reused_handoff_is_refused() {
+    let env = TestEnv::start(&[mock_user("reused")]);
+
+    let login_token = env.login_session();
+    let (login_url, handoff) = env.login_url(&login_token);
+    let callback_url = env.authorize(&login_url);
+    let landed = env.callback(&callback_url, Some(&handoff));
+    assert_eq!(query_param(&landed, "error"), None);
+
+    let landed = env.callback(&callback_url, Some(&handoff));
+    assert_eq!(
+        query_param(&landed, "error").as_ref().map(String::as_str),
+        Some("reused_state")
+    );
+    // the login which did complete still stands
+    let session = env.get(&login_token, "login/session").json();
+    assert_eq!(session["i_am"]["provider"], "google");
+}
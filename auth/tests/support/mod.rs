@@ -0,0 +1,431 @@
+//! Runs the auth server and the mock identity provider as child processes, and talks to them
+//! over plain HTTP/1.1 like a browser without JavaScript would.
+//!
+//! The tests need a migrated Postgres database and a Redis, so they are ignored by default:
+//!
+//! ```sh
+//! TEST_DATABASE_URL=postgres://postgres:@localhost:5432/knot_test \
+//! TEST_REDIS_URL=127.0.0.1:6379 \
+//!   cargo test -- --ignored
+//! ```
+// each test file only uses some of the helpers
+#![allow(dead_code)]
+
+use ring::rand::{SecureRandom, SystemRandom};
+use serde_json::{json, Value};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+/// The auth server and mock identity provider for one test, stopped when dropped
+pub struct TestEnv {
+    pub dir: PathBuf,
+    pub public_url: String,
+    pub idp_url: String,
+    children: Vec<Child>,
+}
+
+impl TestEnv {
+    /// Start both servers. The first of `users` is who the mock identity provider signs in.
+    pub fn start(users: &[Value]) -> TestEnv {
+        let database_url = std::env::var("TEST_DATABASE_URL")
+            .expect("TEST_DATABASE_URL must point at a migrated database");
+        let redis_url =
+            std::env::var("TEST_REDIS_URL").unwrap_or_else(|_| String::from("127.0.0.1:6379"));
+        let dir = std::env::temp_dir().join(format!("auth-test-{}", unique_id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let idp_address = free_address();
+        let users_path = dir.join("mock_idp_users.json");
+        std::fs::write(&users_path, serde_json::to_vec(users).unwrap()).unwrap();
+        let idp = Command::new(bin("mock_idp"))
+            .current_dir(&dir)
+            .env("MOCK_IDP_BIND_ADDRESS", &idp_address)
+            .env("MOCK_IDP_USERS", &users_path)
+            .stdout(Stdio::null())
+            .spawn()
+            .expect("mock_idp starts");
+        let idp_url = format!("http://{}", idp_address);
+
+        let auth_address = free_address();
+        let public_url = format!("http://{}", auth_address);
+        let settings = [
+            ("DATABASE_URL", database_url),
+            ("REDIS_URL", redis_url),
+            ("HTTP_BIND_ADDRESS", auth_address.clone()),
+            ("PUBLIC_URL", public_url.clone()),
+            ("PEPPER_0", String::from("test-pepper")),
+            ("PEPPER_ACTIVE", String::from("0")),
+            ("GOOGLE_OAUTH_CLIENT_ID", String::from("test-client")),
+            ("GOOGLE_OAUTH_CLIENT_SECRET", String::from("test-secret")),
+            ("GOOGLE_AUTHORIZE_URL", format!("{}/authorize", idp_url)),
+            ("GOOGLE_TOKEN_URL", format!("{}/token", idp_url)),
+            ("GOOGLE_PEOPLE_URL", format!("{}/people/me", idp_url)),
+            ("SESSION_COOKIE_SECURE", String::from("false")),
+            ("WEBAUTHN", String::from("true")),
+            ("MAILER", String::from("file")),
+            (
+                "MAIL_FILE_DIR",
+                dir.join("mail").to_string_lossy().into_owned(),
+            ),
+            ("JWT_PRIVATE_KEY_PATH", String::new()),
+        ];
+        // the server reads its settings from .env in its working directory
+        let dotenv: String = settings
+            .iter()
+            .map(|(name, value)| format!("{}={}\n", name, value))
+            .collect();
+        std::fs::write(dir.join(".env"), dotenv).unwrap();
+        let auth = Command::new(bin("auth"))
+            .current_dir(&dir)
+            .envs(settings.iter().map(|(name, value)| (*name, value.as_str())))
+            .stdout(Stdio::null())
+            .spawn()
+            .expect("auth starts");
+
+        let env = TestEnv {
+            dir,
+            public_url,
+            idp_url,
+            children: vec![idp, auth],
+        };
+        wait_for(&idp_address);
+        wait_for(&auth_address);
+        env
+    }
+
+    /// URL of an `/auth/v0` route
+    pub fn url(&self, path: &str) -> String {
+        format!("{}/auth/v0/{}", self.public_url, path)
+    }
+
+    /// A new login session's token
+    pub fn login_session(&self) -> String {
+        let response = request("POST", &self.url("login/session"), &[], None);
+        assert_eq!(response.status, 200, "{}", response.text());
+        response.json()["access_token"]
+            .as_str()
+            .expect("Bearer mode responds with the token")
+            .to_string()
+    }
+
+    /// The url the app sends the browser to for a Google login of the login session
+    pub fn start_url(&self, login_token: &str) -> String {
+        let response = self.post(login_token, "google/login_url", None);
+        assert_eq!(response.status, 200, "{}", response.text());
+        assert_eq!(response.cookie("knot_handoff"), None);
+        response.json()["url"].as_str().unwrap().to_string()
+    }
+
+    /// Open the start url in a browser holding the `handoff` cookie, or in a new browser
+    pub fn start(&self, start_url: &str, handoff: Option<&str>) -> Response {
+        assert!(
+            start_url.starts_with(&self.url("google/start")),
+            "{}",
+            start_url
+        );
+        browser_get(start_url, handoff)
+    }
+
+    /// Start a Google login for the login session in a new browser, resolving with the mock
+    /// identity provider's url and the browser's handoff cookie
+    pub fn login_url(&self, login_token: &str) -> (String, String) {
+        let response = self.start(&self.start_url(login_token), None);
+        assert_eq!(response.status, 302, "{}", response.text());
+        let handoff = response
+            .cookie("knot_handoff")
+            .expect("the start url sets the handoff cookie");
+        (response.header("location").unwrap().to_string(), handoff)
+    }
+
+    /// The callback url the mock identity provider sends the browser back to
+    pub fn authorize(&self, login_url: &str) -> String {
+        assert!(login_url.starts_with(&self.idp_url), "{}", login_url);
+        let response = request("GET", login_url, &[], None);
+        assert_eq!(response.status, 302, "{}", response.text());
+        response.header("location").unwrap().to_string()
+    }
+
+    /// Open the callback url, with the handoff cookie when there is one, resolving with where
+    /// the browser is sent next
+    pub fn callback(&self, callback_url: &str, handoff: Option<&str>) -> String {
+        let response = browser_get(callback_url, handoff);
+        assert_eq!(response.status, 302, "{}", response.text());
+        response.header("location").unwrap().to_string()
+    }
+
+    /// Sign in through the mock identity provider, registering the user if they are new.
+    /// Resolves with the login session token, which is linked to the user.
+    pub fn sign_in_with_google(&self) -> String {
+        let login_token = self.login_session();
+        let (login_url, handoff) = self.login_url(&login_token);
+        let callback_url = self.authorize(&login_url);
+        let landed = self.callback(&callback_url, Some(&handoff));
+        assert_eq!(landed, format!("{}/", self.public_url));
+        let response = self.post(&login_token, "login/session/register", None);
+        assert_eq!(response.status, 200, "{}", response.text());
+        login_token
+    }
+
+    /// The user session token for a login session which has signed in
+    pub fn user_session(&self, login_token: &str) -> String {
+        let response = self.post(login_token, "login/session/user", None);
+        assert_eq!(response.status, 200, "{}", response.text());
+        response.json()["access_token"]
+            .as_str()
+            .unwrap()
+            .to_string()
+    }
+
+    /// POST to an `/auth/v0` route with a token
+    pub fn post(&self, token: &str, path: &str, body: Option<&Value>) -> Response {
+        let authorization = bearer(token);
+        request(
+            "POST",
+            &self.url(path),
+            &[("Authorization", authorization.as_str())],
+            body,
+        )
+    }
+
+    pub fn get(&self, token: &str, path: &str) -> Response {
+        let authorization = bearer(token);
+        request(
+            "GET",
+            &self.url(path),
+            &[("Authorization", authorization.as_str())],
+            None,
+        )
+    }
+}
+
+impl Drop for TestEnv {
+    fn drop(&mut self) {
+        for child in &mut self.children {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// A user for the mock identity provider, with an id no earlier run has used
+pub fn mock_user(name: &str) -> Value {
+    let id = (1_000_000 + rand_u32() % 1_000_000_000).to_string();
+    json!({
+        "id": id,
+        "email": format!("{}-{}@example.com", name, id),
+        "name": name,
+        "given_name": name,
+    })
+}
+
+pub struct Response {
+    pub status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Value of a cookie the response sets
+    pub fn cookie(&self, name: &str) -> Option<String> {
+        let prefix = format!("{}=", name);
+        self.headers
+            .iter()
+            .filter(|(header, _)| header.eq_ignore_ascii_case("set-cookie"))
+            .filter_map(|(_, value)| value.split(';').next())
+            .find(|pair| pair.starts_with(&prefix))
+            .map(|pair| pair[prefix.len()..].to_string())
+    }
+
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+
+    pub fn json(&self) -> Value {
+        serde_json::from_slice(&self.body)
+            .unwrap_or_else(|err| panic!("{} is not JSON: {}", self.text(), err))
+    }
+}
+
+/// Navigate to the url, sending the handoff cookie when the browser has one
+pub fn browser_get(url: &str, handoff: Option<&str>) -> Response {
+    let cookie = handoff.map(|handoff| format!("knot_handoff={}", handoff));
+    let headers: Vec<(&str, &str)> = cookie
+        .as_ref()
+        .map(|cookie| vec![("Cookie", cookie.as_str())])
+        .unwrap_or_default();
+    request("GET", url, &headers, None)
+}
+
+/// Send one request on its own connection
+pub fn request(
+    method: &str,
+    url: &str,
+    headers: &[(&str, &str)],
+    body: Option<&Value>,
+) -> Response {
+    let url = url::Url::parse(url).unwrap();
+    let address = format!(
+        "{}:{}",
+        url.host_str().unwrap(),
+        url.port_or_known_default().unwrap()
+    );
+    let path = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    };
+    let body = body.map(|body| body.to_string()).unwrap_or_default();
+
+    let mut message = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: {}\r\n",
+        method,
+        path,
+        address,
+        body.len()
+    );
+    if !body.is_empty() {
+        message.push_str("Content-Type: application/json\r\n");
+    }
+    for (name, value) in headers {
+        message.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    message.push_str("\r\n");
+    message.push_str(&body);
+
+    let mut stream = TcpStream::connect(&address).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(30)))
+        .unwrap();
+    stream.write_all(message.as_bytes()).unwrap();
+    let mut raw = Vec::new();
+    stream.read_to_end(&mut raw).unwrap();
+    parse_response(&raw)
+}
+
+fn parse_response(raw: &[u8]) -> Response {
+    let head_end = raw
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .expect("Response has headers");
+    let head = String::from_utf8_lossy(&raw[..head_end]);
+    let mut lines = head.split("\r\n");
+    let status = lines
+        .next()
+        .and_then(|status_line| status_line.split(' ').nth(1))
+        .and_then(|code| code.parse().ok())
+        .expect("Response has a status");
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| {
+            let mut parts = line.splitn(2, ':');
+            match (parts.next(), parts.next()) {
+                (Some(name), Some(value)) => Some((name.to_string(), value.trim().to_string())),
+                _ => None,
+            }
+        })
+        .collect();
+    let mut body = raw[head_end + 4..].to_vec();
+    let chunked = headers.iter().any(|(name, value)| {
+        name.eq_ignore_ascii_case("transfer-encoding") && value.eq_ignore_ascii_case("chunked")
+    });
+    if chunked {
+        body = dechunk(&body);
+    }
+    Response {
+        status,
+        headers,
+        body,
+    }
+}
+
+fn dechunk(mut chunked: &[u8]) -> Vec<u8> {
+    let mut body = Vec::new();
+    loop {
+        let line_end = chunked
+            .windows(2)
+            .position(|window| window == b"\r\n")
+            .expect("Chunk has a size");
+        let size_line = String::from_utf8_lossy(&chunked[..line_end]);
+        let size = usize::from_str_radix(size_line.split(';').next().unwrap().trim(), 16).unwrap();
+        if size == 0 {
+            return body;
+        }
+        let start = line_end + 2;
+        body.extend_from_slice(&chunked[start..start + size]);
+        chunked = &chunked[start + size + 2..];
+    }
+}
+
+pub fn bearer(token: &str) -> String {
+    format!("Bearer {}", token)
+}
+
+/// Replace `name`'s value in the query of `url`
+pub fn with_query_param(url: &str, name: &str, value: &str) -> String {
+    let mut url = url::Url::parse(url).unwrap();
+    let pairs: Vec<(String, String)> = url
+        .query_pairs()
+        .filter(|(key, _)| key != name)
+        .map(|(key, value)| (key.into_owned(), value.into_owned()))
+        .collect();
+    url.query_pairs_mut()
+        .clear()
+        .extend_pairs(pairs)
+        .append_pair(name, value);
+    url.into_string()
+}
+
+/// The value of `name` in the query of `url`
+pub fn query_param(url: &str, name: &str) -> Option<String> {
+    url::Url::parse(url)
+        .unwrap()
+        .query_pairs()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.into_owned())
+}
+
+/// Binaries of this package, which cargo builds next to the test's own directory
+pub fn bin(name: &str) -> PathBuf {
+    let test_exe = std::env::current_exe().unwrap();
+    let target_dir = test_exe
+        .parent()
+        .and_then(Path::parent)
+        .expect("Tests run from target/<profile>/deps");
+    target_dir.join(format!("{}{}", name, std::env::consts::EXE_SUFFIX))
+}
+
+fn free_address() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().to_string()
+}
+
+fn wait_for(address: &str) {
+    let started = Instant::now();
+    while TcpStream::connect(address).is_err() {
+        assert!(
+            started.elapsed() < Duration::from_secs(30),
+            "Nothing listening on {} yet",
+            address
+        );
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
+
+fn unique_id() -> String {
+    format!("{}-{}", std::process::id(), rand_u32())
+}
+
+fn rand_u32() -> u32 {
+    let mut bytes = [0u8; 4];
+    SystemRandom::new().fill(&mut bytes).unwrap();
+    u32::from_be_bytes(bytes)
+}
@@ -0,0 +1,170 @@
+//! Registering passkeys and signing in with them, with src/bin/soft_authenticator.rs in place of
+//! the browser and authenticator. See tests/support for what these tests need to run.
+mod support;
+
+use serde_json::Value;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+use support::{bin, mock_user, TestEnv};
+
+fn keys_path(env: &TestEnv) -> PathBuf {
+    env.dir.join("soft_authenticator_keys.json")
+}
+
+/// Answer the server's options like `navigator.credentials.create()` or `.get()` would
+fn soft_authenticator(env: &TestEnv, ceremony: &str, options: &Value) -> Value {
+    let mut child = Command::new(bin("soft_authenticator"))
+        .args(&[ceremony, env.public_url.as_str()])
+        .env("SOFT_AUTHENTICATOR_KEYS", keys_path(env))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("soft_authenticator starts");
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(options.to_string().as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(
+        output.status.success(),
+        "soft_authenticator {} failed",
+        ceremony
+    );
+    serde_json::from_slice(&output.stdout).unwrap()
+}
+
+/// A signed in user with a passkey, and their user session token
+fn user_with_passkey(env: &TestEnv) -> String {
+    let user_token = env.user_session(&env.sign_in_with_google());
+    let options = env.post(&user_token, "me/webauthn/register/start", None);
+    assert_eq!(options.status, 200, "{}", options.text());
+    let credential = soft_authenticator(env, "create", &options.json());
+    let registered = env.post(
+        &user_token,
+        "me/webauthn/register/finish",
+        Some(&credential),
+    );
+    assert_eq!(registered.status, 200, "{}", registered.text());
+    user_token
+}
+
+/// The passkey's answer to a new challenge for the login session
+fn assertion(env: &TestEnv, login_token: &str) -> Value {
+    let options = env.post(login_token, "login/session/webauthn/start", None);
+    assert_eq!(options.status, 200, "{}", options.text());
+    soft_authenticator(env, "get", &options.json())
+}
+
+#[test]
+#[ignore]
+fn register_and_sign_in_with_passkey() {
+    let env = TestEnv::start(&[mock_user("passkey")]);
+    let user_token = user_with_passkey(&env);
+    let credentials = env.get(&user_token, "me/webauthn").json();
+    assert_eq!(credentials["credentials"].as_array().unwrap().len(), 1);
+
+    // a passkey signs in on its own
+    let login_token = env.login_session();
+    let finished = env.post(
+        &login_token,
+        "login/session/webauthn/finish",
+        Some(&assertion(&env, &login_token)),
+    );
+    assert_eq!(finished.status, 200, "{}", finished.text());
+    assert_eq!(
+        env.get(&login_token, "login/session").json()["second_factor"],
+        "satisfied"
+    );
+    env.user_session(&login_token);
+
+    // and is asked for as the second factor after signing in with Google
+    let login_token = env.sign_in_with_google();
+    let refused = env.post(&login_token, "login/session/user", None);
+    assert_eq!(refused.status, 401);
+    assert_eq!(refused.json()["error"], "second_factor_required");
+    let finished = env.post(
+        &login_token,
+        "login/session/webauthn/finish",
+        Some(&assertion(&env, &login_token)),
+    );
+    assert_eq!(finished.status, 200, "{}", finished.text());
+    env.user_session(&login_token);
+}
+
+#[test]
+#[ignore]
+fn replayed_assertion_is_refused() {
+    let env = TestEnv::start(&[mock_user("replay")]);
+    user_with_passkey(&env);
+
+    let login_token = env.login_session();
+    let answered = assertion(&env, &login_token);
+    let finished = env.post(
+        &login_token,
+        "login/session/webauthn/finish",
+        Some(&answered),
+    );
+    assert_eq!(finished.status, 200, "{}", finished.text());
+
+    // the challenge was taken by the first answer
+    let replayed = env.post(
+        &login_token,
+        "login/session/webauthn/finish",
+        Some(&answered),
+    );
+    assert_eq!(replayed.status, 400);
+    assert_eq!(replayed.json(), "Start signing in with the passkey again");
+
+    // and another login session's challenge is different
+    let other_token = env.login_session();
+    assertion(&env, &other_token);
+    let replayed = env.post(
+        &other_token,
+        "login/session/webauthn/finish",
+        Some(&answered),
+    );
+    assert_eq!(replayed.status, 400);
+    assert_eq!(
+        replayed.json(),
+        "Invalid passkey response: clientDataJSON is for a different challenge"
+    );
+}
+
+#[test]
+#[ignore]
+fn copied_passkey_is_refused() {
+    let env = TestEnv::start(&[mock_user("copied")]);
+    user_with_passkey(&env);
+    let copy = env.dir.join("copied_keys.json");
+    std::fs::copy(keys_path(&env), &copy).unwrap();
+
+    let login_token = env.login_session();
+    let finished = env.post(
+        &login_token,
+        "login/session/webauthn/finish",
+        Some(&assertion(&env, &login_token)),
+    );
+    assert_eq!(finished.status, 200, "{}", finished.text());
+
+    // the copy's signature counter is behind the one which was used
+    std::fs::copy(&copy, keys_path(&env)).unwrap();
+    let login_token = env.login_session();
+    let refused = env.post(
+        &login_token,
+        "login/session/webauthn/finish",
+        Some(&assertion(&env, &login_token)),
+    );
+    assert_eq!(refused.status, 401);
+    assert_eq!(
+        refused.json(),
+        "Passkey may have been copied, register it again"
+    );
+    assert_eq!(
+        env.get(&login_token, "login/session").json()["user_id"],
+        Value::Null
+    );
+}